@@ -0,0 +1,696 @@
+//! A BFT finality gadget.
+//!
+//! A fixed set of validators agrees on the block hash at a height through rounds of
+//! propose, prevote and precommit, in the style of Tendermint. A hash that gathers
+//! precommits from more than two thirds of the validators in one round is final, and those
+//! precommits form a `FinalityCertificate` that anyone knowing the `ValidatorSet` can check.
+//!
+//! `Validator` only consumes and produces `Message`s. `SimulatedNetwork` delivers them in
+//! process, in order, so that runs are deterministic.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AuthKeyPair, DigitalSignature, Hash, PublicKey, SigningError};
+
+/// The keys allowed to vote, in proposer order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorSet {
+    validators: Vec<PublicKey>,
+}
+
+impl ValidatorSet {
+    pub fn new(validators: Vec<PublicKey>) -> Self {
+        Self { validators }
+    }
+
+    pub fn validators(&self) -> &[PublicKey] {
+        &self.validators
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.validators.contains(key)
+    }
+
+    /// The number of votes needed to decide: `2f + 1` out of `3f + 1` validators.
+    pub fn quorum(&self) -> usize {
+        self.len() * 2 / 3 + 1
+    }
+
+    /// Returns the validator that proposes in `round` at `height`.
+    pub fn proposer(&self, height: u64, round: u64) -> Option<&PublicKey> {
+        match self.len() as u64 {
+            0 => None,
+            len => self
+                .validators
+                .get((height.wrapping_add(round) % len) as usize),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A signed vote for a block hash, or for nothing (`nil`), in a round.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vote {
+    kind: VoteKind,
+    height: u64,
+    round: u64,
+    block_hash: Option<Hash>,
+    voter: PublicKey,
+    signature: DigitalSignature,
+}
+
+impl Vote {
+    pub fn sign(
+        kind: VoteKind,
+        height: u64,
+        round: u64,
+        block_hash: Option<Hash>,
+        keypair: &AuthKeyPair,
+    ) -> Result<Self, SigningError> {
+        let msg = Self::message(kind, height, round, &block_hash)?;
+        Ok(Self {
+            kind,
+            height,
+            round,
+            block_hash,
+            voter: keypair.clone().into_public_key(),
+            signature: keypair.sign(&msg)?,
+        })
+    }
+
+    pub fn verify(&self) -> bool {
+        match Self::message(self.kind, self.height, self.round, &self.block_hash) {
+            Ok(msg) => self.voter.verify(&msg, &self.signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn message(
+        kind: VoteKind,
+        height: u64,
+        round: u64,
+        block_hash: &Option<Hash>,
+    ) -> Result<Vec<u8>, SigningError> {
+        crate::serialize(&(kind, height, round, block_hash)).map_err(SigningError::SerdeError)
+    }
+
+    pub fn kind(&self) -> VoteKind {
+        self.kind
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn block_hash(&self) -> Option<&Hash> {
+        self.block_hash.as_ref()
+    }
+
+    pub fn voter(&self) -> &PublicKey {
+        &self.voter
+    }
+}
+
+/// A signed proposal of a block hash by the proposer of a round.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proposal {
+    height: u64,
+    round: u64,
+    block_hash: Hash,
+    proposer: PublicKey,
+    signature: DigitalSignature,
+}
+
+impl Proposal {
+    pub fn sign(
+        height: u64,
+        round: u64,
+        block_hash: Hash,
+        keypair: &AuthKeyPair,
+    ) -> Result<Self, SigningError> {
+        let msg =
+            crate::serialize(&(height, round, &block_hash)).map_err(SigningError::SerdeError)?;
+        Ok(Self {
+            height,
+            round,
+            block_hash,
+            proposer: keypair.clone().into_public_key(),
+            signature: keypair.sign(&msg)?,
+        })
+    }
+
+    pub fn verify(&self) -> bool {
+        match crate::serialize(&(self.height, self.round, &self.block_hash)) {
+            Ok(msg) => self.proposer.verify(&msg, &self.signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn block_hash(&self) -> &Hash {
+        &self.block_hash
+    }
+}
+
+/// The precommits that finalized a block hash at a height.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FinalityCertificate {
+    height: u64,
+    round: u64,
+    block_hash: Hash,
+    precommits: Vec<Vote>,
+}
+
+impl FinalityCertificate {
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn block_hash(&self) -> &Hash {
+        &self.block_hash
+    }
+
+    pub fn precommits(&self) -> &[Vote] {
+        &self.precommits
+    }
+
+    /// Checks that the certificate holds valid precommits for its block hash from a quorum
+    /// of distinct members of `set`.
+    pub fn verify(&self, set: &ValidatorSet) -> bool {
+        let mut voters: Vec<&PublicKey> = Vec::with_capacity(self.precommits.len());
+        for vote in &self.precommits {
+            let matches = vote.kind == VoteKind::Precommit
+                && vote.height == self.height
+                && vote.round == self.round
+                && vote.block_hash.as_ref() == Some(&self.block_hash);
+            if !matches || !set.contains(&vote.voter) || voters.contains(&&vote.voter) {
+                return false;
+            }
+            if !vote.verify() {
+                return false;
+            }
+            voters.push(&vote.voter);
+        }
+        voters.len() >= set.quorum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    Proposal(Proposal),
+    Vote(Vote),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// One participant of the finality protocol.
+pub struct Validator {
+    keypair: AuthKeyPair,
+    key: PublicKey,
+    set: ValidatorSet,
+    height: u64,
+    round: u64,
+    step: Step,
+    candidate: Option<Hash>,
+    locked: Option<(Hash, u64)>,
+    proposals: BTreeMap<u64, Hash>,
+    prevotes: BTreeMap<u64, Vec<Vote>>,
+    precommits: BTreeMap<u64, Vec<Vote>>,
+    decision: Option<FinalityCertificate>,
+}
+
+impl Validator {
+    pub fn new(keypair: AuthKeyPair, set: ValidatorSet) -> Self {
+        Self {
+            key: keypair.clone().into_public_key(),
+            keypair,
+            set,
+            height: 0,
+            round: 0,
+            step: Step::Propose,
+            candidate: None,
+            locked: None,
+            proposals: BTreeMap::new(),
+            prevotes: BTreeMap::new(),
+            precommits: BTreeMap::new(),
+            decision: None,
+        }
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    /// Returns the certificate of the current height once it is decided.
+    pub fn decision(&self) -> Option<&FinalityCertificate> {
+        self.decision.as_ref()
+    }
+
+    /// Starts agreeing on `height`. `candidate` is the hash of the block this validator has
+    /// at that height; it only votes for that hash.
+    pub fn start(&mut self, height: u64, candidate: Option<Hash>) -> Vec<Message> {
+        self.height = height;
+        self.candidate = candidate;
+        self.locked = None;
+        self.proposals.clear();
+        self.prevotes.clear();
+        self.precommits.clear();
+        self.decision = None;
+        self.start_round(0)
+    }
+
+    /// Handles a message from another validator and returns the messages to broadcast.
+    pub fn handle(&mut self, msg: Message) -> Vec<Message> {
+        let mut out = vec![];
+        match msg {
+            Message::Proposal(proposal) => {
+                if proposal.height != self.height
+                    || !proposal.verify()
+                    || self.set.proposer(proposal.height, proposal.round)
+                        != Some(&proposal.proposer)
+                {
+                    return out;
+                }
+                self.proposals
+                    .entry(proposal.round)
+                    .or_insert(proposal.block_hash);
+            }
+            Message::Vote(vote) => {
+                if vote.height != self.height || !self.set.contains(&vote.voter) || !vote.verify() {
+                    return out;
+                }
+                self.record(vote);
+            }
+        }
+        self.progress(&mut out);
+        out
+    }
+
+    /// Moves on when no more messages arrive: votes `nil` or starts the next round.
+    pub fn timeout(&mut self) -> Vec<Message> {
+        let mut out = vec![];
+        if self.decision.is_some() {
+            return out;
+        }
+        match self.step {
+            Step::Propose => self.vote(VoteKind::Prevote, None, &mut out),
+            Step::Prevote => self.vote(VoteKind::Precommit, None, &mut out),
+            Step::Precommit => out.extend(self.start_round(self.round + 1)),
+        }
+        self.progress(&mut out);
+        out
+    }
+
+    fn start_round(&mut self, round: u64) -> Vec<Message> {
+        let mut out = vec![];
+        self.round = round;
+        self.step = Step::Propose;
+        if self.set.proposer(self.height, round) == Some(&self.key) {
+            let value = match &self.locked {
+                Some((hash, _)) => Some(hash.clone()),
+                None => self.candidate.clone(),
+            };
+            if let Some(value) = value {
+                if let Ok(proposal) =
+                    Proposal::sign(self.height, round, value.clone(), &self.keypair)
+                {
+                    self.proposals.insert(round, value);
+                    out.push(Message::Proposal(proposal));
+                }
+            }
+        }
+        self.progress(&mut out);
+        out
+    }
+
+    fn record(&mut self, vote: Vote) {
+        let votes = match vote.kind {
+            VoteKind::Prevote => self.prevotes.entry(vote.round).or_default(),
+            VoteKind::Precommit => self.precommits.entry(vote.round).or_default(),
+        };
+        if votes.iter().all(|v| v.voter != vote.voter) {
+            votes.push(vote);
+        }
+    }
+
+    fn vote(&mut self, kind: VoteKind, block_hash: Option<Hash>, out: &mut Vec<Message>) {
+        self.step = match kind {
+            VoteKind::Prevote => Step::Prevote,
+            VoteKind::Precommit => Step::Precommit,
+        };
+        if let Ok(vote) = Vote::sign(kind, self.height, self.round, block_hash, &self.keypair) {
+            self.record(vote.clone());
+            out.push(Message::Vote(vote));
+        }
+    }
+
+    fn acceptable(&self, hash: &Hash) -> bool {
+        let locked_ok = match &self.locked {
+            Some((locked, _)) => locked == hash,
+            None => true,
+        };
+        locked_ok && self.candidate.as_ref() == Some(hash)
+    }
+
+    /// Returns the value that gathered a quorum of `votes`, and whether every validator voted.
+    fn tally(&self, votes: Option<&Vec<Vote>>) -> (Option<Option<Hash>>, bool) {
+        let votes = match votes {
+            Some(votes) => votes,
+            None => return (None, false),
+        };
+        let mut counts: Vec<(Option<&Hash>, usize)> = vec![];
+        for vote in votes {
+            match counts
+                .iter_mut()
+                .find(|(h, _)| *h == vote.block_hash.as_ref())
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((vote.block_hash.as_ref(), 1)),
+            }
+        }
+        let quorum = counts
+            .into_iter()
+            .find(|(_, count)| *count >= self.set.quorum())
+            .map(|(h, _)| h.cloned());
+        (quorum, votes.len() >= self.set.len())
+    }
+
+    fn progress(&mut self, out: &mut Vec<Message>) {
+        loop {
+            if self.decision.is_some() {
+                return;
+            }
+
+            let decided =
+                self.precommits
+                    .iter()
+                    .find_map(|(round, votes)| match self.tally(Some(votes)).0 {
+                        Some(Some(hash)) => Some((*round, hash)),
+                        _ => None,
+                    });
+            if let Some((round, block_hash)) = decided {
+                let precommits = self.precommits[&round]
+                    .iter()
+                    .filter(|v| v.block_hash.as_ref() == Some(&block_hash))
+                    .cloned()
+                    .collect();
+                self.decision = Some(FinalityCertificate {
+                    height: self.height,
+                    round,
+                    block_hash,
+                    precommits,
+                });
+                return;
+            }
+
+            match self.step {
+                Step::Propose => match self.proposals.get(&self.round).cloned() {
+                    Some(hash) => {
+                        let value = Some(hash).filter(|h| self.acceptable(h));
+                        self.vote(VoteKind::Prevote, value, out);
+                    }
+                    None => return,
+                },
+                Step::Prevote => match self.tally(self.prevotes.get(&self.round)) {
+                    (Some(Some(hash)), _) => {
+                        self.locked = Some((hash.clone(), self.round));
+                        self.vote(VoteKind::Precommit, Some(hash), out);
+                    }
+                    (Some(None), _) | (None, true) => self.vote(VoteKind::Precommit, None, out),
+                    (None, false) => return,
+                },
+                // the next round only starts on `timeout`
+                Step::Precommit => return,
+            }
+        }
+    }
+}
+
+/// An in-process network that delivers messages between validators in the order they are
+/// sent. Validators can be taken offline and the network can be partitioned.
+pub struct SimulatedNetwork {
+    validators: Vec<Validator>,
+    groups: Vec<usize>,
+    online: Vec<bool>,
+    queue: VecDeque<(usize, Message)>,
+}
+
+impl SimulatedNetwork {
+    pub fn new(validators: Vec<Validator>) -> Self {
+        let len = validators.len();
+        Self {
+            validators,
+            groups: vec![0; len],
+            online: vec![true; len],
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn validators(&self) -> &[Validator] {
+        &self.validators
+    }
+
+    pub fn set_online(&mut self, index: usize, online: bool) {
+        self.online[index] = online;
+    }
+
+    /// Splits the network so that messages are only delivered within each group.
+    /// Validators that are in no group are isolated.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let len = self.validators.len();
+        self.groups = (0..len).map(|i| len + i).collect();
+        for (group, members) in groups.iter().enumerate() {
+            for member in *members {
+                self.groups[*member] = group;
+            }
+        }
+    }
+
+    /// Removes every partition.
+    pub fn heal(&mut self) {
+        self.groups = vec![0; self.validators.len()];
+    }
+
+    /// Runs the protocol for `height` until a validator decides or `max_rounds` rounds pass.
+    ///
+    /// `candidates[i]` is the block hash that validator `i` has at `height`.
+    pub fn finalize(
+        &mut self,
+        height: u64,
+        candidates: &[Option<Hash>],
+        max_rounds: u64,
+    ) -> Option<FinalityCertificate> {
+        self.queue.clear();
+        for i in 0..self.validators.len() {
+            if self.online[i] {
+                let candidate = candidates.get(i).cloned().flatten();
+                let out = self.validators[i].start(height, candidate);
+                self.enqueue(i, out);
+            }
+        }
+        self.resume(max_rounds)
+    }
+
+    /// Continues the current height, for instance after the network was healed.
+    pub fn resume(&mut self, max_rounds: u64) -> Option<FinalityCertificate> {
+        let limit = self.validators.iter().map(|v| v.round()).max().unwrap_or(0) + max_rounds;
+        loop {
+            while let Some((from, msg)) = self.queue.pop_front() {
+                for to in 0..self.validators.len() {
+                    if to != from && self.online[to] && self.groups[to] == self.groups[from] {
+                        let out = self.validators[to].handle(msg.clone());
+                        self.enqueue(to, out);
+                    }
+                }
+            }
+
+            if let Some(cert) = self.validators.iter().find_map(|v| v.decision()) {
+                return Some(cert.clone());
+            }
+
+            let active: Vec<usize> = (0..self.validators.len())
+                .filter(|i| self.online[*i])
+                .collect();
+            if active.iter().all(|i| self.validators[*i].round() >= limit) {
+                return None;
+            }
+            for i in active {
+                let out = self.validators[i].timeout();
+                self.enqueue(i, out);
+            }
+        }
+    }
+
+    fn enqueue(&mut self, from: usize, msgs: Vec<Message>) {
+        self.queue.extend(msgs.into_iter().map(|msg| (from, msg)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::ChainedInstance,
+        chain::{Chain, ChainError},
+        data::Metadata,
+        AuthKeyPair, SqliteChain,
+    };
+
+    use super::{SimulatedNetwork, Validator, ValidatorSet, VoteKind};
+
+    fn network(n: usize) -> (SimulatedNetwork, ValidatorSet) {
+        let keys = (0..n)
+            .map(|_| crate::generate_ed25519_keypair())
+            .collect::<Vec<AuthKeyPair>>();
+        let set = ValidatorSet::new(keys.iter().map(|k| k.clone().into_public_key()).collect());
+        let validators = keys
+            .into_iter()
+            .map(|k| Validator::new(k, set.clone()))
+            .collect();
+        (SimulatedNetwork::new(validators), set)
+    }
+
+    #[test]
+    fn test_finalize() {
+        let (mut net, set) = network(4);
+        let hash = crate::random_sha256();
+        let cert = net
+            .finalize(1, &vec![Some(hash.clone()); 4], 5)
+            .expect("no decision");
+        assert_eq!(cert.block_hash(), &hash);
+        assert_eq!(cert.height(), 1);
+        assert!(cert.verify(&set));
+        assert!(cert
+            .precommits()
+            .iter()
+            .all(|v| v.kind() == VoteKind::Precommit));
+
+        // a certificate does not verify against another validator set
+        let (_, other) = network(4);
+        assert!(!cert.verify(&other));
+    }
+
+    #[test]
+    fn test_tolerates_one_fault() {
+        let (mut net, set) = network(4);
+        let hash = crate::random_sha256();
+        // the proposer of round 0 at height 1 is offline
+        net.set_online(1, false);
+        let cert = net
+            .finalize(1, &vec![Some(hash.clone()); 4], 5)
+            .expect("no decision");
+        assert!(cert.round() > 0);
+        assert!(cert.verify(&set));
+
+        // validators disagreeing on the block do not vote for it
+        let (mut net, _) = network(4);
+        let other = crate::random_sha256();
+        let candidates = [
+            Some(hash.clone()),
+            Some(hash.clone()),
+            Some(other.clone()),
+            Some(other),
+        ];
+        assert!(net.finalize(1, &candidates, 3).is_none());
+    }
+
+    #[test]
+    fn test_partition() {
+        let (mut net, set) = network(4);
+        let hash = crate::random_sha256();
+        net.partition(&[&[0, 1], &[2, 3]]);
+        assert!(net.finalize(2, &vec![Some(hash.clone()); 4], 3).is_none());
+
+        net.heal();
+        let cert = net.resume(5).expect("no decision after healing");
+        assert_eq!(cert.block_hash(), &hash);
+        assert!(cert.verify(&set));
+    }
+
+    #[test]
+    fn test_chain_finality() {
+        let chain_url = "target2/tests/finalitychain/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::<String>::new(chain_url).expect("sqlite connection cannot be established");
+        for _ in 0..3 {
+            chain
+                .append(&LocalInstance::new(Metadata::empty(), 0))
+                .unwrap();
+        }
+        assert_eq!(chain.finalized_height().unwrap(), 0);
+
+        let (mut net, set) = network(4);
+        let hash = chain.block_at(2.into()).unwrap().hash().unwrap();
+        let cert = net.finalize(2, &vec![Some(hash); 4], 5).unwrap();
+        chain
+            .finalize(cert.clone(), &set)
+            .expect("certificate rejected");
+
+        assert_eq!(chain.finalized_height().unwrap(), 2);
+        assert_eq!(
+            chain.block_at(2.into()).unwrap().finality().unwrap(),
+            Some(cert.clone())
+        );
+        assert!(matches!(
+            chain.rollback(1.into()),
+            Err(ChainError::Finalized)
+        ));
+
+        chain
+            .rollback(2.into())
+            .expect("rollback above finality failed");
+        assert_eq!(chain.len().unwrap(), 2);
+        chain
+            .append(&LocalInstance::new(Metadata::empty(), 0))
+            .unwrap();
+        assert_eq!(chain.len().unwrap(), 3);
+
+        // a certificate for another height is rejected
+        let hash = chain.block_at(3.into()).unwrap().hash().unwrap();
+        let (mut net, set) = network(4);
+        let cert = net.finalize(4, &vec![Some(hash); 4], 5).unwrap();
+        assert!(chain.finalize(cert, &set).is_err());
+    }
+}
@@ -1,12 +1,19 @@
 use crate::{
-    block::{ChainedInstance, BlockError},
-    chain::Chain,
+    block::{ChainedInstance, BlockError, LocalInstance, Seal},
+    chain::{Chain, ChainError},
+    data::Position,
     error::{DataBaseError, SerdeError},
     record::Record,
-    Hash
+    AuthKeyPair, Hash, SigningError,
 };
 
+pub mod finality;
+pub mod poa;
+pub mod pos;
+pub mod pow;
 pub mod puzzles;
+pub mod retarget;
+pub mod rules;
 
 pub trait ConsensusProtocol<R: Record> {
     type ChainedInstanceType: ChainedInstance<R>;
@@ -29,10 +36,58 @@ pub trait ChainBranches<R: Record, C: Chain<R>, X: ConsensusRules<R, C>> {
     fn merge(&mut self, rules: X) -> Result<C, ConsensusError>;
 }
 
+/// Returns the hash of the last block of `chain` and the position of the next block.
+///
+/// The previous hash of the first block of a chain is `Hash::default()`.
+pub fn next_in<R: Record, C: Chain<R>>(chain: &C) -> Result<(Hash, Position), ChainError> {
+    let position = (chain.len()? + 1).into();
+    let prev_hash = match chain.last_block()? {
+        Some(last) => last.hash()?,
+        None => Hash::default(),
+    };
+    Ok((prev_hash, position))
+}
+
+/// Seals `block` with `keypair` for `position` after `prev_hash`, naming the signer as the
+/// producer of the block if it names none.
+///
+/// Fails with `ConsensusError::NotInTurn` if the block names another producer.
+pub(crate) fn seal_block<R>(
+    block: &mut LocalInstance<R>,
+    keypair: &AuthKeyPair,
+    prev_hash: &Hash,
+    position: Position,
+) -> Result<Seal, ConsensusError> {
+    let signer = keypair.clone().into_public_key();
+    if block.producer.as_ref().is_some_and(|producer| producer != &signer) {
+        return Err(ConsensusError::NotInTurn);
+    }
+    block.producer = Some(signer);
+    let seal = Seal::sign(&crate::hash_header(block, prev_hash, &position), keypair)?;
+    block.seal = Some(seal.clone());
+    Ok(seal)
+}
+
+/// Returns `true` if `block` names no producer or names the signer of `seal`.
+pub(crate) fn produced_by<R: Record, B: ChainedInstance<R>>(
+    block: &B,
+    seal: &Seal,
+) -> Result<bool, BlockError> {
+    Ok(match block.producer()? {
+        Some(producer) => &producer == seal.signer(),
+        None => true,
+    })
+}
+
+#[derive(Debug)]
 pub enum ConsensusError {
     Custom(Box<dyn std::error::Error>),
     SerdeError(SerdeError),
     DataBaseError(DataBaseError),
+    ChainError(ChainError),
+    SigningError(SigningError),
+    /// The given key is not allowed to produce the block at this position.
+    NotInTurn,
     Unspecified,
 }
 
@@ -41,3 +96,29 @@ impl ConsensusError {
         ConsensusError::Custom(error)
     }
 }
+
+impl From<ChainError> for ConsensusError {
+    fn from(value: ChainError) -> Self {
+        ConsensusError::ChainError(value)
+    }
+}
+
+impl From<BlockError> for ConsensusError {
+    fn from(value: BlockError) -> Self {
+        ConsensusError::ChainError(value.into())
+    }
+}
+
+impl From<SigningError> for ConsensusError {
+    fn from(value: SigningError) -> Self {
+        ConsensusError::SigningError(value)
+    }
+}
+
+impl std::fmt::Display for ConsensusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConsensusError {}
@@ -0,0 +1,461 @@
+//! Proof-of-authority consensus.
+//!
+//! Every block is sealed (see `Seal`) by one key out of a set of authorities, chosen
+//! round-robin by the position of the block. The set starts from a genesis list and is
+//! changed by records implementing `AuthorityRecord`. A change is only accepted when it is
+//! signed by a current authority and takes effect from the block after the one holding it.
+//!
+//! The set in effect for a block is derived from the blocks before it on its own branch,
+//! and kept for every block it was derived after, so a chain is replayed only once.
+
+use std::{cell::RefCell, collections::HashMap, marker::PhantomData};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockData, BlockError, ChainedInstance, LocalInstance, Seal},
+    chain::{Chain, ChainError},
+    data::Position,
+    error::DataBaseError,
+    record::{Record, SignedRecord},
+    AuthKeyPair, Hash, PublicKey, SqliteBlock, SqliteChain,
+};
+
+use super::{
+    rules::{LongestChain, SqliteBranches},
+    ConsensusError, ConsensusProtocol,
+};
+
+/// A change to the set of authorities of a proof-of-authority chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthorityChange {
+    Add(PublicKey),
+    Remove(PublicKey),
+}
+
+/// Records that can change the set of authorities of a proof-of-authority chain.
+pub trait AuthorityRecord {
+    /// Returns the change this record makes to the authority set, if any.
+    fn authority_change(&self) -> Option<AuthorityChange>;
+}
+
+/// The ordered set of keys allowed to seal blocks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthoritySet {
+    authorities: Vec<PublicKey>,
+}
+
+impl AuthoritySet {
+    pub fn new(authorities: Vec<PublicKey>) -> Self {
+        let mut set = Self {
+            authorities: Vec::with_capacity(authorities.len()),
+        };
+        for authority in authorities {
+            set.add(authority);
+        }
+        set
+    }
+
+    pub fn authorities(&self) -> &[PublicKey] {
+        &self.authorities
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.authorities.contains(key)
+    }
+
+    /// Returns the authority whose turn it is to seal the block at `position`.
+    pub fn in_turn(&self, position: Position) -> Option<&PublicKey> {
+        match self.authorities.len() {
+            0 => None,
+            len => self
+                .authorities
+                .get((position.pos.saturating_sub(1) % len as u64) as usize),
+        }
+    }
+
+    /// Applies the change carried by `record` if it is signed by a current authority.
+    ///
+    /// Returns `true` if the set was changed.
+    pub fn apply<R: AuthorityRecord>(&mut self, record: &SignedRecord<R>) -> bool {
+        if !self.contains(record.signer()) {
+            return false;
+        }
+        match record.record().authority_change() {
+            Some(AuthorityChange::Add(key)) => self.add(key),
+            Some(AuthorityChange::Remove(key)) => self.remove(&key),
+            None => false,
+        }
+    }
+
+    fn add(&mut self, key: PublicKey) -> bool {
+        if self.contains(&key) {
+            return false;
+        }
+        self.authorities.push(key);
+        true
+    }
+
+    /// The last authority can never be removed.
+    fn remove(&mut self, key: &PublicKey) -> bool {
+        let len = self.authorities.len();
+        if len <= 1 {
+            return false;
+        }
+        self.authorities.retain(|a| a != key);
+        self.authorities.len() != len
+    }
+}
+
+/// A proof-of-authority `ConsensusProtocol` over a `SqliteChain`.
+pub struct ProofOfAuthority<R> {
+    url: String,
+    branches: Vec<String>,
+    genesis: AuthoritySet,
+    /// The authority set in effect after each block whose set was derived, by block hash.
+    derived: RefCell<HashMap<Hash, AuthoritySet>>,
+    _data: PhantomData<R>,
+}
+
+impl<R> ProofOfAuthority<R>
+where
+    R: AuthorityRecord + Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static,
+{
+    /// Creates the protocol for the chain stored at `url`, starting with `authorities`.
+    pub fn new(url: &str, authorities: Vec<PublicKey>) -> Self {
+        Self {
+            url: url.to_owned(),
+            branches: vec![url.to_owned()],
+            genesis: AuthoritySet::new(authorities),
+            derived: RefCell::default(),
+            _data: PhantomData,
+        }
+    }
+
+    /// Registers another branch of the chain, stored at `url`.
+    pub fn add_branch(&mut self, url: &str) {
+        self.branches.push(url.to_owned())
+    }
+
+    /// Returns the authority set in effect for the block at `position` of the active chain.
+    ///
+    /// Fails with `ChainError::Pruned` if the records of a block below `position` were pruned.
+    pub fn authorities_at(&self, position: Position) -> Result<AuthoritySet, ConsensusError> {
+        let chain = self.active_chain()?;
+        let last = position.pos.saturating_sub(1).min(chain.len()?);
+        self.authorities_after(&chain, last)
+    }
+
+    /// Returns the authority set in effect for the block at `position` following the block
+    /// hashed `prev_hash`, on the branch that holds that block.
+    ///
+    /// Fails with `ChainError::NotValid(BlockData::PrevHash)` if no branch holds it.
+    pub fn authorities_following(
+        &self,
+        prev_hash: &Hash,
+        position: Position,
+    ) -> Result<AuthoritySet, ConsensusError> {
+        let parent = position.pos.saturating_sub(1);
+        if parent == 0 {
+            return Ok(self.genesis.clone());
+        }
+        if let Some(set) = self.derived.borrow().get(prev_hash) {
+            return Ok(set.clone());
+        }
+        for url in &self.branches {
+            let chain = SqliteChain::<R>::new(url)
+                .map_err(|_| ConsensusError::DataBaseError(DataBaseError::ConnectionFailed))?;
+            if chain.len()? >= parent && &chain.block_at(parent.into())?.hash()? == prev_hash {
+                return self.authorities_after(&chain, parent);
+            }
+        }
+        Err(ChainError::NotValid(BlockData::PrevHash).into())
+    }
+
+    /// Returns the authority set in effect after the block at `pos` of `chain`, replaying
+    /// the blocks after the last one whose set is known.
+    fn authorities_after<C: Chain<R>>(
+        &self,
+        chain: &C,
+        pos: u64,
+    ) -> Result<AuthoritySet, ConsensusError> {
+        let mut set = self.genesis.clone();
+        let mut pending = vec![];
+        for pos in (1..=pos).rev() {
+            let block = chain.block_at(pos.into())?;
+            let hash = block.hash()?;
+            if let Some(known) = self.derived.borrow().get(&hash) {
+                set = known.clone();
+                break;
+            }
+            pending.push((hash, block));
+        }
+        for (hash, block) in pending.into_iter().rev() {
+            for record in block.records()?.iter() {
+                set.apply(record);
+            }
+            self.derived.borrow_mut().insert(hash, set.clone());
+        }
+        Ok(set)
+    }
+
+    /// Seals `block` with `keypair` for the next position of the active chain.
+    ///
+    /// Fails with `ConsensusError::NotInTurn` if `keypair` is not the authority in turn. The
+    /// authority is named the producer of the block unless the block names another one,
+    /// which also fails. Name the producer before preparing the state of the block.
+    pub fn seal(
+        &self,
+        block: &mut LocalInstance<R>,
+        keypair: &AuthKeyPair,
+    ) -> Result<Seal, ConsensusError> {
+        let (prev_hash, position) = super::next_in(&self.active_chain()?)?;
+        let set = self.authorities_following(&prev_hash, position)?;
+        if set.in_turn(position).map(|a| a.as_bytes()) != Some(keypair.public_key_bytes()) {
+            return Err(ConsensusError::NotInTurn);
+        }
+        super::seal_block(block, keypair, &prev_hash, position)
+    }
+
+    /// Checks the seal of `block` against the authority in turn for its position, which
+    /// must also be the producer the block names, if any.
+    pub fn check<B: ChainedInstance<R>>(&self, block: &B) -> Result<bool, ConsensusError> {
+        let seal = match block.seal()? {
+            Some(seal) => seal,
+            None => return Ok(false),
+        };
+        let position = block.position()?;
+        let set = self.authorities_following(&block.prev_hash()?, position)?;
+        if set.in_turn(position) != Some(seal.signer()) || !super::produced_by(block, &seal)? {
+            return Ok(false);
+        }
+        Ok(seal.verify(&block.header()?.seal_hash()).is_ok())
+    }
+}
+
+impl<R> ConsensusProtocol<R> for ProofOfAuthority<R>
+where
+    R: AuthorityRecord + Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static,
+{
+    type ChainedInstanceType = SqliteBlock<R>;
+    type ChainType = SqliteChain<R>;
+    type ConsensusRulesType = LongestChain;
+    type BranchesType = SqliteBranches<R>;
+
+    fn validate<B: ChainedInstance<R>>(&self, block: B) -> bool {
+        self.check(&block).unwrap_or(false)
+    }
+
+    fn active_chain(&self) -> Result<Self::ChainType, ConsensusError> {
+        SqliteChain::new(&self.url)
+            .map_err(|_| ConsensusError::DataBaseError(DataBaseError::ConnectionFailed))
+    }
+
+    fn branches(&mut self) -> Result<Self::BranchesType, ConsensusError> {
+        Ok(SqliteBranches::new(self.branches.clone()))
+    }
+
+    fn hash_block(block: &Self::ChainedInstanceType) -> Result<Hash, BlockError> {
+        block.hash()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::{ChainedInstance, Seal},
+        chain::Chain,
+        consensus::{seal_block, ConsensusError, ConsensusProtocol},
+        data::Metadata,
+        record::Record,
+        AuthKeyPair, PublicKey, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{AuthorityChange, AuthorityRecord, ProofOfAuthority};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    enum Governance {
+        Note(String),
+        AddAuthority(PublicKey),
+    }
+
+    impl AuthorityRecord for Governance {
+        fn authority_change(&self) -> Option<AuthorityChange> {
+            match self {
+                Governance::AddAuthority(key) => Some(AuthorityChange::Add(key.clone())),
+                Governance::Note(_) => None,
+            }
+        }
+    }
+
+    fn block(record: Governance, keypair: &AuthKeyPair) -> LocalInstance<Governance> {
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(record.record(keypair.clone(), Metadata::empty()).unwrap());
+        block
+    }
+
+    fn note() -> Governance {
+        Governance::Note("hello".to_owned())
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let chain_url = "target2/tests/poachain/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+
+        let a = crate::generate_ed25519_keypair();
+        let b = crate::generate_ed25519_keypair();
+        let c = crate::generate_ed25519_keypair();
+        let poa = ProofOfAuthority::new(
+            chain_url,
+            vec![a.clone().into_public_key(), b.clone().into_public_key()],
+        );
+
+        // blocks 1, 2 and 3 are sealed by a, b, a. Block 3 adds c as an authority
+        let mut first = block(note(), &a);
+        assert!(matches!(
+            poa.seal(&mut first, &b),
+            Err(ConsensusError::NotInTurn)
+        ));
+        poa.seal(&mut first, &a).unwrap();
+        chain.append(&first).unwrap();
+
+        let mut second = block(note(), &b);
+        poa.seal(&mut second, &b).unwrap();
+        chain.append(&second).unwrap();
+
+        let mut third = block(Governance::AddAuthority(c.clone().into_public_key()), &a);
+        poa.seal(&mut third, &a).unwrap();
+        chain.append(&third).unwrap();
+
+        // from block 4 on the rotation is a, b, c
+        for keypair in [&a, &b, &c] {
+            let mut next = block(note(), keypair);
+            poa.seal(&mut next, keypair).unwrap();
+            chain.append(&next).unwrap();
+        }
+
+        for pos in 1..=chain.len().unwrap() {
+            assert!(poa.validate(chain.block_at(pos.into()).unwrap()));
+        }
+        assert_eq!(poa.authorities_at(7.into()).unwrap().authorities().len(), 3);
+    }
+
+    #[test]
+    fn test_rejects_bad_seals() {
+        let chain_url = "target2/tests/poachainbad/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+
+        let a = crate::generate_ed25519_keypair();
+        let b = crate::generate_ed25519_keypair();
+        let poa = ProofOfAuthority::new(
+            chain_url,
+            vec![a.clone().into_public_key(), b.clone().into_public_key()],
+        );
+
+        // sealed out of turn by b
+        let mut first = block(note(), &b);
+        first.seal = Some(
+            Seal::sign(
+                &crate::hash_header(&first, &Default::default(), &1.into()),
+                &b,
+            )
+            .unwrap(),
+        );
+        chain.append(&first).unwrap();
+        assert!(!poa.validate(chain.block_at(1.into()).unwrap()));
+
+        // not sealed at all
+        let unsealed = block(note(), &b);
+        chain.append(&unsealed).unwrap();
+        assert!(!poa.validate(chain.block_at(2.into()).unwrap()));
+
+        // sealed by a, who is in turn, but over the header of another position
+        let mut wrong = block(note(), &a);
+        let prev_hash = chain.block_at(2.into()).unwrap().hash().unwrap();
+        wrong.seal =
+            Some(Seal::sign(&crate::hash_header(&wrong, &prev_hash, &4.into()), &a).unwrap());
+        chain.append(&wrong).unwrap();
+        assert!(!poa.validate(chain.block_at(3.into()).unwrap()));
+
+        // sealed by b, who is in turn, but naming a as the producer
+        let mut other = block(note(), &b);
+        other.producer = Some(a.clone().into_public_key());
+        assert!(matches!(
+            poa.seal(&mut other, &b),
+            Err(ConsensusError::NotInTurn)
+        ));
+        let prev_hash = chain.block_at(3.into()).unwrap().hash().unwrap();
+        other.seal =
+            Some(Seal::sign(&crate::hash_header(&other, &prev_hash, &4.into()), &b).unwrap());
+        chain.append(&other).unwrap();
+        assert!(!poa.validate(chain.block_at(4.into()).unwrap()));
+
+        // the authority in turn is named the producer of blocks that name none
+        let mut next = block(note(), &a);
+        poa.seal(&mut next, &a).unwrap();
+        assert_eq!(next.producer, Some(a.into_public_key()));
+    }
+
+    #[test]
+    fn test_fork_authorities() {
+        let chain_url = "target2/tests/poachainactive/";
+        let fork_url = "target2/tests/poachainfork/";
+        for url in [chain_url, fork_url] {
+            let _ = std::fs::remove_dir_all(url);
+            std::fs::create_dir_all(url).expect("could not create chain_url");
+        }
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+        let mut fork = SqliteChain::new(fork_url).expect("sqlite connection cannot be established");
+
+        let a = crate::generate_ed25519_keypair();
+        let b = crate::generate_ed25519_keypair();
+        let c = crate::generate_ed25519_keypair();
+        let mut poa = ProofOfAuthority::new(
+            chain_url,
+            vec![a.clone().into_public_key(), b.clone().into_public_key()],
+        );
+        poa.add_branch(fork_url);
+
+        // the active chain adds c in its first block, the fork does not
+        let mut first = block(Governance::AddAuthority(c.clone().into_public_key()), &a);
+        poa.seal(&mut first, &a).unwrap();
+        chain.append(&first).unwrap();
+        let mut second = block(note(), &b);
+        poa.seal(&mut second, &b).unwrap();
+        chain.append(&second).unwrap();
+
+        let mut prev_hash = Default::default();
+        for (pos, keypair) in [(1, &a), (2, &b), (3, &a)] {
+            let mut next = block(note(), keypair);
+            seal_block(&mut next, keypair, &prev_hash, pos.into()).unwrap();
+            fork.append(&next).unwrap();
+            prev_hash = fork.block_at(pos.into()).unwrap().hash().unwrap();
+        }
+
+        // the third block of the fork is in turn for the fork's set, not the active one
+        assert_eq!(
+            poa.authorities_at(3.into()).unwrap().in_turn(3.into()),
+            Some(&c.into_public_key())
+        );
+        for pos in 1..=3 {
+            assert!(poa.validate(fork.block_at(pos.into()).unwrap()));
+        }
+
+        // a block following no known block is rejected
+        let mut orphan = block(note(), &b);
+        seal_block(&mut orphan, &b, &crate::random_sha256(), 4.into()).unwrap();
+        fork.append(&orphan).unwrap();
+        assert!(!poa.validate(fork.block_at(4.into()).unwrap()));
+    }
+}
@@ -0,0 +1,735 @@
+//! Proof-of-stake consensus.
+//!
+//! Validators lock stake through records implementing `StakeRecord`. The leader of every slot
+//! (block position) is drawn from the stakes in effect for it, weighted by stake, using a seed
+//! derived from the hash of the previous block, so anyone can recompute it. The leader seals
+//! the block (see `Seal`). Sealing two different blocks for the same slot is provable with a
+//! `SlashingEvidence`, which burns the whole stake of the offender once it is recorded.
+//! Evidence of an offence that was already punished burns nothing.
+//!
+//! Stake is locked from the unstaked balance of its signer, which the genesis ledger hands
+//! out (see `StakeLedger::with_balances`) and unlocking returns to. Locks the balance does
+//! not cover are rejected, so no key can create stake, or buy slots, from nothing.
+
+use std::{cell::RefCell, collections::HashMap, marker::PhantomData};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockData, BlockError, BlockHeader, ChainedInstance, LocalInstance, Seal},
+    chain::{Chain, ChainError},
+    data::Position,
+    error::DataBaseError,
+    record::{Record, SignedRecord},
+    AuthKeyPair, Hash, PublicKey, SqliteBlock, SqliteChain,
+};
+
+use super::{
+    rules::{LongestChain, SqliteBranches},
+    ConsensusError, ConsensusProtocol,
+};
+
+/// What a stake record does to the stake of its signer, or to the stake of an offender.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StakeAction {
+    /// Moves the given amount of the signer's unstaked balance into its stake.
+    Lock(u64),
+    /// Moves the given amount of the signer's stake back into its unstaked balance.
+    Unlock(u64),
+    /// Burns the stake of the validator that sealed both headers of the evidence.
+    Slash(Box<SlashingEvidence>),
+}
+
+/// Records that can change the stakes of a proof-of-stake chain.
+pub trait StakeRecord {
+    /// Returns the stake action carried by this record, if any.
+    fn stake_action(&self) -> Option<StakeAction>;
+}
+
+/// The header of a block together with its seal, enough to check the seal without the
+/// block's records.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedHeader {
+    header: BlockHeader,
+    seal: Seal,
+}
+
+impl SealedHeader {
+    pub fn new(header: BlockHeader, seal: Seal) -> Self {
+        Self { header, seal }
+    }
+
+    /// Returns the sealed header of a block in a chain, or `None` if it is not sealed.
+    pub fn of<R: Record, B: ChainedInstance<R>>(block: &B) -> Result<Option<Self>, BlockError> {
+        let seal = match block.seal()? {
+            Some(seal) => seal,
+            None => return Ok(None),
+        };
+        Ok(Some(Self::new(block.header()?, seal)))
+    }
+
+    /// Returns the sealed header of `block` for `position` after `prev_hash`, or `None` if
+    /// it is not sealed.
+    pub fn of_local<R>(
+        block: &LocalInstance<R>,
+        prev_hash: &Hash,
+        position: Position,
+    ) -> Option<Self> {
+        let seal = block.seal.clone()?;
+        Some(Self::new(block.header(prev_hash, position), seal))
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.header.seal_hash()
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn position(&self) -> Position {
+        self.header.position
+    }
+
+    pub fn seal(&self) -> &Seal {
+        &self.seal
+    }
+
+    /// Returns `true` if the seal is a valid signature over the header.
+    pub fn is_valid(&self) -> bool {
+        self.seal.verify(&self.hash()).is_ok()
+    }
+}
+
+/// Proof that a validator sealed two different blocks for the same slot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlashingEvidence {
+    first: SealedHeader,
+    second: SealedHeader,
+}
+
+impl SlashingEvidence {
+    pub fn new(first: SealedHeader, second: SealedHeader) -> Self {
+        Self { first, second }
+    }
+
+    /// Returns the validator that double signed, or `None` if the evidence does not hold.
+    pub fn offender(&self) -> Option<&PublicKey> {
+        let holds = self.first.position() == self.second.position()
+            && self.first.seal.signer() == self.second.seal.signer()
+            && self.first.hash() != self.second.hash()
+            && self.first.is_valid()
+            && self.second.is_valid();
+        holds.then(|| self.first.seal.signer())
+    }
+
+    /// Returns the hash of the offence: the signer and the slot of the headers. Any evidence
+    /// of the same double signing has the same hash.
+    pub fn hash(&self) -> Hash {
+        crate::sha_all([
+            self.first.seal.signer().as_bytes(),
+            &self.first.position().pos.to_be_bytes(),
+        ])
+    }
+}
+
+/// The stakes locked by validators, in the order they were first locked, and the unstaked
+/// balances they are locked from.
+///
+/// A ledger is derived from the genesis stakes along the branch of a block (see
+/// `ProofOfStake::stakes_following`), so the evidence of blocks on other branches does not
+/// count.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StakeLedger {
+    stakes: Vec<(PublicKey, u64)>,
+    /// The units of every key that are not staked.
+    #[serde(default)]
+    balances: Vec<(PublicKey, u64)>,
+    /// The hashes of the offences already slashed.
+    #[serde(default)]
+    slashed: Vec<Hash>,
+}
+
+impl StakeLedger {
+    pub fn new(stakes: Vec<(PublicKey, u64)>) -> Self {
+        let mut ledger = Self::default();
+        for (key, amount) in stakes {
+            ledger.stake(key, amount);
+        }
+        ledger
+    }
+
+    /// Gives every key the given unstaked balance, from which it can lock stake.
+    pub fn with_balances(mut self, balances: Vec<(PublicKey, u64)>) -> Self {
+        for (key, amount) in balances {
+            self.credit(key, amount);
+        }
+        self
+    }
+
+    pub fn stakes(&self) -> &[(PublicKey, u64)] {
+        &self.stakes
+    }
+
+    pub fn stake_of(&self, key: &PublicKey) -> u64 {
+        self.stakes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, amount)| *amount)
+            .unwrap_or(0)
+    }
+
+    /// Returns the unstaked balance of `key`.
+    pub fn balance_of(&self, key: &PublicKey) -> u64 {
+        self.balances
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, amount)| *amount)
+            .unwrap_or(0)
+    }
+
+    /// Returns the sum of the stakes. Locks that would take it above `u64::MAX` are rejected.
+    pub fn total(&self) -> u64 {
+        self.stakes
+            .iter()
+            .fold(0u64, |total, (_, amount)| total.saturating_add(*amount))
+    }
+
+    /// Returns `true` if the offence proven by `evidence` was already slashed.
+    pub fn is_slashed(&self, evidence: &SlashingEvidence) -> bool {
+        self.slashed.contains(&evidence.hash())
+    }
+
+    /// Applies the stake action carried by `record`.
+    ///
+    /// Returns `true` if a stake was changed. Locks the unstaked balance of the signer does
+    /// not cover, unlocks of more than its stake, evidence that does not hold and evidence
+    /// of an offence already slashed are ignored.
+    pub fn apply<R: StakeRecord>(&mut self, record: &SignedRecord<R>) -> bool {
+        match record.record().stake_action() {
+            Some(StakeAction::Lock(amount)) => self.lock(record.signer().clone(), amount),
+            Some(StakeAction::Unlock(amount)) => self.unlock(record.signer(), amount),
+            Some(StakeAction::Slash(evidence)) => match evidence.offender() {
+                Some(offender) if !self.is_slashed(&evidence) => {
+                    self.slashed.push(evidence.hash());
+                    self.burn(offender)
+                }
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the leader of the slot at `position` following the block hashed `prev_hash`.
+    ///
+    /// Every validator is chosen with a probability proportional to its stake.
+    pub fn leader(&self, prev_hash: &Hash, position: Position) -> Option<&PublicKey> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let seed = Self::seed(prev_hash, position);
+        let draw = u64::from_be_bytes(seed.as_bytes()[..8].try_into().unwrap());
+        let mut point = ((draw as u128 * total as u128) >> 64) as u64;
+        for (key, amount) in &self.stakes {
+            if point < *amount {
+                return Some(key);
+            }
+            point -= amount;
+        }
+        None
+    }
+
+    /// The randomness used to choose the leader of the slot at `position`.
+    pub fn seed(prev_hash: &Hash, position: Position) -> Hash {
+        crate::sha_all([prev_hash.as_bytes(), &position.pos.to_be_bytes()])
+    }
+
+    fn lock(&mut self, key: PublicKey, amount: u64) -> bool {
+        if self.total().checked_add(amount).is_none() {
+            return false;
+        }
+        match self.balances.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, balance)) if amount > 0 && *balance >= amount => *balance -= amount,
+            _ => return false,
+        }
+        self.balances.retain(|(_, balance)| *balance > 0);
+        self.stake(key, amount)
+    }
+
+    fn unlock(&mut self, key: &PublicKey, amount: u64) -> bool {
+        match self.stakes.iter_mut().find(|(k, _)| k == key) {
+            Some((_, stake)) if amount > 0 && *stake >= amount => *stake -= amount,
+            _ => return false,
+        }
+        self.stakes.retain(|(_, stake)| *stake > 0);
+        self.credit(key.clone(), amount);
+        true
+    }
+
+    /// Adds `amount` to the stake of `key`, unless it would take the total above `u64::MAX`.
+    fn stake(&mut self, key: PublicKey, amount: u64) -> bool {
+        if amount == 0 || self.total().checked_add(amount).is_none() {
+            return false;
+        }
+        match self.stakes.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, stake)) => *stake += amount,
+            None => self.stakes.push((key, amount)),
+        }
+        true
+    }
+
+    fn credit(&mut self, key: PublicKey, amount: u64) {
+        match self.balances.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, balance)) => *balance = balance.saturating_add(amount),
+            None if amount > 0 => self.balances.push((key, amount)),
+            None => {}
+        }
+    }
+
+    fn burn(&mut self, key: &PublicKey) -> bool {
+        let len = self.stakes.len();
+        self.stakes.retain(|(k, _)| k != key);
+        self.stakes.len() != len
+    }
+}
+
+/// A proof-of-stake `ConsensusProtocol` over a `SqliteChain`.
+pub struct ProofOfStake<R> {
+    url: String,
+    branches: Vec<String>,
+    genesis: StakeLedger,
+    /// The stakes in effect after each block whose stakes were derived, by block hash.
+    derived: RefCell<HashMap<Hash, StakeLedger>>,
+    _data: PhantomData<R>,
+}
+
+impl<R> ProofOfStake<R>
+where
+    R: StakeRecord + Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static,
+{
+    /// Creates the protocol for the chain stored at `url`, starting with the `stakes` and no
+    /// unstaked balances.
+    pub fn new(url: &str, stakes: Vec<(PublicKey, u64)>) -> Self {
+        Self {
+            url: url.to_owned(),
+            branches: vec![url.to_owned()],
+            genesis: StakeLedger::new(stakes),
+            derived: RefCell::default(),
+            _data: PhantomData,
+        }
+    }
+
+    /// Starts every key with the given unstaked balance, from which it can lock stake.
+    pub fn with_balances(mut self, balances: Vec<(PublicKey, u64)>) -> Self {
+        self.genesis = self.genesis.with_balances(balances);
+        self
+    }
+
+    /// Registers another branch of the chain, stored at `url`.
+    pub fn add_branch(&mut self, url: &str) {
+        self.branches.push(url.to_owned())
+    }
+
+    /// Returns the stakes in effect for the block at `position` of the active chain.
+    ///
+    /// Fails with `ChainError::Pruned` if the records of a block below `position` were pruned.
+    pub fn stakes_at(&self, position: Position) -> Result<StakeLedger, ConsensusError> {
+        let chain = self.active_chain()?;
+        let last = position.pos.saturating_sub(1).min(chain.len()?);
+        self.stakes_after(&chain, last)
+    }
+
+    /// Returns the stakes in effect for the block at `position` following the block hashed
+    /// `prev_hash`, on the branch that holds that block.
+    ///
+    /// Fails with `ChainError::NotValid(BlockData::PrevHash)` if no branch holds it.
+    pub fn stakes_following(
+        &self,
+        prev_hash: &Hash,
+        position: Position,
+    ) -> Result<StakeLedger, ConsensusError> {
+        let parent = position.pos.saturating_sub(1);
+        if parent == 0 {
+            return Ok(self.genesis.clone());
+        }
+        if let Some(ledger) = self.derived.borrow().get(prev_hash) {
+            return Ok(ledger.clone());
+        }
+        for url in &self.branches {
+            let chain = SqliteChain::<R>::new(url)
+                .map_err(|_| ConsensusError::DataBaseError(DataBaseError::ConnectionFailed))?;
+            if chain.len()? >= parent && &chain.block_at(parent.into())?.hash()? == prev_hash {
+                return self.stakes_after(&chain, parent);
+            }
+        }
+        Err(ChainError::NotValid(BlockData::PrevHash).into())
+    }
+
+    /// Returns the stakes in effect after the block at `pos` of `chain`, replaying the
+    /// blocks after the last one whose stakes are known.
+    fn stakes_after<C: Chain<R>>(
+        &self,
+        chain: &C,
+        pos: u64,
+    ) -> Result<StakeLedger, ConsensusError> {
+        let mut ledger = self.genesis.clone();
+        let mut pending = vec![];
+        for pos in (1..=pos).rev() {
+            let block = chain.block_at(pos.into())?;
+            let hash = block.hash()?;
+            if let Some(known) = self.derived.borrow().get(&hash) {
+                ledger = known.clone();
+                break;
+            }
+            pending.push((hash, block));
+        }
+        for (hash, block) in pending.into_iter().rev() {
+            for record in block.records()?.iter() {
+                ledger.apply(record);
+            }
+            self.derived.borrow_mut().insert(hash, ledger.clone());
+        }
+        Ok(ledger)
+    }
+
+    /// Returns the leader of the slot at `position` following the block hashed `prev_hash`,
+    /// drawn from the stakes on the branch of that block.
+    pub fn leader_at(
+        &self,
+        prev_hash: &Hash,
+        position: Position,
+    ) -> Result<Option<PublicKey>, ConsensusError> {
+        let ledger = self.stakes_following(prev_hash, position)?;
+        Ok(ledger.leader(prev_hash, position).cloned())
+    }
+
+    /// Seals `block` with `keypair` for the next slot of the active chain.
+    ///
+    /// Fails with `ConsensusError::NotInTurn` if `keypair` is not the leader of that slot.
+    /// The leader is named the producer of the block unless the block names another one,
+    /// which also fails. Name the producer before preparing the state of the block.
+    pub fn seal(
+        &self,
+        block: &mut LocalInstance<R>,
+        keypair: &AuthKeyPair,
+    ) -> Result<Seal, ConsensusError> {
+        let (prev_hash, position) = super::next_in(&self.active_chain()?)?;
+        let leader = self.leader_at(&prev_hash, position)?;
+        if leader.as_ref().map(|l| l.as_bytes()) != Some(keypair.public_key_bytes()) {
+            return Err(ConsensusError::NotInTurn);
+        }
+        super::seal_block(block, keypair, &prev_hash, position)
+    }
+
+    /// Checks the seal of `block` against the leader of its slot, which must also be the
+    /// producer the block names, if any.
+    pub fn check<B: ChainedInstance<R>>(&self, block: &B) -> Result<bool, ConsensusError> {
+        let header = match SealedHeader::of(block)? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        let leader = self.leader_at(&header.header().prev_hash, header.position())?;
+        if leader.as_ref() != Some(header.seal.signer())
+            || !super::produced_by(block, &header.seal)?
+        {
+            return Ok(false);
+        }
+        Ok(header.is_valid())
+    }
+}
+
+impl<R> ConsensusProtocol<R> for ProofOfStake<R>
+where
+    R: StakeRecord + Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static,
+{
+    type ChainedInstanceType = SqliteBlock<R>;
+    type ChainType = SqliteChain<R>;
+    type ConsensusRulesType = LongestChain;
+    type BranchesType = SqliteBranches<R>;
+
+    fn validate<B: ChainedInstance<R>>(&self, block: B) -> bool {
+        self.check(&block).unwrap_or(false)
+    }
+
+    fn active_chain(&self) -> Result<Self::ChainType, ConsensusError> {
+        SqliteChain::new(&self.url)
+            .map_err(|_| ConsensusError::DataBaseError(DataBaseError::ConnectionFailed))
+    }
+
+    fn branches(&mut self) -> Result<Self::BranchesType, ConsensusError> {
+        Ok(SqliteBranches::new(self.branches.clone()))
+    }
+
+    fn hash_block(block: &Self::ChainedInstanceType) -> Result<Hash, BlockError> {
+        block.hash()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::{ChainedInstance, Seal},
+        chain::Chain,
+        consensus::{next_in, seal_block, ConsensusProtocol},
+        data::Metadata,
+        record::Record,
+        AuthKeyPair, Hash, PublicKey, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        ProofOfStake, SealedHeader, SlashingEvidence, StakeAction, StakeLedger, StakeRecord,
+    };
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    enum Staking {
+        Note(String),
+        Lock(u64),
+        Unlock(u64),
+        Slash(SlashingEvidence),
+    }
+
+    impl StakeRecord for Staking {
+        fn stake_action(&self) -> Option<StakeAction> {
+            match self {
+                Staking::Lock(amount) => Some(StakeAction::Lock(*amount)),
+                Staking::Unlock(amount) => Some(StakeAction::Unlock(*amount)),
+                Staking::Slash(evidence) => Some(StakeAction::Slash(Box::new(evidence.clone()))),
+                Staking::Note(_) => None,
+            }
+        }
+    }
+
+    fn block(record: Staking, keypair: &AuthKeyPair) -> LocalInstance<Staking> {
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(record.record(keypair.clone(), Metadata::empty()).unwrap());
+        block
+    }
+
+    fn keys(n: usize) -> Vec<AuthKeyPair> {
+        (0..n).map(|_| crate::generate_ed25519_keypair()).collect()
+    }
+
+    fn find<'a>(keys: &'a [AuthKeyPair], key: &PublicKey) -> &'a AuthKeyPair {
+        keys.iter()
+            .find(|k| k.public_key_bytes() == key.as_bytes())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stake_weighted_leaders() {
+        let keys = keys(2);
+        let ledger = StakeLedger::new(vec![
+            (keys[0].clone().into_public_key(), 90),
+            (keys[1].clone().into_public_key(), 10),
+        ]);
+        assert_eq!(ledger.total(), 100);
+
+        let mut wins = 0;
+        for pos in 1..=1000 {
+            let leader = ledger.leader(&Hash::default(), pos.into()).unwrap();
+            // the draw only depends on the previous hash and the slot
+            assert_eq!(Some(leader), ledger.leader(&Hash::default(), pos.into()));
+            if leader.as_bytes() == keys[0].public_key_bytes() {
+                wins += 1;
+            }
+        }
+        assert!(
+            (850..=950).contains(&wins),
+            "heavy validator led {wins} slots"
+        );
+        assert!(StakeLedger::default()
+            .leader(&Hash::default(), 1.into())
+            .is_none());
+    }
+
+    #[test]
+    fn test_lock_limits() {
+        let keys = keys(3);
+        let mut ledger = StakeLedger::new(vec![(keys[0].clone().into_public_key(), 10)])
+            .with_balances(vec![
+                (keys[1].clone().into_public_key(), 30),
+                (keys[2].clone().into_public_key(), u64::MAX),
+            ]);
+        let lock = |key: &AuthKeyPair, amount| {
+            Staking::Lock(amount)
+                .record(key.clone(), Metadata::empty())
+                .unwrap()
+        };
+
+        // stake is locked from the balance of the signer, and from nothing else
+        assert!(!ledger.apply(&lock(&keys[0], 1)));
+        assert!(!ledger.apply(&lock(&keys[1], 31)));
+        assert!(ledger.apply(&lock(&keys[1], 20)));
+        let second = keys[1].clone().into_public_key();
+        assert_eq!(ledger.stake_of(&second), 20);
+        assert_eq!(ledger.balance_of(&second), 10);
+
+        // unlocked stake goes back to the balance
+        let unlock = Staking::Unlock(15).record(keys[1].clone(), Metadata::empty());
+        assert!(ledger.apply(&unlock.unwrap()));
+        assert_eq!(ledger.stake_of(&second), 5);
+        assert_eq!(ledger.balance_of(&second), 25);
+
+        // and no lock takes the total stake above `u64::MAX`
+        assert!(!ledger.apply(&lock(&keys[2], u64::MAX)));
+        assert!(ledger.apply(&lock(&keys[2], u64::MAX - 15)));
+        assert_eq!(ledger.total(), u64::MAX);
+        assert!(ledger.leader(&Hash::default(), 1.into()).is_some());
+    }
+
+    #[test]
+    fn test_seal_and_slash() {
+        let chain_url = "target2/tests/poschain/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+
+        let keys = keys(3);
+        let stakes = keys
+            .iter()
+            .map(|k| (k.clone().into_public_key(), 50))
+            .collect::<Vec<_>>();
+        let pos = ProofOfStake::new(chain_url, stakes.clone()).with_balances(stakes);
+
+        let mut evidence = None;
+        for _ in 0..4 {
+            let (prev_hash, position) = next_in(&chain).unwrap();
+            let leader = pos.leader_at(&prev_hash, position).unwrap().unwrap();
+            let leader = find(&keys, &leader);
+
+            let mut next = block(Staking::Note("hello".to_owned()), leader);
+            let outsider = keys
+                .iter()
+                .find(|k| k.public_key_bytes() != leader.public_key_bytes())
+                .unwrap();
+            assert!(pos.seal(&mut next, outsider).is_err());
+            pos.seal(&mut next, leader).unwrap();
+
+            // the leader also seals a conflicting block for the same slot
+            if evidence.is_none() {
+                let mut other = block(Staking::Note("conflict".to_owned()), leader);
+                other.seal = Some(
+                    Seal::sign(&crate::hash_header(&other, &prev_hash, &position), leader).unwrap(),
+                );
+                let first = SealedHeader::of_local(&next, &prev_hash, position).unwrap();
+                let second = SealedHeader::of_local(&other, &prev_hash, position).unwrap();
+                assert!(SlashingEvidence::new(first.clone(), first.clone())
+                    .offender()
+                    .is_none());
+                evidence = Some(SlashingEvidence::new(first, second));
+            }
+            chain.append(&next).unwrap();
+        }
+
+        for position in 1..=chain.len().unwrap() {
+            assert!(pos.validate(chain.block_at(position.into()).unwrap()));
+        }
+
+        let evidence = evidence.unwrap();
+        let offender = evidence.offender().unwrap().clone();
+        let before = pos.stakes_at(5.into()).unwrap();
+        assert_eq!(before.stake_of(&offender), 50);
+
+        // anyone can submit the evidence; the block still has to be sealed by the leader
+        let (prev_hash, position) = next_in(&chain).unwrap();
+        let leader = find(
+            &keys,
+            &pos.leader_at(&prev_hash, position).unwrap().unwrap(),
+        )
+        .clone();
+        let mut slash = block(Staking::Slash(evidence.clone()), &keys[0]);
+        pos.seal(&mut slash, &leader).unwrap();
+        chain.append(&slash).unwrap();
+
+        let after = pos.stakes_at(6.into()).unwrap();
+        assert_eq!(after.stake_of(&offender), 0);
+        assert_eq!(after.total(), 100);
+
+        // locking more stake takes effect from the next block
+        let (prev_hash, position) = next_in(&chain).unwrap();
+        let leader = find(
+            &keys,
+            &pos.leader_at(&prev_hash, position).unwrap().unwrap(),
+        )
+        .clone();
+        let mut lock = block(Staking::Lock(25), &leader);
+        pos.seal(&mut lock, &leader).unwrap();
+        chain.append(&lock).unwrap();
+        let mut ledger = pos.stakes_at(7.into()).unwrap();
+        assert_eq!(ledger.stake_of(&leader.clone().into_public_key()), 75);
+
+        // the offender can lock stake again, and the same offence is not slashed twice
+        let offender_keys = find(&keys, &offender);
+        let relock = Staking::Lock(10).record(offender_keys.clone(), Metadata::empty());
+        assert!(ledger.apply(&relock.unwrap()));
+        assert!(ledger.is_slashed(&evidence));
+        let swapped = SlashingEvidence::new(evidence.second.clone(), evidence.first.clone());
+        for evidence in [evidence, swapped] {
+            let again = Staking::Slash(evidence).record(keys[0].clone(), Metadata::empty());
+            assert!(!ledger.apply(&again.unwrap()));
+        }
+        assert_eq!(ledger.stake_of(&offender), 10);
+    }
+
+    #[test]
+    fn test_fork_stakes() {
+        let chain_url = "target2/tests/poschainactive/";
+        let fork_url = "target2/tests/poschainfork/";
+        for url in [chain_url, fork_url] {
+            let _ = std::fs::remove_dir_all(url);
+            std::fs::create_dir_all(url).expect("could not create chain_url");
+        }
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+        let mut fork = SqliteChain::new(fork_url).expect("sqlite connection cannot be established");
+
+        let keys = keys(2);
+        let stakes = keys
+            .iter()
+            .map(|k| (k.clone().into_public_key(), 50))
+            .collect();
+        let mut pos = ProofOfStake::new(chain_url, stakes);
+        pos.add_branch(fork_url);
+        let first = keys[0].clone().into_public_key();
+
+        // the active chain unlocks most of the first stake in its first block, the fork
+        // does not
+        let leader = find(
+            &keys,
+            &pos.leader_at(&Hash::default(), 1.into()).unwrap().unwrap(),
+        );
+        let mut unlock = block(Staking::Unlock(49), &keys[0]);
+        pos.seal(&mut unlock, leader).unwrap();
+        chain.append(&unlock).unwrap();
+        assert_eq!(pos.stakes_at(2.into()).unwrap().stake_of(&first), 1);
+
+        let mut prev_hash = Hash::default();
+        for position in 1..=3u64 {
+            let leader = pos.leader_at(&prev_hash, position.into()).unwrap().unwrap();
+            let leader = find(&keys, &leader);
+            let mut next = block(Staking::Note("fork".to_owned()), leader);
+            seal_block(&mut next, leader, &prev_hash, position.into()).unwrap();
+            fork.append(&next).unwrap();
+            prev_hash = fork.block_at(position.into()).unwrap().hash().unwrap();
+        }
+
+        // the fork is judged by its own stakes
+        let fork_hash = fork.block_at(1.into()).unwrap().hash().unwrap();
+        let stakes = pos.stakes_following(&fork_hash, 2.into()).unwrap();
+        assert_eq!(stakes.stake_of(&first), 50);
+        for position in 1..=3u64 {
+            assert!(pos.validate(fork.block_at(position.into()).unwrap()));
+        }
+
+        // a block following no known block is rejected
+        let mut orphan = block(Staking::Note("orphan".to_owned()), &keys[0]);
+        seal_block(&mut orphan, &keys[0], &crate::random_sha256(), 4.into()).unwrap();
+        fork.append(&orphan).unwrap();
+        assert!(!pos.validate(fork.block_at(4.into()).unwrap()));
+    }
+}
@@ -0,0 +1,285 @@
+//! Proof-of-work mining.
+//!
+//! A `ProofOfWork` engine searches for a `Nonce` whose work hash over a block's header
+//! hash (see `crate::hash_header`) meets a `Target`. The search can be spread across
+//! several threads and stopped at any time through a `Cancellation` handle.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{
+    block::{BlockError, ChainedInstance, LocalInstance},
+    chain::Chain,
+    data::{Nonce, Position, Target},
+    node::MiningError,
+    record::Record,
+    Hash,
+};
+
+use super::puzzles::{work_hash, ConsensusPuzzle, WorkPuzzle};
+
+/// A handle that can be used to stop a running proof-of-work search from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    flag: Arc<AtomicBool>,
+}
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops every search that was started with this handle or one of its clones.
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+/// A proof-of-work engine.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::{block::LocalInstance, data::Metadata, Hash};
+/// use blockify::consensus::pow::{Cancellation, ProofOfWork};
+///
+/// let mut block = LocalInstance::<String>::new(Metadata::empty(), 0);
+/// let engine = ProofOfWork::with_difficulty(8).threads(2);
+///
+/// let nonce = engine
+///     .mine(&mut block, &Hash::default(), 1.into(), &Cancellation::new())
+///     .unwrap();
+///
+/// assert_eq!(block.nonce, nonce);
+/// assert_eq!(block.target.difficulty(), 8);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ProofOfWork {
+    target: Target,
+    threads: usize,
+}
+
+impl ProofOfWork {
+    /// Creates a single-threaded engine that mines for `target`.
+    pub fn new(target: Target) -> Self {
+        Self { target, threads: 1 }
+    }
+
+    /// Creates a single-threaded engine that requires `bits` leading zero bits.
+    pub fn with_difficulty(bits: u32) -> Self {
+        Self::new(Target::from_difficulty(bits))
+    }
+
+    /// Sets the number of threads used by a search.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Returns the puzzle that `block` must solve to be appended at `position` after `prev_hash`.
+    pub fn puzzle<R>(
+        block: &LocalInstance<R>,
+        prev_hash: &Hash,
+        position: &Position,
+    ) -> WorkPuzzle {
+        WorkPuzzle::new(block.target, crate::hash_header(block, prev_hash, position))
+    }
+
+    /// Checks that a block which is already in a chain solves the puzzle for its own target.
+    pub fn verify_block<R: Record, B: ChainedInstance<R>>(block: &B) -> Result<bool, BlockError> {
+        let header = block.header()?;
+        let puzzle = WorkPuzzle::new(header.target, header.seal_hash());
+        Ok(puzzle.verify(header.nonce))
+    }
+
+    /// Searches for a nonce, starting at `start`, whose work hash over `header_hash` meets the target.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Nonce)` once a solution is found
+    /// - `Err(MiningError::Cancelled)` if `cancel` is triggered first
+    /// - `Err(MiningError::Exhausted)` if no nonce above `start` meets the target
+    pub fn search(
+        &self,
+        header_hash: &Hash,
+        start: Nonce,
+        cancel: &Cancellation,
+    ) -> Result<Nonce, MiningError> {
+        let found = AtomicBool::new(false);
+        let solution = Mutex::new(None);
+        let step = self.threads as u64;
+
+        std::thread::scope(|scope| {
+            for offset in 0..step {
+                let (found, solution) = (&found, &solution);
+                scope.spawn(move || {
+                    let mut nonce = match start.nonce.checked_add(offset) {
+                        Some(nonce) => nonce,
+                        None => return,
+                    };
+                    while !found.load(Ordering::Relaxed) && !cancel.is_cancelled() {
+                        let attempt = Nonce::new(nonce);
+                        if self.target.is_met_by(&work_hash(header_hash, &attempt)) {
+                            found.store(true, Ordering::Relaxed);
+                            solution.lock().unwrap().get_or_insert(attempt);
+                            return;
+                        }
+                        nonce = match nonce.checked_add(step) {
+                            Some(nonce) => nonce,
+                            None => return,
+                        };
+                    }
+                });
+            }
+        });
+
+        match solution.into_inner().unwrap() {
+            Some(nonce) => Ok(nonce),
+            None if cancel.is_cancelled() => Err(MiningError::Cancelled),
+            None => Err(MiningError::Exhausted),
+        }
+    }
+
+    /// Mines `block` so that it can be appended at `position` after `prev_hash`.
+    ///
+    /// The block's target is set to the target of this engine and, on success, its nonce
+    /// is replaced by the solution, which is also returned.
+    pub fn mine<R>(
+        &self,
+        block: &mut LocalInstance<R>,
+        prev_hash: &Hash,
+        position: Position,
+        cancel: &Cancellation,
+    ) -> Result<Nonce, MiningError> {
+        block.target = self.target;
+        let header_hash = crate::hash_header(block, prev_hash, &position);
+        let nonce = self.search(&header_hash, block.nonce, cancel)?;
+        block.nonce = nonce;
+        Ok(nonce)
+    }
+
+    /// Mines `block` so that it can be appended to the end of `chain`.
+    pub fn mine_next<R: Record, C: Chain<R>>(
+        &self,
+        chain: &C,
+        block: &mut LocalInstance<R>,
+        cancel: &Cancellation,
+    ) -> Result<Nonce, MiningError> {
+        let (prev_hash, position) = super::next_in(chain)?;
+        self.mine(block, &prev_hash, position, cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::{BlockData, ChainedInstance},
+        chain::{Chain, ChainError},
+        consensus::puzzles::{work_hash, ConsensusPuzzle},
+        data::{Metadata, Target},
+        node::MiningError,
+        record::Record,
+        Hash, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{Cancellation, ProofOfWork};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Vote {
+        data: String,
+    }
+
+    fn block(datas: &[&str]) -> LocalInstance<Vote> {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        for data in datas {
+            let vote = Vote {
+                data: data.to_string(),
+            };
+            block.push(vote.record(keypair.clone(), Metadata::empty()).unwrap());
+        }
+        block
+    }
+
+    #[test]
+    fn test_target_bits() {
+        let target = Target::from_difficulty(12);
+        assert_eq!(target.difficulty(), 12);
+        assert_eq!(&target.as_bytes()[..3], &[0x00, 0x0f, 0xff]);
+        assert_eq!(Target::from_difficulty(0), Target::MAX);
+        assert!(target.is_met_by(&[0x00, 0x0f, 0xff, 0xff].repeat(8)));
+        assert!(!target.is_met_by(&[0x00, 0x10, 0x00, 0x00].repeat(8)));
+    }
+
+    #[test]
+    fn test_mine() {
+        for threads in [1, 4] {
+            let mut block = block(&["abcd", "efgh"]);
+            let prev_hash = crate::random_sha256();
+            let engine = ProofOfWork::with_difficulty(12).threads(threads);
+            let nonce = engine
+                .mine(&mut block, &prev_hash, 3.into(), &Cancellation::new())
+                .expect("mining failed");
+
+            let puzzle = ProofOfWork::puzzle(&block, &prev_hash, &3.into());
+            assert!(puzzle.verify(nonce));
+
+            let header_hash = crate::hash_header(&block, &prev_hash, &3.into());
+            assert!(blockify::data::leading_zero_bits(&work_hash(&header_hash, &nonce)) >= 12);
+        }
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Cancellation::new();
+        cancel.cancel();
+        let engine = ProofOfWork::with_difficulty(128).threads(2);
+        let res = engine.search(&Hash::default(), 0.into(), &cancel);
+        assert!(matches!(res, Err(MiningError::Cancelled)));
+    }
+
+    #[test]
+    fn test_append_mined() {
+        let chain_url = "target2/tests/powchain/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+        let engine = ProofOfWork::with_difficulty(10);
+
+        let mut first = block(&["abcd"]);
+        engine
+            .mine_next(&chain, &mut first, &Cancellation::new())
+            .unwrap();
+        let position = chain.append(&first).expect("mined block rejected");
+        let stored = position.block(&chain).unwrap();
+        assert_eq!(stored.target().unwrap(), engine.target());
+        assert_eq!(stored.nonce().unwrap(), first.nonce);
+
+        let mut second = block(&["efgh"]);
+        engine
+            .mine_next(&chain, &mut second, &Cancellation::new())
+            .unwrap();
+        let puzzle = ProofOfWork::puzzle(&second, &stored.hash().unwrap(), &2.into());
+        while puzzle.verify(second.nonce) {
+            second.nonce.nonce += 1;
+        }
+        assert!(matches!(
+            chain.append(&second),
+            Err(ChainError::NotValid(BlockData::Nonce))
+        ));
+    }
+}
@@ -25,3 +25,32 @@ impl<const D: usize> ConsensusPuzzle for HashPrefixPuzzle<D> {
         self.test_value(&attempt)
     }
 }
+
+/// The puzzle solved by proof-of-work: find a `Nonce` whose work hash over a block's
+/// header hash meets the block's `Target`.
+pub struct WorkPuzzle {
+    target: crate::data::Target,
+    input: crate::Hash,
+}
+
+impl WorkPuzzle {
+    pub fn new(target: crate::data::Target, input: crate::Hash) -> Self {
+        Self { target, input }
+    }
+
+    pub fn test_value(&self, nonce: &crate::data::Nonce) -> bool {
+        self.target.is_met_by(&work_hash(&self.input, nonce))
+    }
+}
+
+impl ConsensusPuzzle for WorkPuzzle {
+    type AttemptType = crate::data::Nonce;
+    fn verify(&self, attempt: Self::AttemptType) -> bool {
+        self.test_value(&attempt)
+    }
+}
+
+/// Computes the hash that a proof-of-work attempt is judged by.
+pub fn work_hash(header_hash: &crate::Hash, nonce: &crate::data::Nonce) -> crate::Hash {
+    crate::sha_all([header_hash.as_bytes(), &nonce.nonce.to_be_bytes()])
+}
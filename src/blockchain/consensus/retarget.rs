@@ -0,0 +1,379 @@
+//! Difficulty adjustment.
+//!
+//! A `Retarget` describes how the proof-of-work `Target` of the next block is derived
+//! from the timestamps and targets of the blocks before it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockData, ChainedInstance},
+    chain::{Chain, ChainError},
+    data::{Position, Target, Timestamp},
+    record::Record,
+};
+
+use super::pow::ProofOfWork;
+
+/// A difficulty adjustment algorithm.
+///
+/// `spacing` is the desired number of seconds between two consecutive blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Retarget {
+    /// Every block uses the same target.
+    Fixed(Target),
+
+    /// Bitcoin-style retargeting: the target is kept for `interval` blocks and then scaled
+    /// by how long those blocks actually took, by at most a factor of four.
+    Epoch {
+        initial: Target,
+        interval: u64,
+        spacing: u64,
+    },
+
+    /// A linearly weighted moving average of the solve times of the last `window` blocks,
+    /// applied on every block.
+    Lwma {
+        initial: Target,
+        window: u64,
+        spacing: u64,
+    },
+}
+
+impl Retarget {
+    pub fn fixed(target: Target) -> Self {
+        Retarget::Fixed(target)
+    }
+
+    pub fn epoch(initial: Target, interval: u64, spacing: u64) -> Self {
+        Retarget::Epoch {
+            initial,
+            interval: interval.max(2),
+            spacing: spacing.max(1),
+        }
+    }
+
+    pub fn lwma(initial: Target, window: u64, spacing: u64) -> Self {
+        Retarget::Lwma {
+            initial,
+            window: window.max(1),
+            spacing: spacing.max(1),
+        }
+    }
+
+    /// Returns how many of the preceding blocks `required` needs to look at.
+    pub fn lookback(&self) -> u64 {
+        match self {
+            Retarget::Fixed(_) => 0,
+            Retarget::Epoch { interval, .. } => (*interval).max(2),
+            Retarget::Lwma { window, .. } => window.saturating_add(1),
+        }
+    }
+
+    /// Computes the target of the block at `next` from the blocks immediately preceding it.
+    ///
+    /// # Arguments
+    ///
+    /// * `next` - The position of the block whose target is computed.
+    /// * `recent` - The timestamps and targets of the blocks before `next`, oldest first.
+    ///   Only the last `lookback()` entries are used.
+    ///
+    /// Parameters that were not built by the constructors, such as those read from a chain
+    /// spec, are raised to the same minimums, and products of them saturate.
+    pub fn required(&self, next: Position, recent: &[(Timestamp, Target)]) -> Target {
+        match *self {
+            Retarget::Fixed(target) => target,
+            Retarget::Epoch {
+                initial,
+                interval,
+                spacing,
+            } => {
+                let (interval, spacing) = (interval.max(2), spacing.max(1));
+                let last = match recent.last() {
+                    Some((_, target)) => *target,
+                    None => return initial,
+                };
+                let at_boundary = next.pos > 1 && (next.pos - 1).is_multiple_of(interval);
+                if !at_boundary || (recent.len() as u64) < interval {
+                    return last;
+                }
+                let first = recent[recent.len() - interval as usize].0.secs();
+                let actual = recent[recent.len() - 1].0.secs().saturating_sub(first);
+                let expected = spacing.saturating_mul(interval - 1);
+                let actual = actual.clamp((expected / 4).max(1), expected.saturating_mul(4));
+                Wide::from_target(&last)
+                    .mul(actual)
+                    .div(expected)
+                    .into_target()
+            }
+            Retarget::Lwma {
+                initial,
+                window,
+                spacing,
+            } => {
+                let spacing = spacing.max(1);
+                let take = recent.len().min((window as usize).saturating_add(1));
+                let recent = &recent[recent.len() - take..];
+                if recent.len() < 2 {
+                    return recent.last().map(|(_, t)| *t).unwrap_or(initial);
+                }
+                let n = recent.len() as u64 - 1;
+                let mut weighted = 0;
+                let mut sum = Wide::default();
+                for (i, pair) in recent.windows(2).enumerate() {
+                    let solve_time = pair[1].0.secs().saturating_sub(pair[0].0.secs());
+                    let solve_time = solve_time.clamp(1, spacing.saturating_mul(6));
+                    weighted = (i as u64 + 1)
+                        .saturating_mul(solve_time)
+                        .saturating_add(weighted);
+                    sum = sum.add(&Wide::from_target(&pair[1].1));
+                }
+                let expected = spacing.saturating_mul(n * (n + 1) / 2);
+                sum.div(n).mul(weighted).div(expected).into_target()
+            }
+        }
+    }
+
+    /// Computes the target that the next block appended to `chain` must carry.
+    pub fn next_target<R: Record, C: Chain<R>>(&self, chain: &C) -> Result<Target, ChainError> {
+        let len = chain.len()?;
+        let recent = Self::history(chain, len.saturating_sub(self.lookback()) + 1, len)?;
+        Ok(self.required((len + 1).into(), &recent))
+    }
+
+    /// Checks that every block of `chain` carries the target required by this algorithm
+    /// and solves the proof-of-work puzzle for it.
+    pub fn validate_chain<R: Record, C: Chain<R>>(&self, chain: &C) -> Result<(), ChainError> {
+        let len = chain.len()?;
+        let mut recent: Vec<(Timestamp, Target)> = Vec::new();
+        for pos in 1..=len {
+            let block = chain.block_at(pos.into())?;
+            let target = block.target()?;
+            let lookback = self.lookback() as usize;
+            let window = &recent[recent.len().saturating_sub(lookback)..];
+            if target != self.required(pos.into(), window) {
+                return Err(ChainError::NotValid(BlockData::Target));
+            }
+            if !ProofOfWork::verify_block(&block)? {
+                return Err(ChainError::NotValid(BlockData::Nonce));
+            }
+            recent.push((block.timestamp()?, target));
+        }
+        Ok(())
+    }
+
+    fn history<R: Record, C: Chain<R>>(
+        chain: &C,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<(Timestamp, Target)>, ChainError> {
+        let mut res = Vec::with_capacity(to.saturating_sub(from) as usize + 1);
+        for pos in from..=to {
+            let block = chain.block_at(pos.into())?;
+            res.push((block.timestamp()?, block.target()?));
+        }
+        Ok(res)
+    }
+}
+
+/// A 384-bit unsigned integer with little-endian limbs, wide enough to hold a target
+/// multiplied by a 64-bit factor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Wide([u64; 6]);
+
+impl Wide {
+    fn from_target(target: &Target) -> Self {
+        let bytes = target.as_bytes();
+        let mut limbs = [0; 6];
+        for (i, limb) in limbs.iter_mut().take(4).enumerate() {
+            let end = 32 - 8 * i;
+            *limb = u64::from_be_bytes(bytes[end - 8..end].try_into().unwrap());
+        }
+        Wide(limbs)
+    }
+
+    /// Converts back into a target, saturating at `Target::MAX`.
+    fn into_target(self) -> Target {
+        if self.0[4] != 0 || self.0[5] != 0 {
+            return Target::MAX;
+        }
+        let mut bytes = [0; 32];
+        for i in 0..4 {
+            let end = 32 - 8 * i;
+            bytes[end - 8..end].copy_from_slice(&self.0[i].to_be_bytes());
+        }
+        Target::new(bytes)
+    }
+
+    fn add(self, other: &Wide) -> Self {
+        let mut res = [0; 6];
+        let mut carry = 0u128;
+        for (i, limb) in res.iter_mut().enumerate() {
+            let sum = self.0[i] as u128 + other.0[i] as u128 + carry;
+            *limb = sum as u64;
+            carry = sum >> 64;
+        }
+        Wide(res)
+    }
+
+    fn mul(self, factor: u64) -> Self {
+        let mut res = [0; 6];
+        let mut carry = 0u128;
+        for (i, limb) in res.iter_mut().enumerate() {
+            let prod = self.0[i] as u128 * factor as u128 + carry;
+            *limb = prod as u64;
+            carry = prod >> 64;
+        }
+        Wide(res)
+    }
+
+    fn div(self, divisor: u64) -> Self {
+        let divisor = divisor.max(1) as u128;
+        let mut res = [0; 6];
+        let mut rem = 0u128;
+        for i in (0..6).rev() {
+            let cur = (rem << 64) | self.0[i] as u128;
+            res[i] = (cur / divisor) as u64;
+            rem = cur % divisor;
+        }
+        Wide(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::BlockData,
+        chain::{Chain, ChainError},
+        consensus::pow::{Cancellation, ProofOfWork},
+        data::{Metadata, Target, Timestamp},
+        SqliteChain,
+    };
+
+    use super::{Retarget, Wide};
+
+    fn history(times: &[u64], target: Target) -> Vec<(Timestamp, Target)> {
+        times
+            .iter()
+            .map(|secs| (Timestamp::from_secs(*secs), target))
+            .collect()
+    }
+
+    #[test]
+    fn test_wide() {
+        let target = Target::from_difficulty(20);
+        let value = Wide::from_target(&target);
+        assert_eq!(value.into_target(), target);
+        assert_eq!(value.mul(1000).div(1000).into_target(), target);
+        assert_eq!(value.mul(2).into_target().difficulty(), 19);
+        assert_eq!(
+            Wide::from_target(&Target::MAX).mul(2).into_target(),
+            Target::MAX
+        );
+    }
+
+    #[test]
+    fn test_epoch() {
+        let start = Target::from_difficulty(20);
+        let retarget = Retarget::epoch(start, 4, 20);
+
+        assert_eq!(retarget.required(1.into(), &[]), start);
+
+        // not on an epoch boundary
+        let on_time = history(&[0, 20, 40], start);
+        assert_eq!(retarget.required(4.into(), &on_time), start);
+
+        // blocks took exactly as long as expected
+        let on_time = history(&[0, 20, 40, 60], start);
+        assert_eq!(retarget.required(5.into(), &on_time), start);
+
+        // blocks came twice as fast
+        let fast = history(&[0, 10, 20, 30], start);
+        assert_eq!(
+            retarget.required(5.into(), &fast),
+            Target::from_difficulty(21)
+        );
+
+        // adjustments are clamped to a factor of four
+        let instant = history(&[0, 0, 0, 0], start);
+        assert_eq!(
+            retarget.required(5.into(), &instant),
+            Target::from_difficulty(22)
+        );
+        let slow = history(&[0, 1000, 2000, 3000], start);
+        assert_eq!(retarget.required(5.into(), &slow).difficulty(), 18);
+
+        // parameters read from a spec are not checked by the constructor
+        let unchecked = Retarget::Epoch {
+            initial: start,
+            interval: 0,
+            spacing: u64::MAX,
+        };
+        let on_time = history(&[0, u64::MAX], start);
+        assert_eq!(unchecked.lookback(), 2);
+        assert_eq!(unchecked.required(3.into(), &on_time), start);
+    }
+
+    #[test]
+    fn test_lwma() {
+        let start = Target::from_difficulty(20);
+        let retarget = Retarget::lwma(start, 3, 10);
+
+        assert_eq!(retarget.required(1.into(), &[]), start);
+
+        let on_time = history(&[0, 10, 20, 30], start);
+        assert_eq!(retarget.required(5.into(), &on_time), start);
+
+        let fast = history(&[0, 5, 10, 15], start);
+        assert_eq!(
+            retarget.required(5.into(), &fast),
+            Target::from_difficulty(21)
+        );
+
+        let slow = history(&[0, 20, 40, 60], start);
+        assert_eq!(retarget.required(5.into(), &slow).difficulty(), 19);
+
+        let unchecked = Retarget::Lwma {
+            initial: start,
+            window: u64::MAX,
+            spacing: u64::MAX,
+        };
+        assert_eq!(unchecked.lookback(), u64::MAX);
+        let on_time = history(&[0, u64::MAX], start);
+        assert_eq!(unchecked.required(3.into(), &on_time), start);
+    }
+
+    #[test]
+    fn test_enforced_on_append() {
+        let chain_url = "target2/tests/retargetchain/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+
+        let retarget = Retarget::fixed(Target::from_difficulty(6));
+        let mut chain = SqliteChain::<String>::new(chain_url)
+            .expect("sqlite connection cannot be established")
+            .with_retarget(retarget);
+
+        for _ in 0..2 {
+            let target = retarget.next_target(&chain).unwrap();
+            let mut block = LocalInstance::new(Metadata::empty(), 0);
+            ProofOfWork::new(target)
+                .mine_next(&chain, &mut block, &Cancellation::new())
+                .unwrap();
+            chain
+                .append(&block)
+                .expect("block with required target rejected");
+        }
+        retarget.validate_chain(&chain).expect("chain is not valid");
+
+        let mut easy = LocalInstance::new(Metadata::empty(), 0);
+        ProofOfWork::with_difficulty(2)
+            .mine_next(&chain, &mut easy, &Cancellation::new())
+            .unwrap();
+        assert!(matches!(
+            chain.append(&easy),
+            Err(ChainError::NotValid(BlockData::Target))
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+//! Fork choice rules and branch stores shared by the consensus protocols of this crate.
+
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{chain::Chain, error::DataBaseError, record::Record, SqliteChain};
+
+use super::{ChainBranches, ConsensusError, ConsensusRules};
+
+/// Picks the branch with the most blocks, preferring the earliest one on a tie.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LongestChain;
+
+impl<R: Record, C: Chain<R>> ConsensusRules<R, C> for LongestChain {
+    fn merge(&mut self, branches: Vec<C>) -> Result<C, ConsensusError> {
+        let mut best: Option<(u64, C)> = None;
+        for branch in branches {
+            let len = branch.len()?;
+            match &best {
+                Some((best_len, _)) if *best_len >= len => {}
+                _ => best = Some((len, branch)),
+            }
+        }
+        best.map(|(_, branch)| branch)
+            .ok_or(ConsensusError::Unspecified)
+    }
+}
+
+/// The known branches of a chain, each stored as a `SqliteChain` in its own directory.
+pub struct SqliteBranches<X> {
+    urls: Vec<String>,
+    _data: PhantomData<X>,
+}
+
+impl<X> SqliteBranches<X> {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self {
+            urls,
+            _data: PhantomData,
+        }
+    }
+
+    pub fn push(&mut self, url: &str) {
+        self.urls.push(url.to_owned())
+    }
+
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+}
+
+impl<X, Rl> ChainBranches<X, SqliteChain<X>, Rl> for SqliteBranches<X>
+where
+    X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static,
+    Rl: ConsensusRules<X, SqliteChain<X>>,
+{
+    fn branches(&self) -> Result<Vec<SqliteChain<X>>, ConsensusError> {
+        self.urls
+            .iter()
+            .map(|url| {
+                SqliteChain::new(url)
+                    .map_err(|_| ConsensusError::DataBaseError(DataBaseError::ConnectionFailed))
+            })
+            .collect()
+    }
+
+    fn merge(&mut self, mut rules: Rl) -> Result<SqliteChain<X>, ConsensusError> {
+        let branches = ChainBranches::<X, SqliteChain<X>, Rl>::branches(self)?;
+        rules.merge(branches)
+    }
+}
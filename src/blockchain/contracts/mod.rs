@@ -0,0 +1,923 @@
+//! Smart contracts.
+//!
+//! Contracts are Rust types implementing `Contract`, made available to a chain by a
+//! `Contracts` registry under a code name. Records implementing `ContractRecord` deploy
+//! instances of them and call them; a `ContractHost` executes these calls when blocks are
+//! applied, as a `StateMachine` kept in step with a chain by a `StateRunner`.
+//!
+//! Every instance has its own key/value `Storage`, which no other instance can read or
+//! write. A call that fails leaves the storage as it was. Every deploy and call has a
+//! `CallOutcome` holding its output and the events it emitted, which is turned into the
+//! receipt stored in the block.
+//!
+//! Calls must be deterministic: a contract must not read the clock, randomness or anything
+//! but its arguments, its storage and the block in `Context`, so that every node reaches the
+//! same state. Every call has a gas limit, which a contract charges as it runs.
+//!
+//! With the `wasm` feature, contracts can also be uploaded as WebAssembly modules, which
+//! `wasm::WasmContract` runs with gas metering.
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "wasm")]
+use std::{cell::RefCell, collections::HashMap, sync::Arc};
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{Position, Timestamp},
+    receipt::{Log, Receipt, Status},
+    record::SignedRecord,
+    sparse_merkle::{SparseMerkleTree, SparseProof},
+    state::{BlockContext, StateError, StateMachine},
+    Hash, PublicKey,
+};
+
+/// The gas an upload uses for every byte of its code, which pays for compiling it.
+pub const UPLOAD_GAS_PER_BYTE: u64 = 10;
+
+/// An error that can occur when deploying, calling or querying a contract.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContractError {
+    /// No contract is registered under the code name.
+    UnknownCode,
+    /// No contract is deployed at the address.
+    UnknownContract,
+    /// A contract is already deployed at the address.
+    AlreadyDeployed,
+    /// The contract has no such method.
+    UnknownMethod,
+    /// The arguments could not be decoded.
+    InvalidArgs,
+    /// The contract rejected the call.
+    Failed(String),
+    /// The uploaded code is not a valid contract, or cannot be run.
+    InvalidCode,
+    /// The call used up its gas.
+    OutOfGas,
+    /// The state before the record to revert is no longer known.
+    NoHistory,
+}
+
+impl ContractError {
+    pub fn failed(reason: &str) -> Self {
+        ContractError::Failed(reason.to_owned())
+    }
+}
+
+crate::impl_display_error!(ContractError);
+
+impl From<ContractError> for StateError {
+    fn from(value: ContractError) -> Self {
+        StateError::custom(Box::new(value))
+    }
+}
+
+/// The key/value storage of a contract instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Storage {
+    /// The values by hex-encoded key.
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl Storage {
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(&hex::encode(key)).map(|v| &v[..])
+    }
+
+    pub fn set(&mut self, key: &[u8], value: Vec<u8>) {
+        self.entries.insert(hex::encode(key), value);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.remove(&hex::encode(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Something a contract reports while it runs, recorded in the receipt of the call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub contract: Hash,
+    pub topic: String,
+    pub data: Vec<u8>,
+}
+
+/// What a contract sees of the call it is executing.
+pub struct Context {
+    address: Hash,
+    caller: PublicKey,
+    position: Position,
+    timestamp: Timestamp,
+    storage: Storage,
+    events: Vec<Event>,
+    gas_limit: u64,
+    gas_used: u64,
+}
+
+impl Context {
+    /// Returns the address of the instance being called.
+    pub fn address(&self) -> &Hash {
+        &self.address
+    }
+
+    /// Returns the key that signed the call.
+    pub fn caller(&self) -> &PublicKey {
+        &self.caller
+    }
+
+    /// Returns the position of the block holding the call.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Returns the timestamp of the block holding the call.
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut Storage {
+        &mut self.storage
+    }
+
+    /// Emits an event into the receipt of the call. Events of failed calls are dropped.
+    pub fn emit(&mut self, topic: &str, data: Vec<u8>) {
+        self.events.push(Event {
+            contract: self.address.clone(),
+            topic: topic.to_owned(),
+            data,
+        });
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    pub fn gas_left(&self) -> u64 {
+        self.gas_limit - self.gas_used
+    }
+
+    /// Uses `gas` of the gas left, failing with `ContractError::OutOfGas` if there is not
+    /// enough, in which case all of it is used.
+    pub fn charge(&mut self, gas: u64) -> Result<(), ContractError> {
+        if gas > self.gas_left() {
+            self.gas_used = self.gas_limit;
+            return Err(ContractError::OutOfGas);
+        }
+        self.gas_used += gas;
+        Ok(())
+    }
+}
+
+/// A contract, the code run by its deployed instances.
+pub trait Contract {
+    /// Initializes the storage of a new instance from `args`.
+    fn deploy(&self, ctx: &mut Context, args: &[u8]) -> Result<(), ContractError>;
+
+    /// Runs `method`, which may change the storage, returning its output.
+    fn call(&self, ctx: &mut Context, method: &str, args: &[u8]) -> Result<Vec<u8>, ContractError>;
+
+    /// Runs the read-only `method` against `storage`.
+    fn query(&self, storage: &Storage, method: &str, args: &[u8])
+        -> Result<Vec<u8>, ContractError>;
+}
+
+/// The contracts available to a chain, by code name.
+///
+/// The registry is code, not state: it is not stored with the state of a `ContractHost`,
+/// and a host read back from a checkpoint gets `Default::default()`.
+pub trait Contracts {
+    fn contract(&self, code: &str) -> Option<&dyn Contract>;
+}
+
+/// A request to upload, deploy or call a contract.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContractCall {
+    /// Uploads a WebAssembly contract, which can then be deployed under the code name
+    /// `ContractHost::code_name(&wasm)`. Fails with `ContractError::InvalidCode` without
+    /// the `wasm` feature.
+    Upload { wasm: Vec<u8> },
+    /// Deploys a new instance of the contract registered or uploaded as `code`.
+    Deploy { code: String, args: Vec<u8> },
+    /// Calls `method` of the instance at `address`.
+    Call {
+        address: Hash,
+        method: String,
+        args: Vec<u8>,
+    },
+}
+
+/// Records that can deploy and call contracts.
+pub trait ContractRecord {
+    /// Returns the deploy or call this record makes, if any.
+    fn contract_call(&self) -> Option<ContractCall>;
+}
+
+/// The outcome of a record that uploaded, deployed or called a contract.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallOutcome {
+    /// The hash of the record.
+    pub record: Hash,
+    /// The address of the instance deployed or called, or the hash of the code uploaded.
+    pub contract: Hash,
+    /// The output of the call, or why it failed.
+    pub output: Result<Vec<u8>, ContractError>,
+    pub events: Vec<Event>,
+    pub gas_used: u64,
+}
+
+impl CallOutcome {
+    pub fn is_success(&self) -> bool {
+        self.output.is_ok()
+    }
+}
+
+impl From<&CallOutcome> for Receipt {
+    /// Returns the receipt stored in the block holding the record, whose logs are the
+    /// events, each with its topic.
+    fn from(value: &CallOutcome) -> Self {
+        let status = match &value.output {
+            Ok(_) => Status::Success,
+            Err(e) => Status::Failed(e.to_string()),
+        };
+        let logs = value
+            .events
+            .iter()
+            .map(|event| Log {
+                source: event.contract.clone(),
+                topics: vec![event.topic.clone()],
+                data: event.data.clone(),
+            })
+            .collect();
+        Self {
+            record: value.record.clone(),
+            status,
+            gas_used: value.gas_used,
+            logs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Instance {
+    code: String,
+    storage: Storage,
+}
+
+/// What applying a record changed, to revert it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Undo {
+    Instance {
+        address: String,
+        previous: Option<Instance>,
+    },
+    Upload {
+        code: String,
+        previous: Option<Vec<u8>>,
+    },
+}
+
+/// What the records of an applied block changed, to revert them, and their outcomes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Applied {
+    position: Position,
+    /// The changes of the records, in the order they were applied.
+    undo: Vec<Option<Undo>>,
+    outcomes: Vec<CallOutcome>,
+}
+
+/// Runs the contracts of a chain and keeps their storage.
+///
+/// A failed deploy or call does not fail the block holding it: its outcome holds the error
+/// and it changes nothing else.
+///
+/// The outcomes and changes of the last blocks applied are kept to revert them, but are not
+/// stored in checkpoints: a host read back from one cannot revert the blocks before it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractHost<C> {
+    #[serde(skip)]
+    codes: C,
+    /// The uploaded WebAssembly code, by code name.
+    uploads: BTreeMap<String, Vec<u8>>,
+    /// The uploaded contracts compiled so far, by code name.
+    #[cfg(feature = "wasm")]
+    #[serde(skip)]
+    compiled: RefCell<HashMap<String, Arc<wasm::WasmContract>>>,
+    /// The deployed instances, by hex address.
+    instances: BTreeMap<String, Instance>,
+    /// The last blocks applied, oldest first.
+    #[serde(skip)]
+    history: VecDeque<Applied>,
+    history_depth: usize,
+    gas_limit: u64,
+    /// The position and timestamp of the block being applied.
+    block: (Position, Timestamp),
+}
+
+impl<C: Contracts> ContractHost<C> {
+    pub fn new(codes: C) -> Self {
+        Self {
+            codes,
+            uploads: BTreeMap::new(),
+            #[cfg(feature = "wasm")]
+            compiled: RefCell::new(HashMap::new()),
+            instances: BTreeMap::new(),
+            history: VecDeque::new(),
+            history_depth: 64,
+            gas_limit: 10_000_000,
+            block: (Position::new(0), Timestamp::from_secs(0)),
+        }
+    }
+
+    /// Sets the gas every deploy, call and query may use. Defaults to 10,000,000.
+    pub fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = gas_limit;
+        self
+    }
+
+    /// Keeps what the records of the last `depth` blocks changed, to revert them. Defaults
+    /// to 64, and should be at least the journal depth of the `StateRunner` running this
+    /// host, which restores a checkpoint when a block cannot be reverted.
+    pub fn with_history(mut self, depth: usize) -> Self {
+        self.history_depth = depth;
+        self
+    }
+
+    /// Returns the address of the instance deployed by the record with hash `record`.
+    pub fn address_of(record: &Hash) -> Hash {
+        crate::sha_all([&b"contract"[..], record.as_bytes()])
+    }
+
+    /// Returns the code name under which `wasm` is deployed once uploaded.
+    pub fn code_name(wasm: &[u8]) -> String {
+        crate::sha(&wasm).to_hex()
+    }
+
+    /// Returns `true` if `code` is registered or uploaded.
+    pub fn has_code(&self, code: &str) -> bool {
+        self.codes.contract(code).is_some() || self.uploads.contains_key(code)
+    }
+
+    /// Returns the code name of the instance at `address`, if one is deployed.
+    pub fn code_of(&self, address: &Hash) -> Option<&str> {
+        self.instances
+            .get(&address.to_hex())
+            .map(|instance| instance.code.as_str())
+    }
+
+    /// Returns the storage of the instance at `address`.
+    pub fn storage(&self, address: &Hash) -> Option<&Storage> {
+        self.instances
+            .get(&address.to_hex())
+            .map(|instance| &instance.storage)
+    }
+
+    /// Runs the read-only `method` of the instance at `address`.
+    pub fn query(
+        &self,
+        address: &Hash,
+        method: &str,
+        args: &[u8],
+    ) -> Result<Vec<u8>, ContractError> {
+        let instance = self
+            .instances
+            .get(&address.to_hex())
+            .ok_or(ContractError::UnknownContract)?;
+        self.with_contract(&instance.code, |contract| {
+            contract.query(&instance.storage, method, args)
+        })
+    }
+
+    /// Runs `f` with the contract registered or uploaded as `code`.
+    fn with_contract<T>(
+        &self,
+        code: &str,
+        f: impl FnOnce(&dyn Contract) -> Result<T, ContractError>,
+    ) -> Result<T, ContractError> {
+        if let Some(contract) = self.codes.contract(code) {
+            return f(contract);
+        }
+        match self.uploads.get(code) {
+            #[cfg(feature = "wasm")]
+            Some(wasm) => f(&*self.compile(code, wasm)?),
+            #[cfg(not(feature = "wasm"))]
+            Some(_) => Err(ContractError::InvalidCode),
+            None => Err(ContractError::UnknownCode),
+        }
+    }
+
+    /// Returns the contract uploaded as `code`, compiling `wasm` only the first time.
+    #[cfg(feature = "wasm")]
+    fn compile(&self, code: &str, wasm: &[u8]) -> Result<Arc<wasm::WasmContract>, ContractError> {
+        if let Some(contract) = self.compiled.borrow().get(code) {
+            return Ok(contract.clone());
+        }
+        let contract = Arc::new(wasm::WasmContract::new(wasm)?.with_gas_limit(self.gas_limit));
+        self.compiled
+            .borrow_mut()
+            .insert(code.to_owned(), contract.clone());
+        Ok(contract)
+    }
+
+    /// Returns the outcomes of the uploads, deploys and calls of the blocks this host can
+    /// still revert, oldest first.
+    pub fn outcomes(&self) -> Vec<&CallOutcome> {
+        self.history.iter().flat_map(|a| &a.outcomes).collect()
+    }
+
+    /// Returns the outcome of the record with hash `record`, if its block can still be
+    /// reverted.
+    pub fn outcome(&self, record: &Hash) -> Option<&CallOutcome> {
+        self.outcomes()
+            .into_iter()
+            .rev()
+            .find(|o| &o.record == record)
+    }
+
+    /// Returns the events emitted under `topic` in the blocks this host can still revert,
+    /// oldest first.
+    pub fn events(&self, topic: &str) -> Vec<&Event> {
+        self.outcomes()
+            .into_iter()
+            .flat_map(|o| &o.events)
+            .filter(|e| e.topic == topic)
+            .collect()
+    }
+
+    /// Returns the proof of the value of `key` in the storage of the instance at `address`
+    /// against `root()`.
+    pub fn prove(&self, address: &Hash, key: &[u8]) -> SparseProof {
+        self.tree().prove(&Self::storage_key(address, key))
+    }
+
+    /// Returns the key under which `key` of the instance at `address` is stored in the tree
+    /// whose root is the root of this host.
+    pub fn storage_key(address: &Hash, key: &[u8]) -> Hash {
+        crate::sha_all([address.as_bytes(), key])
+    }
+
+    fn tree(&self) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for (code, wasm) in &self.uploads {
+            let code = hex::decode(code).unwrap_or_default();
+            tree.insert(&crate::sha_all([&b"code"[..], &code]), wasm.clone());
+        }
+        for (address, instance) in &self.instances {
+            let address = hex::decode(address).unwrap_or_default();
+            for (key, value) in &instance.storage.entries {
+                let key = hex::decode(key).unwrap_or_default();
+                tree.insert(&crate::sha_all([&address, &key]), value.clone());
+            }
+        }
+        tree
+    }
+
+    /// Runs the deploy or call `call` against `ctx`, whose storage it sets to the storage
+    /// of the instance, returning the code name of the instance and the output.
+    fn execute(
+        &self,
+        call: &ContractCall,
+        ctx: &mut Context,
+    ) -> Result<(String, Vec<u8>), ContractError> {
+        let key = ctx.address.to_hex();
+        let code = match call {
+            ContractCall::Upload { .. } => return Err(ContractError::InvalidCode),
+            ContractCall::Deploy { .. } if self.instances.contains_key(&key) => {
+                return Err(ContractError::AlreadyDeployed);
+            }
+            ContractCall::Deploy { code, .. } => code.clone(),
+            ContractCall::Call { .. } => {
+                let instance = self
+                    .instances
+                    .get(&key)
+                    .ok_or(ContractError::UnknownContract)?;
+                ctx.storage = instance.storage.clone();
+                instance.code.clone()
+            }
+        };
+        let output = self.with_contract(&code, |contract| match call {
+            ContractCall::Deploy { args, .. } => contract.deploy(ctx, args).map(|_| vec![]),
+            ContractCall::Call { method, args, .. } => contract.call(ctx, method, args),
+            ContractCall::Upload { .. } => unreachable!(),
+        })?;
+        Ok((code, output))
+    }
+
+    /// Stores `wasm` under its code name, returning the outcome of the upload, which uses
+    /// `UPLOAD_GAS_PER_BYTE` for every byte of `wasm`.
+    fn upload(&mut self, record: &Hash, wasm: Vec<u8>) -> (CallOutcome, Undo) {
+        let code = Self::code_name(&wasm);
+        let previous = self.uploads.get(&code).cloned();
+        let gas = (wasm.len() as u64).saturating_mul(UPLOAD_GAS_PER_BYTE);
+        let output = match previous {
+            _ if gas > self.gas_limit => Err(ContractError::OutOfGas),
+            Some(_) => Err(ContractError::AlreadyDeployed),
+            #[cfg(feature = "wasm")]
+            None => self.compile(&code, &wasm).map(|_| vec![]),
+            #[cfg(not(feature = "wasm"))]
+            None => Err(ContractError::InvalidCode),
+        };
+        if output.is_ok() {
+            self.uploads.insert(code.clone(), wasm.clone());
+        }
+        let outcome = CallOutcome {
+            record: record.clone(),
+            contract: crate::sha(&wasm),
+            output,
+            events: vec![],
+            gas_used: gas.min(self.gas_limit),
+        };
+        (outcome, Undo::Upload { code, previous })
+    }
+
+    /// Returns the changes of the block being applied, making room for them if it is not
+    /// the last block applied.
+    fn applied(&mut self) -> &mut Applied {
+        let position = self.block.0;
+        if self.history.back().map(|a| a.position) != Some(position) {
+            self.history.push_back(Applied {
+                position,
+                undo: vec![],
+                outcomes: vec![],
+            });
+            if self.history.len() > self.history_depth {
+                self.history.pop_front();
+            }
+        }
+        self.history.back_mut().unwrap()
+    }
+}
+
+impl<R: ContractRecord, C: Contracts> StateMachine<R> for ContractHost<C> {
+    fn apply(&mut self, record: &SignedRecord<R>) -> Result<(), StateError> {
+        let (undo, outcome) = match record.record().contract_call() {
+            Some(ContractCall::Upload { wasm }) => {
+                let (outcome, undo) = self.upload(record.hash(), wasm);
+                (Some(undo), Some(outcome))
+            }
+            Some(call) => {
+                let address = match &call {
+                    ContractCall::Call { address, .. } => address.clone(),
+                    _ => Self::address_of(record.hash()),
+                };
+                let key = address.to_hex();
+                let previous = self.instances.get(&key).cloned();
+                let mut ctx = Context {
+                    address: address.clone(),
+                    caller: record.signer().clone(),
+                    position: self.block.0,
+                    timestamp: self.block.1,
+                    storage: Storage::default(),
+                    events: vec![],
+                    gas_limit: self.gas_limit,
+                    gas_used: 0,
+                };
+                let (output, events) = match self.execute(&call, &mut ctx) {
+                    Ok((code, output)) => {
+                        let storage = std::mem::take(&mut ctx.storage);
+                        self.instances
+                            .insert(key.clone(), Instance { code, storage });
+                        (Ok(output), std::mem::take(&mut ctx.events))
+                    }
+                    Err(e) => (Err(e), vec![]),
+                };
+                let outcome = CallOutcome {
+                    record: record.hash().clone(),
+                    contract: address,
+                    output,
+                    events,
+                    gas_used: ctx.gas_used,
+                };
+                let undo = Undo::Instance {
+                    address: key,
+                    previous,
+                };
+                (Some(undo), Some(outcome))
+            }
+            None => (None, None),
+        };
+
+        let applied = self.applied();
+        applied.undo.push(undo);
+        applied.outcomes.extend(outcome);
+        Ok(())
+    }
+
+    fn revert(&mut self, _record: &SignedRecord<R>) -> Result<(), StateError> {
+        let applied = match self.history.back_mut() {
+            Some(applied) if applied.position == self.block.0 => applied,
+            _ => return Err(ContractError::NoHistory.into()),
+        };
+        let undo = applied.undo.pop().ok_or(ContractError::NoHistory)?;
+        if undo.is_some() {
+            applied.outcomes.pop();
+        }
+        if applied.undo.is_empty() {
+            self.history.pop_back();
+        }
+        match undo {
+            Some(Undo::Instance { address, previous }) => {
+                match previous {
+                    Some(instance) => self.instances.insert(address, instance),
+                    None => self.instances.remove(&address),
+                };
+            }
+            Some(Undo::Upload { code, previous }) => match previous {
+                Some(wasm) => {
+                    self.uploads.insert(code, wasm);
+                }
+                None => {
+                    #[cfg(feature = "wasm")]
+                    self.compiled.get_mut().remove(&code);
+                    self.uploads.remove(&code);
+                }
+            },
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn begin_block(&mut self, block: &BlockContext) {
+        self.block = (block.position, block.timestamp);
+    }
+
+    fn receipt(&self, record: &SignedRecord<R>) -> Receipt {
+        match self.history.back().and_then(|a| a.outcomes.last()) {
+            Some(outcome) if &outcome.record == record.hash() => outcome.into(),
+            _ => Receipt::success(record.hash()),
+        }
+    }
+
+    fn root(&self) -> Hash {
+        self.tree().root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::ChainedInstance,
+        chain::Chain,
+        data::Metadata,
+        light::verify_state,
+        receipt::{receipts_root, Status},
+        record::{Record, SignedRecord},
+        state::{BlockContext, StateMachine, StateRunner},
+        AuthKeyPair, Hash, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        Context, Contract, ContractCall, ContractError, ContractHost, ContractRecord, Contracts,
+        Storage,
+    };
+
+    /// Counts up from the value it is deployed with.
+    struct Counter;
+
+    impl Counter {
+        fn read(storage: &Storage) -> u64 {
+            storage
+                .get(b"count")
+                .map(|v| u64::from_be_bytes(v.try_into().unwrap()))
+                .unwrap_or(0)
+        }
+
+        fn decode(args: &[u8]) -> Result<u64, ContractError> {
+            Ok(u64::from_be_bytes(
+                args.try_into().map_err(|_| ContractError::InvalidArgs)?,
+            ))
+        }
+    }
+
+    impl Contract for Counter {
+        fn deploy(&self, ctx: &mut Context, args: &[u8]) -> Result<(), ContractError> {
+            let start = Self::decode(args)?;
+            ctx.storage_mut()
+                .set(b"count", start.to_be_bytes().to_vec());
+            Ok(())
+        }
+
+        fn call(
+            &self,
+            ctx: &mut Context,
+            method: &str,
+            args: &[u8],
+        ) -> Result<Vec<u8>, ContractError> {
+            match method {
+                "add" => {
+                    let count = Self::read(ctx.storage()) + Self::decode(args)?;
+                    ctx.storage_mut()
+                        .set(b"count", count.to_be_bytes().to_vec());
+                    ctx.emit("added", args.to_vec());
+                    Ok(count.to_be_bytes().to_vec())
+                }
+                "reset" => {
+                    ctx.storage_mut().remove(b"count");
+                    ctx.emit("reset", vec![]);
+                    Err(ContractError::failed("cannot reset"))
+                }
+                _ => Err(ContractError::UnknownMethod),
+            }
+        }
+
+        fn query(
+            &self,
+            storage: &Storage,
+            method: &str,
+            _args: &[u8],
+        ) -> Result<Vec<u8>, ContractError> {
+            match method {
+                "get" => Ok(Self::read(storage).to_be_bytes().to_vec()),
+                _ => Err(ContractError::UnknownMethod),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct Codes;
+
+    impl Contracts for Codes {
+        fn contract(&self, code: &str) -> Option<&dyn Contract> {
+            match code {
+                "counter" => Some(&Counter),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Tx(ContractCall);
+
+    impl ContractRecord for Tx {
+        fn contract_call(&self) -> Option<ContractCall> {
+            Some(self.0.clone())
+        }
+    }
+
+    fn signed(call: ContractCall, keypair: &AuthKeyPair) -> SignedRecord<Tx> {
+        Tx(call).record(keypair.clone(), Metadata::empty()).unwrap()
+    }
+
+    fn deploy(start: u64, keypair: &AuthKeyPair) -> SignedRecord<Tx> {
+        let call = ContractCall::Deploy {
+            code: "counter".to_owned(),
+            args: start.to_be_bytes().to_vec(),
+        };
+        signed(call, keypair)
+    }
+
+    fn call(address: &Hash, method: &str, amount: u64, keypair: &AuthKeyPair) -> SignedRecord<Tx> {
+        let call = ContractCall::Call {
+            address: address.clone(),
+            method: method.to_owned(),
+            args: amount.to_be_bytes().to_vec(),
+        };
+        signed(call, keypair)
+    }
+
+    fn block(records: Vec<SignedRecord<Tx>>) -> LocalInstance<Tx> {
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        for record in records {
+            block.push(record);
+        }
+        block
+    }
+
+    fn count(host: &ContractHost<Codes>, address: &Hash) -> u64 {
+        let out = host.query(address, "get", &[]).unwrap();
+        u64::from_be_bytes(out.try_into().unwrap())
+    }
+
+    #[test]
+    fn test_contracts() {
+        let url = "target2/tests/contractchain/";
+        let _ = std::fs::remove_dir_all(url);
+        std::fs::create_dir_all(url).expect("could not create url");
+
+        let keypair = crate::generate_ed25519_keypair();
+        let mut chain = SqliteChain::new(url).unwrap();
+        let mut runner = StateRunner::new(ContractHost::new(Codes));
+
+        let (first, second) = (deploy(10, &keypair), deploy(0, &keypair));
+        let a = ContractHost::<Codes>::address_of(first.hash());
+        let b = ContractHost::<Codes>::address_of(second.hash());
+        let mut next = block(vec![first, second]);
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+
+        let added = call(&a, "add", 5, &keypair);
+        let failed = call(&a, "reset", 0, &keypair);
+        let unknown = call(&crate::sha(&"nowhere"), "add", 1, &keypair);
+        let mut next = block(vec![added.clone(), failed.clone(), unknown.clone()]);
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+
+        // storage is isolated, and failed calls change nothing
+        let host = runner.state();
+        assert_eq!(count(host, &a), 15);
+        assert_eq!(count(host, &b), 0);
+        assert_eq!(host.code_of(&b), Some("counter"));
+        let receipt = host.outcome(added.hash()).unwrap();
+        assert_eq!(receipt.output, Ok(15u64.to_be_bytes().to_vec()));
+        assert_eq!(receipt.events[0].contract, a);
+        assert_eq!(
+            host.outcome(failed.hash()).unwrap().output,
+            Err(ContractError::failed("cannot reset"))
+        );
+        assert!(host.outcome(failed.hash()).unwrap().events.is_empty());
+        assert_eq!(
+            host.outcome(unknown.hash()).unwrap().output,
+            Err(ContractError::UnknownContract)
+        );
+        assert_eq!(host.events("added").len(), 1);
+        assert!(host.events("reset").is_empty());
+
+        // the storage is committed into the header
+        let key = ContractHost::<Codes>::storage_key(&a, b"count");
+        let tip = chain.block_at(2.into()).unwrap();
+        let proof = host.prove(&a, b"count");
+        let value = 15u64.to_be_bytes();
+        assert!(verify_state(&tip, &key, Some(&value), &proof).unwrap());
+
+        // so are the receipts, whose logs are indexed by topic
+        let receipts = tip.receipts().unwrap();
+        assert_eq!(receipts.len(), 3);
+        assert_eq!(tip.receipts_root().unwrap(), receipts_root(&receipts));
+        assert_eq!(receipts[0].logs[0].topics, vec!["added".to_owned()]);
+        assert!(matches!(receipts[1].status, Status::Failed(_)));
+        let logs = chain.logs("added").unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!((logs[0].position.pos, &logs[0].record), (2, added.hash()));
+        assert_eq!(logs[0].log.source, a);
+        assert!(chain.logs("reset").unwrap().is_empty());
+
+        // calls of blocks removed by a reorg are reverted
+        chain.rollback(1.into()).unwrap();
+        runner.sync(&chain).unwrap();
+        let mut next = block(vec![call(&b, "add", 3, &keypair)]);
+        runner.prepare(&mut next).unwrap();
+        chain.append(&next).unwrap();
+        runner.sync(&chain).unwrap();
+        let host = runner.state();
+        assert_eq!(count(host, &a), 10);
+        assert_eq!(count(host, &b), 3);
+        assert!(host.outcome(added.hash()).is_none());
+        assert_eq!(host.outcomes().len(), 3);
+        let logs = chain.logs("added").unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].log.source, b);
+    }
+
+    #[test]
+    fn test_history() {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut host = ContractHost::new(Codes).with_history(1);
+        let at = |pos: u64| BlockContext {
+            position: pos.into(),
+            ..Default::default()
+        };
+
+        let first = deploy(10, &keypair);
+        let a = ContractHost::<Codes>::address_of(first.hash());
+        StateMachine::<Tx>::begin_block(&mut host, &at(1));
+        host.apply(&first).unwrap();
+        let calls = (1..=3)
+            .map(|i| call(&a, "add", i, &keypair))
+            .collect::<Vec<_>>();
+        StateMachine::<Tx>::begin_block(&mut host, &at(2));
+        for record in &calls {
+            host.apply(record).unwrap();
+        }
+        assert_eq!(count(&host, &a), 16);
+        assert_eq!(host.outcomes().len(), 3);
+
+        // the history covers every record of the last block, however many it holds
+        for record in calls.iter().rev() {
+            host.revert(record).unwrap();
+        }
+        assert_eq!(count(&host, &a), 10);
+        assert!(host.outcomes().is_empty());
+
+        // but not the block before it
+        StateMachine::<Tx>::begin_block(&mut host, &at(1));
+        assert!(host.revert(&first).is_err());
+        assert_eq!(count(&host, &a), 10);
+    }
+}
@@ -0,0 +1,645 @@
+//! WebAssembly contracts.
+//!
+//! An uploaded module is run by the `wasmi` interpreter. It must export its `memory` and an
+//! `alloc(len: i32) -> i32` function the host calls to place arguments in it, and can
+//! export:
+//!
+//! - `deploy(args_ptr: i32, args_len: i32)`, run when an instance is deployed;
+//! - `call(method_ptr: i32, method_len: i32, args_ptr: i32, args_len: i32) -> i64`;
+//! - `query(method_ptr: i32, method_len: i32, args_ptr: i32, args_len: i32) -> i64`.
+//!
+//! `call` and `query` return the pointer to their output in the upper 32 bits of the result
+//! and its length in the lower 32 bits. The module can import these functions from `env`:
+//!
+//! - `storage_get(key_ptr, key_len, out_ptr, out_cap) -> i32` writes the value of the key
+//!   if it fits in `out_cap` bytes and returns its length, or `-1` if the key has no value;
+//! - `storage_set(key_ptr, key_len, value_ptr, value_len)` and
+//!   `storage_remove(key_ptr, key_len)`, which trap in a query;
+//! - `caller(out_ptr, out_cap) -> i32` writes the public key of the caller like
+//!   `storage_get`;
+//! - `position() -> i64` and `timestamp() -> i64` return the position and timestamp, in
+//!   seconds, of the block holding the call;
+//! - `emit(topic_ptr, topic_len, data_ptr, data_len)` emits an event, `topic` being UTF-8;
+//! - `fail(reason_ptr, reason_len)` aborts with `ContractError::Failed`.
+//!
+//! Gas is the fuel of the interpreter: every instruction uses some, and the host functions
+//! use `HOST_CALL_GAS` plus one for every byte they move. Floating point instructions are
+//! rejected, so that every node computes the same result.
+//!
+//! A memory cannot grow past `MAX_MEMORY` bytes nor a table past `MAX_TABLE_ELEMENTS`
+//! elements: `memory.grow` and `table.grow` return `-1` instead, and a module declaring
+//! more cannot be uploaded.
+
+use wasmi::{
+    core::{HostError, TrapCode},
+    Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+use crate::data::{Position, Timestamp};
+
+use super::{Context, Contract, ContractError, Storage};
+
+/// The gas every host function uses, on top of the bytes it moves.
+pub const HOST_CALL_GAS: u64 = 100;
+
+/// The most bytes the memory of a module can hold.
+pub const MAX_MEMORY: usize = 16 << 20;
+
+/// The most elements a table of a module can hold.
+pub const MAX_TABLE_ELEMENTS: u32 = 10_000;
+
+impl HostError for ContractError {}
+
+/// What a module can reach through its imports.
+struct Host {
+    storage: Storage,
+    caller: Vec<u8>,
+    position: Position,
+    timestamp: Timestamp,
+    events: Vec<(String, Vec<u8>)>,
+    read_only: bool,
+    limits: StoreLimits,
+}
+
+/// An uploaded WebAssembly contract.
+#[derive(Debug)]
+pub struct WasmContract {
+    engine: Engine,
+    module: Module,
+    gas_limit: u64,
+}
+
+impl WasmContract {
+    /// Compiles `wasm`, failing with `ContractError::InvalidCode` if it is not a module
+    /// following the ABI of this module.
+    pub fn new(wasm: &[u8]) -> Result<Self, ContractError> {
+        let mut config = Config::default();
+        config.consume_fuel(true).floats(false);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|_| ContractError::InvalidCode)?;
+        let contract = Self {
+            engine,
+            module,
+            gas_limit: 10_000_000,
+        };
+
+        let mut store = contract.store(Host::empty());
+        contract
+            .linker()
+            .instantiate(&mut store, &contract.module)
+            .map_err(|_| ContractError::InvalidCode)?;
+        for export in ["memory", "alloc"] {
+            if contract.module.get_export(export).is_none() {
+                return Err(ContractError::InvalidCode);
+            }
+        }
+        Ok(contract)
+    }
+
+    /// Sets the gas a query may use. Deploys and calls use the gas left in their `Context`.
+    pub fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = gas_limit;
+        self
+    }
+
+    fn store(&self, host: Host) -> Store<Host> {
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store
+    }
+
+    fn linker(&self) -> Linker<Host> {
+        let mut linker = Linker::new(&self.engine);
+        linker
+            .func_wrap(
+                "env",
+                "storage_get",
+                |mut caller: Caller<'_, Host>, kp: i32, kl: i32, out: i32, cap: i32| {
+                    let key = read(&mut caller, kp, kl)?;
+                    match caller.data().storage.get(&key).map(<[u8]>::to_vec) {
+                        Some(value) => write(&mut caller, out, cap, &value),
+                        None => Ok(-1),
+                    }
+                },
+            )
+            .and_then(|l| {
+                l.func_wrap(
+                    "env",
+                    "storage_set",
+                    |mut caller: Caller<'_, Host>, kp: i32, kl: i32, vp: i32, vl: i32| {
+                        writable(&caller)?;
+                        let key = read(&mut caller, kp, kl)?;
+                        let value = read(&mut caller, vp, vl)?;
+                        caller.data_mut().storage.set(&key, value);
+                        Ok(())
+                    },
+                )
+            })
+            .and_then(|l| {
+                l.func_wrap(
+                    "env",
+                    "storage_remove",
+                    |mut caller: Caller<'_, Host>, kp: i32, kl: i32| {
+                        writable(&caller)?;
+                        let key = read(&mut caller, kp, kl)?;
+                        caller.data_mut().storage.remove(&key);
+                        Ok(())
+                    },
+                )
+            })
+            .and_then(|l| {
+                l.func_wrap(
+                    "env",
+                    "caller",
+                    |mut caller: Caller<'_, Host>, out: i32, cap: i32| {
+                        let key = caller.data().caller.clone();
+                        write(&mut caller, out, cap, &key)
+                    },
+                )
+            })
+            .and_then(|l| {
+                l.func_wrap("env", "position", |mut caller: Caller<'_, Host>| {
+                    charge(&mut caller, 0)?;
+                    Ok(caller.data().position.pos() as i64)
+                })
+            })
+            .and_then(|l| {
+                l.func_wrap("env", "timestamp", |mut caller: Caller<'_, Host>| {
+                    charge(&mut caller, 0)?;
+                    Ok(caller.data().timestamp.secs() as i64)
+                })
+            })
+            .and_then(|l| {
+                l.func_wrap(
+                    "env",
+                    "emit",
+                    |mut caller: Caller<'_, Host>, tp: i32, tl: i32, dp: i32, dl: i32| {
+                        writable(&caller)?;
+                        let topic = String::from_utf8(read(&mut caller, tp, tl)?)
+                            .map_err(|_| wasmi::Error::host(ContractError::InvalidArgs))?;
+                        let data = read(&mut caller, dp, dl)?;
+                        caller.data_mut().events.push((topic, data));
+                        Ok(())
+                    },
+                )
+            })
+            .and_then(|l| {
+                l.func_wrap(
+                    "env",
+                    "fail",
+                    |mut caller: Caller<'_, Host>, rp: i32, rl: i32| -> Result<(), wasmi::Error> {
+                        let reason = read(&mut caller, rp, rl)?;
+                        let reason = String::from_utf8_lossy(&reason);
+                        Err(wasmi::Error::host(ContractError::failed(&reason)))
+                    },
+                )
+            })
+            .expect("host functions are defined once");
+        linker
+    }
+
+    /// Instantiates the module with `host` and runs `f` with at most `gas` fuel, returning
+    /// its result, the host and the gas used.
+    fn run<T>(
+        &self,
+        host: Host,
+        gas: u64,
+        f: impl FnOnce(&mut Store<Host>, Instance) -> Result<T, wasmi::Error>,
+    ) -> (Result<T, ContractError>, Host, u64) {
+        let mut store = self.store(host);
+        let result = store
+            .set_fuel(gas)
+            .map_err(wasmi::Error::from)
+            .and_then(|_| self.linker().instantiate(&mut store, &self.module))
+            .and_then(|pre| pre.start(&mut store))
+            .and_then(|instance| f(&mut store, instance))
+            .map_err(|e| match e.as_trap_code() {
+                Some(TrapCode::OutOfFuel) => ContractError::OutOfGas,
+                _ => match e.downcast_ref::<ContractError>() {
+                    Some(e) => e.clone(),
+                    None => ContractError::Failed(e.to_string()),
+                },
+            });
+        let left = store.get_fuel().unwrap_or(0);
+        let used = match result {
+            Err(ContractError::OutOfGas) => gas,
+            _ => gas - left,
+        };
+        (result, store.into_data(), used)
+    }
+
+    /// Runs `call` or `query` in the context of `host`.
+    fn invoke(
+        &self,
+        host: Host,
+        gas: u64,
+        export: &str,
+        method: &str,
+        args: &[u8],
+    ) -> (Result<Vec<u8>, ContractError>, Host, u64) {
+        self.run(host, gas, |store, instance| {
+            let func = instance
+                .get_typed_func::<(i32, i32, i32, i32), i64>(&*store, export)
+                .map_err(|_| wasmi::Error::host(ContractError::UnknownMethod))?;
+            let (mp, ml) = pass(store, instance, method.as_bytes())?;
+            let (ap, al) = pass(store, instance, args)?;
+            let packed = func.call(&mut *store, (mp, ml, ap, al))? as u64;
+            let (ptr, len) = ((packed >> 32) as i32, packed as u32 as i32);
+            let memory = memory(&*store, instance)?;
+            slice(memory.data(&*store), ptr, len).map(<[u8]>::to_vec)
+        })
+    }
+}
+
+impl Host {
+    fn empty() -> Self {
+        Self::new(Storage::default(), true, None)
+    }
+
+    fn new(storage: Storage, read_only: bool, ctx: Option<&Context>) -> Self {
+        Self {
+            storage,
+            caller: ctx
+                .map(|ctx| ctx.caller().as_bytes().to_vec())
+                .unwrap_or_default(),
+            position: ctx.map_or(Position::new(0), Context::position),
+            timestamp: ctx.map_or(Timestamp::from_secs(0), Context::timestamp),
+            events: vec![],
+            read_only,
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY)
+                .table_elements(MAX_TABLE_ELEMENTS)
+                .instances(1)
+                .build(),
+        }
+    }
+}
+
+impl Contract for WasmContract {
+    fn deploy(&self, ctx: &mut Context, args: &[u8]) -> Result<(), ContractError> {
+        let host = Host::new(std::mem::take(ctx.storage_mut()), false, Some(ctx));
+        let (result, host, used) = self.run(host, ctx.gas_left(), |store, instance| {
+            let Ok(func) = instance.get_typed_func::<(i32, i32), ()>(&*store, "deploy") else {
+                return Ok(());
+            };
+            let (ap, al) = pass(store, instance, args)?;
+            func.call(&mut *store, (ap, al))
+        });
+        finish(ctx, host, used)?;
+        result
+    }
+
+    fn call(&self, ctx: &mut Context, method: &str, args: &[u8]) -> Result<Vec<u8>, ContractError> {
+        let host = Host::new(std::mem::take(ctx.storage_mut()), false, Some(ctx));
+        let (result, host, used) = self.invoke(host, ctx.gas_left(), "call", method, args);
+        finish(ctx, host, used)?;
+        result
+    }
+
+    fn query(
+        &self,
+        storage: &Storage,
+        method: &str,
+        args: &[u8],
+    ) -> Result<Vec<u8>, ContractError> {
+        let host = Host::new(storage.clone(), true, None);
+        self.invoke(host, self.gas_limit, "query", method, args).0
+    }
+}
+
+/// Hands the storage and events of `host` back to `ctx` and charges the gas used.
+fn finish(ctx: &mut Context, host: Host, used: u64) -> Result<(), ContractError> {
+    *ctx.storage_mut() = host.storage;
+    for (topic, data) in host.events {
+        ctx.emit(&topic, data);
+    }
+    ctx.charge(used)
+}
+
+fn memory(store: &Store<Host>, instance: Instance) -> Result<Memory, wasmi::Error> {
+    instance
+        .get_memory(store, "memory")
+        .ok_or_else(|| wasmi::Error::host(ContractError::InvalidCode))
+}
+
+/// Copies `bytes` into memory the module allocates, returning their pointer and length.
+fn pass(
+    store: &mut Store<Host>,
+    instance: Instance,
+    bytes: &[u8],
+) -> Result<(i32, i32), wasmi::Error> {
+    let len =
+        i32::try_from(bytes.len()).map_err(|_| wasmi::Error::host(ContractError::InvalidArgs))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&*store, "alloc")?;
+    let ptr = alloc.call(&mut *store, len)?;
+    memory(store, instance)?
+        .write(&mut *store, ptr as u32 as usize, bytes)
+        .map_err(|_| TrapCode::MemoryOutOfBounds)?;
+    Ok((ptr, len))
+}
+
+fn slice(data: &[u8], ptr: i32, len: i32) -> Result<&[u8], wasmi::Error> {
+    let start = ptr as u32 as usize;
+    data.get(start..start + len as u32 as usize)
+        .ok_or_else(|| TrapCode::MemoryOutOfBounds.into())
+}
+
+/// Uses `HOST_CALL_GAS` and `bytes` more of the fuel of `caller`.
+fn charge(caller: &mut Caller<'_, Host>, bytes: usize) -> Result<(), wasmi::Error> {
+    let gas = HOST_CALL_GAS + bytes as u64;
+    let fuel = caller.get_fuel()?;
+    if fuel < gas {
+        caller.set_fuel(0)?;
+        return Err(TrapCode::OutOfFuel.into());
+    }
+    caller.set_fuel(fuel - gas)?;
+    Ok(())
+}
+
+fn caller_memory(caller: &Caller<'_, Host>) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::host(ContractError::InvalidCode))
+}
+
+fn read(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    charge(caller, len as u32 as usize)?;
+    let memory = caller_memory(caller)?;
+    slice(memory.data(&*caller), ptr, len).map(<[u8]>::to_vec)
+}
+
+/// Writes `bytes` at `ptr` if they fit in `cap` bytes, returning their length.
+fn write(
+    caller: &mut Caller<'_, Host>,
+    ptr: i32,
+    cap: i32,
+    bytes: &[u8],
+) -> Result<i32, wasmi::Error> {
+    charge(caller, bytes.len())?;
+    if bytes.len() <= cap as u32 as usize {
+        caller_memory(caller)?
+            .write(&mut *caller, ptr as u32 as usize, bytes)
+            .map_err(|_| TrapCode::MemoryOutOfBounds)?;
+    }
+    Ok(bytes.len() as i32)
+}
+
+fn writable(caller: &Caller<'_, Host>) -> Result<(), wasmi::Error> {
+    match caller.data().read_only {
+        true => Err(wasmi::Error::host(ContractError::failed(
+            "cannot write in a query",
+        ))),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, data::Metadata, Hash};
+
+    use blockify::{
+        data::{Position, Timestamp},
+        record::{Record, SignedRecord},
+        state::{BlockContext, StateMachine},
+        AuthKeyPair,
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{
+        super::{
+            Contract, ContractCall, ContractError, ContractHost, ContractRecord, Contracts,
+            UPLOAD_GAS_PER_BYTE,
+        },
+        MAX_MEMORY,
+    };
+
+    /// Counts up from the little-endian `u64` it is deployed with.
+    const COUNTER: &str = r#"
+        (module
+            (import "env" "storage_get" (func $get (param i32 i32 i32 i32) (result i32)))
+            (import "env" "storage_set" (func $set (param i32 i32 i32 i32)))
+            (import "env" "caller" (func $caller (param i32 i32) (result i32)))
+            (import "env" "position" (func $position (result i64)))
+            (import "env" "timestamp" (func $timestamp (result i64)))
+            (import "env" "emit" (func $emit (param i32 i32 i32 i32)))
+            (import "env" "fail" (func $fail (param i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 600) "count")
+            (data (i32.const 610) "added")
+            (data (i32.const 620) "no")
+            (global $heap (mut i32) (i32.const 1024))
+            (func (export "alloc") (param $len i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+            (func (export "deploy") (param $ap i32) (param $al i32)
+                (call $set (i32.const 600) (i32.const 5) (local.get $ap) (local.get $al)))
+            (func $count (result i64)
+                (if (i32.lt_s
+                        (call $get (i32.const 600) (i32.const 5) (i32.const 0) (i32.const 8))
+                        (i32.const 0))
+                    (then (i64.store (i32.const 0) (i64.const 0))))
+                (i64.load (i32.const 0)))
+            (func (export "call")
+                (param $mp i32) (param $ml i32) (param $ap i32) (param $al i32) (result i64)
+                (local $m i32)
+                (local.set $m (i32.load8_u (local.get $mp)))
+                ;; add
+                (if (i32.eq (local.get $m) (i32.const 97))
+                    (then
+                        (i64.store (i32.const 0) (i64.add (call $count) (i64.load (local.get $ap))))
+                        (call $set (i32.const 600) (i32.const 5) (i32.const 0) (i32.const 8))
+                        (call $emit (i32.const 610) (i32.const 5) (local.get $ap) (local.get $al))
+                        (return (i64.const 8))))
+                ;; loop
+                (if (i32.eq (local.get $m) (i32.const 108))
+                    (then (loop $forever (br $forever))))
+                ;; grow
+                (if (i32.eq (local.get $m) (i32.const 103))
+                    (then
+                        (i32.store (i32.const 0) (memory.grow (i32.load (local.get $ap))))
+                        (return (i64.const 4))))
+                ;; where
+                (if (i32.eq (local.get $m) (i32.const 119))
+                    (then
+                        (i64.store (i32.const 0) (call $position))
+                        (i64.store (i32.const 8) (call $timestamp))
+                        (return (i64.extend_i32_u (i32.add
+                            (i32.const 16)
+                            (call $caller (i32.const 16) (i32.const 512)))))))
+                (call $fail (i32.const 620) (i32.const 2))
+                (unreachable))
+            (func (export "query")
+                (param $mp i32) (param $ml i32) (param $ap i32) (param $al i32) (result i64)
+                ;; set
+                (if (i32.eq (i32.load8_u (local.get $mp)) (i32.const 115))
+                    (then (call $set (i32.const 600) (i32.const 5) (i32.const 0) (i32.const 8))))
+                (drop (call $count))
+                (i64.const 8)))
+    "#;
+
+    /// Declares more memory than `MAX_MEMORY`.
+    const WIDE: &str = r#"
+        (module
+            (memory (export "memory") 257)
+            (func (export "alloc") (param $len i32) (result i32)
+                (i32.const 0)))
+    "#;
+
+    #[derive(Debug, Clone, Default)]
+    struct NoCodes;
+
+    impl Contracts for NoCodes {
+        fn contract(&self, _code: &str) -> Option<&dyn Contract> {
+            None
+        }
+    }
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Tx(ContractCall);
+
+    impl ContractRecord for Tx {
+        fn contract_call(&self) -> Option<ContractCall> {
+            Some(self.0.clone())
+        }
+    }
+
+    fn signed(call: ContractCall, keypair: &AuthKeyPair) -> SignedRecord<Tx> {
+        Tx(call).record(keypair.clone(), Metadata::empty()).unwrap()
+    }
+
+    fn call(address: &Hash, method: &str, args: &[u8], keypair: &AuthKeyPair) -> SignedRecord<Tx> {
+        let call = ContractCall::Call {
+            address: address.clone(),
+            method: method.to_owned(),
+            args: args.to_vec(),
+        };
+        signed(call, keypair)
+    }
+
+    fn host(records: &[SignedRecord<Tx>]) -> ContractHost<NoCodes> {
+        let mut host = ContractHost::new(NoCodes).with_gas_limit(100_000);
+        let block = BlockContext {
+            position: Position::new(7),
+            timestamp: Timestamp::from_secs(1000),
+            ..Default::default()
+        };
+        StateMachine::<Tx>::begin_block(&mut host, &block);
+        for record in records {
+            host.apply(record).unwrap();
+        }
+        host
+    }
+
+    #[test]
+    fn test_wasm() {
+        let keypair = crate::generate_ed25519_keypair();
+        let wasm = wat::parse_str(COUNTER).unwrap();
+        let code = ContractHost::<NoCodes>::code_name(&wasm);
+
+        let upload = signed(ContractCall::Upload { wasm: wasm.clone() }, &keypair);
+        let garbage = signed(
+            ContractCall::Upload {
+                wasm: vec![0, 1, 2],
+            },
+            &keypair,
+        );
+        let deploy = signed(
+            ContractCall::Deploy {
+                code: code.clone(),
+                args: 10u64.to_le_bytes().to_vec(),
+            },
+            &keypair,
+        );
+        let address = ContractHost::<NoCodes>::address_of(deploy.hash());
+        let added = call(&address, "add", &5u64.to_le_bytes(), &keypair);
+        let looped = call(&address, "loop", &[], &keypair);
+        let located = call(&address, "where", &[], &keypair);
+        let grown = call(&address, "grow", &1i32.to_le_bytes(), &keypair);
+        let huge = (MAX_MEMORY / 65536) as i32;
+        let overgrown = call(&address, "grow", &huge.to_le_bytes(), &keypair);
+        let failed = call(&address, "fail", &[], &keypair);
+        let records = [
+            upload.clone(),
+            garbage.clone(),
+            deploy.clone(),
+            added.clone(),
+            looped.clone(),
+            located.clone(),
+            grown.clone(),
+            overgrown.clone(),
+            failed.clone(),
+        ];
+        let host = host(&records);
+
+        let receipt = |record: &SignedRecord<Tx>| host.outcome(record.hash()).unwrap().clone();
+        assert_eq!(receipt(&upload).contract, crate::sha(&wasm));
+        let gas = wasm.len() as u64 * UPLOAD_GAS_PER_BYTE;
+        assert_eq!(receipt(&upload).gas_used, gas);
+        assert!(host.has_code(&code));
+        // the upload is compiled once, and the calls reuse it
+        assert_eq!(host.compiled.borrow().len(), 1);
+        assert_eq!(receipt(&garbage).output, Err(ContractError::InvalidCode));
+        assert!(receipt(&deploy).is_success());
+        assert!(receipt(&deploy).gas_used > 0);
+
+        let added = receipt(&added);
+        assert_eq!(added.output, Ok(15u64.to_le_bytes().to_vec()));
+        assert_eq!(added.events[0].topic, "added");
+        let get = host.query(&address, "get", &[]).unwrap();
+        assert_eq!(get, 15u64.to_le_bytes().to_vec());
+        assert_eq!(
+            host.query(&address, "set", &[]),
+            Err(ContractError::failed("cannot write in a query"))
+        );
+
+        // runaway code uses up its gas and changes nothing
+        let looped = receipt(&looped);
+        assert_eq!(looped.output, Err(ContractError::OutOfGas));
+        assert_eq!(looped.gas_used, 100_000);
+        assert_eq!(host.query(&address, "get", &[]).unwrap(), get);
+
+        let mut expected = 7u64.to_le_bytes().to_vec();
+        expected.extend(1000u64.to_le_bytes());
+        expected.extend(located.signer().as_bytes());
+        assert_eq!(receipt(&located).output, Ok(expected));
+        assert_eq!(receipt(&failed).output, Err(ContractError::failed("no")));
+
+        // memory cannot grow past the limit, and neither can uploads declare more
+        assert_eq!(receipt(&grown).output, Ok(1i32.to_le_bytes().to_vec()));
+        assert_eq!(
+            receipt(&overgrown).output,
+            Ok((-1i32).to_le_bytes().to_vec())
+        );
+        let wide = wat::parse_str(WIDE).unwrap();
+        let wide = signed(ContractCall::Upload { wasm: wide }, &keypair);
+        let wide = self::host(&[wide.clone()])
+            .outcome(wide.hash())
+            .unwrap()
+            .clone();
+        assert_eq!(wide.output, Err(ContractError::InvalidCode));
+
+        // uploads pay for their code
+        let mut poor = ContractHost::new(NoCodes).with_gas_limit(gas - 1);
+        poor.apply(&upload).unwrap();
+        let outcome = poor.outcome(upload.hash()).unwrap();
+        assert_eq!(outcome.output, Err(ContractError::OutOfGas));
+        assert_eq!(outcome.gas_used, gas - 1);
+        assert!(!poor.has_code(&code));
+
+        // every node reaches the same state, and reverting undoes the upload
+        let mut other = self::host(&records);
+        assert_eq!(
+            StateMachine::<Tx>::root(&other),
+            StateMachine::<Tx>::root(&host)
+        );
+        assert_eq!(other.outcomes(), host.outcomes());
+        for record in records.iter().rev() {
+            other.revert(record).unwrap();
+        }
+        assert!(!other.has_code(&code));
+        assert!(other.compiled.borrow().is_empty());
+        assert_eq!(StateMachine::<Tx>::root(&other), Hash::default());
+    }
+}
@@ -0,0 +1,268 @@
+//! Fees.
+//!
+//! Records implementing `FeeRecord` offer a `Fee`: the most they pay to be included in a
+//! block, and a tip for the producer of the block. Every block carries a base fee in its
+//! header, set by a `FeeMarket` in the style of EIP-1559: it rises after blocks larger than
+//! the target size and falls after smaller ones. A record pays the base fee, which is
+//! burned, and as much of its tip as its maximum fee leaves room for, which goes to the
+//! producer of the block.
+//!
+//! A `PriorityMemPool` with the `FeeTip` prioritizer hands out the records that offer the
+//! most tips first, a `BlockBuilder` picks the records that pay the most tips within its size
+//! limit, and a `TokenLedger` with fees charges them. The tips go to the producer the block
+//! names (see `LocalInstance::producer`).
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::ChainedInstance,
+    chain::{Chain, ChainError},
+    record::{Record, SignedRecord},
+};
+
+/// What a record offers to pay to be included in a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fee {
+    /// The most the record pays, base fee and tip included.
+    pub max_fee: u128,
+    /// What the record offers to the producer of the block on top of the base fee.
+    pub tip: u128,
+}
+
+impl Fee {
+    pub fn new(max_fee: u128, tip: u128) -> Self {
+        Self { max_fee, tip }
+    }
+
+    /// Returns what a record with this fee pays in a block with `base_fee`, or `None` if
+    /// its maximum fee is lower than the base fee.
+    pub fn charge(&self, base_fee: u128) -> Option<FeeCharge> {
+        let room = self.max_fee.checked_sub(base_fee)?;
+        Some(FeeCharge {
+            burned: base_fee,
+            tip: self.tip.min(room),
+        })
+    }
+}
+
+/// What a record pays for its inclusion in a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeCharge {
+    /// The base fee, which is destroyed.
+    pub burned: u128,
+    /// The part of the tip paid to the producer of the block.
+    pub tip: u128,
+}
+
+impl FeeCharge {
+    pub fn total(&self) -> u128 {
+        self.burned.saturating_add(self.tip)
+    }
+}
+
+/// Records that pay fees.
+pub trait FeeRecord {
+    /// Returns the fee this record offers. Records that offer none can only be included
+    /// in blocks with a base fee of zero.
+    fn fee(&self) -> Fee {
+        Fee::default()
+    }
+}
+
+/// An EIP-1559 style base fee adjustment.
+///
+/// The size of a block is the total serialized size of its records, as a `BlockBuilder`
+/// counts it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeMarket {
+    /// The base fee of the first block with one.
+    pub initial: u128,
+    /// The size of the blocks at which the base fee stays the same.
+    pub target_size: u64,
+    /// The largest block allowed, twice the target size by default.
+    pub max_size: u64,
+    /// The base fee never goes below this, which is at least one.
+    pub minimum: u128,
+    /// The base fee changes by at most one in this many per block. Defaults to 8.
+    pub change_denominator: u128,
+}
+
+impl FeeMarket {
+    pub fn new(initial: u128, target_size: u64) -> Self {
+        let target_size = target_size.max(1);
+        Self {
+            initial: initial.max(1),
+            target_size,
+            max_size: target_size.saturating_mul(2),
+            minimum: 1,
+            change_denominator: 8,
+        }
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size.max(self.target_size);
+        self
+    }
+
+    pub fn with_minimum(mut self, minimum: u128) -> Self {
+        self.minimum = minimum.max(1);
+        self
+    }
+
+    pub fn with_change_denominator(mut self, denominator: u128) -> Self {
+        self.change_denominator = denominator.max(1);
+        self
+    }
+
+    /// Computes the base fee of the block after one with `parent_base_fee` and
+    /// `parent_size`.
+    ///
+    /// A parent without a base fee, such as a genesis block, is followed by `initial`.
+    pub fn required(&self, parent_base_fee: u128, parent_size: u64) -> u128 {
+        if parent_base_fee == 0 {
+            return self.initial.max(self.minimum);
+        }
+        let target = self.target_size as u128;
+        let size = parent_size as u128;
+        let change = |delta: u128| {
+            parent_base_fee
+                .saturating_mul(delta)
+                .checked_div(target)
+                .unwrap_or(0)
+                / self.change_denominator
+        };
+        let next = if size > target {
+            parent_base_fee.saturating_add(change(size - target).max(1))
+        } else {
+            parent_base_fee - change(target - size)
+        };
+        next.max(self.minimum)
+    }
+
+    /// Computes the base fee that the next block appended to `chain` must carry.
+    pub fn next_base_fee<R, C>(&self, chain: &C) -> Result<u128, ChainError>
+    where
+        R: Record + Serialize,
+        C: Chain<R>,
+    {
+        match chain.last_block()? {
+            Some(last) => {
+                let size = Self::size(&last.records()?)?;
+                Ok(self.required(last.base_fee()?, size))
+            }
+            None => Ok(self.initial.max(self.minimum)),
+        }
+    }
+
+    /// Returns the size of a block holding `records`.
+    pub fn size<R: Serialize>(records: &[SignedRecord<R>]) -> Result<u64, ChainError> {
+        records.iter().try_fold(0u64, |size, record| {
+            let bytes = crate::serialize(record).map_err(ChainError::SerdeError)?;
+            Ok(size.saturating_add(bytes.len() as u64))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::{BlockBuilder, BlockData},
+        chain::{Chain, ChainError},
+        data::Metadata,
+        fees::{Fee, FeeMarket, FeeRecord},
+        record::{Record, SignedRecord},
+        AuthKeyPair, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Bid {
+        fee: Fee,
+        note: String,
+    }
+
+    impl FeeRecord for Bid {
+        fn fee(&self) -> Fee {
+            self.fee
+        }
+    }
+
+    fn bid(max_fee: u128, tip: u128, note: &str, keypair: &AuthKeyPair) -> SignedRecord<Bid> {
+        let bid = Bid {
+            fee: Fee::new(max_fee, tip),
+            note: note.to_owned(),
+        };
+        bid.record(keypair.clone(), Metadata::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_fee_market() {
+        let market = FeeMarket::new(100, 1000);
+        assert_eq!(market.required(0, 5000), 100);
+        assert_eq!(market.required(100, 1000), 100);
+        assert_eq!(market.required(100, 2000), 112);
+        assert_eq!(market.required(100, 0), 88);
+        assert_eq!(market.required(1, 1000), 1);
+        assert_eq!(market.required(1, 1001), 2);
+
+        let fee = Fee::new(150, 80);
+        assert_eq!(fee.charge(100).map(|c| (c.burned, c.tip)), Some((100, 50)));
+        assert_eq!(fee.charge(151), None);
+
+        // the builder prefers the records that tip the most for their size
+        let keypair = crate::generate_ed25519_keypair();
+        let one = FeeMarket::size(&[bid(200, 10, "a", &keypair)]).unwrap() as usize;
+        let candidates = vec![
+            bid(200, 10, "a", &keypair),
+            bid(200, 90, "b", &keypair),
+            bid(99, 90, "c", &keypair),
+            bid(200, 50, "d", &keypair),
+        ];
+        let mut builder = BlockBuilder::new(Metadata::empty())
+            .base_fee(100)
+            .max_bytes(2 * one);
+        let left = builder.push_by_fee(candidates);
+        let notes = |records: &[SignedRecord<Bid>]| {
+            records
+                .iter()
+                .map(|r| r.record().note.clone())
+                .collect::<Vec<_>>()
+        };
+        let candidate = builder.build(&Default::default(), 1.into());
+        assert_eq!(candidate.instance.base_fee, 100);
+        assert_eq!(notes(&candidate.instance.records), vec!["b", "d"]);
+        assert_eq!(notes(&left), vec!["a", "c"]);
+
+        // the chain requires the base fee of the market
+        let chain_url = "target2/tests/feemarketchain/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let market = FeeMarket::new(100, one as u64);
+        let mut chain = SqliteChain::<Bid>::new(chain_url)
+            .unwrap()
+            .with_fee_market(market);
+
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(bid(200, 1, "e", &keypair));
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::NotValid(BlockData::BaseFee))
+        ));
+        block.base_fee = market.next_base_fee(&chain).unwrap();
+        chain.append(&block).unwrap();
+        block.push(bid(200, 1, "f", &keypair));
+        block.base_fee = market.next_base_fee(&chain).unwrap();
+        assert_eq!(block.base_fee, 100);
+        chain.append(&block).unwrap();
+        assert_eq!(market.next_base_fee(&chain).unwrap(), 112);
+
+        block.push(bid(200, 1, "g", &keypair));
+        block.base_fee = 112;
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::NotValid(BlockData::Records))
+        ));
+    }
+}
@@ -1 +1,6 @@
-pub mod consensus;
\ No newline at end of file
+pub mod consensus;
+pub mod contracts;
+pub mod fees;
+pub mod state;
+pub mod token;
+pub mod utxo;
@@ -0,0 +1,746 @@
+//! State derived from records.
+//!
+//! A `StateMachine` is built by applying the records of a chain in order. A `StateRunner`
+//! keeps a state machine in step with a `Chain`: it applies new blocks, reverts the blocks
+//! a reorg removed and can persist checkpoints of the state so that it does not have to
+//! replay the whole chain after a restart.
+//!
+//! A state machine can commit to its state with the root of a `SparseMerkleTree`. Blocks
+//! carry the root of the state after them in their header, so that any value of the state
+//! can be proven to a light client against a block.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockData, BlockError, BlockHeader, ChainedInstance, LocalInstance, PositionInstance},
+    chain::{Chain, ChainError},
+    data::{Position, Timestamp},
+    error::{DataBaseError, SerdeError},
+    receipt::{self, Receipt},
+    record::{Record, SignedRecord},
+    Hash, PublicKey, SqliteCheckpoints,
+};
+
+#[derive(Debug)]
+pub enum StateError {
+    /// The state machine rejected a record.
+    Custom(Box<dyn std::error::Error>),
+    ChainError(ChainError),
+    SerdeError(SerdeError),
+    DataBaseError(DataBaseError),
+}
+
+impl StateError {
+    pub fn custom(error: Box<dyn std::error::Error>) -> Self {
+        StateError::Custom(error)
+    }
+}
+
+impl From<ChainError> for StateError {
+    fn from(value: ChainError) -> Self {
+        StateError::ChainError(value)
+    }
+}
+
+impl From<BlockError> for StateError {
+    fn from(value: BlockError) -> Self {
+        StateError::ChainError(value.into())
+    }
+}
+
+crate::impl_display_error!(StateError);
+
+/// The block whose records are being applied or reverted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockContext {
+    pub position: Position,
+    pub timestamp: Timestamp,
+    /// The base fee the records of the block pay.
+    pub base_fee: u128,
+    /// The producer the block names, which is paid the tips of its records.
+    pub producer: Option<PublicKey>,
+}
+
+impl Default for BlockContext {
+    fn default() -> Self {
+        Self {
+            position: Position::new(0),
+            timestamp: Timestamp::from_secs(0),
+            base_fee: 0,
+            producer: None,
+        }
+    }
+}
+
+/// A state that is changed by records.
+///
+/// `revert` must undo `apply`: applying a record and then reverting it leaves the state as
+/// it was. Records are reverted in the opposite order to the one they were applied in.
+pub trait StateMachine<R> {
+    fn apply(&mut self, record: &SignedRecord<R>) -> Result<(), StateError>;
+
+    fn revert(&mut self, record: &SignedRecord<R>) -> Result<(), StateError>;
+
+    /// Called before the records of `block` are applied, and again before they are
+    /// reverted. States that do not depend on the block can ignore it.
+    fn begin_block(&mut self, _block: &BlockContext) {}
+
+    /// Returns the receipt of `record`, which was just applied.
+    ///
+    /// States that do not report on their records can keep the default, a receipt of
+    /// success without gas or logs.
+    fn receipt(&self, record: &SignedRecord<R>) -> Receipt {
+        Receipt::success(record.hash())
+    }
+
+    /// Returns the root of a `SparseMerkleTree` over this state, which is committed into
+    /// the header of the blocks after which the state is reached.
+    ///
+    /// States that are not proven to light clients can keep the default, `Hash::default()`.
+    fn root(&self) -> Hash {
+        Hash::default()
+    }
+}
+
+/// Keeps a `StateMachine` in step with a chain.
+///
+/// The records of the last blocks applied are kept so that they can be reverted when the
+/// chain switches to another branch. A reorg deeper than that restarts from the latest
+/// checkpoint below it, or from the initial state.
+pub struct StateRunner<R, S> {
+    initial: S,
+    state: S,
+    /// The position and hash of the block the state was restored after.
+    base: (u64, Hash),
+    /// The hashes of the blocks applied after `base`.
+    hashes: Vec<Hash>,
+    /// The records of the last blocks applied, oldest first.
+    journal: VecDeque<(BlockContext, Vec<SignedRecord<R>>)>,
+    journal_depth: usize,
+    checkpoints: Option<(SqliteCheckpoints<S>, u64)>,
+}
+
+impl<R, S: Clone> StateRunner<R, S> {
+    /// Creates a runner that starts from `initial` before the first block.
+    pub fn new(initial: S) -> Self {
+        Self {
+            state: initial.clone(),
+            initial,
+            base: (0, Hash::default()),
+            hashes: vec![],
+            journal: VecDeque::new(),
+            journal_depth: 64,
+            checkpoints: None,
+        }
+    }
+
+    /// Keeps the records of the last `depth` blocks to revert them on a reorg.
+    pub fn with_journal(mut self, depth: usize) -> Self {
+        self.journal_depth = depth;
+        self
+    }
+
+    /// Stores a checkpoint of the state in `checkpoints` after every `interval` blocks.
+    pub fn with_checkpoints(mut self, checkpoints: SqliteCheckpoints<S>, interval: u64) -> Self {
+        self.checkpoints = Some((checkpoints, interval.max(1)));
+        self
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Returns the position of the last block applied to the state.
+    pub fn height(&self) -> u64 {
+        self.base.0 + self.hashes.len() as u64
+    }
+
+    /// Returns the hash of the block applied at `pos`, if the runner still knows it.
+    pub fn hash_at(&self, pos: u64) -> Option<&Hash> {
+        match pos.checked_sub(self.base.0)? {
+            0 => Some(&self.base.1),
+            n => self.hashes.get(n as usize - 1),
+        }
+    }
+
+    fn reset(&mut self, state: S, base: (u64, Hash)) {
+        self.state = state;
+        self.base = base;
+        self.hashes.clear();
+        self.journal.clear();
+    }
+}
+
+impl<R, S> StateRunner<R, S>
+where
+    R: Record + Clone,
+    S: StateMachine<R> + Clone + Serialize + for<'a> Deserialize<'a>,
+{
+    /// Sets the state root of `block` to the root of the state after its records, and its
+    /// receipts to the receipts of its records, so that it can be appended to a chain this
+    /// runner is in step with.
+    ///
+    /// The records are applied at the timestamp of the block, which the chain keeps, so the
+    /// timestamp must not change once the block is prepared.
+    pub fn prepare(&self, block: &mut LocalInstance<R>) -> Result<(), StateError> {
+        let (state, receipts) = self.execute(block)?;
+        block.state_root = state.root();
+        block.receipts = receipts;
+        Ok(())
+    }
+
+    /// Checks, without changing the state, that every record of `block` can be applied
+    /// after the last block applied and that the block commits to the state and receipts
+    /// they lead to.
+    pub fn check(&self, block: &LocalInstance<R>) -> Result<(), StateError> {
+        let (state, receipts) = self.execute(block)?;
+        let receipts_root = receipt::receipts_root(&block.receipts);
+        match invalid_commitments(
+            &state,
+            &receipts,
+            BlockHeader::VERSION,
+            &block.state_root,
+            &receipts_root,
+        ) {
+            Some(data) => Err(ChainError::NotValid(data).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies the records of `block` to a copy of the state, as the next block.
+    fn execute(&self, block: &LocalInstance<R>) -> Result<(S, Vec<Receipt>), StateError> {
+        let mut state = self.state.clone();
+        state.begin_block(&BlockContext {
+            position: Position::new(self.height() + 1),
+            timestamp: block.timestamp,
+            base_fee: block.base_fee,
+            producer: block.producer.clone(),
+        });
+        let mut receipts = vec![];
+        for record in &block.records {
+            state.apply(record)?;
+            receipts.push(state.receipt(record));
+        }
+        Ok((state, receipts))
+    }
+
+    /// Appends `block` to `chain` and applies it.
+    ///
+    /// The block is checked against the state first, so a block whose records cannot all
+    /// be applied, or which commits to another state, is not appended.
+    pub fn append<C>(
+        &mut self,
+        chain: &mut C,
+        block: &LocalInstance<R>,
+    ) -> Result<PositionInstance, StateError>
+    where
+        C: Chain<R, UnchainedInstanceType = LocalInstance<R>>,
+    {
+        self.sync(chain)?;
+        self.check(block)?;
+        let position = chain.append(block)?;
+        self.sync(chain)?;
+        Ok(position)
+    }
+
+    /// Brings the state up to date with `chain`, returning the number of blocks applied.
+    ///
+    /// Blocks that are no longer in `chain` are reverted first. Fails with
+    /// `ChainError::Pruned` if a block it has to apply again was pruned.
+    pub fn sync<C: Chain<R>>(&mut self, chain: &C) -> Result<u64, StateError> {
+        let len = chain.len()?;
+        if self.height() == 0 {
+            self.restore(chain, len)?;
+        }
+
+        let mut common = self.height().min(len);
+        while common > self.base.0 && !self.agrees(chain, common)? {
+            common -= 1;
+        }
+        if !self.agrees(chain, common)? {
+            self.restore(chain, common.saturating_sub(1))?;
+        }
+        while self.height() > common {
+            match self.journal.pop_back() {
+                Some((context, records)) => {
+                    self.state.begin_block(&context);
+                    if self.revert_or_restore(chain, &records, common)? {
+                        self.hashes.pop();
+                    }
+                }
+                None => self.restore(chain, common)?,
+            }
+        }
+        if let Some((checkpoints, _)) = &self.checkpoints {
+            checkpoints.remove_above(self.height())?;
+        }
+
+        let start = self.height();
+        for pos in start + 1..=len {
+            let block = chain.block_at(pos.into())?;
+            let context = BlockContext {
+                position: block.position()?,
+                timestamp: block.timestamp()?,
+                base_fee: block.base_fee()?,
+                producer: block.producer()?,
+            };
+            self.state.begin_block(&context);
+            self.apply_block(
+                chain,
+                context,
+                block.records()?.into_inner(),
+                block.hash()?,
+                block.version()?,
+                block.state_root()?,
+                block.receipts_root()?,
+            )?;
+        }
+        Ok(self.height() - start)
+    }
+
+    /// Returns `true` if the block the state has at `pos` is the block of `chain` at `pos`.
+    fn agrees<C: Chain<R>>(&self, chain: &C, pos: u64) -> Result<bool, StateError> {
+        if pos == 0 {
+            return Ok(true);
+        }
+        match self.hash_at(pos) {
+            Some(hash) if pos <= chain.len()? => Ok(&chain.block_at(pos.into())?.hash()? == hash),
+            _ => Ok(false),
+        }
+    }
+
+    /// Restores the latest checkpoint at or below `pos` that is still in `chain`, or the
+    /// initial state if there is none.
+    fn restore<C: Chain<R>>(&mut self, chain: &C, pos: u64) -> Result<(), StateError> {
+        if let Some((checkpoints, _)) = &self.checkpoints {
+            for (position, hash) in checkpoints.at_or_below(pos)? {
+                if position > chain.len()? || chain.block_at(position.into())?.hash()? != hash {
+                    continue;
+                }
+                if let Some(state) = checkpoints.load(position)? {
+                    self.reset(state, (position, hash));
+                    return Ok(());
+                }
+            }
+        }
+        self.reset(self.initial.clone(), (0, Hash::default()));
+        Ok(())
+    }
+
+    /// Reverts `records` in the opposite order, returning `true`. If one of them cannot be
+    /// reverted, the half reverted state is discarded and the state is restored at or below
+    /// `pos` instead, returning `false`.
+    fn revert_or_restore<C: Chain<R>>(
+        &mut self,
+        chain: &C,
+        records: &[SignedRecord<R>],
+        pos: u64,
+    ) -> Result<bool, StateError> {
+        for record in records.iter().rev() {
+            if self.state.revert(record).is_err() {
+                self.restore(chain, pos)?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Applies the records of a block, reverting the ones already applied if one fails or
+    /// if the block commits to another state or other receipts.
+    fn apply_block<C: Chain<R>>(
+        &mut self,
+        chain: &C,
+        context: BlockContext,
+        records: Vec<SignedRecord<R>>,
+        hash: Hash,
+        version: u32,
+        state_root: Hash,
+        receipts_root: Hash,
+    ) -> Result<(), StateError> {
+        let mut receipts = vec![];
+        for (i, record) in records.iter().enumerate() {
+            if let Err(e) = self.state.apply(record) {
+                self.revert_or_restore(chain, &records[..i], self.height())?;
+                return Err(e);
+            }
+            receipts.push(self.state.receipt(record));
+        }
+        if let Some(data) =
+            invalid_commitments(&self.state, &receipts, version, &state_root, &receipts_root)
+        {
+            self.revert_or_restore(chain, &records, self.height())?;
+            return Err(ChainError::NotValid(data).into());
+        }
+
+        self.hashes.push(hash);
+        self.journal.push_back((context, records));
+        if self.journal.len() > self.journal_depth {
+            self.journal.pop_front();
+        }
+
+        let height = self.height();
+        if let Some((checkpoints, interval)) = &self.checkpoints {
+            if height.is_multiple_of(*interval) {
+                checkpoints.save(height, &self.hashes[self.hashes.len() - 1], &self.state)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the part of a block with header version `version` that commits to another
+/// state than `state` or to other receipts than `receipts`, if any.
+///
+/// Blocks at `BlockHeader::LEGACY_VERSION` have no state root or receipts root, which is
+/// not checked.
+fn invalid_commitments<R, S: StateMachine<R>>(
+    state: &S,
+    receipts: &[Receipt],
+    version: u32,
+    state_root: &Hash,
+    receipts_root: &Hash,
+) -> Option<BlockData> {
+    if version <= BlockHeader::LEGACY_VERSION {
+        None
+    } else if state_root != &state.root() {
+        Some(BlockData::StateRoot)
+    } else if receipts_root != &receipt::receipts_root(receipts) {
+        Some(BlockData::ReceiptsRoot)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{self as blockify, block::LocalInstance, inner::test_dir};
+
+    use blockify::{
+        block::{BlockData, ChainedInstance},
+        chain::{Chain, ChainError},
+        data::Metadata,
+        light::{verify_state, HeaderChain, SealRule},
+        record::{Record, SignedRecord},
+        sparse_merkle::SparseMerkleTree,
+        state::{StateError, StateMachine, StateRunner},
+        AuthKeyPair, Hash, SqliteChain, SqliteCheckpoints,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    enum Op {
+        Mint {
+            to: String,
+            amount: u64,
+        },
+        Transfer {
+            from: String,
+            to: String,
+            amount: u64,
+        },
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct Balances(BTreeMap<String, u64>);
+
+    #[derive(Debug)]
+    struct Insufficient;
+
+    impl std::fmt::Display for Insufficient {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Debug::fmt(self, f)
+        }
+    }
+
+    impl std::error::Error for Insufficient {}
+
+    impl Balances {
+        fn credit(&mut self, account: &str, amount: u64) {
+            *self.0.entry(account.to_owned()).or_default() += amount;
+        }
+
+        fn debit(&mut self, account: &str, amount: u64) -> Result<(), StateError> {
+            match self.0.get_mut(account) {
+                Some(balance) if *balance >= amount => {
+                    *balance -= amount;
+                    Ok(())
+                }
+                _ => Err(StateError::custom(Box::new(Insufficient))),
+            }
+        }
+
+        fn tree(&self) -> SparseMerkleTree {
+            let mut tree = SparseMerkleTree::new();
+            // reverted blocks leave empty accounts behind, which are not part of the state
+            for (account, balance) in self.0.iter().filter(|(_, balance)| **balance > 0) {
+                tree.insert(&crate::sha(account), balance.to_be_bytes().to_vec());
+            }
+            tree
+        }
+    }
+
+    impl StateMachine<Op> for Balances {
+        fn apply(&mut self, record: &SignedRecord<Op>) -> Result<(), StateError> {
+            match record.record() {
+                Op::Mint { to, amount } => self.credit(to, *amount),
+                Op::Transfer { from, to, amount } => {
+                    self.debit(from, *amount)?;
+                    self.credit(to, *amount);
+                }
+            }
+            Ok(())
+        }
+
+        fn revert(&mut self, record: &SignedRecord<Op>) -> Result<(), StateError> {
+            match record.record() {
+                // stands for a state that cannot undo some of its records
+                Op::Mint { to, .. } if to == "frozen" => {
+                    Err(StateError::custom(Box::new(Insufficient)))
+                }
+                Op::Mint { to, amount } => self.debit(to, *amount),
+                Op::Transfer { from, to, amount } => {
+                    self.debit(to, *amount)?;
+                    self.credit(from, *amount);
+                    Ok(())
+                }
+            }
+        }
+
+        fn root(&self) -> Hash {
+            self.tree().root()
+        }
+    }
+
+    fn block(keypair: &AuthKeyPair, ops: Vec<Op>) -> LocalInstance<Op> {
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        for op in ops {
+            block.push(op.record(keypair.clone(), Metadata::empty()).unwrap());
+        }
+        block
+    }
+
+    /// Returns a block of `ops` that commits to the state and receipts they lead to after
+    /// the last block of `chain`.
+    fn next_block(
+        chain: &SqliteChain<Op>,
+        keypair: &AuthKeyPair,
+        ops: Vec<Op>,
+    ) -> LocalInstance<Op> {
+        let mut runner = StateRunner::new(Balances::default());
+        runner.sync(chain).unwrap();
+        let mut next = block(keypair, ops);
+        runner.prepare(&mut next).unwrap();
+        next
+    }
+
+    fn mint(to: &str, amount: u64) -> Op {
+        Op::Mint {
+            to: to.to_owned(),
+            amount,
+        }
+    }
+
+    fn transfer(from: &str, to: &str, amount: u64) -> Op {
+        Op::Transfer {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            amount,
+        }
+    }
+
+    fn balance(runner: &StateRunner<Op, Balances>, account: &str) -> u64 {
+        runner.state().0.get(account).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_append_and_reorg() {
+        let url = test_dir("statereorg");
+        let keypair = crate::generate_ed25519_keypair();
+        let mut chain = SqliteChain::new(&url).unwrap();
+        let mut runner = StateRunner::new(Balances::default()).with_journal(1);
+
+        for ops in [
+            vec![mint("alice", 100)],
+            vec![transfer("alice", "bob", 30)],
+            vec![transfer("bob", "carol", 10)],
+        ] {
+            let next = next_block(&chain, &keypair, ops);
+            runner.append(&mut chain, &next).unwrap();
+        }
+        assert_eq!(runner.height(), 3);
+        assert_eq!(balance(&runner, "carol"), 10);
+
+        // a block whose records cannot all be applied is not applied at all
+        let bad = block(
+            &keypair,
+            vec![mint("dave", 5), transfer("bob", "dave", 500)],
+        );
+        assert!(matches!(
+            runner.append(&mut chain, &bad),
+            Err(StateError::Custom(_))
+        ));
+        assert_eq!(runner.height(), 3);
+        assert_eq!(balance(&runner, "dave"), 0);
+        assert_eq!(chain.len().unwrap(), 3);
+
+        // one block is reverted from the journal
+        chain.rollback(2.into()).unwrap();
+        chain
+            .append(&next_block(
+                &chain,
+                &keypair,
+                vec![transfer("bob", "dave", 20)],
+            ))
+            .unwrap();
+        assert_eq!(runner.sync(&chain).unwrap(), 1);
+        assert_eq!(balance(&runner, "carol"), 0);
+        assert_eq!(balance(&runner, "dave"), 20);
+
+        // two blocks are reverted by replaying from the start
+        chain.rollback(1.into()).unwrap();
+        chain
+            .append(&next_block(
+                &chain,
+                &keypair,
+                vec![transfer("alice", "erin", 50)],
+            ))
+            .unwrap();
+        assert_eq!(runner.sync(&chain).unwrap(), 2);
+        assert_eq!(balance(&runner, "alice"), 50);
+        assert_eq!(balance(&runner, "bob"), 0);
+        assert_eq!(balance(&runner, "erin"), 50);
+
+        let mut fresh = StateRunner::new(Balances::default());
+        fresh.sync(&chain).unwrap();
+        assert_eq!(fresh.state(), runner.state());
+
+        // a block that cannot be reverted is replayed over instead
+        let mut runner = StateRunner::new(Balances::default()).with_journal(4);
+        chain
+            .append(&next_block(&chain, &keypair, vec![mint("frozen", 1)]))
+            .unwrap();
+        assert_eq!(runner.sync(&chain).unwrap(), 3);
+        chain.rollback(2.into()).unwrap();
+        chain
+            .append(&next_block(&chain, &keypair, vec![mint("bob", 5)]))
+            .unwrap();
+        assert_eq!(runner.sync(&chain).unwrap(), 3);
+        assert_eq!(balance(&runner, "frozen"), 0);
+        assert_eq!(balance(&runner, "bob"), 5);
+
+        // and so is a block whose records cannot all be applied or reverted
+        let mut bad = block(
+            &keypair,
+            vec![mint("frozen", 1), transfer("nobody", "dave", 5)],
+        );
+        bad.state_root = runner.state().root();
+        chain.append(&bad).unwrap();
+        assert!(matches!(runner.sync(&chain), Err(StateError::Custom(_))));
+        assert_eq!(balance(&runner, "frozen"), 0);
+        assert_eq!(runner.height(), 0);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let url = test_dir("statecheckpoints");
+        let keypair = crate::generate_ed25519_keypair();
+        let mut chain = SqliteChain::new(&url).unwrap();
+        let checkpoints = || SqliteCheckpoints::new(&format!("{url}state.db")).unwrap();
+
+        let mut runner = StateRunner::new(Balances::default()).with_checkpoints(checkpoints(), 2);
+        for i in 0..5 {
+            let next = next_block(&chain, &keypair, vec![mint("alice", i)]);
+            runner.append(&mut chain, &next).unwrap();
+        }
+        let stored = checkpoints().at_or_below(10).unwrap();
+        assert_eq!(
+            stored.iter().map(|(p, _)| *p).collect::<Vec<_>>(),
+            vec![4, 2]
+        );
+
+        // a new runner starts from the checkpoint at 4 and applies only the last block
+        let mut restarted =
+            StateRunner::new(Balances::default()).with_checkpoints(checkpoints(), 2);
+        assert_eq!(restarted.sync(&chain).unwrap(), 1);
+        assert_eq!(restarted.state(), runner.state());
+
+        // checkpoints of blocks that are no longer in the chain are not used
+        chain.rollback(3.into()).unwrap();
+        chain
+            .append(&next_block(&chain, &keypair, vec![mint("bob", 7)]))
+            .unwrap();
+        let mut restarted =
+            StateRunner::new(Balances::default()).with_checkpoints(checkpoints(), 2);
+        assert_eq!(restarted.sync(&chain).unwrap(), 2);
+        assert_eq!(balance(&restarted, "alice"), 3);
+        assert_eq!(balance(&restarted, "bob"), 7);
+    }
+
+    #[test]
+    fn test_state_root() {
+        let url = test_dir("stateroot");
+        let keypair = crate::generate_ed25519_keypair();
+        let mut chain = SqliteChain::new(&url).unwrap();
+        let mut runner = StateRunner::new(Balances::default());
+
+        for ops in [vec![mint("alice", 100)], vec![transfer("alice", "bob", 30)]] {
+            let mut next = block(&keypair, ops);
+            runner.prepare(&mut next).unwrap();
+            runner.append(&mut chain, &next).unwrap();
+        }
+        let tip = chain.block_at(2.into()).unwrap();
+        assert_eq!(tip.state_root().unwrap(), runner.state().root());
+        assert_ne!(
+            chain.block_at(1.into()).unwrap().state_root().unwrap(),
+            tip.state_root().unwrap()
+        );
+
+        let mut headers = HeaderChain::new(SealRule::None);
+        headers.sync(&chain).unwrap();
+
+        let tree = runner.state().tree();
+        let bob = crate::sha(&"bob");
+        let proof = tree.prove(&bob);
+        let value = 30u64.to_be_bytes();
+        assert!(verify_state(&tip, &bob, Some(&value), &proof).unwrap());
+        assert!(headers.verify_state(2.into(), &bob, Some(&value), &proof));
+        assert!(!headers.verify_state(1.into(), &bob, Some(&value), &proof));
+
+        let carol = crate::sha(&"carol");
+        assert!(headers.verify_state(2.into(), &carol, None, &tree.prove(&carol)));
+
+        // a block that commits to another state is not applied
+        let mut bad = block(&keypair, vec![mint("carol", 5)]);
+        bad.state_root = tip.state_root().unwrap();
+        assert!(matches!(
+            runner.append(&mut chain, &bad),
+            Err(StateError::ChainError(ChainError::NotValid(
+                BlockData::StateRoot
+            )))
+        ));
+
+        // nor is one that leaves out its commitments
+        let mut bare = block(&keypair, vec![mint("carol", 5)]);
+        assert!(matches!(
+            runner.append(&mut chain, &bare),
+            Err(StateError::ChainError(ChainError::NotValid(
+                BlockData::StateRoot
+            )))
+        ));
+        runner.prepare(&mut bare).unwrap();
+        bare.receipts.clear();
+        assert!(matches!(
+            runner.append(&mut chain, &bare),
+            Err(StateError::ChainError(ChainError::NotValid(
+                BlockData::ReceiptsRoot
+            )))
+        ));
+        assert_eq!(runner.height(), 2);
+        assert_eq!(balance(&runner, "carol"), 0);
+        assert_eq!(chain.len().unwrap(), 2);
+    }
+}
@@ -0,0 +1,559 @@
+//! Fungible tokens.
+//!
+//! A `TokenLedger` keeps the balance of every account in every `Micron`, changed by records
+//! implementing `TokenRecord`. Accounts are the public keys that sign records: transfers
+//! and burns take units from the signer, and a micron can only be minted by its issuer.
+//! The ledger is a `StateMachine`, so a `StateRunner` can keep it in step with a chain and
+//! commit its balances into block headers.
+//!
+//! A ledger created `with_fees` also charges every record its `Fee`: the base fee of the
+//! block is burned and the tip is paid to the producer of the block.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{MicQuan, Micron, Quantity, UnitError, UnitManager},
+    fees::{FeeCharge, FeeRecord},
+    record::SignedRecord,
+    sparse_merkle::{SparseMerkleTree, SparseProof},
+    state::{BlockContext, StateError, StateMachine},
+    Hash, PublicKey,
+};
+
+/// A change to the balances of a `TokenLedger`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenOp {
+    /// Creates new units in the account of `to`. Only the issuer of the micron can mint it.
+    Mint { to: PublicKey, units: MicQuan },
+    /// Moves units from the signer to `to`.
+    Transfer { to: PublicKey, units: MicQuan },
+    /// Destroys units of the signer.
+    Burn(MicQuan),
+}
+
+/// Records that can change the balances of a `TokenLedger`.
+///
+/// Records that pay no fees can keep the default `FeeRecord::fee`.
+pub trait TokenRecord: FeeRecord {
+    /// Returns the change this record makes to the balances, if any.
+    fn token_op(&self) -> Option<TokenOp>;
+}
+
+/// An error that can occur when applying a `TokenOp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// The account does not hold the units it spends.
+    InsufficientBalance,
+    /// The signer is not the issuer of the micron it mints.
+    Unauthorized,
+    /// The record offers less than the base fee of its block.
+    FeeTooLow,
+    UnitError(UnitError),
+}
+
+impl From<UnitError> for TokenError {
+    fn from(value: UnitError) -> Self {
+        TokenError::UnitError(value)
+    }
+}
+
+crate::impl_display_error!(TokenError);
+
+impl From<TokenError> for StateError {
+    fn from(value: TokenError) -> Self {
+        StateError::custom(Box::new(value))
+    }
+}
+
+/// The balances and supply of every micron.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::{data::{MicQuan, Micron, Quantity}, token::TokenLedger};
+///
+/// let issuer = blockify::generate_ed25519_keypair().into_public_key();
+/// let alice = blockify::generate_ed25519_keypair().into_public_key();
+/// let coin = Micron::new(0);
+///
+/// let mut ledger = TokenLedger::new().with_issuer(coin, issuer.clone());
+/// ledger.mint(&issuer, &alice, MicQuan::new(coin, 100.into())).unwrap();
+/// ledger.burn(&alice, MicQuan::new(coin, 40.into())).unwrap();
+/// assert_eq!(ledger.balance(&alice, coin), Quantity::new(60));
+/// assert!(ledger.burn(&alice, MicQuan::new(coin, 70.into())).is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenLedger {
+    /// The issuer of every micron, by micron id.
+    issuers: BTreeMap<i32, PublicKey>,
+    /// The balances of every account, by hex key and micron id.
+    balances: BTreeMap<String, BTreeMap<i32, Quantity>>,
+    /// The total supply of every micron, by micron id.
+    supply: BTreeMap<i32, Quantity>,
+    /// The micron fees are paid in, if records pay fees.
+    fees: Option<Micron>,
+    /// The block whose records are applied.
+    #[serde(skip)]
+    block: BlockContext,
+}
+
+impl TokenLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `issuer` to mint `micron`.
+    pub fn with_issuer(mut self, micron: Micron, issuer: PublicKey) -> Self {
+        self.issuers.insert(micron.id(), issuer);
+        self
+    }
+
+    /// Charges every record its fee in `micron`.
+    pub fn with_fees(mut self, micron: Micron) -> Self {
+        self.fees = Some(micron);
+        self
+    }
+
+    /// Returns the micron fees are paid in, if records pay fees.
+    pub fn fee_micron(&self) -> Option<Micron> {
+        self.fees
+    }
+
+    pub fn issuer(&self, micron: Micron) -> Option<&PublicKey> {
+        self.issuers.get(&micron.id())
+    }
+
+    pub fn balance(&self, account: &PublicKey, micron: Micron) -> Quantity {
+        self.balances
+            .get(&account.to_hex())
+            .and_then(|balances| balances.get(&micron.id()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the key under which the balance of `account` in `micron` is stored in the
+    /// tree whose root is the root of this ledger.
+    pub fn balance_key(account: &PublicKey, micron: Micron) -> Hash {
+        crate::sha_all([account.as_bytes(), &micron.id().to_be_bytes()[..]])
+    }
+
+    /// Returns the proof of the balance of `account` in `micron` against `root()`.
+    ///
+    /// The value of an account holding units is its balance as a big-endian `u128`. An
+    /// account without units has no value.
+    pub fn prove(&self, account: &PublicKey, micron: Micron) -> SparseProof {
+        self.tree().prove(&Self::balance_key(account, micron))
+    }
+
+    fn tree(&self) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for (account, balances) in &self.balances {
+            let account = hex::decode(account).unwrap_or_default();
+            for (micron, balance) in balances {
+                let key = crate::sha_all([&account[..], &micron.to_be_bytes()[..]]);
+                tree.insert(&key, balance.value().to_be_bytes().to_vec());
+            }
+        }
+        tree
+    }
+
+    /// Creates `units` in the account of `to`, signed by `signer`.
+    pub fn mint(
+        &mut self,
+        signer: &PublicKey,
+        to: &PublicKey,
+        units: MicQuan,
+    ) -> Result<(), TokenError> {
+        if self.issuer(units.micron()) != Some(signer) {
+            return Err(TokenError::Unauthorized);
+        }
+        let supply = self
+            .supply_of(units.micron())
+            .checked_add(units.quantity())?;
+        self.credit(to, units)?;
+        self.supply.insert(units.micron().id(), supply);
+        Ok(())
+    }
+
+    /// Moves `units` from `from` to `to`.
+    pub fn transfer(
+        &mut self,
+        from: &PublicKey,
+        to: &PublicKey,
+        units: MicQuan,
+    ) -> Result<(), TokenError> {
+        self.debit(from, units)?;
+        if let Err(e) = self.credit(to, units) {
+            self.credit(from, units)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Destroys `units` of `from`.
+    pub fn burn(&mut self, from: &PublicKey, units: MicQuan) -> Result<(), TokenError> {
+        self.debit(from, units)?;
+        let supply = self
+            .supply_of(units.micron())
+            .checked_sub(units.quantity())?;
+        self.supply.insert(units.micron().id(), supply);
+        Ok(())
+    }
+
+    /// Returns what `record` pays in the current block, with the tip
+    /// counted as burned if the block has no producer.
+    fn fee_charge<R: FeeRecord>(&self, record: &R) -> Result<FeeCharge, TokenError> {
+        let charge = record
+            .fee()
+            .charge(self.block.base_fee)
+            .ok_or(TokenError::FeeTooLow)?;
+        Ok(match self.block.producer {
+            Some(_) => charge,
+            None => FeeCharge {
+                burned: charge.total(),
+                tip: 0,
+            },
+        })
+    }
+
+    /// Takes `charge` from `payer`, burning its base fee and paying its tip to the
+    /// producer of the current block.
+    fn pay_fee(
+        &mut self,
+        micron: Micron,
+        payer: &PublicKey,
+        charge: FeeCharge,
+    ) -> Result<(), TokenError> {
+        let supply = self.supply_of(micron).checked_sub(charge.burned.into())?;
+        self.debit(payer, MicQuan::new(micron, charge.total().into()))?;
+        if let Some(producer) = self.block.producer.clone() {
+            if let Err(e) = self.credit(&producer, MicQuan::new(micron, charge.tip.into())) {
+                self.credit(payer, MicQuan::new(micron, charge.total().into()))?;
+                return Err(e);
+            }
+        }
+        self.supply.insert(micron.id(), supply);
+        Ok(())
+    }
+
+    /// Undoes `pay_fee`.
+    fn refund_fee(
+        &mut self,
+        micron: Micron,
+        payer: &PublicKey,
+        charge: FeeCharge,
+    ) -> Result<(), TokenError> {
+        let supply = self.supply_of(micron).checked_add(charge.burned.into())?;
+        if let Some(producer) = self.block.producer.clone() {
+            self.debit(&producer, MicQuan::new(micron, charge.tip.into()))?;
+        }
+        self.credit(payer, MicQuan::new(micron, charge.total().into()))?;
+        self.supply.insert(micron.id(), supply);
+        Ok(())
+    }
+
+    fn credit(&mut self, account: &PublicKey, units: MicQuan) -> Result<(), TokenError> {
+        let balance = self
+            .balance(account, units.micron())
+            .checked_add(units.quantity())?;
+        self.set_balance(account, units.micron(), balance);
+        Ok(())
+    }
+
+    fn debit(&mut self, account: &PublicKey, units: MicQuan) -> Result<(), TokenError> {
+        let balance = self
+            .balance(account, units.micron())
+            .checked_sub(units.quantity())
+            .map_err(|_| TokenError::InsufficientBalance)?;
+        self.set_balance(account, units.micron(), balance);
+        Ok(())
+    }
+
+    /// Accounts without units are removed, so that the ledger only depends on balances.
+    fn set_balance(&mut self, account: &PublicKey, micron: Micron, balance: Quantity) {
+        let key = account.to_hex();
+        let balances = self.balances.entry(key.clone()).or_default();
+        if balance.is_none() {
+            balances.remove(&micron.id());
+        } else {
+            balances.insert(micron.id(), balance);
+        }
+        if balances.is_empty() {
+            self.balances.remove(&key);
+        }
+    }
+}
+
+impl UnitManager for TokenLedger {
+    fn all_units(&self) -> Vec<MicQuan> {
+        self.supply
+            .iter()
+            .map(|(micron, supply)| MicQuan::new((*micron).into(), *supply))
+            .collect()
+    }
+}
+
+impl TokenLedger {
+    fn apply_op(&mut self, signer: &PublicKey, op: Option<TokenOp>) -> Result<(), TokenError> {
+        match op {
+            Some(TokenOp::Mint { to, units }) => self.mint(signer, &to, units),
+            Some(TokenOp::Transfer { to, units }) => self.transfer(signer, &to, units),
+            Some(TokenOp::Burn(units)) => self.burn(signer, units),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: TokenRecord> StateMachine<R> for TokenLedger {
+    /// Charges the fee of `record` before applying its op, and refunds it if the op fails.
+    fn apply(&mut self, record: &SignedRecord<R>) -> Result<(), StateError> {
+        let signer = record.signer();
+        let fee = match self.fees {
+            Some(micron) => {
+                let charge = self.fee_charge(record.record())?;
+                self.pay_fee(micron, signer, charge)?;
+                Some((micron, charge))
+            }
+            None => None,
+        };
+        if let Err(e) = self.apply_op(signer, record.record().token_op()) {
+            if let Some((micron, charge)) = fee {
+                self.refund_fee(micron, signer, charge)?;
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn revert(&mut self, record: &SignedRecord<R>) -> Result<(), StateError> {
+        let signer = record.signer();
+        match record.record().token_op() {
+            Some(TokenOp::Mint { to, units }) => {
+                self.debit(&to, units)?;
+                let supply = self.supply_of(units.micron()).checked_sub(units.quantity());
+                self.supply
+                    .insert(units.micron().id(), supply.map_err(TokenError::from)?);
+            }
+            Some(TokenOp::Transfer { to, units }) => self.transfer(&to, signer, units)?,
+            Some(TokenOp::Burn(units)) => {
+                let supply = self.supply_of(units.micron()).checked_add(units.quantity());
+                self.supply
+                    .insert(units.micron().id(), supply.map_err(TokenError::from)?);
+                self.credit(signer, units)?;
+            }
+            None => {}
+        }
+        if let Some(micron) = self.fees {
+            let charge = self.fee_charge(record.record())?;
+            self.refund_fee(micron, signer, charge)?;
+        }
+        Ok(())
+    }
+
+    fn begin_block(&mut self, block: &BlockContext) {
+        self.block = block.clone();
+    }
+
+    fn root(&self) -> Hash {
+        self.tree().root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        chain::Chain,
+        data::{Metadata, MicQuan, Micron, Quantity, UnitManager},
+        fees::{Fee, FeeRecord},
+        light::verify_state,
+        record::{Record, SignedRecord},
+        state::{BlockContext, StateError, StateMachine, StateRunner},
+        token::{TokenLedger, TokenOp, TokenRecord},
+        AuthKeyPair, PublicKey, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    enum Wallet {
+        Note(String),
+        Op(TokenOp),
+    }
+
+    impl TokenRecord for Wallet {
+        fn token_op(&self) -> Option<TokenOp> {
+            match self {
+                Wallet::Op(op) => Some(op.clone()),
+                Wallet::Note(_) => None,
+            }
+        }
+    }
+
+    impl FeeRecord for Wallet {}
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Paid {
+        op: TokenOp,
+        fee: Fee,
+    }
+
+    impl FeeRecord for Paid {
+        fn fee(&self) -> Fee {
+            self.fee
+        }
+    }
+
+    impl TokenRecord for Paid {
+        fn token_op(&self) -> Option<TokenOp> {
+            Some(self.op.clone())
+        }
+    }
+
+    fn paid(keypair: &AuthKeyPair, op: TokenOp, max_fee: u128, tip: u128) -> SignedRecord<Paid> {
+        let paid = Paid {
+            op,
+            fee: Fee::new(max_fee, tip),
+        };
+        paid.record(keypair.clone(), Metadata::empty()).unwrap()
+    }
+
+    fn block(keypair: &AuthKeyPair, op: TokenOp) -> LocalInstance<Wallet> {
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        let record = Wallet::Op(op).record(keypair.clone(), Metadata::empty());
+        block.push(record.unwrap());
+        block
+    }
+
+    fn key(keypair: &AuthKeyPair) -> PublicKey {
+        keypair.clone().into_public_key()
+    }
+
+    #[test]
+    fn test_ledger() {
+        let url = "target2/tests/tokenledger/";
+        let _ = std::fs::remove_dir_all(url);
+        std::fs::create_dir_all(url).expect("could not create url");
+
+        let issuer = crate::generate_ed25519_keypair();
+        let alice = crate::generate_ed25519_keypair();
+        let bob = crate::generate_ed25519_keypair();
+        let coin = Micron::new(1);
+        let units = |quantity: u128| MicQuan::new(coin, quantity.into());
+
+        let mut chain = SqliteChain::new(url).unwrap();
+        let ledger = TokenLedger::new().with_issuer(coin, key(&issuer));
+        let mut runner = StateRunner::<Wallet, _>::new(ledger);
+
+        let mint = TokenOp::Mint {
+            to: key(&alice),
+            units: units(100),
+        };
+        let mut next = block(&issuer, mint.clone());
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+        let transfer = TokenOp::Transfer {
+            to: key(&bob),
+            units: units(30),
+        };
+        let mut next = block(&alice, transfer);
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+        let mut next = block(&bob, TokenOp::Burn(units(10)));
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+
+        let ledger = runner.state();
+        assert_eq!(ledger.balance(&key(&alice), coin), Quantity::new(70));
+        assert_eq!(ledger.balance(&key(&bob), coin), Quantity::new(20));
+        assert_eq!(ledger.supply_of(coin), Quantity::new(90));
+        assert_eq!(ledger.all_units_raw(), vec![(1, 90)]);
+
+        // only the issuer can mint, and nobody can spend more than they hold
+        assert!(matches!(
+            runner.append(&mut chain, &block(&alice, mint)),
+            Err(StateError::Custom(_))
+        ));
+        let overdraft = TokenOp::Transfer {
+            to: key(&alice),
+            units: units(21),
+        };
+        assert!(runner.append(&mut chain, &block(&bob, overdraft)).is_err());
+        assert_eq!(chain.len().unwrap(), 3);
+
+        // the balance of bob after the second block is proven against its header
+        chain.rollback(2.into()).unwrap();
+        runner.sync(&chain).unwrap();
+        assert_eq!(runner.state().supply_of(coin), Quantity::new(100));
+        let tip = chain.block_at(2.into()).unwrap();
+        let bob = key(&bob);
+        let proof = runner.state().prove(&bob, coin);
+        let balance = 30u128.to_be_bytes();
+        let key = TokenLedger::balance_key(&bob, coin);
+        assert!(verify_state(&tip, &key, Some(&balance), &proof).unwrap());
+        assert!(!verify_state(&tip, &key, None, &proof).unwrap());
+    }
+
+    #[test]
+    fn test_fees() {
+        let issuer = crate::generate_ed25519_keypair();
+        let alice = crate::generate_ed25519_keypair();
+        let producer = crate::generate_ed25519_keypair();
+        let coin = Micron::new(1);
+        let units = |quantity: u128| MicQuan::new(coin, quantity.into());
+        let mut ledger = TokenLedger::new()
+            .with_issuer(coin, key(&issuer))
+            .with_fees(coin);
+
+        // a block without a base fee lets records without fees in
+        let mint = TokenOp::Mint {
+            to: key(&alice),
+            units: units(100),
+        };
+        ledger.apply(&paid(&issuer, mint, 0, 0)).unwrap();
+
+        // the base fee is burned and as much of the tip as the maximum fee allows is paid
+        StateMachine::<Paid>::begin_block(
+            &mut ledger,
+            &BlockContext {
+                base_fee: 10,
+                producer: Some(key(&producer)),
+                ..Default::default()
+            },
+        );
+        let transfer = |amount: u128| TokenOp::Transfer {
+            to: key(&producer),
+            units: units(amount),
+        };
+        let record = paid(&alice, transfer(20), 15, 8);
+        ledger.apply(&record).unwrap();
+        assert_eq!(ledger.balance(&key(&alice), coin), Quantity::new(65));
+        assert_eq!(ledger.balance(&key(&producer), coin), Quantity::new(25));
+        assert_eq!(ledger.supply_of(coin), Quantity::new(90));
+
+        // records that cannot pay, or whose op fails, leave the ledger as it was
+        let before = ledger.clone();
+        assert!(ledger.apply(&paid(&alice, transfer(1), 9, 0)).is_err());
+        assert!(ledger.apply(&paid(&alice, transfer(60), 15, 0)).is_err());
+        assert_eq!(ledger, before);
+
+        ledger.revert(&record).unwrap();
+        assert_eq!(ledger.balance(&key(&alice), coin), Quantity::new(100));
+        assert_eq!(ledger.balance(&key(&producer), coin), Quantity::new(0));
+        assert_eq!(ledger.supply_of(coin), Quantity::new(100));
+
+        // without a producer the tip is burned too
+        StateMachine::<Paid>::begin_block(
+            &mut ledger,
+            &BlockContext {
+                base_fee: 10,
+                ..Default::default()
+            },
+        );
+        ledger.apply(&paid(&alice, transfer(20), 15, 8)).unwrap();
+        assert_eq!(ledger.balance(&key(&alice), coin), Quantity::new(65));
+        assert_eq!(ledger.supply_of(coin), Quantity::new(85));
+    }
+}
@@ -0,0 +1,492 @@
+//! Unspent transaction outputs.
+//!
+//! A `Transaction` spends outputs of earlier transactions and creates new ones. Every
+//! input names the output it spends with an `OutPoint` and carries a signature of the
+//! transaction by the owner of that output. A `UtxoSet` holds the outputs that have not
+//! been spent yet; as a `StateMachine` it is kept in step with a chain by a `StateRunner`,
+//! which rejects blocks spending an output twice, within the block or across blocks.
+//!
+//! Transactions without inputs create new units. They are only accepted when signed by
+//! one of the minters of the set, and carry a nonce so that two mints of the same outputs
+//! have different ids.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::LocalInstance,
+    data::{MicQuan, Micron, Quantity, UnitError},
+    record::SignedRecord,
+    sparse_merkle::{SparseMerkleTree, SparseProof},
+    state::{StateError, StateMachine},
+    AuthKeyPair, DigitalSignature, Hash, PublicKey, SigningError,
+};
+
+/// Names an output of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, index: u32) -> Self {
+        Self { txid, index }
+    }
+
+    fn key(&self) -> String {
+        format!("{}:{}", self.txid, self.index)
+    }
+}
+
+/// Spends the output at `outpoint`, with a signature of the transaction by its owner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxInput {
+    pub outpoint: OutPoint,
+    pub signature: DigitalSignature,
+}
+
+impl TxInput {
+    pub fn new(outpoint: OutPoint, signature: DigitalSignature) -> Self {
+        Self {
+            outpoint,
+            signature,
+        }
+    }
+}
+
+/// An amount that can be spent by the holder of `owner`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxOutput {
+    pub amount: MicQuan,
+    pub owner: PublicKey,
+}
+
+impl TxOutput {
+    pub fn new(amount: MicQuan, owner: PublicKey) -> Self {
+        Self { amount, owner }
+    }
+}
+
+/// A transfer of units from the outputs it spends to the outputs it creates.
+///
+/// The inputs of a transaction must hold at least as many units of every micron as its
+/// outputs. The units left over are not given to anyone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    inputs: Vec<TxInput>,
+    outputs: Vec<TxOutput>,
+    /// Tells mints of the same outputs apart, like the height of a coinbase. `0` for
+    /// transactions with inputs, whose outpoints already make their id unique.
+    nonce: u64,
+}
+
+crate::record::impl_record_for!(Transaction);
+
+impl Transaction {
+    pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
+        Self {
+            inputs,
+            outputs,
+            nonce: 0,
+        }
+    }
+
+    /// Creates a transaction spending every outpoint with the key pair that owns it.
+    pub fn sign(
+        spends: Vec<(OutPoint, &AuthKeyPair)>,
+        outputs: Vec<TxOutput>,
+    ) -> Result<Self, SigningError> {
+        let outpoints = spends.iter().map(|(o, _)| o).collect::<Vec<_>>();
+        let txid = Self::id_of(&outpoints, &outputs, 0);
+        let inputs = spends
+            .iter()
+            .map(|(outpoint, keypair)| Ok(TxInput::new(outpoint.clone(), keypair.sign(&txid)?)))
+            .collect::<Result<_, SigningError>>()?;
+        Ok(Self::new(inputs, outputs))
+    }
+
+    /// Creates a transaction without inputs, which creates new units.
+    ///
+    /// Mints of the same outputs need different nonces, such as the height of the block
+    /// they are minted in; a mint with the id of one already applied is rejected.
+    pub fn mint(outputs: Vec<TxOutput>, nonce: u64) -> Self {
+        Self {
+            inputs: vec![],
+            outputs,
+            nonce,
+        }
+    }
+
+    pub fn inputs(&self) -> &[TxInput] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[TxOutput] {
+        &self.outputs
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn is_mint(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Returns the hash the inputs sign, which identifies the transaction.
+    ///
+    /// It covers the outpoints, outputs and nonce but not the signatures, so a transaction
+    /// cannot be given another id by changing its signatures.
+    pub fn txid(&self) -> Hash {
+        let outpoints = self.inputs.iter().map(|i| &i.outpoint).collect::<Vec<_>>();
+        Self::id_of(&outpoints, &self.outputs, self.nonce)
+    }
+
+    fn id_of(outpoints: &[&OutPoint], outputs: &[TxOutput], nonce: u64) -> Hash {
+        crate::hash(&(outpoints, outputs, nonce))
+    }
+
+    /// Returns the outpoint of the output at `index`.
+    pub fn outpoint(&self, index: u32) -> OutPoint {
+        OutPoint::new(self.txid(), index)
+    }
+}
+
+/// An error that can occur when applying a `Transaction` to a `UtxoSet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtxoError {
+    /// An input spends an output that does not exist.
+    MissingInput,
+    /// An input spends an output that was already spent.
+    DoubleSpend,
+    /// The signature of an input is not by the owner of the output it spends.
+    BadSignature,
+    /// The outputs hold more units of a micron than the inputs.
+    InsufficientInputs,
+    /// A transaction without inputs is not signed by a minter.
+    Unauthorized,
+    /// A transaction with the same id was already applied.
+    DuplicateTransaction,
+    UnitError(UnitError),
+}
+
+impl From<UnitError> for UtxoError {
+    fn from(value: UnitError) -> Self {
+        UtxoError::UnitError(value)
+    }
+}
+
+crate::impl_display_error!(UtxoError);
+
+impl From<UtxoError> for StateError {
+    fn from(value: UtxoError) -> Self {
+        StateError::custom(Box::new(value))
+    }
+}
+
+/// The outputs that have not been spent.
+///
+/// The outputs spent are kept as well, to tell double spends apart from unknown outputs
+/// and to revert transactions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtxoSet {
+    minters: Vec<PublicKey>,
+    unspent: BTreeMap<String, TxOutput>,
+    spent: BTreeMap<String, TxOutput>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `minter` to sign transactions without inputs.
+    pub fn with_minter(mut self, minter: PublicKey) -> Self {
+        self.minters.push(minter);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.unspent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unspent.is_empty()
+    }
+
+    /// Returns the output at `outpoint` if it is unspent.
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.unspent.get(&outpoint.key())
+    }
+
+    /// Returns the units of `micron` that `owner` can spend.
+    pub fn balance(&self, owner: &PublicKey, micron: Micron) -> Result<Quantity, UnitError> {
+        self.unspent
+            .values()
+            .filter(|o| &o.owner == owner && o.amount.micron() == micron)
+            .try_fold(Quantity::none(), |total, o| {
+                total.checked_add(o.amount.quantity())
+            })
+    }
+
+    /// Checks that `record` can be applied to this set.
+    pub fn validate(&self, record: &SignedRecord<Transaction>) -> Result<(), UtxoError> {
+        let tx = record.record();
+        let first = tx.outpoint(0).key();
+        if self.unspent.contains_key(&first) || self.spent.contains_key(&first) {
+            return Err(UtxoError::DuplicateTransaction);
+        }
+        if tx.is_mint() {
+            if !self.minters.contains(record.signer()) {
+                return Err(UtxoError::Unauthorized);
+            }
+            return Ok(());
+        }
+
+        let txid = tx.txid();
+        let mut totals = BTreeMap::<Micron, Quantity>::new();
+        for (i, input) in tx.inputs.iter().enumerate() {
+            let key = input.outpoint.key();
+            let spends_again = tx.inputs[..i].iter().any(|o| o.outpoint == input.outpoint);
+            if spends_again || self.spent.contains_key(&key) {
+                return Err(UtxoError::DoubleSpend);
+            }
+            let output = self.unspent.get(&key).ok_or(UtxoError::MissingInput)?;
+            if output.owner.verify(&txid, &input.signature).is_err() {
+                return Err(UtxoError::BadSignature);
+            }
+            let total = totals.entry(output.amount.micron()).or_default();
+            *total = total.checked_add(output.amount.quantity())?;
+        }
+        for output in &tx.outputs {
+            let total = totals.entry(output.amount.micron()).or_default();
+            *total = total
+                .checked_sub(output.amount.quantity())
+                .map_err(|_| UtxoError::InsufficientInputs)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `record`, spending its inputs and adding its outputs.
+    pub fn apply_transaction(
+        &mut self,
+        record: &SignedRecord<Transaction>,
+    ) -> Result<(), UtxoError> {
+        self.validate(record)?;
+        let tx = record.record();
+        for input in &tx.inputs {
+            let key = input.outpoint.key();
+            if let Some(output) = self.unspent.remove(&key) {
+                self.spent.insert(key, output);
+            }
+        }
+        let txid = tx.txid();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint::new(txid.clone(), index as u32);
+            self.unspent.insert(outpoint.key(), output.clone());
+        }
+        Ok(())
+    }
+
+    /// Undoes `apply_transaction` for `record`, which must be the last transaction applied.
+    pub fn revert_transaction(&mut self, record: &SignedRecord<Transaction>) {
+        let tx = record.record();
+        let txid = tx.txid();
+        for index in 0..tx.outputs.len() {
+            self.unspent
+                .remove(&OutPoint::new(txid.clone(), index as u32).key());
+        }
+        for input in &tx.inputs {
+            let key = input.outpoint.key();
+            if let Some(output) = self.spent.remove(&key) {
+                self.unspent.insert(key, output);
+            }
+        }
+    }
+
+    /// Checks that every transaction of `block` can be applied after the ones before it,
+    /// so that no output is spent twice within the block.
+    pub fn check_block(&self, block: &LocalInstance<Transaction>) -> Result<(), UtxoError> {
+        let mut set = self.clone();
+        for record in &block.records {
+            set.apply_transaction(record)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the proof that the output at `outpoint` is unspent, or that it is not,
+    /// against `root()`.
+    ///
+    /// The value of an unspent output is its encoding (see `crate::serialize`).
+    pub fn prove(&self, outpoint: &OutPoint) -> SparseProof {
+        self.tree().prove(&crate::sha(&outpoint.key()))
+    }
+
+    fn tree(&self) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for (key, output) in &self.unspent {
+            if let Ok(value) = crate::serialize(output) {
+                tree.insert(&crate::sha(key), value);
+            }
+        }
+        tree
+    }
+}
+
+impl StateMachine<Transaction> for UtxoSet {
+    fn apply(&mut self, record: &SignedRecord<Transaction>) -> Result<(), StateError> {
+        Ok(self.apply_transaction(record)?)
+    }
+
+    fn revert(&mut self, record: &SignedRecord<Transaction>) -> Result<(), StateError> {
+        self.revert_transaction(record);
+        Ok(())
+    }
+
+    fn root(&self) -> Hash {
+        self.tree().root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::LocalInstance,
+        chain::Chain,
+        data::{Metadata, MicQuan, Micron, Quantity},
+        record::{Record, SignedRecord},
+        state::{StateError, StateRunner},
+        AuthKeyPair, SqliteChain,
+    };
+
+    use super::{OutPoint, Transaction, TxOutput, UtxoError, UtxoSet};
+
+    fn coins(quantity: u128) -> MicQuan {
+        MicQuan::new(Micron::new(0), quantity.into())
+    }
+
+    fn output(quantity: u128, owner: &AuthKeyPair) -> TxOutput {
+        TxOutput::new(coins(quantity), owner.clone().into_public_key())
+    }
+
+    fn signed(tx: Transaction, keypair: &AuthKeyPair) -> SignedRecord<Transaction> {
+        tx.record(keypair.clone(), Metadata::empty()).unwrap()
+    }
+
+    fn block(records: Vec<SignedRecord<Transaction>>) -> LocalInstance<Transaction> {
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        for record in records {
+            block.push(record);
+        }
+        block
+    }
+
+    #[test]
+    fn test_double_spends() {
+        let url = "target2/tests/utxochain/";
+        let _ = std::fs::remove_dir_all(url);
+        std::fs::create_dir_all(url).expect("could not create url");
+
+        let minter = crate::generate_ed25519_keypair();
+        let alice = crate::generate_ed25519_keypair();
+        let bob = crate::generate_ed25519_keypair();
+        let set = UtxoSet::new().with_minter(minter.clone().into_public_key());
+        let mut chain = SqliteChain::new(url).unwrap();
+        let mut runner = StateRunner::new(set);
+
+        let mint = Transaction::mint(vec![output(100, &alice)], 1);
+        let again = signed(mint.clone(), &minter);
+        assert_eq!(
+            runner.state().validate(&signed(mint.clone(), &alice)),
+            Err(UtxoError::Unauthorized)
+        );
+        let coin = mint.outpoint(0);
+        let mut next = block(vec![signed(mint, &minter)]);
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+
+        assert_eq!(
+            runner.state().validate(&again),
+            Err(UtxoError::DuplicateTransaction)
+        );
+
+        // the same outputs can be minted again under another nonce
+        let remint = Transaction::mint(vec![output(100, &alice)], 2);
+        assert_ne!(remint.txid(), coin.txid);
+        assert_eq!(runner.state().validate(&signed(remint, &minter)), Ok(()));
+
+        let pay = |to: &AuthKeyPair| {
+            let outputs = vec![output(60, to), output(40, &alice)];
+            signed(
+                Transaction::sign(vec![(coin.clone(), &alice)], outputs).unwrap(),
+                &alice,
+            )
+        };
+
+        // only the owner can spend an output, and not for more than it holds
+        let stolen = Transaction::sign(vec![(coin.clone(), &bob)], vec![output(100, &bob)]);
+        assert_eq!(
+            runner.state().validate(&signed(stolen.unwrap(), &bob)),
+            Err(UtxoError::BadSignature)
+        );
+        let inflated = Transaction::sign(vec![(coin.clone(), &alice)], vec![output(101, &bob)]);
+        assert_eq!(
+            runner.state().validate(&signed(inflated.unwrap(), &alice)),
+            Err(UtxoError::InsufficientInputs)
+        );
+        let twice = Transaction::sign(
+            vec![(coin.clone(), &alice), (coin.clone(), &alice)],
+            vec![output(200, &bob)],
+        );
+        assert_eq!(
+            runner.state().validate(&signed(twice.unwrap(), &alice)),
+            Err(UtxoError::DoubleSpend)
+        );
+
+        // within a block
+        let doubled = block(vec![pay(&bob), pay(&alice)]);
+        assert_eq!(
+            runner.state().check_block(&doubled),
+            Err(UtxoError::DoubleSpend)
+        );
+
+        // across blocks
+        let payment = pay(&bob);
+        let change = payment.record().outpoint(1);
+        let mut next = block(vec![payment]);
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+        assert_eq!(
+            runner
+                .state()
+                .balance(&bob.clone().into_public_key(), Micron::new(0)),
+            Ok(Quantity::new(60))
+        );
+        assert!(matches!(
+            runner.append(&mut chain, &block(vec![pay(&alice)])),
+            Err(StateError::Custom(_))
+        ));
+        assert_eq!(chain.len().unwrap(), 2);
+
+        // reverting the payment makes the coin spendable again
+        let unknown = OutPoint::new(crate::sha(&"nothing"), 0);
+        assert!(runner.state().get(&unknown).is_none());
+        assert!(runner.state().get(&change).is_some());
+        chain.rollback(1.into()).unwrap();
+        runner.sync(&chain).unwrap();
+        assert!(runner.state().get(&change).is_none());
+        assert!(runner.state().get(&coin).is_some());
+        let mut next = block(vec![pay(&alice)]);
+        runner.prepare(&mut next).unwrap();
+        runner.append(&mut chain, &next).unwrap();
+
+        let proof = runner.state().prove(&coin);
+        assert!(proof.verify(
+            &crate::sha(&coin.key()),
+            None,
+            &runner.state().tree().root()
+        ));
+    }
+}
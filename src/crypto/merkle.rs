@@ -2,67 +2,100 @@ use serde::{Deserialize, Serialize};
 
 use super::Hash;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MerkleNode {
-    hash: Hash,
-    left: Option<Box<MerkleNode>>,
-    right: Option<Box<MerkleNode>>,
-    center: Option<Box<MerkleNode>>,
+/// Hashes a leaf. Leaves and inner nodes are hashed with different prefixes so that an
+/// inner node can never be passed off as a leaf.
+fn leaf_hash(hash: &Hash) -> Hash {
+    super::sha_all([&[0u8][..], hash.as_bytes()])
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    super::sha_all([&[1u8][..], left.as_bytes(), right.as_bytes()])
 }
 
-impl MerkleNode {
-    pub fn build(
+#[allow(deprecated)]
+pub use legacy::MerkleNode;
+
+// the deprecated node is kept in a module of its own so that its derives do not warn
+#[allow(deprecated)]
+mod legacy {
+    use serde::{Deserialize, Serialize};
+
+    use crate::Hash;
+
+    /// A node of the ternary Merkle tree this crate used to build.
+    #[deprecated(note = "`MerkleTree` no longer uses `MerkleNode`, which will be removed")]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct MerkleNode {
         hash: Hash,
-        left: Option<MerkleNode>,
-        center: Option<MerkleNode>,
-        right: Option<MerkleNode>,
-    ) -> Self {
-        Self {
-            hash,
-            left: left.map(Box::new),
-            center: center.map(Box::new),
-            right: right.map(Box::new),
-        }
+        left: Option<Box<MerkleNode>>,
+        right: Option<Box<MerkleNode>>,
+        center: Option<Box<MerkleNode>>,
     }
 
-    pub fn new() -> Self {
-        Self {
-            hash: super::random_sha256(),
-            left: None,
-            center: None,
-            right: None,
+    impl MerkleNode {
+        pub fn build(
+            hash: Hash,
+            left: Option<MerkleNode>,
+            center: Option<MerkleNode>,
+            right: Option<MerkleNode>,
+        ) -> Self {
+            Self {
+                hash,
+                left: left.map(Box::new),
+                center: center.map(Box::new),
+                right: right.map(Box::new),
+            }
         }
-    }
 
-    pub fn dummy() -> Self {
-        Self::new()
-    }
+        pub fn new() -> Self {
+            Self {
+                hash: crate::random_sha256(),
+                left: None,
+                center: None,
+                right: None,
+            }
+        }
 
-    /// Returns the hash of the node.
-    pub fn hash(&self) -> &Hash {
-        &self.hash
-    }
+        pub fn dummy() -> Self {
+            Self::new()
+        }
 
-    /// Returns a reference to the left child of the node.
-    pub fn left(&self) -> &Option<Box<MerkleNode>> {
-        &self.left
-    }
+        /// Returns the hash of the node.
+        pub fn hash(&self) -> &Hash {
+            &self.hash
+        }
+
+        /// Returns a reference to the left child of the node.
+        pub fn left(&self) -> &Option<Box<MerkleNode>> {
+            &self.left
+        }
 
-    /// Returns a reference to the right child of the node.
-    pub fn right(&self) -> &Option<Box<MerkleNode>> {
-        &self.right
+        /// Returns a reference to the right child of the node.
+        pub fn right(&self) -> &Option<Box<MerkleNode>> {
+            &self.right
+        }
+
+        pub fn center(&self) -> &Option<Box<MerkleNode>> {
+            &self.center
+        }
     }
 
-    pub fn center(&self) -> &Option<Box<MerkleNode>> {
-        &self.center
+    impl Default for MerkleNode {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 }
 
-/// A Merkle tree.
+/// A binary Merkle tree over the hashes of the records of a block.
 ///
+/// A node without a sibling is carried up to the next level unchanged. The root of an
+/// empty tree is `Hash::default()`. Trees built from the same hashes in the same order
+/// always have the same root.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MerkleTree {
-    root: MerkleNode,
+    root: Hash,
+    levels: Vec<Vec<Hash>>,
     size: usize,
 }
 
@@ -72,78 +105,205 @@ impl std::hash::Hash for MerkleTree {
     }
 }
 
+impl Default for MerkleTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MerkleTree {
-    /// Creates a new Merkle tree from the given leaf node hashes.
+    /// Creates an empty Merkle tree.
     pub fn new() -> Self {
-        let left = MerkleNode::dummy();
-        let center = MerkleNode::dummy();
-        let right = MerkleNode::dummy();
-        let dummy_root = MerkleNode::build(
-            super::random_sha256(),
-            Some(left),
-            Some(center),
-            Some(right),
-        );
-
         Self {
-            root: dummy_root,
+            root: Hash::default(),
+            levels: vec![],
             size: 0,
         }
     }
 
+    /// Creates a Merkle tree with the given leaf hashes.
+    pub fn from_hashes<'a, I: IntoIterator<Item = &'a Hash>>(hashes: I) -> Self {
+        let mut tree = Self::new();
+        for hash in hashes {
+            tree.push(hash);
+        }
+        tree
+    }
+
     /// Returns the Merkle root of the tree.
     pub fn root(&self) -> &Hash {
-        &self.root.hash
+        &self.root
     }
 
+    /// Adds a leaf to the tree, updating the nodes on its path to the root.
     pub fn push(&mut self, hash: &Hash) {
+        let mut index = self.size;
+        let mut node = leaf_hash(hash);
         self.size += 1;
 
-        let left_hash = self.root.left().as_deref().unwrap().hash();
-
-        if let None = &self.root.center {
-            let new_hash = super::sha_all([hash, left_hash, self.root()]);
+        for level in 0.. {
+            if self.levels.len() == level {
+                self.levels.push(vec![]);
+            }
+            let nodes = &mut self.levels[level];
+            if index == nodes.len() {
+                nodes.push(node);
+            } else {
+                nodes[index] = node;
+            }
 
-            let mut new_node = MerkleNode::build(new_hash, None, None, None);
+            if nodes.len() == 1 {
+                self.root = nodes[0].clone();
+                break;
+            }
 
-            new_node.left = self.root.left().clone();
+            node = match index % 2 {
+                0 if index + 1 < nodes.len() => node_hash(&nodes[index], &nodes[index + 1]),
+                0 => nodes[index].clone(),
+                _ => node_hash(&nodes[index - 1], &nodes[index]),
+            };
+            index /= 2;
+        }
+    }
 
-            self.root.left = None;
-            new_node.center = Some(Box::new(self.root.clone()));
+    /// Removes the last leaf of the tree, updating the nodes on its path to the root.
+    ///
+    /// Returns `false` if the tree is empty.
+    pub fn pop(&mut self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        self.size -= 1;
+        if self.size == 0 {
+            *self = Self::new();
+            return true;
+        }
 
-            self.root = new_node;
-        } else if let None = self.root.right {
-            let center_hash = self.root.center().as_deref().unwrap().hash();
-            let new_hash = super::sha_all([hash, left_hash, center_hash, self.root()]);
+        let mut len = self.size;
+        let mut level = 0;
+        self.levels[0].truncate(len);
+        while len > 1 {
+            let parents = len.div_ceil(2);
+            let last = parents - 1;
+            let nodes = &self.levels[level];
+            let node = match nodes.get(2 * last + 1) {
+                Some(right) => node_hash(&nodes[2 * last], right),
+                None => nodes[2 * last].clone(),
+            };
+            self.levels[level + 1].truncate(parents);
+            self.levels[level + 1][last] = node;
+            len = parents;
+            level += 1;
+        }
+        self.levels.truncate(level + 1);
+        self.root = self.levels[level][0].clone();
+        true
+    }
 
-            let mut new_node = MerkleNode::build(new_hash, None, None, None);
+    /// Returns the proof that the leaf at `index` is part of this tree, or `None` if there
+    /// is no such leaf.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.size {
+            return None;
+        }
+        let mut steps = vec![];
+        let mut position = index;
+        for nodes in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if let Some(hash) = nodes.get(sibling) {
+                steps.push(ProofStep {
+                    hash: hash.clone(),
+                    left: sibling < position,
+                });
+            }
+            position /= 2;
+        }
+        Some(MerkleProof { index, steps })
+    }
 
-            new_node.left = self.root.left().clone();
-            new_node.center = self.root.center().clone();
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
 
-            self.root.left = None;
-            self.root.center = None;
-            new_node.center = Some(Box::new(self.root.clone()));
+/// A sibling on the path from a leaf to the root of a `MerkleTree`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: Hash,
+    /// Whether the sibling is on the left of the path.
+    pub left: bool,
+}
 
-            self.root = new_node;
-        } else {
-            let center_hash = self.root.center().as_deref().unwrap().hash();
-            let right_hash = self.root.right().as_deref().unwrap().hash();
-            let new_hash = super::sha_all([hash, left_hash, center_hash, right_hash, self.root()]);
+/// A proof that a hash is a leaf of a `MerkleTree` with a given root.
+///
+/// Its size grows with the logarithm of the number of leaves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    index: usize,
+    steps: Vec<ProofStep>,
+}
 
-            let mut new_node = MerkleNode::build(new_hash, None, None, None);
+impl MerkleProof {
+    /// Returns the position of the proven leaf in the tree.
+    pub fn index(&self) -> usize {
+        self.index
+    }
 
-            new_node.left = Some(Box::new(self.root.clone()));
+    pub fn steps(&self) -> &[ProofStep] {
+        &self.steps
+    }
 
-            self.root = new_node;
-        }
+    /// Returns the root of the tree this proof leads to from `leaf`.
+    pub fn root(&self, leaf: &Hash) -> Hash {
+        self.steps.iter().fold(leaf_hash(leaf), |node, step| {
+            if step.left {
+                node_hash(&step.hash, &node)
+            } else {
+                node_hash(&node, &step.hash)
+            }
+        })
     }
 
-    pub fn pop(&self) -> bool {
-        todo!()
+    /// Returns `true` if `leaf` is a leaf of the tree with the given `root`.
+    pub fn verify(&self, leaf: &Hash, root: &Hash) -> bool {
+        &self.root(leaf) == root
     }
+}
 
-    pub fn size(&self) -> usize {
-        self.size
+#[cfg(test)]
+mod tests {
+    use super::MerkleTree;
+    use crate::Hash;
+
+    #[test]
+    fn test_proofs() {
+        assert_eq!(MerkleTree::new().root(), &Hash::default());
+
+        let leaves = (0..11u8).map(|i| crate::sha(&[i])).collect::<Vec<_>>();
+        for size in 1..=leaves.len() {
+            let tree = MerkleTree::from_hashes(&leaves[..size]);
+            assert_eq!(tree.root(), MerkleTree::from_hashes(&leaves[..size]).root());
+            assert!(tree.proof(size).is_none());
+            for (index, leaf) in leaves[..size].iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(proof.verify(leaf, tree.root()));
+                assert!(!proof.verify(&leaves[(index + 1) % leaves.len()], tree.root()));
+            }
+        }
+
+        // popping a leaf gives the tree of the leaves before it
+        let mut tree = MerkleTree::from_hashes(&leaves);
+        for size in (0..leaves.len()).rev() {
+            assert!(tree.pop());
+            assert_eq!(tree.size(), size);
+            assert_eq!(tree.root(), MerkleTree::from_hashes(&leaves[..size]).root());
+        }
+        assert!(!tree.pop());
+        tree.push(&leaves[0]);
+        assert_eq!(tree.root(), MerkleTree::from_hashes(&leaves[..1]).root());
+
+        let tree = MerkleTree::from_hashes(&leaves[..4]);
+        let other = MerkleTree::from_hashes([&leaves[1], &leaves[0], &leaves[2], &leaves[3]]);
+        assert_ne!(tree.root(), other.root());
     }
 }
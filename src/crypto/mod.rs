@@ -5,6 +5,7 @@ use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
 
 pub mod merkle;
+pub mod sparse_merkle;
 
 /// An error that can occur while signing a piece of message
 #[derive(Debug, Clone, Copy)]
@@ -95,15 +96,19 @@ pub fn hash_bytes(bytes: &[u8]) -> Vec<u8> {
 }
 
 use crate::{
-    block::LocalInstance,
+    block::{BlockError, BlockHeader, ChainedInstance, LocalInstance},
     data::{Position, Timestamp},
     error::SerdeError,
-    record::Record,
+    record::{Record, SignedRecord},
 };
 use serde::{Deserialize, Serialize};
 
 /// Hashes a block of records along with other parameters to compute the block's hash using the SHA-256 algorithm.
 ///
+/// This is the hash of blocks whose header version is `BlockHeader::LEGACY_VERSION`. It does
+/// not commit to the nonce, target or metadata of the block; newer blocks are hashed with
+/// `BlockHeader::hash` instead.
+///
 /// # Arguments
 ///
 /// * `block` - The block of records to be hashed.
@@ -120,19 +125,67 @@ pub fn hash_block<R: Record + Serialize>(
     timestamp: &Timestamp,
     position: &Position,
 ) -> Hash {
-    let records = bincode::serialize(block.get_records()).unwrap().into();
+    hash_block_parts(
+        block.get_records(),
+        prevhash,
+        block.get_merkle_root(),
+        timestamp,
+        position,
+    )
+}
+
+/// Computes the same hash as `hash_block` from the records and header fields of a block.
+pub fn hash_block_parts<R: Serialize>(
+    records: &[SignedRecord<R>],
+    prevhash: &Hash,
+    merkle_root: &Hash,
+    timestamp: &Timestamp,
+    position: &Position,
+) -> Hash {
+    let records = bincode::serialize(records).unwrap().into();
     let timestamp = bincode::serialize(timestamp).unwrap().into();
     let position = bincode::serialize(position).unwrap().into();
-    let buffer = sha_all([
-        prevhash,
-        &records,
-        &block.get_merkle_root(),
-        &timestamp,
-        &position,
-    ]);
+    let buffer = sha_all([prevhash, &records, merkle_root, &timestamp, &position]);
     buffer.into()
 }
 
+/// Recomputes the hash a block in a chain should have, according to its header version.
+///
+/// A block whose stored hash differs from this value was altered after it was appended.
+pub fn expected_block_hash<R: Record + Serialize, B: ChainedInstance<R>>(
+    block: &B,
+) -> Result<Hash, BlockError> {
+    let header = block.header()?;
+    if header.version > BlockHeader::LEGACY_VERSION {
+        return Ok(header.hash());
+    }
+    Ok(hash_block_parts(
+        block.records()?.as_slice(),
+        &header.prev_hash,
+        &header.merkle_root,
+        &header.timestamp,
+        &header.position,
+    ))
+}
+
+/// Hashes the header a block gets when it is appended to a chain, leaving out its nonce.
+///
+/// This is the value that proofs-of-work and seals are computed over (see
+/// `BlockHeader::seal_hash`).
+///
+/// # Arguments
+///
+/// * `block` - The block of records to be hashed.
+/// * `prev_hash` - The hash of the block it will be appended after.
+/// * `position` - The position it will occupy in the chain.
+///
+/// # Returns
+///
+/// The computed hash as a `Hash` type.
+pub fn hash_header<R>(block: &LocalInstance<R>, prev_hash: &Hash, position: &Position) -> Hash {
+    block.header(prev_hash, *position).seal_hash()
+}
+
 /// Generates a random SHA-256 hash.
 ///
 /// # Returns
@@ -475,7 +528,7 @@ impl AuthKeyPair {
 
 /// A `Hash` is the result of hashing a piece of data.
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, std::hash::Hash, Serialize, Deserialize)]
 pub struct Hash {
     bytes: Box<[u8]>,
 }
@@ -0,0 +1,220 @@
+//! A sparse Merkle tree.
+//!
+//! The tree has a leaf for every possible 256-bit key; the leaves that hold no value are
+//! empty. A subtree that holds a single value is stored as that value's leaf, and an empty
+//! subtree hashes to `Hash::default()`, so the tree only costs as much as the values in it.
+//! A `SparseProof` shows either that a key holds a value or that it holds none.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::Hash;
+
+fn leaf_hash(key: &[u8], value_hash: &Hash) -> Hash {
+    super::sha_all([&[0u8][..], key, value_hash.as_bytes()])
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let empty = Hash::default();
+    if left == &empty && right == &empty {
+        return empty;
+    }
+    super::sha_all([&[1u8][..], left.as_bytes(), right.as_bytes()])
+}
+
+/// Returns bit `depth` of `key`, counting from the most significant bit of the first byte.
+fn bit(key: &[u8], depth: usize) -> bool {
+    key.get(depth / 8)
+        .map(|byte| byte & (0x80 >> (depth % 8)) != 0)
+        .unwrap_or(false)
+}
+
+type Leaf<'a> = (&'a Vec<u8>, &'a (Vec<u8>, Hash));
+
+/// A sparse Merkle tree mapping `Hash` keys to byte values.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseMerkleTree {
+    /// The value and value hash of every key, in key order.
+    leaves: BTreeMap<Vec<u8>, (Vec<u8>, Hash)>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn get(&self, key: &Hash) -> Option<&[u8]> {
+        self.leaves.get(key.as_bytes()).map(|(value, _)| &value[..])
+    }
+
+    /// Sets the value of `key`, returning its previous value.
+    pub fn insert(&mut self, key: &Hash, value: Vec<u8>) -> Option<Vec<u8>> {
+        let value_hash = super::sha(&value);
+        self.leaves
+            .insert(key.to_vec(), (value, value_hash))
+            .map(|(value, _)| value)
+    }
+
+    /// Removes the value of `key`, returning it.
+    pub fn delete(&mut self, key: &Hash) -> Option<Vec<u8>> {
+        self.leaves.remove(key.as_bytes()).map(|(value, _)| value)
+    }
+
+    /// Returns the root of the tree. The root of an empty tree is `Hash::default()`.
+    pub fn root(&self) -> Hash {
+        let leaves = self.leaves.iter().collect::<Vec<_>>();
+        Self::subtree(&leaves, 0)
+    }
+
+    fn subtree(leaves: &[Leaf], depth: usize) -> Hash {
+        match leaves {
+            [] => Hash::default(),
+            [(key, (_, value_hash))] => leaf_hash(key, value_hash),
+            _ => {
+                let (left, right) = Self::split(leaves, depth);
+                node_hash(
+                    &Self::subtree(left, depth + 1),
+                    &Self::subtree(right, depth + 1),
+                )
+            }
+        }
+    }
+
+    fn split<'a, 'b>(leaves: &'b [Leaf<'a>], depth: usize) -> (&'b [Leaf<'a>], &'b [Leaf<'a>]) {
+        leaves.split_at(leaves.partition_point(|(key, _)| !bit(key, depth)))
+    }
+
+    /// Returns the proof of the value of `key`, or that it has none.
+    pub fn prove(&self, key: &Hash) -> SparseProof {
+        let all = self.leaves.iter().collect::<Vec<_>>();
+        let mut leaves = &all[..];
+        let mut siblings = vec![];
+        let mut depth = 0;
+        while leaves.len() > 1 {
+            let (left, right) = Self::split(leaves, depth);
+            if bit(key, depth) {
+                siblings.push(Self::subtree(left, depth + 1));
+                leaves = right;
+            } else {
+                siblings.push(Self::subtree(right, depth + 1));
+                leaves = left;
+            }
+            depth += 1;
+        }
+        let leaf = leaves
+            .first()
+            .map(|(key, (_, value_hash))| (Hash::from(key.to_vec()), value_hash.clone()));
+        SparseProof { siblings, leaf }
+    }
+}
+
+/// A proof of the value of a key of a `SparseMerkleTree`, or that it has none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SparseProof {
+    /// The siblings of the subtrees on the path to the key, from the root down.
+    siblings: Vec<Hash>,
+    /// The key and value hash of the leaf the path ends at, if it is not empty.
+    leaf: Option<(Hash, Hash)>,
+}
+
+impl SparseProof {
+    pub fn siblings(&self) -> &[Hash] {
+        &self.siblings
+    }
+
+    /// Returns `true` if `key` holds `value` in the tree with the given `root`, or holds no
+    /// value if `value` is `None`.
+    pub fn verify(&self, key: &Hash, value: Option<&[u8]>, root: &Hash) -> bool {
+        if self.siblings.len() > 256 {
+            return false;
+        }
+        let node = match (&self.leaf, value) {
+            (Some((leaf_key, value_hash)), Some(value)) => {
+                if leaf_key != key || value_hash != &super::sha(&value) {
+                    return false;
+                }
+                leaf_hash(leaf_key, value_hash)
+            }
+            (Some((leaf_key, value_hash)), None) => {
+                // another key alone in the subtree that `key` would be in
+                let shares_path =
+                    (0..self.siblings.len()).all(|depth| bit(leaf_key, depth) == bit(key, depth));
+                if leaf_key == key || !shares_path {
+                    return false;
+                }
+                leaf_hash(leaf_key, value_hash)
+            }
+            (None, None) => Hash::default(),
+            (None, Some(_)) => return false,
+        };
+
+        let computed =
+            self.siblings
+                .iter()
+                .enumerate()
+                .rev()
+                .fold(node, |node, (depth, sibling)| {
+                    if bit(key, depth) {
+                        node_hash(sibling, &node)
+                    } else {
+                        node_hash(&node, sibling)
+                    }
+                });
+        &computed == root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SparseMerkleTree;
+    use crate::Hash;
+
+    #[test]
+    fn test_proofs() {
+        let mut tree = SparseMerkleTree::new();
+        assert_eq!(tree.root(), Hash::default());
+
+        let keys = (0..20u8).map(|i| crate::sha(&[i])).collect::<Vec<_>>();
+        for (i, key) in keys[..10].iter().enumerate() {
+            tree.insert(key, vec![i as u8; 3]);
+        }
+        let root = tree.root();
+
+        for (i, key) in keys.iter().enumerate() {
+            let proof = tree.prove(key);
+            if i < 10 {
+                assert!(proof.verify(key, Some(&[i as u8; 3]), &root));
+                assert!(!proof.verify(key, Some(&[0xff; 3]), &root));
+                assert!(!proof.verify(key, None, &root));
+            } else {
+                assert!(proof.verify(key, None, &root));
+                assert!(!proof.verify(key, Some(&[i as u8; 3]), &root));
+            }
+        }
+
+        // the root only depends on the contents of the tree
+        let mut other = SparseMerkleTree::new();
+        for (i, key) in keys[..10].iter().enumerate().rev() {
+            other.insert(key, vec![i as u8; 3]);
+        }
+        other.insert(&keys[15], vec![1]);
+        assert_ne!(other.root(), root);
+        assert_eq!(other.delete(&keys[15]), Some(vec![1]));
+        assert_eq!(other.root(), root);
+
+        tree.insert(&keys[0], vec![9]);
+        assert_ne!(tree.root(), root);
+        assert!(tree
+            .prove(&keys[0])
+            .verify(&keys[0], Some(&[9]), &tree.root()));
+    }
+}
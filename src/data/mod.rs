@@ -1,11 +1,15 @@
 //! This module contains different data types that of some importance to this crate.
 //!
 
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Datelike, NaiveDateTime, TimeZone, Timelike};
 use serde::{Deserialize, Serialize};
 
+mod schema;
 mod unit;
 
+pub use schema::*;
 pub use unit::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -16,17 +20,131 @@ pub enum Detail {
     Bytes(Box<[u8]>),
     Timestamp(Timestamp),
     Boolean(bool),
+    List(Vec<Detail>),
+    /// Details by key, ordered by key so that equal maps serialize the same.
+    Map(BTreeMap<String, Detail>),
+}
+
+impl Detail {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Detail::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Detail::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Detail::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Detail::Timestamp(timestamp) => Some(*timestamp),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Detail::Boolean(boolean) => Some(*boolean),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Detail]> {
+        match self {
+            Detail::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&BTreeMap<String, Detail>> {
+        match self {
+            Detail::Map(map) => Some(map),
+            _ => None,
+        }
+    }
 }
 
+/// Details attached to a record or a block.
+///
+/// Details can be pushed by position or inserted by key. Keyed details are kept ordered by
+/// key, so that metadata with the same details hashes the same whatever order they were
+/// inserted in.
+///
+/// Keyed details are serialized as a `Detail::Map` after the positional ones, which leaves
+/// metadata without keyed details serialized as it always was.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::data::{Detail, Metadata};
+///
+/// let mut metadata = Metadata::new();
+/// metadata.push(Detail::Integer(7));
+/// metadata.insert("author", Detail::Text("alice".to_owned()));
+/// assert_eq!(metadata.get_text("author"), Some("alice"));
+/// assert_eq!(metadata.get_integer("author"), None);
+/// assert_eq!(metadata.details(), &[Detail::Integer(7)]);
+/// ```
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "RawMetadata", into = "RawMetadata")]
 pub struct Metadata {
     details: Vec<Detail>,
+    fields: BTreeMap<String, Detail>,
+}
+
+/// The serialized form of `Metadata`.
+#[derive(Serialize, Deserialize)]
+struct RawMetadata {
+    details: Vec<Detail>,
+}
+
+impl From<Metadata> for RawMetadata {
+    fn from(value: Metadata) -> Self {
+        let Metadata {
+            mut details,
+            fields,
+        } = value;
+        // a trailing map is read back as the keyed details, so one is written whenever
+        // the last positional detail is a map too
+        if !fields.is_empty() || matches!(details.last(), Some(Detail::Map(_))) {
+            details.push(Detail::Map(fields));
+        }
+        Self { details }
+    }
+}
+
+impl From<RawMetadata> for Metadata {
+    fn from(value: RawMetadata) -> Self {
+        let mut details = value.details;
+        let fields = match details.pop() {
+            Some(Detail::Map(fields)) => fields,
+            Some(detail) => {
+                details.push(detail);
+                BTreeMap::new()
+            }
+            None => BTreeMap::new(),
+        };
+        Self { details, fields }
+    }
 }
 
 impl Metadata {
     pub fn new() -> Self {
         Self {
             details: Vec::with_capacity(0),
+            fields: BTreeMap::new(),
         }
     }
 
@@ -43,9 +161,60 @@ impl Metadata {
         Self::new()
     }
 
+    /// Returns the positional details.
     pub fn details(&self) -> &[Detail] {
         &self.details
     }
+
+    /// Sets the detail under `key`, returning the one it replaces.
+    pub fn insert(&mut self, key: impl Into<String>, value: Detail) -> Option<Detail> {
+        self.fields.insert(key.into(), value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Detail> {
+        self.fields.get(key)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Detail> {
+        self.fields.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.fields.contains_key(key)
+    }
+
+    /// Returns the keyed details, ordered by key.
+    pub fn fields(&self) -> &BTreeMap<String, Detail> {
+        &self.fields
+    }
+
+    pub fn get_text(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Detail::as_text)
+    }
+
+    pub fn get_integer(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(Detail::as_integer)
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.get(key).and_then(Detail::as_bytes)
+    }
+
+    pub fn get_timestamp(&self, key: &str) -> Option<Timestamp> {
+        self.get(key).and_then(Detail::as_timestamp)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(Detail::as_bool)
+    }
+
+    pub fn get_list(&self, key: &str) -> Option<&[Detail]> {
+        self.get(key).and_then(Detail::as_list)
+    }
+
+    pub fn get_map(&self, key: &str) -> Option<&BTreeMap<String, Detail>> {
+        self.get(key).and_then(Detail::as_map)
+    }
 }
 
 impl Default for Metadata {
@@ -193,6 +362,11 @@ impl Timestamp {
     pub fn from_secs(secs: u64) -> Self {
         Self { secs }
     }
+
+    /// Returns the number of seconds since the Unix epoch.
+    pub fn secs(&self) -> u64 {
+        self.secs
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -231,6 +405,71 @@ impl From<u64> for Nonce {
     }
 }
 
+/// The proof-of-work target of a block.
+///
+/// A block hash meets the target when, read as a 256-bit big-endian integer, it is
+/// less than or equal to the target. `Target::MAX` is met by every hash and is used
+/// for blocks that carry no proof-of-work.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Target {
+    bytes: [u8; 32],
+}
+
+impl Target {
+    /// The easiest possible target.
+    pub const MAX: Target = Target { bytes: [0xff; 32] };
+
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self { bytes }
+    }
+
+    /// Creates the target that requires at least `bits` leading zero bits in a hash.
+    pub fn from_difficulty(bits: u32) -> Self {
+        let bits = bits.min(256) as usize;
+        let (zeros, rest) = (bits / 8, bits % 8);
+        let mut bytes = [0xff; 32];
+        bytes[..zeros].fill(0);
+        if rest != 0 {
+            bytes[zeros] = 0xff >> rest;
+        }
+        Self { bytes }
+    }
+
+    /// Returns the number of leading zero bits of this target.
+    pub fn difficulty(&self) -> u32 {
+        leading_zero_bits(&self.bytes)
+    }
+
+    /// Returns `true` if `hash` is less than or equal to this target.
+    pub fn is_met_by(&self, hash: &[u8]) -> bool {
+        hash.len() == 32 && hash <= &self.bytes[..]
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self::MAX
+    }
+}
+
+/// Returns the number of leading zero bits in `bytes`.
+pub fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut count = 0;
+    for byte in bytes {
+        if *byte == 0 {
+            count += 8;
+        } else {
+            count += byte.leading_zeros();
+            break;
+        }
+    }
+    count
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Position {
     pub pos: u64,
@@ -251,3 +490,69 @@ impl From<u64> for Position {
         Position::new(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::{Detail, Metadata};
+
+    /// The form of `Metadata` before it had keyed details.
+    #[derive(Serialize, Deserialize)]
+    struct Positional {
+        details: Vec<Detail>,
+    }
+
+    fn round_trip(metadata: &Metadata) -> (Metadata, Metadata) {
+        let bytes = crate::serialize(metadata).unwrap();
+        let json = serde_json::to_string(metadata).unwrap();
+        (
+            bincode::deserialize(&bytes).unwrap(),
+            serde_json::from_str(&json).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_metadata() {
+        // positional metadata serializes as it always did
+        let details = vec![Detail::Text("producer".to_owned()), Detail::Integer(7)];
+        let mut metadata = Metadata::new();
+        details.iter().for_each(|d| metadata.push(d.clone()));
+        let old = Positional { details };
+        assert_eq!(
+            crate::serialize(&metadata).unwrap(),
+            crate::serialize(&old).unwrap()
+        );
+        let (bin, json) = round_trip(&metadata);
+        assert_eq!((&bin, &json), (&metadata, &metadata));
+        assert!(bin.fields().is_empty());
+
+        // keyed details hash the same whatever order they are inserted in
+        let mut origin = BTreeMap::new();
+        origin.insert("city".to_owned(), Detail::Text("Accra".to_owned()));
+        let mut other = metadata.clone();
+        metadata.insert("origin", Detail::Map(origin.clone()));
+        metadata.insert("tags", Detail::List(vec![Detail::Boolean(true)]));
+        other.insert("tags", Detail::List(vec![Detail::Boolean(true)]));
+        other.insert("origin", Detail::Map(origin.clone()));
+        assert_eq!(crate::hash(&metadata), crate::hash(&other));
+        let (bin, json) = round_trip(&metadata);
+        assert_eq!((&bin, &json), (&metadata, &metadata));
+        assert_eq!(bin.get_map("origin"), Some(&origin));
+        assert_eq!(bin.get_list("tags"), Some(&[Detail::Boolean(true)][..]));
+        assert_eq!(bin.get_text("tags"), None);
+        assert_eq!(bin.details().len(), 2);
+
+        // a map pushed last stays positional
+        let mut metadata = Metadata::new();
+        metadata.push(Detail::Map(origin));
+        let (bin, json) = round_trip(&metadata);
+        assert_eq!((&bin, &json), (&metadata, &metadata));
+        assert!(bin.fields().is_empty());
+
+        assert!(other.remove("tags").is_some());
+        assert!(!other.contains_key("tags"));
+    }
+}
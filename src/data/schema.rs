@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Detail, Metadata};
+
+/// An error that can occur when validating metadata against a `MetadataSchema`.
+///
+/// Each variant holds the path of the offending detail, such as `tags[1]` or
+/// `origin.city`. Positional details are named by their index, as in `#0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// A required detail is absent.
+    Missing(String),
+    /// A detail does not have the shape the schema gives it.
+    Mismatch(String),
+    /// A key is not in the schema, which does not allow unknown keys.
+    Unknown(String),
+    /// There are not as many positional details as the schema lists.
+    DetailCount { expected: usize, found: usize },
+}
+
+crate::impl_display_error!(SchemaError);
+
+/// The shape a `Detail` must have.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Shape {
+    /// Any detail.
+    Any,
+    Text,
+    Integer,
+    Bytes,
+    Timestamp,
+    Boolean,
+    /// A list whose every item has the given shape.
+    List(Box<Shape>),
+    /// A map of details following the given schema.
+    Map(MetadataSchema),
+}
+
+impl Shape {
+    pub fn list(item: Shape) -> Self {
+        Shape::List(Box::new(item))
+    }
+
+    fn validate(&self, detail: &Detail, path: &str) -> Result<(), SchemaError> {
+        match (self, detail) {
+            (Shape::Any, _)
+            | (Shape::Text, Detail::Text(_))
+            | (Shape::Integer, Detail::Integer(_))
+            | (Shape::Bytes, Detail::Bytes(_))
+            | (Shape::Timestamp, Detail::Timestamp(_))
+            | (Shape::Boolean, Detail::Boolean(_)) => Ok(()),
+            (Shape::List(item), Detail::List(list)) => {
+                for (i, detail) in list.iter().enumerate() {
+                    item.validate(detail, &format!("{path}[{i}]"))?;
+                }
+                Ok(())
+            }
+            (Shape::Map(schema), Detail::Map(map)) => {
+                schema.validate_fields(map, &format!("{path}."))
+            }
+            _ => Err(SchemaError::Mismatch(path.to_owned())),
+        }
+    }
+}
+
+/// The details a `Metadata` must have.
+///
+/// A schema lists the keys that must or may be present with the shape of their details,
+/// and optionally the shapes of the positional details. Keys it does not list are
+/// rejected unless the schema is built `with_unknown_keys`.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::data::{Detail, Metadata, MetadataSchema, Shape};
+///
+/// let schema = MetadataSchema::new()
+///     .with_required("author", Shape::Text)
+///     .with_optional("tags", Shape::list(Shape::Text));
+///
+/// let mut metadata = Metadata::new();
+/// metadata.insert("author", Detail::Text("alice".to_owned()));
+/// assert!(schema.validate(&metadata).is_ok());
+///
+/// metadata.insert("tags", Detail::List(vec![Detail::Integer(3)]));
+/// assert!(schema.validate(&metadata).is_err());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataSchema {
+    required: BTreeMap<String, Shape>,
+    optional: BTreeMap<String, Shape>,
+    details: Option<Vec<Shape>>,
+    unknown_keys: bool,
+}
+
+impl MetadataSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires a detail with `shape` under `key`.
+    pub fn with_required(mut self, key: impl Into<String>, shape: Shape) -> Self {
+        let key = key.into();
+        self.optional.remove(&key);
+        self.required.insert(key, shape);
+        self
+    }
+
+    /// Allows a detail with `shape` under `key`.
+    pub fn with_optional(mut self, key: impl Into<String>, shape: Shape) -> Self {
+        let key = key.into();
+        self.required.remove(&key);
+        self.optional.insert(key, shape);
+        self
+    }
+
+    /// Requires exactly one positional detail per shape in `shapes`, in order.
+    pub fn with_details(mut self, shapes: Vec<Shape>) -> Self {
+        self.details = Some(shapes);
+        self
+    }
+
+    /// Allows keys the schema does not list, whatever their details.
+    pub fn with_unknown_keys(mut self) -> Self {
+        self.unknown_keys = true;
+        self
+    }
+
+    /// Checks that `metadata` has the details this schema requires.
+    pub fn validate(&self, metadata: &Metadata) -> Result<(), SchemaError> {
+        if let Some(shapes) = &self.details {
+            let details = metadata.details();
+            if shapes.len() != details.len() {
+                return Err(SchemaError::DetailCount {
+                    expected: shapes.len(),
+                    found: details.len(),
+                });
+            }
+            for (i, (shape, detail)) in shapes.iter().zip(details).enumerate() {
+                shape.validate(detail, &format!("#{i}"))?;
+            }
+        }
+        self.validate_fields(metadata.fields(), "")
+    }
+
+    fn validate_fields(
+        &self,
+        fields: &BTreeMap<String, Detail>,
+        prefix: &str,
+    ) -> Result<(), SchemaError> {
+        for (key, shape) in &self.required {
+            match fields.get(key) {
+                Some(detail) => shape.validate(detail, &format!("{prefix}{key}"))?,
+                None => return Err(SchemaError::Missing(format!("{prefix}{key}"))),
+            }
+        }
+        for (key, detail) in fields {
+            if self.required.contains_key(key) {
+                continue;
+            }
+            match self.optional.get(key) {
+                Some(shape) => shape.validate(detail, &format!("{prefix}{key}"))?,
+                None if self.unknown_keys => {}
+                None => return Err(SchemaError::Unknown(format!("{prefix}{key}"))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records whose metadata must follow a schema.
+///
+/// `SignedRecord::check_metadata` validates the metadata of a signed record against the
+/// schema of its type.
+pub trait MetadataRecord {
+    fn metadata_schema() -> MetadataSchema;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{self as blockify};
+
+    use blockify::{
+        data::{Detail, Metadata, MetadataRecord, MetadataSchema, SchemaError, Shape, Timestamp},
+        record::Record,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Shipment {
+        weight: u64,
+    }
+
+    impl MetadataRecord for Shipment {
+        fn metadata_schema() -> MetadataSchema {
+            let origin = MetadataSchema::new()
+                .with_required("city", Shape::Text)
+                .with_optional("at", Shape::Timestamp);
+            MetadataSchema::new()
+                .with_details(vec![Shape::Integer])
+                .with_required("origin", Shape::Map(origin))
+                .with_optional("tags", Shape::list(Shape::Text))
+        }
+    }
+
+    fn text(text: &str) -> Detail {
+        Detail::Text(text.to_owned())
+    }
+
+    #[test]
+    fn test_schema() {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut origin = BTreeMap::new();
+        origin.insert("city".to_owned(), text("Accra"));
+        origin.insert("at".to_owned(), Detail::Timestamp(Timestamp::from_secs(5)));
+        let mut metadata = Metadata::new();
+        metadata.push(Detail::Integer(1));
+        metadata.insert("origin", Detail::Map(origin.clone()));
+        metadata.insert("tags", Detail::List(vec![text("fragile")]));
+
+        let shipment = Shipment { weight: 3 };
+        let record = shipment.record(keypair.clone(), metadata.clone()).unwrap();
+        assert_eq!(record.check_metadata(), Ok(()));
+
+        let schema = Shipment::metadata_schema();
+        let check = |edit: &dyn Fn(&mut Metadata)| {
+            let mut metadata = metadata.clone();
+            edit(&mut metadata);
+            schema.validate(&metadata)
+        };
+        assert_eq!(
+            check(&|m| {
+                m.pop();
+            }),
+            Err(SchemaError::DetailCount {
+                expected: 1,
+                found: 0
+            })
+        );
+        assert_eq!(
+            check(&|m| {
+                m.remove("origin");
+            }),
+            Err(SchemaError::Missing("origin".to_owned()))
+        );
+        assert_eq!(
+            check(&|m| {
+                m.insert("tags", Detail::List(vec![text("a"), Detail::Integer(2)]));
+            }),
+            Err(SchemaError::Mismatch("tags[1]".to_owned()))
+        );
+        assert_eq!(
+            check(&|m| {
+                let mut origin = origin.clone();
+                origin.insert("city".to_owned(), Detail::Boolean(false));
+                m.insert("origin", Detail::Map(origin));
+            }),
+            Err(SchemaError::Mismatch("origin.city".to_owned()))
+        );
+        assert_eq!(
+            check(&|m| {
+                m.insert("colour", text("red"));
+            }),
+            Err(SchemaError::Unknown("colour".to_owned()))
+        );
+        let open = schema.clone().with_unknown_keys();
+        metadata.insert("colour", text("red"));
+        assert_eq!(open.validate(&metadata), Ok(()));
+    }
+}
@@ -1,17 +1,57 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+/// An error that can occur when working with quantities of units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitError {
+    /// The result does not fit in a `Quantity`.
+    Overflow,
+    /// The result would be less than zero.
+    Underflow,
+    /// Quantities of different microns were combined.
+    MicronMismatch,
+    /// No denomination with the given name is registered for the micron.
+    UnknownDenomination,
+    /// A denomination must be worth at least one base unit.
+    ZeroRate,
+}
+
+crate::impl_display_error!(UnitError);
+
+/// Keeps track of the units of every `Micron` in circulation.
 pub trait UnitManager {
-    fn all_units(&self);
-    fn all_units_raw(&self);
+    /// Returns the total supply of every micron, in base units.
+    fn all_units(&self) -> Vec<MicQuan>;
+
+    /// Returns the total supply of every micron as pairs of micron id and quantity.
+    fn all_units_raw(&self) -> Vec<(i32, u128)> {
+        self.all_units()
+            .into_iter()
+            .map(|units| (units.micron().id(), units.quantity().value()))
+            .collect()
+    }
+
+    /// Returns the total supply of `micron`, in base units.
+    fn supply_of(&self, micron: Micron) -> Quantity {
+        self.all_units()
+            .into_iter()
+            .find(|units| units.micron() == micron)
+            .map(|units| units.quantity())
+            .unwrap_or_default()
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Hash, Default)]
+/// A number of base units. All arithmetic on quantities is checked.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Hash, Default,
+)]
 pub struct Quantity {
-    val: i32,
+    val: u128,
 }
 
-impl From<i32> for Quantity {
-    fn from(val: i32) -> Self {
+impl From<u128> for Quantity {
+    fn from(val: u128) -> Self {
         Self { val }
     }
 }
@@ -20,22 +60,50 @@ impl Quantity {
     pub fn none() -> Self {
         Self::new(0)
     }
-    pub fn new(val: i32) -> Self {
+    pub fn new(val: u128) -> Self {
         Self { val }
     }
-    /// increases the internal count by 1 and returns the new count
-    pub fn increment(&mut self) -> i32 {
-        self.val += 1;
+    pub fn value(&self) -> u128 {
         self.val
     }
+    pub fn is_none(&self) -> bool {
+        self.val == 0
+    }
+    /// increases the internal count by 1 and returns the new count
+    pub fn increment(&mut self) -> Result<u128, UnitError> {
+        self.increment_by(1)
+    }
     /// increases the internal count by `val` and returns the new count
-    pub fn increment_by(&mut self, val: i32) -> i32 {
-        self.val += val;
+    ///
+    /// The count is left unchanged if it would overflow.
+    pub fn increment_by(&mut self, val: u128) -> Result<u128, UnitError> {
+        *self = self.checked_add(val.into())?;
+        Ok(self.val)
+    }
+    pub fn checked_add(self, other: Quantity) -> Result<Quantity, UnitError> {
         self.val
+            .checked_add(other.val)
+            .map(Quantity::new)
+            .ok_or(UnitError::Overflow)
+    }
+    pub fn checked_sub(self, other: Quantity) -> Result<Quantity, UnitError> {
+        self.val
+            .checked_sub(other.val)
+            .map(Quantity::new)
+            .ok_or(UnitError::Underflow)
+    }
+    pub fn checked_mul(self, factor: u128) -> Result<Quantity, UnitError> {
+        self.val
+            .checked_mul(factor)
+            .map(Quantity::new)
+            .ok_or(UnitError::Overflow)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Hash, Default)]
+/// Identifies a fungible token.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Hash, Default,
+)]
 pub struct Micron {
     id: i32,
 }
@@ -50,6 +118,9 @@ impl Micron {
     pub fn new(id: i32) -> Self {
         Self { id }
     }
+    pub fn id(&self) -> i32 {
+        self.id
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -57,6 +128,7 @@ pub struct Units<const N: usize> {
     value: [MicQuan; N],
 }
 
+/// A quantity of a micron, in its base unit.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct MicQuan {
     micron: Micron,
@@ -65,7 +137,7 @@ pub struct MicQuan {
 
 impl MicQuan {
     #[cfg(debug_assertions)]
-    pub fn debug_with(m: i32, q: i32) -> Self {
+    pub fn debug_with(m: i32, q: u128) -> Self {
         Self::new(m.into(), q.into())
     }
 
@@ -73,8 +145,125 @@ impl MicQuan {
         Self { micron, quantity }
     }
 
-    pub fn increment(&mut self) -> Quantity {
-        self.quantity.increment().into()
+    pub fn micron(&self) -> Micron {
+        self.micron
+    }
+
+    pub fn quantity(&self) -> Quantity {
+        self.quantity
+    }
+
+    pub fn increment(&mut self) -> Result<Quantity, UnitError> {
+        self.quantity.increment().map(Quantity::new)
+    }
+
+    /// Adds `other`, which must be of the same micron.
+    pub fn checked_add(self, other: MicQuan) -> Result<MicQuan, UnitError> {
+        if self.micron != other.micron {
+            return Err(UnitError::MicronMismatch);
+        }
+        Ok(Self::new(
+            self.micron,
+            self.quantity.checked_add(other.quantity)?,
+        ))
+    }
+
+    /// Subtracts `other`, which must be of the same micron.
+    pub fn checked_sub(self, other: MicQuan) -> Result<MicQuan, UnitError> {
+        if self.micron != other.micron {
+            return Err(UnitError::MicronMismatch);
+        }
+        Ok(Self::new(
+            self.micron,
+            self.quantity.checked_sub(other.quantity)?,
+        ))
+    }
+}
+
+/// The named denominations of every micron, each worth a fixed number of base units.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::data::{Denominations, Micron, Quantity};
+///
+/// let coin = Micron::new(0);
+/// let mut denominations = Denominations::new();
+/// denominations.add(coin, "cent", 1).unwrap();
+/// denominations.add(coin, "dollar", 100).unwrap();
+///
+/// let units = denominations.to_base(coin, "dollar", 3).unwrap();
+/// assert_eq!(units.quantity(), Quantity::new(300));
+///
+/// let (dollars, cents) = denominations.convert(coin, 250, "cent", "dollar").unwrap();
+/// assert_eq!((dollars.value(), cents.value()), (2, 50));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Denominations {
+    rates: BTreeMap<i32, BTreeMap<String, Quantity>>,
+}
+
+impl Denominations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `name` as a denomination of `micron` worth `rate` base units, replacing
+    /// any denomination of that name.
+    pub fn add(&mut self, micron: Micron, name: &str, rate: u128) -> Result<(), UnitError> {
+        if rate == 0 {
+            return Err(UnitError::ZeroRate);
+        }
+        self.rates
+            .entry(micron.id())
+            .or_default()
+            .insert(name.to_owned(), rate.into());
+        Ok(())
+    }
+
+    /// Returns the number of base units of `micron` one `name` is worth.
+    pub fn rate(&self, micron: Micron, name: &str) -> Result<Quantity, UnitError> {
+        self.rates
+            .get(&micron.id())
+            .and_then(|rates| rates.get(name))
+            .copied()
+            .ok_or(UnitError::UnknownDenomination)
+    }
+
+    /// Returns the names and rates of the denominations of `micron`, smallest first.
+    pub fn of(&self, micron: Micron) -> Vec<(&str, Quantity)> {
+        let mut denominations: Vec<_> = self
+            .rates
+            .get(&micron.id())
+            .map(|rates| rates.iter().map(|(n, r)| (n.as_str(), *r)).collect())
+            .unwrap_or_default();
+        denominations.sort_by_key(|(_, rate)| *rate);
+        denominations
+    }
+
+    /// Converts `amount` of the denomination `name` of `micron` to base units.
+    pub fn to_base(&self, micron: Micron, name: &str, amount: u128) -> Result<MicQuan, UnitError> {
+        let quantity = Quantity::new(amount).checked_mul(self.rate(micron, name)?.value())?;
+        Ok(MicQuan::new(micron, quantity))
+    }
+
+    /// Splits `units` into whole units of the denomination `name` and the base units left.
+    pub fn from_base(&self, units: MicQuan, name: &str) -> Result<(Quantity, Quantity), UnitError> {
+        let rate = self.rate(units.micron(), name)?.value();
+        let value = units.quantity().value();
+        Ok(((value / rate).into(), (value % rate).into()))
+    }
+
+    /// Converts `amount` of the denomination `from` of `micron` to whole units of the
+    /// denomination `to` and the base units left.
+    pub fn convert(
+        &self,
+        micron: Micron,
+        amount: u128,
+        from: &str,
+        to: &str,
+    ) -> Result<(Quantity, Quantity), UnitError> {
+        self.from_base(self.to_base(micron, from, amount)?, to)
     }
 }
 
@@ -97,9 +286,7 @@ impl<'d, const N: usize> Deserialize<'d> for Units<N> {
             )));
         }
 
-        for i in 0..N {
-            real[i] = vec[i];
-        }
+        real.copy_from_slice(&vec);
 
         Ok(real.into())
     }
@@ -108,14 +295,14 @@ impl<'d, const N: usize> Deserialize<'d> for Units<N> {
 #[cfg(debug_assertions)]
 mod test_units {
     #[allow(unused)]
-    use super::{MicQuan, Units};
+    use super::{Denominations, MicQuan, Micron, Quantity, UnitError, Units};
 
     #[test]
     fn test_serde() {
         let units = Units::new([
             MicQuan::debug_with(0, 0),
             MicQuan::debug_with(1, 1),
-            MicQuan::debug_with(2, 2),
+            MicQuan::debug_with(2, u128::MAX),
         ]);
         let serde_str = serde_json::to_string(&units).expect("couldn't stringify units");
 
@@ -124,6 +311,44 @@ mod test_units {
 
         assert_eq!(units, gen_units);
     }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut max = Quantity::new(u128::MAX);
+        assert_eq!(max.increment(), Err(UnitError::Overflow));
+        assert_eq!(max, Quantity::new(u128::MAX));
+        assert_eq!(
+            Quantity::none().checked_sub(1.into()),
+            Err(UnitError::Underflow)
+        );
+        assert_eq!(
+            MicQuan::debug_with(0, 1).checked_add(MicQuan::debug_with(1, 1)),
+            Err(UnitError::MicronMismatch)
+        );
+
+        let units = Units::new([
+            MicQuan::debug_with(0, 5),
+            MicQuan::debug_with(1, 7),
+            MicQuan::debug_with(0, 6),
+        ]);
+        assert_eq!(units.get_value(Micron::new(0)), Ok(Quantity::new(11)));
+        assert_eq!(units.get_value(Micron::new(2)), Ok(Quantity::none()));
+
+        let mut denominations = Denominations::new();
+        assert_eq!(
+            denominations.add(Micron::new(0), "nothing", 0),
+            Err(UnitError::ZeroRate)
+        );
+        denominations.add(Micron::new(0), "big", u128::MAX).unwrap();
+        assert_eq!(
+            denominations.to_base(Micron::new(0), "big", 2),
+            Err(UnitError::Overflow)
+        );
+        assert_eq!(
+            denominations.to_base(Micron::new(1), "big", 1),
+            Err(UnitError::UnknownDenomination)
+        );
+    }
 }
 
 impl<const N: usize> From<[MicQuan; N]> for Units<N> {
@@ -136,7 +361,13 @@ impl<const N: usize> Units<N> {
     pub fn new(value: [MicQuan; N]) -> Self {
         Self { value }
     }
-    pub fn get_value(&self) -> f64 {
-        todo!()
+    /// Returns the total quantity of `micron` in these units.
+    pub fn get_value(&self, micron: Micron) -> Result<Quantity, UnitError> {
+        self.value
+            .iter()
+            .filter(|units| units.micron() == micron)
+            .try_fold(Quantity::none(), |total, units| {
+                total.checked_add(units.quantity())
+            })
     }
 }
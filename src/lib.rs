@@ -128,6 +128,15 @@ pub use trans::*;
 #[cfg(test)]
 mod inner {
     use crate as blockify;
+
+    /// Empties the test directory `target2/tests/<name>/`, creating it if needed, and
+    /// returns its path.
+    pub(crate) fn test_dir(name: &str) -> String {
+        let url = format!("target2/tests/{name}/");
+        let _ = std::fs::remove_dir_all(&url);
+        std::fs::create_dir_all(&url).expect("could not create url");
+        url
+    }
 }
 
 pub mod blockchain;
@@ -0,0 +1,368 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+};
+
+use serde::Serialize;
+
+use crate::{
+    block::LocalInstance,
+    fees::FeeRecord,
+    record::{Record, SignedRecord},
+    Hash,
+};
+
+use super::{MemPool, MemPoolError};
+
+/// Decides the order in which a `PriorityMemPool` hands out its records.
+///
+/// Records with a higher priority are handed out first. Records with the same priority are
+/// handed out in the order they arrived.
+pub trait Prioritizer<R> {
+    fn priority(&self, record: &SignedRecord<R>) -> u64;
+}
+
+impl<R, F: Fn(&SignedRecord<R>) -> u64> Prioritizer<R> for F {
+    fn priority(&self, record: &SignedRecord<R>) -> u64 {
+        self(record)
+    }
+}
+
+/// Gives every record the same priority, so that records are handed out in arrival order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArrivalOrder;
+
+impl<R> Prioritizer<R> for ArrivalOrder {
+    fn priority(&self, _: &SignedRecord<R>) -> u64 {
+        0
+    }
+}
+
+/// Prioritizes records by the tip of the `Fee` they offer, so that the records paying the
+/// producer of the block the most are handed out first. Records offering no tip get the
+/// lowest priority.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeeTip;
+
+impl<R: FeeRecord> Prioritizer<R> for FeeTip {
+    fn priority(&self, record: &SignedRecord<R>) -> u64 {
+        u64::try_from(record.record().fee().tip).unwrap_or(u64::MAX)
+    }
+}
+
+/// The position of a record in the pool: highest priority first, then earliest arrival.
+type Slot = (Reverse<u64>, u64);
+
+struct Entry<R> {
+    record: SignedRecord<R>,
+    size: usize,
+}
+
+/// An in-memory `MemPool` that orders its records by a `Prioritizer`.
+///
+/// Records are only accepted if their signature is valid and are deduplicated by hash. When
+/// the pool is full, a new record evicts the records with the lowest priority, but only if
+/// its own priority is higher than theirs.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::{block::LocalInstance, data::Metadata, record::Record};
+/// use blockify::node::{ArrivalOrder, MemPool, PriorityMemPool};
+///
+/// let keypair = blockify::generate_ed25519_keypair();
+/// let mut pool = PriorityMemPool::new(ArrivalOrder).with_max_records(100);
+///
+/// let record = "hello".to_owned().record(keypair, Metadata::empty()).unwrap();
+/// pool.append(record.clone()).unwrap();
+/// assert!(pool.append(record).is_err());
+///
+/// let mut block = LocalInstance::new(Metadata::empty(), 0);
+/// assert_eq!(pool.drain_into(&mut block, 10), 1);
+/// assert!(pool.is_empty());
+/// ```
+pub struct PriorityMemPool<R, P = ArrivalOrder> {
+    prioritizer: P,
+    entries: BTreeMap<Slot, Entry<R>>,
+    index: HashMap<Box<[u8]>, Slot>,
+    arrivals: u64,
+    bytes: usize,
+    max_records: usize,
+    max_bytes: usize,
+}
+
+impl<R, P> PriorityMemPool<R, P> {
+    /// Creates an unbounded pool that orders records with `prioritizer`.
+    pub fn new(prioritizer: P) -> Self {
+        Self {
+            prioritizer,
+            entries: BTreeMap::new(),
+            index: HashMap::new(),
+            arrivals: 0,
+            bytes: 0,
+            max_records: usize::MAX,
+            max_bytes: usize::MAX,
+        }
+    }
+
+    /// Limits the number of records the pool holds.
+    pub fn with_max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records;
+        self
+    }
+
+    /// Limits the total serialized size of the records the pool holds.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the total serialized size of the records in the pool.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.index.contains_key(hash.as_bytes())
+    }
+
+    /// Removes the record with the given hash from the pool.
+    pub fn remove(&mut self, hash: &Hash) -> Option<SignedRecord<R>> {
+        let slot = self.index.remove(hash.as_bytes())?;
+        let entry = self.entries.remove(&slot)?;
+        self.bytes -= entry.size;
+        Some(entry.record)
+    }
+
+    /// Moves up to `max_records` of the records with the highest priority into `block`.
+    ///
+    /// Returns the number of records moved.
+    pub fn drain_into(&mut self, block: &mut LocalInstance<R>, max_records: usize) -> usize {
+        let mut moved = 0;
+        while moved < max_records {
+            match self.pop() {
+                Some(record) => block.push(record),
+                None => break,
+            }
+            moved += 1;
+        }
+        moved
+    }
+
+    fn pop(&mut self) -> Option<SignedRecord<R>> {
+        let (_, entry) = self.entries.pop_first()?;
+        self.index.remove(entry.record.hash().as_bytes());
+        self.bytes -= entry.size;
+        Some(entry.record)
+    }
+
+    fn is_full(&self, size: usize) -> bool {
+        self.entries.len() >= self.max_records || self.bytes + size > self.max_bytes
+    }
+}
+
+impl<R: Record + Serialize, P: Prioritizer<R>> PriorityMemPool<R, P> {
+    fn insert(&mut self, record: SignedRecord<R>) -> Result<(), MemPoolError> {
+        if self.contains(record.hash()) {
+            return Err(MemPoolError::Duplicate);
+        }
+        if record.verify().is_err() || &record.record().hash() != record.hash() {
+            return Err(MemPoolError::VerificationFailed);
+        }
+        let size = crate::serialize(&record)
+            .map_err(MemPoolError::SerdeError)?
+            .len();
+        if size > self.max_bytes || self.max_records == 0 {
+            return Err(MemPoolError::TooLarge);
+        }
+
+        let priority = self.prioritizer.priority(&record);
+
+        // find the records that have to go, lowest priority and latest arrival first
+        let mut evicted = vec![];
+        let (mut len, mut bytes) = (self.entries.len(), self.bytes);
+        for (slot, entry) in self.entries.iter().rev() {
+            if len < self.max_records && bytes + size <= self.max_bytes {
+                break;
+            }
+            if slot.0 .0 >= priority {
+                return Err(MemPoolError::PoolFull);
+            }
+            evicted.push(*slot);
+            len -= 1;
+            bytes -= entry.size;
+        }
+        for slot in evicted {
+            if let Some(entry) = self.entries.remove(&slot) {
+                self.index.remove(entry.record.hash().as_bytes());
+                self.bytes -= entry.size;
+            }
+        }
+        debug_assert!(!self.is_full(size));
+
+        let slot = (Reverse(priority), self.arrivals);
+        self.arrivals += 1;
+        self.index.insert(record.hash().as_bytes().into(), slot);
+        self.entries.insert(slot, Entry { record, size });
+        self.bytes += size;
+        Ok(())
+    }
+}
+
+impl<R, P> MemPool<R> for PriorityMemPool<R, P>
+where
+    R: Record + Serialize + Clone,
+    P: Prioritizer<R>,
+{
+    fn records(&self) -> Result<Vec<SignedRecord<R>>, MemPoolError> {
+        Ok(self.entries.values().map(|e| e.record.clone()).collect())
+    }
+
+    fn poll(&mut self) -> Result<Option<SignedRecord<R>>, MemPoolError> {
+        Ok(self.pop())
+    }
+
+    fn append(&mut self, record: SignedRecord<R>) -> Result<(), MemPoolError> {
+        self.insert(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::UnchainedInstance,
+        data::Metadata,
+        fees::{Fee, FeeRecord},
+        node::{MemPool, MemPoolError},
+        record::{Record, SignedRecord},
+        AuthKeyPair,
+    };
+    use serde::{Deserialize, Serialize};
+
+    use super::{ArrivalOrder, FeeTip, PriorityMemPool};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Transfer {
+        memo: String,
+        tip: u128,
+    }
+
+    impl FeeRecord for Transfer {
+        fn fee(&self) -> Fee {
+            Fee::new(self.tip, self.tip)
+        }
+    }
+
+    fn transfer(memo: &str, tip: u128, keypair: &AuthKeyPair) -> SignedRecord<Transfer> {
+        Transfer {
+            memo: memo.to_owned(),
+            tip,
+        }
+        .record(keypair.clone(), Metadata::empty())
+        .unwrap()
+    }
+
+    fn memos(records: &[SignedRecord<Transfer>]) -> Vec<&str> {
+        records.iter().map(|r| r.record().memo.as_str()).collect()
+    }
+
+    #[test]
+    fn test_priority_and_dedup() {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut pool = PriorityMemPool::new(FeeTip);
+        pool.append(transfer("a", 5, &keypair)).unwrap();
+        pool.append(transfer("b", 10, &keypair)).unwrap();
+        pool.append(transfer("c", 5, &keypair)).unwrap();
+        assert!(matches!(
+            pool.append(transfer("b", 10, &keypair)),
+            Err(MemPoolError::Duplicate)
+        ));
+        assert_eq!(memos(&pool.records().unwrap()), ["b", "a", "c"]);
+
+        let mut fifo = PriorityMemPool::new(ArrivalOrder);
+        for memo in ["x", "y", "z"] {
+            fifo.append(transfer(memo, 0, &keypair)).unwrap();
+        }
+        assert_eq!(fifo.poll().unwrap().unwrap().record().memo, "x");
+
+        let mut custom =
+            PriorityMemPool::new(|r: &SignedRecord<Transfer>| r.record().memo.len() as u64);
+        custom.append(transfer("short", 0, &keypair)).unwrap();
+        custom.append(transfer("much longer", 0, &keypair)).unwrap();
+        assert_eq!(custom.poll().unwrap().unwrap().record().memo, "much longer");
+    }
+
+    #[test]
+    fn test_rejects_bad_signatures() {
+        let keypair = crate::generate_ed25519_keypair();
+        let other = crate::generate_ed25519_keypair();
+        let good = transfer("a", 1, &keypair);
+        let forged = SignedRecord::new(
+            good.record().clone(),
+            good.signature().clone(),
+            other.into_public_key(),
+            good.hash().clone(),
+            good.metadata().clone(),
+        );
+        let mut pool = PriorityMemPool::new(FeeTip);
+        assert!(matches!(
+            pool.append(forged),
+            Err(MemPoolError::VerificationFailed)
+        ));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut pool = PriorityMemPool::new(FeeTip).with_max_records(2);
+        pool.append(transfer("a", 5, &keypair)).unwrap();
+        pool.append(transfer("b", 3, &keypair)).unwrap();
+
+        // not better than anything in the pool
+        assert!(matches!(
+            pool.append(transfer("c", 3, &keypair)),
+            Err(MemPoolError::PoolFull)
+        ));
+
+        pool.append(transfer("d", 7, &keypair)).unwrap();
+        assert_eq!(memos(&pool.records().unwrap()), ["d", "a"]);
+
+        let size = pool.bytes() / 2;
+        let mut small = PriorityMemPool::new(FeeTip).with_max_bytes(size);
+        small.append(transfer("a", 1, &keypair)).unwrap();
+        assert!(matches!(
+            small.append(transfer("long memo", 9, &keypair)),
+            Err(MemPoolError::TooLarge)
+        ));
+        small.append(transfer("e", 2, &keypair)).unwrap();
+        assert_eq!(memos(&small.records().unwrap()), ["e"]);
+        assert!(small.bytes() <= size);
+    }
+
+    #[test]
+    fn test_drain_into() {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut pool = PriorityMemPool::new(FeeTip);
+        for (memo, fee) in [("a", 1), ("b", 3), ("c", 2)] {
+            pool.append(transfer(memo, fee, &keypair)).unwrap();
+        }
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        assert_eq!(pool.drain_into(&mut block, 2), 2);
+        assert_eq!(memos(&block.records().unwrap()), ["b", "c"]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.drain_into(&mut block, 5), 1);
+        assert!(pool.is_empty());
+        assert_eq!(pool.bytes(), 0);
+    }
+}
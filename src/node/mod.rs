@@ -1,2 +1,4 @@
+mod mempool;
 mod node;
+pub use mempool::*;
 pub use node::*;
\ No newline at end of file
@@ -2,8 +2,9 @@ use crate::{
     block::{ChainedInstance, PositionInstance, UnchainedInstance},
     chain::{Chain, ChainError},
     data::Metadata,
+    error::{DataBaseError, SerdeError},
     record::{Record, SignedRecord},
-    AuthKeyPair, DigitalSignature, PublicKey, SigningError,
+    impl_display_error, AuthKeyPair, DigitalSignature, PublicKey, SigningError,
 };
 
 pub enum NodeError {
@@ -19,7 +20,22 @@ pub trait MemPool<R: Record> {
     fn append(&mut self, record: SignedRecord<R>) -> Result<(), MemPoolError>;
 }
 
-pub enum MemPoolError {}
+/// An error that can occur when adding records to, or taking records from, a `MemPool`
+#[derive(Debug, Clone, Copy)]
+pub enum MemPoolError {
+    /// The record is already in the pool.
+    Duplicate,
+    /// The signature or the hash of the record is not valid.
+    VerificationFailed,
+    /// The record is larger than the pool can ever hold.
+    TooLarge,
+    /// The pool is full of records with at least the same priority.
+    PoolFull,
+    SerdeError(SerdeError),
+    DataBaseError(DataBaseError),
+}
+
+impl_display_error!(MemPoolError);
 
 pub trait Node<R: Record>: Sized {
     type UnchainedInstanceType: UnchainedInstance<R>;
@@ -72,7 +88,23 @@ pub trait Peer<R: Record> {
     }
 }
 
-pub enum MiningError {}
+/// An error that can occur while mining a block
+#[derive(Debug, Clone, Copy)]
+pub enum MiningError {
+    /// The search was cancelled before a solution was found.
+    Cancelled,
+    /// Every nonce was tried without meeting the target.
+    Exhausted,
+    ChainError(ChainError),
+}
+
+impl From<ChainError> for MiningError {
+    fn from(value: ChainError) -> Self {
+        MiningError::ChainError(value)
+    }
+}
+
+impl_display_error!(MiningError);
 
 pub trait Miner<R: Record> {
     fn append(&self, record: SignedRecord<R>) -> Result<(), MiningError>;
@@ -0,0 +1,476 @@
+//! Chains of several record types.
+//!
+//! A `Chain<R>` holds one record type. To keep several types on one chain, each type
+//! implements `TypedRecord` with a name of its own, and the chain holds `AnyRecord`s: a
+//! record of any of those types in JSON form, tagged with the name and version of its
+//! type. Records are read back by type, with `AnyRecord::downcast`, `records_of` or a
+//! `Dispatcher` that calls the handler registered for the type of each record.
+//!
+//! When a type changes, its `VERSION` is raised and its records are read with a
+//! `RecordRegistry` holding upgraders from the older versions, as with the records of a
+//! `SqliteChain` (see `registry`).
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    block::ChainedInstance,
+    chain::{Chain, ChainError},
+    data::Position,
+    error::SerdeError,
+    record::{Record, SignedRecord},
+    registry::{RecordError, RecordRegistry, RecordTag},
+};
+
+/// Record types that can be stored in an `AnyRecord`.
+pub trait TypedRecord: Record + Serialize + DeserializeOwned {
+    /// The name records of this type are tagged with. It must differ from the names of the
+    /// other types on the same chain and must not change once records are on a chain.
+    const TYPE_NAME: &'static str;
+
+    /// The version records of this type are tagged with, raised whenever the type changes.
+    /// Defaults to `RecordRegistry::LEGACY_VERSION`.
+    const VERSION: u32 = 1;
+}
+
+/// An error that can occur when reading an `AnyRecord` as a given type.
+#[derive(Debug, Clone)]
+pub enum AnyRecordError {
+    /// The record is of another type.
+    WrongType {
+        expected: &'static str,
+        found: String,
+    },
+    /// No handler is registered for the type of the record.
+    UnknownType(String),
+    /// The record could not be read, or upgraded, as the current version of its type.
+    RecordError(RecordError),
+    SerdeError(SerdeError),
+    ChainError(ChainError),
+}
+
+impl From<RecordError> for AnyRecordError {
+    fn from(value: RecordError) -> Self {
+        AnyRecordError::RecordError(value)
+    }
+}
+
+impl From<ChainError> for AnyRecordError {
+    fn from(value: ChainError) -> Self {
+        AnyRecordError::ChainError(value)
+    }
+}
+
+crate::impl_display_error!(AnyRecordError);
+
+/// A record of any `TypedRecord` type, tagged with the name and version of its type.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::{any::{AnyRecord, TypedRecord}, record::Record};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Record)]
+/// struct Vote {
+///     choice: i32,
+/// }
+///
+/// impl TypedRecord for Vote {
+///     const TYPE_NAME: &'static str = "vote";
+/// }
+///
+/// let record = AnyRecord::new(&Vote { choice: 2 }).unwrap();
+/// assert_eq!(record.type_name(), "vote");
+/// assert_eq!(record.version(), 1);
+/// assert!(record.is::<Vote>());
+/// assert_eq!(record.downcast::<Vote>().unwrap(), Vote { choice: 2 });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnyRecord {
+    type_name: String,
+    version: u32,
+    /// The JSON form of the record.
+    json: String,
+}
+
+crate::record::impl_record_for!(AnyRecord);
+
+impl AnyRecord {
+    pub fn new<T: TypedRecord>(record: &T) -> Result<Self, SerdeError> {
+        Ok(Self {
+            type_name: T::TYPE_NAME.to_owned(),
+            version: T::VERSION,
+            json: serde_json::to_string(record).map_err(|_| SerdeError::SerializationError)?,
+        })
+    }
+
+    pub fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the type name and version this record is tagged with.
+    pub fn tag(&self) -> RecordTag {
+        RecordTag {
+            type_name: self.type_name.clone(),
+            version: self.version,
+        }
+    }
+
+    /// Returns the JSON form of this record.
+    pub fn json(&self) -> &str {
+        &self.json
+    }
+
+    /// Returns `true` if this record is of type `T`.
+    pub fn is<T: TypedRecord>(&self) -> bool {
+        self.type_name == T::TYPE_NAME
+    }
+
+    /// Reads this record as a `T`. Fails with `RecordError::MissingUpgrader` if it was
+    /// tagged with an older version of `T`, which `downcast_with` can upgrade.
+    pub fn downcast<T: TypedRecord>(&self) -> Result<T, AnyRecordError> {
+        if !self.is::<T>() {
+            return Err(AnyRecordError::WrongType {
+                expected: T::TYPE_NAME,
+                found: self.type_name.clone(),
+            });
+        }
+        self.downcast_with(&RecordRegistry::<T>::new(T::TYPE_NAME, T::VERSION))
+    }
+
+    /// Reads this record as the current version of the type of `registry`, upgrading it
+    /// from the version it was tagged with.
+    pub fn downcast_with<T: DeserializeOwned>(
+        &self,
+        registry: &RecordRegistry<T>,
+    ) -> Result<T, AnyRecordError> {
+        let malformed = |e: serde_json::Error| RecordError::Malformed(e.to_string());
+        let value = serde_json::from_str(&self.json).map_err(malformed)?;
+        let value = registry.upgrade(&self.tag(), value)?;
+        Ok(serde_json::from_value(value).map_err(malformed)?)
+    }
+
+    /// Returns the records of type `T` among `records`, read as `T`, in order.
+    pub fn filter<'a, T: TypedRecord>(
+        records: &'a [SignedRecord<AnyRecord>],
+    ) -> impl Iterator<Item = Result<(&'a SignedRecord<AnyRecord>, T), AnyRecordError>> + 'a {
+        records
+            .iter()
+            .filter(|signed| signed.record().is::<T>())
+            .map(|signed| Ok((signed, signed.record().downcast()?)))
+    }
+}
+
+/// Returns every record of type `T` in `chain`, read as `T`, with the position of its
+/// block.
+///
+/// Fails with `ChainError::Pruned` if the records of a block were pruned.
+pub fn records_of<T, C>(
+    chain: &C,
+) -> Result<Vec<(Position, SignedRecord<AnyRecord>, T)>, AnyRecordError>
+where
+    T: TypedRecord,
+    C: Chain<AnyRecord>,
+{
+    let mut found = vec![];
+    for pos in 1..=chain.len()? {
+        let block = chain.block_at(pos.into())?;
+        let records = block.records().map_err(ChainError::from)?;
+        for item in AnyRecord::filter::<T>(records.as_slice()) {
+            let (signed, record) = item?;
+            found.push((pos.into(), signed.clone(), record));
+        }
+    }
+    Ok(found)
+}
+
+type Handler<'a, O> = Rc<dyn Fn(&SignedRecord<AnyRecord>) -> Result<O, AnyRecordError> + 'a>;
+
+/// Calls the handler registered for the type of each record it is given.
+///
+/// A `StateMachine<AnyRecord>` can use a dispatcher to apply every type of record of a
+/// chain in its own way.
+pub struct Dispatcher<'a, O> {
+    /// The handlers, by type name.
+    handlers: BTreeMap<String, Handler<'a, O>>,
+}
+
+impl<'a, O> Dispatcher<'a, O> {
+    pub fn new() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Handles records of type `T` with `handler`, which is given the signed record and
+    /// the record read as `T`.
+    pub fn with_handler<T, F>(self, handler: F) -> Self
+    where
+        T: TypedRecord + 'a,
+        F: Fn(&SignedRecord<AnyRecord>, T) -> O + 'a,
+    {
+        self.with_registry(RecordRegistry::new(T::TYPE_NAME, T::VERSION), handler)
+    }
+
+    /// Handles records tagged with the type name of `registry`, or one of its aliases,
+    /// with `handler`, which is given the signed record and the record upgraded by
+    /// `registry` to its current version.
+    pub fn with_registry<T, F>(mut self, registry: RecordRegistry<T>, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'a,
+        F: Fn(&SignedRecord<AnyRecord>, T) -> O + 'a,
+    {
+        let mut names = registry.aliases().to_vec();
+        names.push(registry.type_name().to_owned());
+        let handler: Handler<'a, O> = Rc::new(move |signed: &SignedRecord<AnyRecord>| {
+            Ok(handler(signed, signed.record().downcast_with(&registry)?))
+        });
+        for name in names {
+            self.handlers.insert(name, handler.clone());
+        }
+        self
+    }
+
+    /// Returns `true` if records tagged `type_name` have a handler.
+    pub fn handles(&self, type_name: &str) -> bool {
+        self.handlers.contains_key(type_name)
+    }
+
+    /// Calls the handler of the type of `record`.
+    pub fn dispatch(&self, record: &SignedRecord<AnyRecord>) -> Result<O, AnyRecordError> {
+        match self.handlers.get(record.record().type_name()) {
+            Some(handler) => handler(record),
+            None => Err(AnyRecordError::UnknownType(
+                record.record().type_name().to_owned(),
+            )),
+        }
+    }
+}
+
+impl<'a, O> Default for Dispatcher<'a, O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        any::{records_of, AnyRecord, AnyRecordError, Dispatcher, TypedRecord},
+        block::ChainedInstance,
+        chain::Chain,
+        data::Metadata,
+        record::{Record, SignedRecord},
+        registry::{RecordError, RecordRegistry},
+        AuthKeyPair, SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Vote {
+        proposal: u32,
+        yes: bool,
+    }
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Transfer {
+        to: String,
+        amount: u64,
+    }
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Proposal {
+        id: u32,
+        title: String,
+    }
+
+    impl TypedRecord for Vote {
+        const TYPE_NAME: &'static str = "vote";
+    }
+
+    impl TypedRecord for Transfer {
+        const TYPE_NAME: &'static str = "transfer";
+    }
+
+    impl TypedRecord for Proposal {
+        const TYPE_NAME: &'static str = "proposal";
+    }
+
+    /// The first version of `Note`.
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct OldNote {
+        text: String,
+    }
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Note {
+        text: String,
+        pinned: bool,
+    }
+
+    impl TypedRecord for OldNote {
+        const TYPE_NAME: &'static str = "memo";
+    }
+
+    impl TypedRecord for Note {
+        const TYPE_NAME: &'static str = "note";
+        const VERSION: u32 = 2;
+    }
+
+    fn signed<T: TypedRecord>(record: &T, keypair: &AuthKeyPair) -> SignedRecord<AnyRecord> {
+        let record = AnyRecord::new(record).unwrap();
+        record.record(keypair.clone(), Metadata::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_any_record() {
+        let url = "target2/tests/anyrecordchain/";
+        let _ = std::fs::remove_dir_all(url);
+        std::fs::create_dir_all(url).expect("could not create url");
+        let keypair = crate::generate_ed25519_keypair();
+
+        let proposal = Proposal {
+            id: 1,
+            title: "raise the limit".to_owned(),
+        };
+        let votes = [
+            Vote {
+                proposal: 1,
+                yes: true,
+            },
+            Vote {
+                proposal: 1,
+                yes: false,
+            },
+        ];
+        let transfer = Transfer {
+            to: "bob".to_owned(),
+            amount: 5,
+        };
+
+        let mut chain = SqliteChain::<AnyRecord>::new(url).unwrap();
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(signed(&proposal, &keypair));
+        block.push(signed(&votes[0], &keypair));
+        chain.append(&block).unwrap();
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(signed(&transfer, &keypair));
+        block.push(signed(&votes[1], &keypair));
+        chain.append(&block).unwrap();
+
+        // filtered by type
+        let found = records_of::<Vote, _>(&chain).unwrap();
+        let found = found
+            .into_iter()
+            .map(|(pos, _, vote)| (pos.pos, vote))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![(1, votes[0].clone()), (2, votes[1].clone())]);
+        let found = records_of::<Transfer, _>(&chain).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].2, transfer);
+
+        // dispatched by type
+        let tally = RefCell::new((0, 0));
+        let dispatcher = Dispatcher::new()
+            .with_handler(|_, vote: Vote| {
+                let mut tally = tally.borrow_mut();
+                match vote.yes {
+                    true => tally.0 += 1,
+                    false => tally.1 += 1,
+                }
+                "vote"
+            })
+            .with_handler(|_, _: Proposal| "proposal");
+        let mut handled = vec![];
+        for pos in 1..=chain.len().unwrap() {
+            let block = chain.block_at(pos.into()).unwrap();
+            let records = block.records().unwrap();
+            for record in records.as_slice() {
+                handled.push(dispatcher.dispatch(record).map_err(|e| e.to_string()));
+            }
+        }
+        assert_eq!(
+            handled,
+            vec![
+                Ok("proposal"),
+                Ok("vote"),
+                Err("UnknownType(\"transfer\")".to_owned()),
+                Ok("vote")
+            ]
+        );
+        assert_eq!(*tally.borrow(), (1, 1));
+
+        let record = AnyRecord::new(&transfer).unwrap();
+        assert!(matches!(
+            record.downcast::<Vote>(),
+            Err(AnyRecordError::WrongType {
+                expected: "vote",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_versions() {
+        let keypair = crate::generate_ed25519_keypair();
+        let old = signed(
+            &OldNote {
+                text: "old".to_owned(),
+            },
+            &keypair,
+        );
+        let new = Note {
+            text: "new".to_owned(),
+            pinned: true,
+        };
+        let new = signed(&new, &keypair);
+        assert_eq!((old.record().version(), new.record().version()), (1, 2));
+
+        // the record was renamed from "memo" and gained `pinned` in version 2
+        let registry = || {
+            RecordRegistry::<Note>::new("note", 2)
+                .with_alias("memo")
+                .with_upgrader(1, |mut note| {
+                    note["pinned"] = false.into();
+                    Ok(note)
+                })
+        };
+        let upgraded = old.record().downcast_with(&registry()).unwrap();
+        assert_eq!(
+            upgraded,
+            Note {
+                text: "old".to_owned(),
+                pinned: false
+            }
+        );
+        let unupgraded = RecordRegistry::<Note>::new("note", 2).with_alias("memo");
+        assert!(matches!(
+            old.record().downcast_with(&unupgraded),
+            Err(AnyRecordError::RecordError(RecordError::MissingUpgrader(1)))
+        ));
+
+        // dispatched under the old name too
+        let dispatcher = Dispatcher::new().with_registry(registry(), |_, note: Note| note.pinned);
+        assert!(!dispatcher.dispatch(&old).unwrap());
+        assert!(dispatcher.dispatch(&new).unwrap());
+
+        // a newer version than the type knows is not read
+        let registry = RecordRegistry::<Note>::new("note", 1);
+        assert!(matches!(
+            new.record().downcast_with(&registry),
+            Err(AnyRecordError::RecordError(
+                RecordError::UnsupportedVersion(2)
+            ))
+        ));
+    }
+}
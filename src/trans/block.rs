@@ -2,11 +2,15 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     chain::Chain,
+    consensus::finality::FinalityCertificate,
     crypto::*,
-    data::{Metadata, Nonce, Position, Timestamp},
+    data::{Metadata, Nonce, Position, Target, Timestamp, ToTimestamp},
     error::{DataBaseError, SerdeError},
+    fees::FeeRecord,
     merkle::MerkleTree,
+    receipt::{self, Receipt},
     record::Records,
+    registry::{RecordError, StoredRecord},
 };
 
 use super::{
@@ -20,6 +24,8 @@ use super::{
 /// This `Block` trait provides methods for accessing these properties.
 pub trait ChainedInstance<R: Record> {
     /// Returns a reference to the records in this block.
+    ///
+    /// Fails with `BlockError::Pruned` if the records have been deleted to save space.
     fn records(&self) -> Result<Records<R>, BlockError>;
 
     /// Returns the previous hash of this block.
@@ -34,11 +40,69 @@ pub trait ChainedInstance<R: Record> {
     /// Returns the merkle root of this block.
     fn merkle_root(&self) -> Result<Hash, BlockError>;
 
+    /// Returns the root of the state after this block (see `BlockHeader::state_root`).
+    fn state_root(&self) -> Result<Hash, BlockError>;
+
+    /// Returns the receipts of the records of this block, in the order of the records.
+    fn receipts(&self) -> Result<Vec<Receipt>, BlockError>;
+
+    /// Returns the Merkle root of the receipts of this block (see `BlockHeader::receipts_root`).
+    fn receipts_root(&self) -> Result<Hash, BlockError>;
+
     /// Returns the timestamp of this block.
     fn timestamp(&self) -> Result<Timestamp, BlockError>;
 
     /// Returns the nonce of this block.
     fn nonce(&self) -> Result<Nonce, BlockError>;
+
+    /// Returns the proof-of-work target of this block.
+    fn target(&self) -> Result<Target, BlockError>;
+
+    /// Returns the base fee of this block (see `BlockHeader::base_fee`).
+    fn base_fee(&self) -> Result<u128, BlockError>;
+
+    /// Returns the producer of this block (see `BlockHeader::producer`).
+    fn producer(&self) -> Result<Option<PublicKey>, BlockError>;
+
+    /// Returns the seal of this block, if it was sealed.
+    fn seal(&self) -> Result<Option<Seal>, BlockError>;
+
+    /// Returns the certificate that finalized this block, if it was finalized.
+    fn finality(&self) -> Result<Option<FinalityCertificate>, BlockError>;
+
+    /// Returns the metadata the block was built with.
+    fn metadata(&self) -> Result<Metadata, BlockError>;
+
+    /// Returns the version of the header of this block.
+    fn version(&self) -> Result<u32, BlockError>;
+
+    /// Returns the records of this block that were upgraded from an older version of
+    /// their type as they were read, with their index, in the form they are stored.
+    ///
+    /// The hash and signature of an upgraded record are those of the record that was
+    /// signed (see `crate::registry`).
+    fn upgraded_records(&self) -> Result<Vec<(u64, StoredRecord)>, BlockError> {
+        Ok(vec![])
+    }
+
+    /// Returns the header of this block, which can be transmitted and checked without
+    /// the records.
+    fn header(&self) -> Result<BlockHeader, BlockError> {
+        Ok(BlockHeader {
+            version: self.version()?,
+            position: self.position()?,
+            prev_hash: self.prev_hash()?,
+            merkle_root: self.merkle_root()?,
+            state_root: self.state_root()?,
+            receipts_root: self.receipts_root()?,
+            timestamp: self.timestamp()?,
+            nonce: self.nonce()?,
+            target: self.target()?,
+            base_fee: self.base_fee()?,
+            producer: self.producer()?,
+            metadata: self.metadata()?,
+        })
+    }
 }
 
 /// An error that can occur when working with blocks.
@@ -53,6 +117,15 @@ pub enum BlockError {
     /// The block is not valid.
     NotValid(BlockData),
 
+    /// The records of the block have been pruned; only its header is kept.
+    Pruned,
+
+    /// The operation would remove a finalized block.
+    Finalized,
+
+    /// A record of the block could not be decoded into the record type of the chain.
+    RecordError(RecordError),
+
     /// An unspecified error occurred.
     Unspecified,
 }
@@ -69,12 +142,33 @@ pub enum BlockData {
     /// The merkle root of the block.
     MerkleRoot,
 
+    /// The state root of the block.
+    StateRoot,
+
+    /// The receipts root of the block.
+    ReceiptsRoot,
+
     /// The timestamp of the block.
     Timestamp,
 
     /// The nonce of the block.
     Nonce,
 
+    /// The proof-of-work target of the block.
+    Target,
+
+    /// The base fee of the block.
+    BaseFee,
+
+    /// The seal of the block.
+    Seal,
+
+    /// The records of the block.
+    Records,
+
+    /// The finality certificate of the block.
+    Finality,
+
     /// The position of the block in the blockchain.
     Position,
 }
@@ -93,6 +187,10 @@ impl From<ChainError> for BlockError {
             ChainError::SerdeError(v) => BlockError::SerdeError(v),
             ChainError::DataBaseError(u) => BlockError::DataBaseError(u),
             ChainError::Unspecified => BlockError::Unspecified,
+            ChainError::NotValid(d) => BlockError::NotValid(d),
+            ChainError::Finalized => BlockError::Finalized,
+            ChainError::Pruned => BlockError::Pruned,
+            ChainError::RecordError(kind) => BlockError::RecordError(kind.into()),
             ChainError::AbsentValue => unimplemented!(),
         }
     }
@@ -128,12 +226,148 @@ impl PositionInstance {
     }
 }
 
+/// The fields of a block that describe it without its records.
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub position: Position,
+    pub prev_hash: Hash,
+    pub merkle_root: Hash,
+    /// The root of a `SparseMerkleTree` over the state after this block, against which
+    /// values of the state can be proven. `Hash::default()` if the chain keeps no state.
+    pub state_root: Hash,
+    /// The Merkle root of the receipts of the records of this block (see
+    /// `receipt::receipts_root`). `Hash::default()` if the block has no receipts.
+    pub receipts_root: Hash,
+    pub timestamp: Timestamp,
+    pub nonce: Nonce,
+    pub target: Target,
+    /// The fee every record of this block pays, which is burned (see `fees::FeeMarket`).
+    /// `0` if the chain has no fee market.
+    pub base_fee: u128,
+    /// The party that produced the block, which is paid the tips of its records. `None` if
+    /// the block names no producer.
+    pub producer: Option<PublicKey>,
+    pub metadata: Metadata,
+}
+
+impl BlockHeader {
+    /// The version of the headers of the blocks built by this crate. The hash of these
+    /// blocks is the hash of their header.
+    pub const VERSION: u32 = 2;
+
+    /// The version of blocks hashed with `crate::hash_block`, which ignores the nonce, target
+    /// and metadata. Blocks stored before headers were versioned are read with this version.
+    pub const LEGACY_VERSION: u32 = 1;
+
+    /// Returns the canonical hash of this header, over the fields its version commits to.
+    ///
+    /// Each version hashes a fixed list of fields, so fields added to the header later do
+    /// not change the hash of older headers, such as those of genesis blocks. The hash of
+    /// legacy blocks cannot be computed from their header (see `crate::expected_block_hash`);
+    /// they are hashed as version 2 headers here.
+    pub fn hash(&self) -> Hash {
+        match self.version {
+            ..=2 => crate::hash(&(
+                &self.version,
+                &self.position,
+                &self.prev_hash,
+                &self.merkle_root,
+                &self.state_root,
+                &self.receipts_root,
+                &self.timestamp,
+                &self.nonce,
+                &self.target,
+                &self.base_fee,
+                &self.producer,
+                &self.metadata,
+            )),
+            // a version newer than this crate is hashed over every field the crate knows
+            _ => crate::hash(self),
+        }
+    }
+
+    /// Returns the hash that proofs-of-work and seals of this block are computed over
+    /// (see `crate::hash_header`), which covers every field but the nonce.
+    pub fn seal_hash(&self) -> Hash {
+        crate::hash(&(
+            &self.version,
+            &self.position,
+            &self.prev_hash,
+            &self.merkle_root,
+            &self.state_root,
+            &self.receipts_root,
+            &self.timestamp,
+            &self.target,
+            &self.base_fee,
+            &self.producer,
+            &self.metadata,
+        ))
+    }
+
+    /// Encodes this header for transmission.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SerdeError> {
+        crate::serialize(self)
+    }
+
+    /// Decodes a header encoded with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SerdeError> {
+        bincode::deserialize(bytes).map_err(|_| SerdeError::DeserializationError)
+    }
+}
+
+/// A signature over the header hash of a block (see `crate::hash_header`) by the party
+/// that produced it.
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct Seal {
+    signer: PublicKey,
+    signature: DigitalSignature,
+}
+
+impl Seal {
+    pub fn new(signer: PublicKey, signature: DigitalSignature) -> Self {
+        Self { signer, signature }
+    }
+
+    /// Signs `header_hash` with `keypair`.
+    pub fn sign(header_hash: &Hash, keypair: &AuthKeyPair) -> Result<Self, SigningError> {
+        let signature = keypair.sign(header_hash)?;
+        Ok(Self::new(keypair.clone().into_public_key(), signature))
+    }
+
+    /// Verifies that this seal is a valid signature over `header_hash`.
+    pub fn verify(&self, header_hash: &Hash) -> Result<(), VerificationError> {
+        self.signer.verify(header_hash, &self.signature)
+    }
+
+    pub fn signer(&self) -> &PublicKey {
+        &self.signer
+    }
+
+    pub fn signature(&self) -> &DigitalSignature {
+        &self.signature
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize, Clone)]
 pub struct LocalInstance<R> {
     pub records: Vec<SignedRecord<R>>,
     pub merkle: merkle::MerkleTree,
     pub metadata: Metadata,
     pub nonce: Nonce,
+    pub target: Target,
+    pub seal: Option<Seal>,
+    /// The root of the state after this block, committed into its header.
+    pub state_root: Hash,
+    /// The receipts of the records, committed into the header by their Merkle root.
+    pub receipts: Vec<Receipt>,
+    /// The base fee the records pay, which the chain may require to follow its fee market.
+    pub base_fee: u128,
+    /// The time the producer gives the block. The chain keeps it in the header once it has
+    /// checked it against the parent block and its own clock.
+    pub timestamp: Timestamp,
+    /// The party that produces the block, which is paid the tips of its records.
+    pub producer: Option<PublicKey>,
 }
 
 impl<R> LocalInstance<R> {
@@ -143,6 +377,13 @@ impl<R> LocalInstance<R> {
             merkle: MerkleTree::new(),
             metadata,
             nonce: nonce.into(),
+            target: Target::MAX,
+            seal: None,
+            state_root: Hash::default(),
+            receipts: vec![],
+            base_fee: 0,
+            timestamp: chrono::Utc::now().to_timestamp(),
+            producer: None,
         }
     }
 }
@@ -161,6 +402,25 @@ impl<R> LocalInstance<R> {
     pub fn get_merkle_root(&self) -> &Hash {
         self.merkle.root()
     }
+
+    /// Returns the header this block gets when it is appended at `position` after
+    /// `prev_hash`.
+    pub fn header(&self, prev_hash: &Hash, position: Position) -> BlockHeader {
+        BlockHeader {
+            version: BlockHeader::VERSION,
+            position,
+            prev_hash: prev_hash.clone(),
+            merkle_root: self.get_merkle_root().clone(),
+            state_root: self.state_root.clone(),
+            receipts_root: receipt::receipts_root(&self.receipts),
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            target: self.target,
+            base_fee: self.base_fee,
+            producer: self.producer.clone(),
+            metadata: self.metadata.clone(),
+        }
+    }
 }
 
 pub trait UnchainedInstance<R> {
@@ -189,3 +449,400 @@ impl<R: Clone> UnchainedInstance<R> for LocalInstance<R> {
         Ok(self.merkle.root().clone())
     }
 }
+
+/// An error that can occur when adding records to a `BlockBuilder`.
+#[derive(Debug, Clone, Copy)]
+pub enum BuildError {
+    /// The signature or the hash of the record is not valid.
+    VerificationFailed,
+    /// The block already holds the maximum number of records.
+    TooManyRecords,
+    /// The record would make the block larger than the maximum size.
+    TooLarge,
+    SerdeError(SerdeError),
+    ChainError(ChainError),
+}
+
+impl From<ChainError> for BuildError {
+    fn from(value: ChainError) -> Self {
+        BuildError::ChainError(value)
+    }
+}
+
+crate::impl_display_error!(BuildError);
+
+/// Collects verified records into a `LocalInstance` within a size limit, a record count
+/// limit and a maximum age, after which the block should be sealed.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::{block::BlockBuilder, data::Metadata, record::Record, Hash};
+///
+/// let keypair = blockify::generate_ed25519_keypair();
+/// let mut builder = BlockBuilder::new(Metadata::empty()).max_records(1);
+///
+/// builder.push("hello".to_owned().record(keypair.clone(), Metadata::empty()).unwrap()).unwrap();
+/// assert!(builder.is_full());
+/// assert!(builder.push("world".to_owned().record(keypair, Metadata::empty()).unwrap()).is_err());
+///
+/// let candidate = builder.build(&Hash::default(), 1.into());
+/// assert_eq!(candidate.instance.records.len(), 1);
+/// ```
+pub struct BlockBuilder<R> {
+    instance: LocalInstance<R>,
+    size: usize,
+    opened: Timestamp,
+    max_bytes: usize,
+    max_records: usize,
+    max_age: Option<u64>,
+}
+
+/// A block produced by a `BlockBuilder`, ready to be appended at `position` after `prev_hash`.
+#[derive(Debug, Clone)]
+pub struct BlockCandidate<R> {
+    pub instance: LocalInstance<R>,
+    pub prev_hash: Hash,
+    pub position: Position,
+    pub merkle_root: Hash,
+    /// The hash that proofs-of-work and seals are computed over (see `crate::hash_header`).
+    pub header_hash: Hash,
+    /// The serialized size of the records of the block.
+    pub size: usize,
+}
+
+impl<R> BlockBuilder<R> {
+    /// Creates an unbounded builder for a block carrying `metadata`, opened now.
+    pub fn new(metadata: Metadata) -> Self {
+        Self {
+            instance: LocalInstance::new(metadata, 0),
+            size: 0,
+            opened: chrono::Utc::now().to_timestamp(),
+            max_bytes: usize::MAX,
+            max_records: usize::MAX,
+            max_age: None,
+        }
+    }
+
+    /// Limits the total serialized size of the records of the block.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Limits the number of records of the block.
+    pub fn max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records;
+        self
+    }
+
+    /// Requires the block to be sealed `secs` seconds after the builder was opened.
+    pub fn max_age(mut self, secs: u64) -> Self {
+        self.max_age = Some(secs);
+        self
+    }
+
+    /// Sets the base fee of the block, which the records added by `push_by_fee` must pay.
+    pub fn base_fee(mut self, base_fee: u128) -> Self {
+        self.instance.base_fee = base_fee;
+        self
+    }
+
+    /// Names `producer` as the producer of the block, which is paid the tips of its records.
+    pub fn producer(mut self, producer: PublicKey) -> Self {
+        self.instance.producer = Some(producer);
+        self
+    }
+
+    /// Sets the time the builder was opened at, from which its age is measured.
+    pub fn opened_at(mut self, opened: Timestamp) -> Self {
+        self.opened = opened;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.instance.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instance.records.is_empty()
+    }
+
+    /// Returns the serialized size of the records added so far.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if no more records can be added.
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.max_records || self.size >= self.max_bytes
+    }
+
+    /// Returns `true` if the block is older than its maximum age at `now`.
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        match self.max_age {
+            Some(max_age) => now.secs() >= self.opened.secs().saturating_add(max_age),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if the block should be sealed at `now`, because it is full or expired.
+    pub fn should_seal(&self, now: Timestamp) -> bool {
+        self.is_full() || self.is_expired(now)
+    }
+
+    /// Finishes the block so that it can be appended at `position` after `prev_hash`.
+    ///
+    /// The block is stamped with the time it is finished.
+    pub fn build(mut self, prev_hash: &Hash, position: Position) -> BlockCandidate<R> {
+        self.instance.timestamp = chrono::Utc::now().to_timestamp();
+        let merkle_root = self.instance.get_merkle_root().clone();
+        let header_hash = crate::hash_header(&self.instance, prev_hash, &position);
+        BlockCandidate {
+            instance: self.instance,
+            prev_hash: prev_hash.clone(),
+            position,
+            merkle_root,
+            header_hash,
+            size: self.size,
+        }
+    }
+}
+
+impl<R: Record + Serialize> BlockBuilder<R> {
+    /// Adds `record` to the block if its signature is valid and it fits within the limits.
+    pub fn push(&mut self, record: SignedRecord<R>) -> Result<(), BuildError> {
+        let size = self.admit(&record)?;
+        self.size += size;
+        self.instance.push(record);
+        Ok(())
+    }
+
+    /// Returns the size `record` would add to the block, or why it cannot be added.
+    fn admit(&self, record: &SignedRecord<R>) -> Result<usize, BuildError> {
+        if record.verify().is_err() || &record.record().hash() != record.hash() {
+            return Err(BuildError::VerificationFailed);
+        }
+        if self.len() >= self.max_records {
+            return Err(BuildError::TooManyRecords);
+        }
+        let size = crate::serialize(record)
+            .map_err(BuildError::SerdeError)?
+            .len();
+        if self.size.saturating_add(size) > self.max_bytes {
+            return Err(BuildError::TooLarge);
+        }
+        Ok(size)
+    }
+
+    /// Finishes the block so that it can be appended to the end of `chain`.
+    pub fn build_next<C: Chain<R>>(self, chain: &C) -> Result<BlockCandidate<R>, BuildError> {
+        let (prev_hash, position) = crate::consensus::next_in(chain)?;
+        Ok(self.build(&prev_hash, position))
+    }
+}
+
+impl<R: Record + FeeRecord + Serialize> BlockBuilder<R> {
+    /// Adds the records of `candidates` that pay the base fee, those paying the highest
+    /// tip per byte first, until the block is full. Returns the records left out, in the
+    /// order they came in.
+    ///
+    /// Picking by tip per byte maximises the tips of the block, but for the space left
+    /// over when the next record does not fit.
+    pub fn push_by_fee(&mut self, candidates: Vec<SignedRecord<R>>) -> Vec<SignedRecord<R>> {
+        let base_fee = self.instance.base_fee;
+        let mut ranked = vec![];
+        let mut left = vec![];
+        for (i, record) in candidates.into_iter().enumerate() {
+            let size = crate::serialize(&record).map(|bytes| bytes.len() as u128);
+            match (record.record().fee().charge(base_fee), size) {
+                (Some(charge), Ok(size)) => ranked.push((i, charge.tip, size.max(1), record)),
+                _ => left.push((i, record)),
+            }
+        }
+        ranked.sort_by(|(i, a_tip, a_size, _), (j, b_tip, b_size, _)| {
+            let a = a_tip.saturating_mul(*b_size);
+            let b = b_tip.saturating_mul(*a_size);
+            b.cmp(&a).then(i.cmp(j))
+        });
+        for (i, _, _, record) in ranked {
+            match self.admit(&record) {
+                Ok(size) => {
+                    self.size += size;
+                    self.instance.push(record);
+                }
+                Err(_) => left.push((i, record)),
+            }
+        }
+        left.sort_by_key(|(i, _)| *i);
+        left.into_iter().map(|(_, record)| record).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::{BlockBuilder, BlockHeader, BuildError, ChainedInstance},
+        chain::Chain,
+        data::{Detail, Metadata, Timestamp},
+        record::{Record, SignedRecord},
+        SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Vote {
+        data: String,
+    }
+
+    fn vote(data: &str) -> SignedRecord<Vote> {
+        let keypair = crate::generate_ed25519_keypair();
+        Vote {
+            data: data.to_owned(),
+        }
+        .record(keypair, Metadata::empty())
+        .unwrap()
+    }
+
+    #[test]
+    fn test_limits() {
+        let one = crate::serialize(&vote("a")).unwrap().len();
+        let mut builder = BlockBuilder::new(Metadata::empty())
+            .max_bytes(one * 2)
+            .max_records(3);
+        builder.push(vote("a")).unwrap();
+        assert!(matches!(
+            builder.push(vote("abcdefgh")),
+            Err(BuildError::TooLarge)
+        ));
+        builder.push(vote("b")).unwrap();
+        assert!(builder.is_full());
+        assert_eq!(builder.size(), one * 2);
+
+        let mut builder = BlockBuilder::new(Metadata::empty()).max_records(1);
+        builder.push(vote("a")).unwrap();
+        assert!(matches!(
+            builder.push(vote("b")),
+            Err(BuildError::TooManyRecords)
+        ));
+
+        let good = vote("a");
+        let forged = SignedRecord::new(
+            Vote {
+                data: "b".to_owned(),
+            },
+            good.signature().clone(),
+            good.signer().clone(),
+            good.hash().clone(),
+            Metadata::empty(),
+        );
+        let mut builder = BlockBuilder::new(Metadata::empty());
+        assert!(matches!(
+            builder.push(forged),
+            Err(BuildError::VerificationFailed)
+        ));
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn test_age() {
+        let builder = BlockBuilder::<Vote>::new(Metadata::empty())
+            .max_age(30)
+            .opened_at(Timestamp::from_secs(100));
+        assert!(!builder.should_seal(Timestamp::from_secs(129)));
+        assert!(builder.should_seal(Timestamp::from_secs(130)));
+        let unbounded =
+            BlockBuilder::<Vote>::new(Metadata::empty()).opened_at(Timestamp::from_secs(0));
+        assert!(!unbounded.should_seal(Timestamp::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn test_build_next() {
+        let chain_url = "target2/tests/blockbuilder/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+        chain
+            .append(&LocalInstance::new(Metadata::empty(), 0))
+            .unwrap();
+
+        let mut builder = BlockBuilder::new(Metadata::empty());
+        builder.push(vote("a")).unwrap();
+        let candidate = builder.build_next(&chain).unwrap();
+        assert_eq!(candidate.position, 2.into());
+        assert_eq!(
+            candidate.prev_hash,
+            chain.block_at(1.into()).unwrap().hash().unwrap()
+        );
+        assert_eq!(
+            candidate.header_hash,
+            crate::hash_header(
+                &candidate.instance,
+                &candidate.prev_hash,
+                &candidate.position
+            )
+        );
+
+        chain.append(&candidate.instance).unwrap();
+        let block = chain.block_at(2.into()).unwrap();
+        assert_eq!(block.merkle_root().unwrap(), candidate.merkle_root);
+    }
+
+    #[test]
+    fn test_header() {
+        let chain_url = "target2/tests/blockheader/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+
+        let mut metadata = Metadata::empty();
+        metadata.push(Detail::Text("producer".to_owned()));
+        metadata.push(Detail::Integer(7));
+        let mut local = LocalInstance::new(metadata.clone(), 0);
+        local.push(vote("a"));
+        local.producer = Some(crate::generate_ed25519_keypair().into_public_key());
+        chain.append(&local).unwrap();
+
+        let block = chain.block_at(1.into()).unwrap();
+        let header = block.header().unwrap();
+        assert_eq!(header.version, BlockHeader::VERSION);
+        assert_eq!(header.metadata, metadata);
+        assert_eq!(header.prev_hash, Default::default());
+        assert_eq!(header, local.header(&header.prev_hash, 1.into()));
+        assert_eq!(
+            header.seal_hash(),
+            crate::hash_header(&local, &header.prev_hash, &1.into())
+        );
+
+        let bytes = header.to_bytes().unwrap();
+        let decoded = BlockHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.hash(), header.hash());
+
+        let mut altered = header.clone();
+        altered.metadata.pop();
+        assert_ne!(altered.hash(), header.hash());
+
+        // the seal hash covers every field but the nonce
+        let mut altered = header.clone();
+        altered.state_root = crate::sha(&"state");
+        assert_ne!(altered.seal_hash(), header.seal_hash());
+        let mut altered = header.clone();
+        altered.nonce.nonce += 1;
+        assert_eq!(altered.seal_hash(), header.seal_hash());
+        assert_ne!(altered.hash(), header.hash());
+        let mut altered = header.clone();
+        altered.producer = None;
+        assert_ne!(altered.seal_hash(), header.seal_hash());
+        assert_ne!(altered.hash(), header.hash());
+        let mut altered = header.clone();
+        altered.receipts_root = crate::sha(&"receipts");
+        assert_ne!(altered.seal_hash(), header.seal_hash());
+        assert_ne!(altered.hash(), header.hash());
+        assert!(BlockHeader::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -1,12 +1,16 @@
+use serde::Serialize;
+
 use crate::{
     block::UnchainedInstance,
     data::Position,
     error::{DataBaseError, SerdeError},
+    snapshot::SnapshotError,
 };
 
 use super::{
-    block::{ChainedInstance, BlockError, PositionInstance},
+    block::{BlockData, BlockError, ChainedInstance, PositionInstance},
     record::Record,
+    registry::RecordErrorKind,
 };
 
 /// The types of error that can occur in operations associated with the `Chain` trait
@@ -15,6 +19,15 @@ pub enum ChainError {
     SerdeError(SerdeError),
     DataBaseError(DataBaseError),
     AbsentValue,
+    /// The block was rejected because the given part of it is not valid.
+    NotValid(BlockData),
+    /// The operation would remove a finalized block.
+    Finalized,
+    /// The records of the block have been pruned.
+    Pruned,
+    /// A record of the block could not be decoded into the record type of the chain. The
+    /// details are kept by the `BlockError` it was raised as.
+    RecordError(RecordErrorKind),
     Unspecified,
 }
 
@@ -24,7 +37,10 @@ impl From<BlockError> for ChainError {
             BlockError::SerdeError(v) => ChainError::SerdeError(v),
             BlockError::DataBaseError(u) => ChainError::DataBaseError(u),
             BlockError::Unspecified => ChainError::Unspecified,
-            BlockError::NotValid(_) => unimplemented!(),
+            BlockError::NotValid(d) => ChainError::NotValid(d),
+            BlockError::Pruned => ChainError::Pruned,
+            BlockError::Finalized => ChainError::Finalized,
+            BlockError::RecordError(e) => ChainError::RecordError(e.kind()),
         }
     }
 }
@@ -66,6 +82,19 @@ pub trait Chain<R: Record>: Sized {
 
     fn len(&self) -> Result<u64, ChainError>;
 
+    /// Removes every block after `pos`, making the block at `pos` the last block of the chain.
+    ///
+    /// Fails with `ChainError::Finalized` if a finalized block would be removed. Chains that
+    /// cannot remove blocks keep the default, which fails with `ChainError::Unspecified`.
+    fn rollback(&mut self, _pos: Position) -> Result<(), ChainError> {
+        Err(ChainError::Unspecified)
+    }
+
+    /// Returns the position of the last finalized block, or `0` if no block is finalized.
+    fn finalized_height(&self) -> Result<u64, ChainError> {
+        Ok(0)
+    }
+
     fn last_block(&self) -> Result<Option<Self::ChainedInstanceType>, ChainError> {
         let last = match self.len()? {
             0 => return Ok(None),
@@ -74,4 +103,15 @@ pub trait Chain<R: Record>: Sized {
 
         self.block_at(last).map(|value| Some(value))
     }
+
+    /// Writes every block of the chain to `writer` as a compressed, checksummed and
+    /// versioned snapshot (see `crate::snapshot`).
+    ///
+    /// Fails with `ChainError::Pruned` if the records of a block were pruned.
+    fn export_snapshot<W: std::io::Write>(&self, writer: W) -> Result<(), SnapshotError>
+    where
+        R: Serialize + Clone,
+    {
+        crate::snapshot::export(self, writer).map(|_| ())
+    }
 }
@@ -0,0 +1,279 @@
+//! Chain specifications.
+//!
+//! A `ChainSpec` fixes everything about the first block of a chain: the chain ID, the
+//! records it starts with, the consensus parameters and the genesis timestamp. Every node
+//! that builds a chain from the same spec gets the same genesis block and the same genesis
+//! hash, so the genesis hash identifies the chain.
+//!
+//! The genesis block is built at the header version the spec names, and headers are hashed
+//! over the fields of their own version (see `BlockHeader::hash`), so the genesis hash of a
+//! spec does not change when the crate raises its header version.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockHeader, LocalInstance},
+    consensus::retarget::Retarget,
+    data::{Detail, Metadata, Position, Target, Timestamp},
+    error::SerdeError,
+    record::{Record, SignedRecord},
+    Hash, PublicKey,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpecError {
+    /// The spec file could not be read.
+    NoSuchFile,
+    SerdeError(SerdeError),
+    /// One of the initial records is not correctly signed.
+    VerificationFailed,
+}
+
+crate::impl_display_error!(SpecError);
+
+/// The consensus parameters a chain starts with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusParams {
+    /// The target of the genesis block.
+    #[serde(default)]
+    pub target: Target,
+    /// How the target of the blocks after the genesis block is derived, if they carry
+    /// proof-of-work.
+    #[serde(default)]
+    pub retarget: Option<Retarget>,
+    /// The keys allowed to seal or finalize blocks, if the chain uses authorities.
+    #[serde(default)]
+    pub authorities: Vec<PublicKey>,
+}
+
+/// The description of a chain, from which its genesis block is built.
+///
+/// A spec can be written to and read from JSON or TOML.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainSpec<R> {
+    pub chain_id: String,
+    pub timestamp: Timestamp,
+    #[serde(default)]
+    pub consensus: ConsensusParams,
+    /// The records of the genesis block, such as initial balances or authorities.
+    #[serde(default = "Vec::new")]
+    pub records: Vec<SignedRecord<R>>,
+    /// The header version of the genesis block. Specs that name none are built at the
+    /// current `BlockHeader::VERSION`; `to_json` and `to_toml` write it out.
+    #[serde(default = "genesis_version")]
+    pub header_version: u32,
+}
+
+fn genesis_version() -> u32 {
+    BlockHeader::VERSION
+}
+
+impl<R> ChainSpec<R> {
+    pub fn new(chain_id: &str, timestamp: Timestamp) -> Self {
+        Self {
+            chain_id: chain_id.to_owned(),
+            timestamp,
+            consensus: ConsensusParams::default(),
+            records: vec![],
+            header_version: BlockHeader::VERSION,
+        }
+    }
+
+    pub fn with_consensus(mut self, consensus: ConsensusParams) -> Self {
+        self.consensus = consensus;
+        self
+    }
+
+    pub fn with_record(mut self, record: SignedRecord<R>) -> Self {
+        self.records.push(record);
+        self
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+}
+
+impl<R: Clone> ChainSpec<R> {
+    /// Builds the genesis block of this spec.
+    ///
+    /// The chain ID and the consensus parameters are committed to in the block's metadata,
+    /// so two specs that differ in any way have different genesis hashes.
+    pub fn genesis(&self) -> LocalInstance<R> {
+        let mut metadata = Metadata::new();
+        metadata.push(Detail::Text(self.chain_id.clone()));
+        metadata.push(Detail::Bytes(
+            crate::hash(&self.consensus).as_bytes().into(),
+        ));
+
+        let mut block = LocalInstance::new(metadata, 0);
+        block.target = self.consensus.target;
+        block.timestamp = self.timestamp;
+        for record in &self.records {
+            block.push(record.clone());
+        }
+        block
+    }
+
+    /// Returns the header of the genesis block, which is the first block of the chain.
+    pub fn genesis_header(&self) -> BlockHeader {
+        self.genesis_header_at(self.header_version)
+    }
+
+    /// Returns the header the genesis block has when it is built at header version `version`.
+    pub fn genesis_header_at(&self, version: u32) -> BlockHeader {
+        let mut header = self.genesis().header(&Hash::default(), Position::new(1));
+        header.version = version;
+        header
+    }
+}
+
+impl<R: Clone + Serialize> ChainSpec<R> {
+    pub fn genesis_hash(&self) -> Hash {
+        self.genesis_hash_at(self.header_version)
+    }
+
+    /// Returns the hash the genesis block has when it is built at header version `version`,
+    /// against which a chain started at that version is checked.
+    pub fn genesis_hash_at(&self, version: u32) -> Hash {
+        let header = self.genesis_header_at(version);
+        if version > BlockHeader::LEGACY_VERSION {
+            return header.hash();
+        }
+        crate::hash_block_parts(
+            &self.records,
+            &header.prev_hash,
+            &header.merkle_root,
+            &header.timestamp,
+            &header.position,
+        )
+    }
+}
+
+impl<R: Record> ChainSpec<R> {
+    /// Checks that every initial record is signed by its signer.
+    pub fn verify(&self) -> Result<(), SpecError> {
+        for record in &self.records {
+            if record.verify().is_err() || &record.record().hash() != record.hash() {
+                return Err(SpecError::VerificationFailed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Serialize + for<'a> Deserialize<'a>> ChainSpec<R> {
+    pub fn from_json(json: &str) -> Result<Self, SpecError> {
+        serde_json::from_str(json)
+            .map_err(|_| SpecError::SerdeError(SerdeError::DeserializationError))
+    }
+
+    pub fn to_json(&self) -> Result<String, SpecError> {
+        serde_json::to_string_pretty(self)
+            .map_err(|_| SpecError::SerdeError(SerdeError::SerializationError))
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, SpecError> {
+        toml::from_str(toml).map_err(|_| SpecError::SerdeError(SerdeError::DeserializationError))
+    }
+
+    pub fn to_toml(&self) -> Result<String, SpecError> {
+        toml::to_string(self).map_err(|_| SpecError::SerdeError(SerdeError::SerializationError))
+    }
+
+    /// Reads the spec at `path`, as TOML if the file has a `.toml` extension and as JSON
+    /// otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SpecError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|_| SpecError::NoSuchFile)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml(&contents),
+            _ => Self::from_json(&contents),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, consensus::retarget::Retarget};
+
+    use blockify::{
+        block::BlockHeader,
+        data::{Metadata, Target, Timestamp},
+        genesis::{ChainSpec, ConsensusParams},
+        record::Record,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    struct Balance {
+        owner: String,
+        amount: u64,
+    }
+
+    fn spec() -> ChainSpec<Balance> {
+        let keypair = crate::generate_ed25519_keypair();
+        let consensus = ConsensusParams {
+            target: Target::from_difficulty(4),
+            retarget: Some(Retarget::epoch(Target::from_difficulty(4), 10, 60)),
+            authorities: vec![keypair.clone().into_public_key()],
+        };
+        let mut spec = ChainSpec::new("testnet", Timestamp::from_secs(1_700_000_000))
+            .with_consensus(consensus);
+        for (owner, amount) in [("alice", 100), ("bob", 50)] {
+            let balance = Balance {
+                owner: owner.to_owned(),
+                amount,
+            };
+            spec = spec.with_record(balance.record(keypair.clone(), Metadata::empty()).unwrap());
+        }
+        spec
+    }
+
+    #[test]
+    fn test_formats() {
+        let spec = spec();
+        spec.verify().unwrap();
+
+        let json = ChainSpec::<Balance>::from_json(&spec.to_json().unwrap()).unwrap();
+        let toml = ChainSpec::<Balance>::from_toml(&spec.to_toml().unwrap()).unwrap();
+        assert_eq!(json, spec);
+        assert_eq!(toml, spec);
+        assert_eq!(json.genesis_hash(), spec.genesis_hash());
+        assert_eq!(toml.genesis_hash(), spec.genesis_hash());
+    }
+
+    #[test]
+    fn test_genesis_hash() {
+        let spec = spec();
+        assert_eq!(spec.genesis_hash(), spec.clone().genesis_hash());
+
+        let mut other = spec.clone();
+        other.chain_id = "mainnet".to_owned();
+        assert_ne!(other.genesis_hash(), spec.genesis_hash());
+
+        let mut other = spec.clone();
+        other.consensus.retarget = None;
+        assert_ne!(other.genesis_hash(), spec.genesis_hash());
+
+        let mut other = spec.clone();
+        other.records.pop();
+        assert_ne!(other.genesis_hash(), spec.genesis_hash());
+
+        let mut other = spec.clone();
+        other.timestamp = Timestamp::from_secs(1_700_000_001);
+        assert_ne!(other.genesis_hash(), spec.genesis_hash());
+
+        // the genesis block is built at the version of the spec, not the current one
+        assert_eq!(spec.genesis_header().version, BlockHeader::VERSION);
+        let mut other = spec.clone();
+        other.header_version = BlockHeader::LEGACY_VERSION;
+        assert_eq!(
+            other.genesis_hash(),
+            spec.genesis_hash_at(BlockHeader::LEGACY_VERSION)
+        );
+        assert_ne!(other.genesis_hash(), spec.genesis_hash());
+    }
+}
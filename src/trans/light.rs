@@ -0,0 +1,414 @@
+//! Light clients.
+//!
+//! A `HeaderChain` keeps only the headers of the blocks of a chain, a few hundred bytes
+//! per block. It checks that every header links to the one before it and carries a valid
+//! proof-of-work or seal, and it can then check that a record was included in a block
+//! from a `MerkleProof` supplied by a full node, or that the state after a block holds a
+//! value from a `SparseProof`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{BlockData, BlockError, BlockHeader, ChainedInstance, Seal},
+    chain::{Chain, ChainError},
+    consensus::{
+        poa::AuthoritySet,
+        puzzles::{ConsensusPuzzle, WorkPuzzle},
+        retarget::Retarget,
+    },
+    data::Position,
+    genesis::ChainSpec,
+    merkle::{MerkleProof, MerkleTree},
+    record::Record,
+    sparse_merkle::SparseProof,
+    Hash,
+};
+
+/// How a `HeaderChain` checks the consensus proof of a header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SealRule {
+    /// Headers are only checked for linkage.
+    None,
+    /// Every header must carry the target the retarget requires after the headers before
+    /// it, and a nonce that solves the work puzzle for that target.
+    Work(Retarget),
+    /// Every header must be sealed by the authority in turn for its position.
+    ///
+    /// A light client does not see the records that change the authority set, so the
+    /// set is fixed.
+    Authorities(AuthoritySet),
+}
+
+impl SealRule {
+    /// Returns `true` if `header`, sealed with `seal`, satisfies this rule.
+    ///
+    /// The target of a header is not checked here, since it depends on the headers before
+    /// it; `HeaderChain::append` checks it.
+    pub fn check(&self, header: &BlockHeader, seal: Option<&Seal>) -> bool {
+        match self {
+            SealRule::None => true,
+            SealRule::Work(_) => {
+                WorkPuzzle::new(header.target, header.seal_hash()).verify(header.nonce)
+            }
+            SealRule::Authorities(set) => match seal {
+                Some(seal) => {
+                    set.in_turn(header.position) == Some(seal.signer())
+                        && seal.verify(&header.seal_hash()).is_ok()
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    header: BlockHeader,
+    seal: Option<Seal>,
+    hash: Hash,
+}
+
+/// A chain of block headers without their records.
+///
+/// A `HeaderChain` can be serialized to persist it between runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderChain {
+    rule: SealRule,
+    entries: Vec<Entry>,
+}
+
+impl HeaderChain {
+    /// Creates an empty header chain whose headers are checked with `rule`.
+    pub fn new(rule: SealRule) -> Self {
+        Self {
+            rule,
+            entries: vec![],
+        }
+    }
+
+    /// Creates a header chain that starts with the genesis header of `spec`.
+    ///
+    /// Proof-of-work is required if the spec has a retarget, and seals by its authorities
+    /// if it has any.
+    pub fn from_spec<R: Clone + Serialize>(spec: &ChainSpec<R>) -> Self {
+        let rule = if !spec.consensus.authorities.is_empty() {
+            SealRule::Authorities(AuthoritySet::new(spec.consensus.authorities.clone()))
+        } else if let Some(retarget) = spec.consensus.retarget {
+            SealRule::Work(retarget)
+        } else {
+            SealRule::None
+        };
+        Self {
+            rule,
+            entries: vec![Entry {
+                hash: spec.genesis_hash(),
+                header: spec.genesis_header(),
+                seal: None,
+            }],
+        }
+    }
+
+    pub fn rule(&self) -> &SealRule {
+        &self.rule
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the header of the block at `pos`.
+    pub fn header_at(&self, pos: Position) -> Option<&BlockHeader> {
+        self.entry(pos).map(|e| &e.header)
+    }
+
+    /// Returns the hash of the block at `pos`.
+    pub fn hash_at(&self, pos: Position) -> Option<&Hash> {
+        self.entry(pos).map(|e| &e.hash)
+    }
+
+    /// Returns the header of the last block.
+    pub fn tip(&self) -> Option<&BlockHeader> {
+        self.entries.last().map(|e| &e.header)
+    }
+
+    /// Returns the hash of the last block, or `Hash::default()` if the chain is empty.
+    pub fn tip_hash(&self) -> Hash {
+        self.entries
+            .last()
+            .map(|e| e.hash.clone())
+            .unwrap_or_default()
+    }
+
+    fn entry(&self, pos: Position) -> Option<&Entry> {
+        self.entries.get(pos.pos.checked_sub(1)? as usize)
+    }
+
+    /// Appends the header of the next block.
+    ///
+    /// The header must be at the next position, name the hash of the last header as its
+    /// previous hash and satisfy the seal rule of this chain, carrying the target it
+    /// requires if it is `SealRule::Work`.
+    pub fn append(
+        &mut self,
+        header: BlockHeader,
+        seal: Option<Seal>,
+    ) -> Result<Position, ChainError> {
+        // the hash of older blocks cannot be computed from their header
+        if header.version <= BlockHeader::LEGACY_VERSION {
+            return Err(ChainError::NotValid(BlockData::Hash));
+        }
+        if header.position.pos != self.len() + 1 {
+            return Err(ChainError::NotValid(BlockData::Position));
+        }
+        if header.prev_hash != self.tip_hash() {
+            return Err(ChainError::NotValid(BlockData::PrevHash));
+        }
+        if let SealRule::Work(retarget) = &self.rule {
+            let lookback = retarget.lookback() as usize;
+            let recent = self.entries[self.entries.len().saturating_sub(lookback)..]
+                .iter()
+                .map(|e| (e.header.timestamp, e.header.target))
+                .collect::<Vec<_>>();
+            if header.target != retarget.required(header.position, &recent) {
+                return Err(ChainError::NotValid(BlockData::Target));
+            }
+        }
+        if !self.rule.check(&header, seal.as_ref()) {
+            return Err(match self.rule {
+                SealRule::Work(_) => ChainError::NotValid(BlockData::Nonce),
+                _ => ChainError::NotValid(BlockData::Seal),
+            });
+        }
+
+        let position = header.position;
+        self.entries.push(Entry {
+            hash: header.hash(),
+            header,
+            seal,
+        });
+        Ok(position)
+    }
+
+    /// Removes every header after `pos`.
+    pub fn rollback(&mut self, pos: Position) {
+        self.entries.truncate(pos.pos as usize);
+    }
+
+    /// Follows `chain`, returning the number of headers appended.
+    ///
+    /// Headers that are no longer in `chain` because it switched to another branch are
+    /// removed first. The first header is never removed: a `chain` whose first block is
+    /// another one is another chain, and fails with `ChainError::NotValid(BlockData::Hash)`.
+    pub fn sync<R: Record, C: Chain<R>>(&mut self, chain: &C) -> Result<u64, ChainError> {
+        let mut common = self.len().min(chain.len()?);
+        while common > 0 {
+            let block = chain.block_at(common.into())?;
+            if Some(&block.header()?.hash()) == self.hash_at(common.into()) {
+                break;
+            }
+            if common == 1 {
+                return Err(ChainError::NotValid(BlockData::Hash));
+            }
+            common -= 1;
+        }
+        self.rollback(common.max(1).into());
+
+        let start = self.len();
+        for pos in start + 1..=chain.len()? {
+            let block = chain.block_at(pos.into())?;
+            self.append(block.header()?, block.seal()?)?;
+        }
+        Ok(self.len() - start)
+    }
+
+    /// Returns `true` if `proof` shows that the record with hash `record_hash` is in the
+    /// block at `pos`.
+    pub fn verify_record(&self, pos: Position, record_hash: &Hash, proof: &MerkleProof) -> bool {
+        match self.header_at(pos) {
+            Some(header) => proof.verify(record_hash, &header.merkle_root),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if `proof` shows that `key` holds `value` in the state after the block
+    /// at `pos`, or holds no value if `value` is `None`.
+    pub fn verify_state(
+        &self,
+        pos: Position,
+        key: &Hash,
+        value: Option<&[u8]>,
+        proof: &SparseProof,
+    ) -> bool {
+        match self.header_at(pos) {
+            Some(header) => proof.verify(key, value, &header.state_root),
+            None => false,
+        }
+    }
+}
+
+/// Returns `true` if `proof` shows that `key` holds `value` in the state after `block`, or
+/// holds no value if `value` is `None`.
+pub fn verify_state<R: Record, B: ChainedInstance<R>>(
+    block: &B,
+    key: &Hash,
+    value: Option<&[u8]>,
+    proof: &SparseProof,
+) -> Result<bool, BlockError> {
+    Ok(proof.verify(key, value, &block.state_root()?))
+}
+
+/// Builds the proof that the record at `index` of `block` is part of it, for a
+/// `HeaderChain` to check.
+///
+/// Returns `None` if there is no record at `index`.
+pub fn record_proof<R: Record, B: ChainedInstance<R>>(
+    block: &B,
+    index: usize,
+) -> Result<Option<MerkleProof>, BlockError> {
+    let records = block.records()?;
+    let tree = MerkleTree::from_hashes(records.iter().map(|r| r.hash()));
+    Ok(tree.proof(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance, inner::test_dir};
+
+    use blockify::{
+        block::{BlockData, ChainedInstance, Seal},
+        chain::{Chain, ChainError},
+        consensus::{poa::AuthoritySet, retarget::Retarget},
+        data::{Metadata, Position, Target, Timestamp},
+        genesis::ChainSpec,
+        light::{record_proof, HeaderChain, SealRule},
+        record::Record,
+        SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Payment {
+        to: String,
+        amount: u64,
+    }
+
+    fn block(amounts: &[u64]) -> LocalInstance<Payment> {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        for amount in amounts {
+            let payment = Payment {
+                to: "alice".to_owned(),
+                amount: *amount,
+            };
+            block.push(payment.record(keypair.clone(), Metadata::empty()).unwrap());
+        }
+        block
+    }
+
+    #[test]
+    fn test_sync_and_proofs() {
+        let url = test_dir("headerchain");
+        let spec = ChainSpec::new("light", Timestamp::from_secs(1_700_000_000));
+        let mut chain = SqliteChain::open(&url, &spec).unwrap();
+        chain.append(&block(&[1, 2, 3])).unwrap();
+        chain.append(&block(&[4, 5, 6, 7, 8])).unwrap();
+
+        let mut headers = HeaderChain::from_spec(&spec);
+        assert_eq!(headers.sync(&chain).unwrap(), 2);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(
+            &headers.tip_hash(),
+            &chain.block_at(3.into()).unwrap().hash().unwrap()
+        );
+
+        let full = chain.block_at(3.into()).unwrap();
+        let records = full.records().unwrap();
+        let proof = record_proof(&full, 3).unwrap().unwrap();
+        assert!(headers.verify_record(3.into(), records[3].hash(), &proof));
+        assert!(!headers.verify_record(3.into(), records[2].hash(), &proof));
+        assert!(!headers.verify_record(2.into(), records[3].hash(), &proof));
+        assert!(record_proof(&full, 5).unwrap().is_none());
+
+        // the full chain switches to another branch
+        chain.rollback(2.into()).unwrap();
+        chain.append(&block(&[9])).unwrap();
+        chain.append(&block(&[10])).unwrap();
+        assert_eq!(headers.sync(&chain).unwrap(), 2);
+        assert_eq!(headers.len(), 4);
+        assert_eq!(
+            &headers.tip_hash(),
+            &chain.block_at(4.into()).unwrap().hash().unwrap()
+        );
+        assert!(!headers.verify_record(3.into(), records[3].hash(), &proof));
+
+        // a header that does not link to the tip is rejected
+        let tip = headers.tip().unwrap().clone();
+        let mut header = tip.clone();
+        header.position = Position::new(5);
+        assert!(matches!(
+            headers.append(header.clone(), None),
+            Err(ChainError::NotValid(BlockData::PrevHash))
+        ));
+        header.prev_hash = headers.tip_hash();
+        headers.append(header, None).unwrap();
+
+        // a chain with another genesis block is not followed
+        let other = ChainSpec::<Payment>::new("other", Timestamp::from_secs(1_700_000_000));
+        let mut headers = HeaderChain::from_spec(&other);
+        assert!(matches!(
+            headers.sync(&chain),
+            Err(ChainError::NotValid(BlockData::Hash))
+        ));
+        assert_eq!(headers.tip_hash(), other.genesis_hash());
+    }
+
+    #[test]
+    fn test_seal_rules() {
+        let keypair = crate::generate_ed25519_keypair();
+        let other = crate::generate_ed25519_keypair();
+        let rule =
+            SealRule::Authorities(AuthoritySet::new(vec![keypair.clone().into_public_key()]));
+        let mut headers = HeaderChain::new(rule);
+
+        let header = block(&[1]).header(&Default::default(), Position::new(1));
+        let bad = Seal::sign(&header.seal_hash(), &other).unwrap();
+        assert!(headers.append(header.clone(), None).is_err());
+        assert!(headers.append(header.clone(), Some(bad)).is_err());
+        let seal = Seal::sign(&header.seal_hash(), &keypair).unwrap();
+        // the seal covers every field of the header but the nonce
+        let mut tampered = header.clone();
+        tampered.state_root = crate::sha(&"state");
+        assert!(headers.append(tampered, Some(seal.clone())).is_err());
+        let mut tampered = header.clone();
+        tampered.timestamp = Timestamp::from_secs(1);
+        assert!(headers.append(tampered, Some(seal.clone())).is_err());
+        headers.append(header, Some(seal)).unwrap();
+
+        // headers must carry the target of the retarget, which the nonce must solve
+        let target = Target::from_difficulty(255);
+        let mut hard = block(&[1]);
+        hard.target = target;
+        let hard = hard.header(&Default::default(), Position::new(1));
+        let easy = block(&[1]).header(&Default::default(), Position::new(1));
+        let mut headers = HeaderChain::new(SealRule::Work(Retarget::fixed(target)));
+        assert!(matches!(
+            headers.append(easy.clone(), None),
+            Err(ChainError::NotValid(BlockData::Target))
+        ));
+        assert!(matches!(
+            headers.append(hard.clone(), None),
+            Err(ChainError::NotValid(BlockData::Nonce))
+        ));
+        // with the easiest target every nonce solves the work puzzle
+        let mut headers = HeaderChain::new(SealRule::Work(Retarget::fixed(Target::MAX)));
+        assert!(matches!(
+            headers.append(hard, None),
+            Err(ChainError::NotValid(BlockData::Target))
+        ));
+        headers.append(easy, None).unwrap();
+    }
+}
@@ -1,9 +1,21 @@
+pub mod any;
+
 pub mod block;
 
 pub mod chain;
 
+pub mod genesis;
+
+pub mod light;
+
+pub mod receipt;
+
 pub mod record;
 
+pub mod registry;
+
+pub mod snapshot;
+
 
 mod sqlite;
 
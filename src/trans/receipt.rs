@@ -0,0 +1,74 @@
+//! Receipts.
+//!
+//! Applying a record to the state of a chain leaves a `Receipt`: whether it succeeded,
+//! the gas it used and the logs it emitted. The receipts of a block are stored with its
+//! records and committed into its header by `receipts_root`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{data::Position, merkle::MerkleTree, Hash};
+
+/// Whether applying a record succeeded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    Success,
+    /// The record was included but had no effect, for the given reason.
+    Failed(String),
+}
+
+/// Something reported while a record was applied, which can be looked up by topic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Log {
+    /// What emitted the log, such as the address of a contract.
+    pub source: Hash,
+    pub topics: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+/// The outcome of applying a record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// The hash of the record.
+    pub record: Hash,
+    pub status: Status,
+    pub gas_used: u64,
+    pub logs: Vec<Log>,
+}
+
+impl Receipt {
+    /// Returns the receipt of the record with hash `record` that succeeded without gas or
+    /// logs.
+    pub fn success(record: &Hash) -> Self {
+        Self {
+            record: record.clone(),
+            status: Status::Success,
+            gas_used: 0,
+            logs: vec![],
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == Status::Success
+    }
+
+    /// Returns the hash of this receipt, the leaf of the receipts root committing to it.
+    pub fn hash(&self) -> Hash {
+        crate::hash(self)
+    }
+}
+
+/// Returns the Merkle root of `receipts`, or `Hash::default()` if there are none.
+pub fn receipts_root(receipts: &[Receipt]) -> Hash {
+    let hashes = receipts.iter().map(Receipt::hash).collect::<Vec<_>>();
+    MerkleTree::from_hashes(&hashes).root().clone()
+}
+
+/// A log found in a chain, with where it was emitted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// The position of the block holding the record.
+    pub position: Position,
+    /// The hash of the record that emitted the log.
+    pub record: Hash,
+    pub log: Log,
+}
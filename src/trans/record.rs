@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    data::Metadata, AuthKeyPair, DigitalSignature, Hash, KeyPairAlgorithm, PublicKey, SigningError,
+    data::{Metadata, MetadataRecord, SchemaError},
+    AuthKeyPair, DigitalSignature, Hash, KeyPairAlgorithm, PublicKey, SigningError,
     VerificationError,
 };
 
@@ -86,14 +87,15 @@ pub trait Record: Sized {
 }
 
 // This macro is not exported in favor of the derive macro Record which is also in this module.
+// Records defined inside this crate use it because the derive macro names `blockify`.
 macro_rules! impl_record_for {
     ($type:ty) => {
-        impl Record for $type {
+        impl crate::record::Record for $type {
             fn sign(
                 &self,
                 key: &crate::AuthKeyPair,
             ) -> Result<crate::DigitalSignature, crate::SigningError> {
-                let msg = crate::serialize(self).map_err(|e| SigningError::SerdeError(e))?;
+                let msg = crate::serialize(self).map_err(|e| crate::SigningError::SerdeError(e))?;
                 let signature = crate::sign_msg(&msg, key)?;
                 Ok(signature)
             }
@@ -131,6 +133,8 @@ macro_rules! impl_record_for {
     };
 }
 
+pub(crate) use impl_record_for;
+
 impl_record_for!(String);
 impl_record_for!(bool);
 impl_record_for!(i64);
@@ -267,6 +271,13 @@ impl<R: Record> SignedRecord<R> {
     }
 }
 
+impl<R: MetadataRecord> SignedRecord<R> {
+    /// Validates the `Metadata` of this `SignedRecord` instance against the schema of `R`.
+    pub fn check_metadata(&self) -> Result<(), SchemaError> {
+        R::metadata_schema().validate(&self.metadata)
+    }
+}
+
 impl<R> AsRef<R> for SignedRecord<R> {
     fn as_ref(&self) -> &R {
         self.record()
@@ -0,0 +1,429 @@
+//! Record versions.
+//!
+//! Every record a `SqliteChain` stores is tagged with the name and version of its type,
+//! taken from the chain's `RecordRegistry`. When the type of a chain changes, the version
+//! in its registry is raised and an upgrader is registered for the previous version.
+//! Records stored under older versions are then migrated into the current type as they
+//! are read. Chains without a registry store their records untagged and read records
+//! of any type name at the legacy version.
+//!
+//! Upgraders work on the JSON form of the record. An upgraded record keeps the hash and
+//! signature of the record that was signed, so its Merkle proofs still hold but `verify`
+//! fails on it. Snapshots carry upgraded records as they are stored (see `StoredRecord`)
+//! so that they can be checked when imported.
+
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::record::SignedRecord;
+
+/// An error that can occur when decoding a stored record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    /// The record was stored under a type name the registry does not know.
+    UnknownType(String),
+    /// The record was stored under a version newer than the registry's.
+    UnsupportedVersion(u32),
+    /// No upgrader migrates records from the given version.
+    MissingUpgrader(u32),
+    /// The upgrader from `version` rejected the record.
+    UpgradeFailed { version: u32, reason: String },
+    /// The record does not deserialize into the current type.
+    Malformed(String),
+}
+
+crate::impl_display_error!(RecordError);
+
+/// The kind of a `RecordError`, without its details, as reported by `ChainError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordErrorKind {
+    UnknownType,
+    UnsupportedVersion(u32),
+    MissingUpgrader(u32),
+    UpgradeFailed(u32),
+    Malformed,
+}
+
+impl RecordError {
+    pub fn kind(&self) -> RecordErrorKind {
+        match self {
+            RecordError::UnknownType(_) => RecordErrorKind::UnknownType,
+            RecordError::UnsupportedVersion(v) => RecordErrorKind::UnsupportedVersion(*v),
+            RecordError::MissingUpgrader(v) => RecordErrorKind::MissingUpgrader(*v),
+            RecordError::UpgradeFailed { version, .. } => RecordErrorKind::UpgradeFailed(*version),
+            RecordError::Malformed(_) => RecordErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<RecordErrorKind> for RecordError {
+    /// Restores the error of the given kind, with empty details.
+    fn from(kind: RecordErrorKind) -> Self {
+        match kind {
+            RecordErrorKind::UnknownType => RecordError::UnknownType(String::new()),
+            RecordErrorKind::UnsupportedVersion(v) => RecordError::UnsupportedVersion(v),
+            RecordErrorKind::MissingUpgrader(v) => RecordError::MissingUpgrader(v),
+            RecordErrorKind::UpgradeFailed(version) => RecordError::UpgradeFailed {
+                version,
+                reason: String::new(),
+            },
+            RecordErrorKind::Malformed => RecordError::Malformed(String::new()),
+        }
+    }
+}
+
+/// The type name and version a record was stored under.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordTag {
+    pub type_name: String,
+    pub version: u32,
+}
+
+/// A record in the form it is stored, under the tag it was stored with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredRecord {
+    pub tag: Option<RecordTag>,
+    pub json: String,
+}
+
+/// Migrates the JSON form of a record to the next version of its type.
+pub type Upgrader = Box<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+/// The name and version of the record type of a chain, and the upgraders from its older
+/// versions.
+///
+/// # Examples
+///
+/// ```
+/// use blockify::registry::RecordRegistry;
+///
+/// #[derive(serde::Deserialize)]
+/// struct Note {
+///     text: String,
+///     pinned: bool,
+/// }
+///
+/// // version 1 of `Note` had no `pinned` field
+/// let registry = RecordRegistry::<Note>::new("note", 2).with_upgrader(1, |mut note| {
+///     note["pinned"] = false.into();
+///     Ok(note)
+/// });
+/// assert_eq!(registry.version(), 2);
+/// ```
+pub struct RecordRegistry<X> {
+    type_name: String,
+    aliases: Vec<String>,
+    version: u32,
+    upgraders: BTreeMap<u32, Upgrader>,
+    _data: PhantomData<fn() -> X>,
+}
+
+impl<X> RecordRegistry<X> {
+    /// The version of records stored before records were tagged.
+    pub const LEGACY_VERSION: u32 = 1;
+
+    /// Creates a registry storing records under `type_name` at `version`. Records are
+    /// stored untagged if `type_name` is empty.
+    pub fn new(type_name: impl Into<String>, version: u32) -> Self {
+        Self {
+            type_name: type_name.into(),
+            aliases: vec![],
+            version: version.max(Self::LEGACY_VERSION),
+            upgraders: BTreeMap::new(),
+            _data: PhantomData,
+        }
+    }
+
+    /// Registers `upgrader` to migrate records from version `from` to version `from + 1`.
+    pub fn with_upgrader<F>(mut self, from: u32, upgrader: F) -> Self
+    where
+        F: Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.upgraders.insert(from, Box::new(upgrader));
+        self
+    }
+
+    /// Also reads records stored under `type_name`, such as a former name of the type.
+    pub fn with_alias(mut self, type_name: impl Into<String>) -> Self {
+        self.aliases.push(type_name.into());
+        self
+    }
+
+    pub fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the other type names records of this type are read under.
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    /// Returns the tag new records are stored under, or `None` if the registry has no
+    /// type name.
+    pub fn tag(&self) -> Option<RecordTag> {
+        (!self.type_name.is_empty()).then(|| RecordTag {
+            type_name: self.type_name.clone(),
+            version: self.version,
+        })
+    }
+
+    /// Returns `true` if records stored under `tag` are upgraded as they are read.
+    pub fn is_upgraded(&self, tag: Option<&RecordTag>) -> bool {
+        tag.map_or(Self::LEGACY_VERSION, |tag| tag.version) < self.version
+    }
+
+    /// Returns `true` if records stored under `type_name` are of this type. A registry
+    /// without a type name accepts any.
+    fn accepts(&self, type_name: &str) -> bool {
+        self.type_name.is_empty()
+            || type_name == self.type_name
+            || self.aliases.iter().any(|alias| alias == type_name)
+    }
+
+    /// Migrates the JSON form of a record stored under `tag` to the current version.
+    pub fn upgrade(&self, tag: &RecordTag, mut record: Value) -> Result<Value, RecordError> {
+        if !self.accepts(&tag.type_name) {
+            return Err(RecordError::UnknownType(tag.type_name.clone()));
+        }
+        if tag.version > self.version {
+            return Err(RecordError::UnsupportedVersion(tag.version));
+        }
+        for version in tag.version..self.version {
+            let upgrader = self
+                .upgraders
+                .get(&version)
+                .ok_or(RecordError::MissingUpgrader(version))?;
+            record = upgrader(record)
+                .map_err(|reason| RecordError::UpgradeFailed { version, reason })?;
+        }
+        Ok(record)
+    }
+}
+
+impl<X: DeserializeOwned> RecordRegistry<X> {
+    /// Decodes the JSON form of a `SignedRecord` stored under `tag`, upgrading its record
+    /// to the current version.
+    ///
+    /// Records stored without a tag are read as the legacy version of this type.
+    pub fn decode(
+        &self,
+        tag: Option<&RecordTag>,
+        json: &str,
+    ) -> Result<SignedRecord<X>, RecordError> {
+        let malformed = |e: serde_json::Error| RecordError::Malformed(e.to_string());
+        let legacy = RecordTag {
+            type_name: self.type_name.clone(),
+            version: Self::LEGACY_VERSION,
+        };
+        let tag = tag.unwrap_or(&legacy);
+        if tag.version == self.version && self.accepts(&tag.type_name) {
+            return serde_json::from_str(json).map_err(malformed);
+        }
+        let mut value = serde_json::from_str::<Value>(json).map_err(malformed)?;
+        let record = value
+            .get_mut("record")
+            .ok_or_else(|| RecordError::Malformed("missing record".to_owned()))?;
+        *record = self.upgrade(tag, record.take())?;
+        serde_json::from_value(value).map_err(malformed)
+    }
+}
+
+/// Stores records untagged, and reads records of any type name, at the legacy version.
+impl<X> Default for RecordRegistry<X> {
+    fn default() -> Self {
+        Self::new("", Self::LEGACY_VERSION)
+    }
+}
+
+impl<X> std::fmt::Debug for RecordRegistry<X> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecordRegistry")
+            .field("type_name", &self.type_name)
+            .field("aliases", &self.aliases)
+            .field("version", &self.version)
+            .field("upgraders", &self.upgraders.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance};
+
+    use blockify::{
+        block::{BlockError, ChainedInstance},
+        chain::{Chain, ChainError},
+        data::Metadata,
+        record::Record,
+        registry::{RecordError, RecordErrorKind, RecordRegistry},
+        snapshot::ImportMode,
+        SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct NoteV1 {
+        text: String,
+    }
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Note {
+        text: String,
+        pinned: bool,
+    }
+
+    fn upgraded() -> RecordRegistry<Note> {
+        RecordRegistry::new("note", 2).with_upgrader(1, |mut note| {
+            note["pinned"] = false.into();
+            Ok(note)
+        })
+    }
+
+    #[test]
+    fn test_registry() {
+        let url = "target2/tests/recordregistry/";
+        let _ = std::fs::remove_dir_all(url);
+        std::fs::create_dir_all(url).expect("could not create url");
+        let keypair = crate::generate_ed25519_keypair();
+
+        // a chain of the first version of `Note`
+        let mut chain = SqliteChain::<NoteV1>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("note", 1));
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        let note = NoteV1 {
+            text: "hello".to_owned(),
+        };
+        let signed = note.record(keypair.clone(), Metadata::empty()).unwrap();
+        block.push(signed.clone());
+        chain.append(&block).unwrap();
+
+        // read back as the second version, through the upgrader
+        let mut chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(upgraded());
+        let block = chain.block_at(1.into()).unwrap();
+        let records = block.records().unwrap();
+        let record = &records.as_slice()[0];
+        assert_eq!(
+            record.record(),
+            &Note {
+                text: "hello".to_owned(),
+                pinned: false,
+            }
+        );
+        assert_eq!(record.hash(), signed.hash());
+
+        // records of the current version are read as they are
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        let note = Note {
+            text: "pinned".to_owned(),
+            pinned: true,
+        };
+        block.push(note.clone().record(keypair, Metadata::empty()).unwrap());
+        chain.append(&block).unwrap();
+        let block = chain.block_at(2.into()).unwrap();
+        let records = block.records().unwrap();
+        assert_eq!(records.as_slice()[0].record(), &note);
+
+        // records that cannot be decoded are reported instead of panicking
+        let decode = |chain: &SqliteChain<Note>, pos: u64| {
+            chain.block_at(pos.into()).unwrap().records().map(|_| ())
+        };
+        let chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("note", 2));
+        assert!(matches!(
+            decode(&chain, 1),
+            Err(BlockError::RecordError(RecordError::MissingUpgrader(1)))
+        ));
+        let chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("memo", 2));
+        assert!(matches!(
+            decode(&chain, 2),
+            Err(BlockError::RecordError(RecordError::UnknownType(name))) if name == "note"
+        ));
+        let chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("memo", 2).with_alias("note"));
+        assert!(decode(&chain, 2).is_ok());
+        let chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("note", 1));
+        assert!(matches!(
+            decode(&chain, 2),
+            Err(BlockError::RecordError(RecordError::UnsupportedVersion(2)))
+        ));
+        // and keep their cause when they surface as chain errors
+        assert!(matches!(
+            decode(&chain, 2).map_err(ChainError::from),
+            Err(ChainError::RecordError(
+                RecordErrorKind::UnsupportedVersion(2)
+            ))
+        ));
+        let chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("note", 1).with_alias("x"));
+        assert!(matches!(
+            decode(&chain, 1),
+            Err(BlockError::RecordError(RecordError::Malformed(_)))
+        ));
+
+        // the default registry reads records of any name at the legacy version
+        let chain = SqliteChain::<NoteV1>::new(url).unwrap();
+        assert!(chain.block_at(1.into()).unwrap().records().is_ok());
+        assert!(RecordRegistry::<Note>::default().tag().is_none());
+    }
+
+    #[test]
+    fn test_import_upgraded() {
+        let url = "target2/tests/upgradedexport/";
+        let copy = "target2/tests/upgradedimport/";
+        for dir in [url, copy] {
+            let _ = std::fs::remove_dir_all(dir);
+            std::fs::create_dir_all(dir).expect("could not create dir");
+        }
+        let keypair = crate::generate_ed25519_keypair();
+
+        let mut chain = SqliteChain::<NoteV1>::new(url)
+            .unwrap()
+            .with_registry(RecordRegistry::new("note", 1));
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        let note = NoteV1 {
+            text: "hello".to_owned(),
+        };
+        block.push(note.record(keypair, Metadata::empty()).unwrap());
+        chain.append(&block).unwrap();
+
+        // the upgraded record is exported as it is stored and checked against its
+        // upgrade when imported
+        let chain = SqliteChain::<Note>::new(url)
+            .unwrap()
+            .with_registry(upgraded());
+        let mut snapshot = vec![];
+        chain.export_snapshot(&mut snapshot).unwrap();
+        let mut imported = SqliteChain::<Note>::new(copy)
+            .unwrap()
+            .with_registry(upgraded());
+        assert_eq!(
+            imported
+                .import_snapshot(&snapshot[..], ImportMode::Full)
+                .unwrap(),
+            1
+        );
+        let (a, b) = (
+            chain.block_at(1.into()).unwrap(),
+            imported.block_at(1.into()).unwrap(),
+        );
+        assert_eq!(*a.records().unwrap(), *b.records().unwrap());
+        assert_eq!(a.upgraded_records().unwrap(), b.upgraded_records().unwrap());
+        assert_eq!(b.upgraded_records().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,420 @@
+//! Chain snapshots.
+//!
+//! A snapshot is an archive of every block of a chain, with its header, seal, records and
+//! receipts, that a new node can restore instead of replaying the chain block by block.
+//!
+//! A snapshot starts with `MAGIC` and the format version as a big-endian `u32`, followed
+//! by a zlib stream. The stream holds the number of blocks, the blocks in order, each
+//! encoded with bincode, and finally the SHA-256 checksum of everything before it in the
+//! stream.
+
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    block::{BlockError, BlockHeader, ChainedInstance, Seal},
+    chain::{Chain, ChainError},
+    error::SerdeError,
+    receipt::Receipt,
+    record::{Record, SignedRecord},
+    registry::StoredRecord,
+    Hash,
+};
+
+/// The bytes every snapshot starts with.
+pub const MAGIC: &[u8; 8] = b"BLKFYSNP";
+
+/// The version of the snapshot format written by this crate.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SnapshotError {
+    /// The snapshot could not be read or written.
+    Io,
+    /// The data does not start with `MAGIC`.
+    NotASnapshot,
+    /// The snapshot was written in a format version this crate cannot read.
+    UnsupportedVersion(u32),
+    /// The checksum of the snapshot does not match its contents.
+    ChecksumMismatch,
+    /// The snapshot does not hold the number of blocks it announces.
+    Truncated,
+    /// No block of the snapshot has the trusted checkpoint hash.
+    CheckpointNotFound,
+    SerdeError(SerdeError),
+    ChainError(ChainError),
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(_: std::io::Error) -> Self {
+        SnapshotError::Io
+    }
+}
+
+impl From<ChainError> for SnapshotError {
+    fn from(value: ChainError) -> Self {
+        SnapshotError::ChainError(value)
+    }
+}
+
+impl From<BlockError> for SnapshotError {
+    fn from(value: BlockError) -> Self {
+        SnapshotError::ChainError(value.into())
+    }
+}
+
+crate::impl_display_error!(SnapshotError);
+
+/// How the blocks of a snapshot are checked when it is imported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Every block is checked as if it was appended, including the signatures of its
+    /// records.
+    Full,
+    /// The signatures of the records of the blocks up to and including the block with
+    /// the given hash are not checked. Linkage, Merkle roots and proofs-of-work are still
+    /// checked, so the hash commits to every block before it. The import fails if no
+    /// block has this hash.
+    Checkpoint(Hash),
+}
+
+/// A block as it is stored in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotBlock<R> {
+    pub header: BlockHeader,
+    pub seal: Option<Seal>,
+    pub records: Vec<SignedRecord<R>>,
+    pub receipts: Vec<Receipt>,
+    /// The records that were upgraded from an older version of their type, with their
+    /// index, as they are stored (see `ChainedInstance::upgraded_records`).
+    pub upgraded: Vec<(u64, StoredRecord)>,
+}
+
+impl<R: Record + Clone> SnapshotBlock<R> {
+    /// Returns the snapshot block of `block`.
+    pub fn of<B: ChainedInstance<R>>(block: &B) -> Result<Self, ChainError> {
+        Ok(Self {
+            header: block.header()?,
+            seal: block.seal()?,
+            records: block.records()?.into_inner(),
+            receipts: block.receipts()?,
+            upgraded: block.upgraded_records()?,
+        })
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Writes the blocks of a snapshot one at a time.
+pub struct SnapshotWriter<W: Write> {
+    stream: HashingWriter<ZlibEncoder<W>>,
+    remaining: u64,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Starts a snapshot of `count` blocks.
+    pub fn new(mut writer: W, count: u64) -> Result<Self, SnapshotError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        let mut stream = HashingWriter {
+            inner: ZlibEncoder::new(writer, Compression::default()),
+            hasher: Sha256::new(),
+        };
+        stream.write_all(&count.to_be_bytes())?;
+        Ok(Self {
+            stream,
+            remaining: count,
+        })
+    }
+
+    pub fn write_block<R: Serialize>(
+        &mut self,
+        block: &SnapshotBlock<R>,
+    ) -> Result<(), SnapshotError> {
+        if self.remaining == 0 {
+            return Err(SnapshotError::Truncated);
+        }
+        bincode::serialize_into(&mut self.stream, block)
+            .map_err(|_| SnapshotError::SerdeError(SerdeError::SerializationError))?;
+        self.remaining -= 1;
+        Ok(())
+    }
+
+    /// Writes the checksum and returns the underlying writer.
+    pub fn finish(self) -> Result<W, SnapshotError> {
+        if self.remaining != 0 {
+            return Err(SnapshotError::Truncated);
+        }
+        let HashingWriter { mut inner, hasher } = self.stream;
+        inner.write_all(&hasher.finalize())?;
+        Ok(inner.finish()?)
+    }
+}
+
+/// Reads the blocks of a snapshot one at a time.
+///
+/// The checksum is checked when the last block has been read, so the blocks read before
+/// that must not be trusted until `next_block` has returned `Ok(None)`.
+pub struct SnapshotReader<Rd: Read> {
+    stream: HashingReader<ZlibDecoder<Rd>>,
+    count: u64,
+    remaining: u64,
+    verified: bool,
+}
+
+impl<Rd: Read> SnapshotReader<Rd> {
+    pub fn new(mut reader: Rd) -> Result<Self, SnapshotError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_be_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut stream = HashingReader {
+            inner: ZlibDecoder::new(reader),
+            hasher: Sha256::new(),
+        };
+        let mut count = [0; 8];
+        stream
+            .read_exact(&mut count)
+            .map_err(|_| SnapshotError::Truncated)?;
+        let count = u64::from_be_bytes(count);
+        Ok(Self {
+            stream,
+            count,
+            remaining: count,
+            verified: false,
+        })
+    }
+
+    /// Returns the number of blocks in the snapshot.
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the next block, or `None` once every block has been read and the checksum
+    /// matched.
+    pub fn next_block<R: for<'a> Deserialize<'a>>(
+        &mut self,
+    ) -> Result<Option<SnapshotBlock<R>>, SnapshotError> {
+        if self.remaining == 0 {
+            self.verify()?;
+            return Ok(None);
+        }
+        let block = bincode::deserialize_from(&mut self.stream)
+            .map_err(|_| SnapshotError::SerdeError(SerdeError::DeserializationError))?;
+        self.remaining -= 1;
+        Ok(Some(block))
+    }
+
+    fn verify(&mut self) -> Result<(), SnapshotError> {
+        if self.verified {
+            return Ok(());
+        }
+        let expected = self.stream.hasher.clone().finalize();
+        let mut checksum = [0; 32];
+        self.stream
+            .inner
+            .read_exact(&mut checksum)
+            .map_err(|_| SnapshotError::Truncated)?;
+        if checksum[..] != expected[..] {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        self.verified = true;
+        Ok(())
+    }
+}
+
+/// Writes every block of `chain` to `writer` as a snapshot.
+///
+/// This is what `Chain::export_snapshot` does.
+pub fn export<R, C, W>(chain: &C, writer: W) -> Result<W, SnapshotError>
+where
+    R: Record + Serialize + Clone,
+    C: Chain<R>,
+    W: Write,
+{
+    let len = chain.len()?;
+    let mut snapshot = SnapshotWriter::new(writer, len)?;
+    for pos in 1..=len {
+        let block = chain.block_at(pos.into())?;
+        snapshot.write_block(&SnapshotBlock::of(&block)?)?;
+    }
+    snapshot.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance, inner::test_dir};
+
+    use blockify::{
+        block::ChainedInstance,
+        chain::{Chain, ChainError},
+        data::{Metadata, Timestamp},
+        genesis::ChainSpec,
+        record::Record,
+        snapshot::{ImportMode, SnapshotError, SnapshotReader},
+        SqliteChain,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Entry {
+        data: String,
+    }
+
+    fn exported(name: &str, spec: &ChainSpec<Entry>) -> (Vec<u8>, SqliteChain<Entry>) {
+        let keypair = crate::generate_ed25519_keypair();
+        let mut chain = SqliteChain::open(&test_dir(name), spec).unwrap();
+        for i in 0..3 {
+            let mut block = LocalInstance::new(Metadata::empty(), 0);
+            for j in 0..4 {
+                let entry = Entry {
+                    data: format!("{i}-{j}"),
+                };
+                block.push(entry.record(keypair.clone(), Metadata::empty()).unwrap());
+            }
+            chain.append(&block).unwrap();
+        }
+        let mut snapshot = vec![];
+        chain.export_snapshot(&mut snapshot).unwrap();
+        (snapshot, chain)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let spec = ChainSpec::new("snapshots", Timestamp::from_secs(1_700_000_000));
+        let (snapshot, source) = exported("snapshotsource", &spec);
+        assert_eq!(SnapshotReader::new(&snapshot[..]).unwrap().len(), 4);
+
+        let mut chain = SqliteChain::open(&test_dir("snapshotfull"), &spec).unwrap();
+        let imported = chain
+            .import_snapshot(&snapshot[..], ImportMode::Full)
+            .unwrap();
+        assert_eq!(imported, 3);
+        assert_eq!(chain.len().unwrap(), 4);
+        for pos in 1..=4 {
+            let (a, b) = (
+                source.block_at(pos.into()).unwrap(),
+                chain.block_at(pos.into()).unwrap(),
+            );
+            assert_eq!(a.header().unwrap(), b.header().unwrap());
+            assert_eq!(a.hash().unwrap(), b.hash().unwrap());
+            assert_eq!(
+                *a.records().unwrap().unwrap(),
+                *b.records().unwrap().unwrap()
+            );
+        }
+
+        // importing again adds nothing
+        assert_eq!(
+            chain
+                .import_snapshot(&snapshot[..], ImportMode::Full)
+                .unwrap(),
+            0
+        );
+
+        let checkpoint = source.block_at(3.into()).unwrap().hash().unwrap();
+        let mut chain = SqliteChain::open(&test_dir("snapshottrusted"), &spec).unwrap();
+        let imported = chain
+            .import_snapshot(&snapshot[..], ImportMode::Checkpoint(checkpoint))
+            .unwrap();
+        assert_eq!(imported, 3);
+
+        let mut chain = SqliteChain::open(&test_dir("snapshotnocheckpoint"), &spec).unwrap();
+        assert!(matches!(
+            chain.import_snapshot(&snapshot[..], ImportMode::Checkpoint(crate::sha(b"x"))),
+            Err(SnapshotError::CheckpointNotFound)
+        ));
+        assert_eq!(chain.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_rejects_corrupted() {
+        let spec = ChainSpec::new("snapshots", Timestamp::from_secs(1_700_000_000));
+        let (snapshot, _) = exported("snapshotsource2", &spec);
+        let mut chain = SqliteChain::open(&test_dir("snapshotcorrupted"), &spec).unwrap();
+
+        let mut bad = snapshot.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            chain.import_snapshot(&bad[..], ImportMode::Full),
+            Err(SnapshotError::NotASnapshot)
+        ));
+
+        let mut bad = snapshot.clone();
+        bad[11] = 2;
+        assert!(matches!(
+            chain.import_snapshot(&bad[..], ImportMode::Full),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+
+        // rewrite the stream with a wrong checksum
+        let mut data = vec![];
+        std::io::Read::read_to_end(
+            &mut flate2::read::ZlibDecoder::new(&snapshot[12..]),
+            &mut data,
+        )
+        .unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let mut bad = snapshot[..12].to_vec();
+        let mut encoder = flate2::write::ZlibEncoder::new(&mut bad, Default::default());
+        std::io::Write::write_all(&mut encoder, &data).unwrap();
+        encoder.finish().unwrap();
+        assert!(matches!(
+            chain.import_snapshot(&bad[..], ImportMode::Full),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+        assert_eq!(chain.len().unwrap(), 1);
+
+        // a chain with another genesis block
+        let other = ChainSpec::<Entry>::new("other", Timestamp::from_secs(1_700_000_000));
+        let mut chain = SqliteChain::open(&test_dir("snapshotothergenesis"), &other).unwrap();
+        assert!(matches!(
+            chain.import_snapshot(&snapshot[..], ImportMode::Full),
+            Err(SnapshotError::ChainError(ChainError::NotValid(_)))
+        ));
+    }
+}
@@ -3,10 +3,12 @@ use serde::Serialize;
 use std::marker::PhantomData;
 
 use crate::{
-    block::{BlockError, ChainedInstance, UnchainedInstance},
-    data::{Nonce, Position, Timestamp},
+    block::{BlockError, ChainedInstance, Seal, UnchainedInstance},
+    consensus::finality::FinalityCertificate,
+    data::{Metadata, Nonce, Position, Target, Timestamp},
+    receipt::Receipt,
     record::{Record, Records, SignedRecord},
-    Hash, WrapperMut,
+    Hash, PublicKey, WrapperMut,
 };
 
 // records others
@@ -130,6 +132,26 @@ impl<R: Record> ChainedInstance<R> for GenericBlock<R> {
         todo!()
     }
 
+    fn state_root(&self) -> Result<Hash, BlockError> {
+        todo!()
+    }
+
+    fn receipts(&self) -> Result<Vec<Receipt>, BlockError> {
+        todo!()
+    }
+
+    fn receipts_root(&self) -> Result<Hash, BlockError> {
+        todo!()
+    }
+
+    fn base_fee(&self) -> Result<u128, BlockError> {
+        todo!()
+    }
+
+    fn producer(&self) -> Result<Option<PublicKey>, BlockError> {
+        todo!()
+    }
+
     fn timestamp(&self) -> Result<Timestamp, BlockError> {
         todo!()
     }
@@ -137,4 +159,24 @@ impl<R: Record> ChainedInstance<R> for GenericBlock<R> {
     fn nonce(&self) -> Result<Nonce, BlockError> {
         todo!()
     }
+
+    fn target(&self) -> Result<Target, BlockError> {
+        todo!()
+    }
+
+    fn seal(&self) -> Result<Option<Seal>, BlockError> {
+        todo!()
+    }
+
+    fn finality(&self) -> Result<Option<FinalityCertificate>, BlockError> {
+        todo!()
+    }
+
+    fn metadata(&self) -> Result<Metadata, BlockError> {
+        todo!()
+    }
+
+    fn version(&self) -> Result<u32, BlockError> {
+        todo!()
+    }
 }
@@ -1,43 +1,36 @@
 mod sqlite_block;
 mod sqlite_chain;
+mod sqlite_mempool;
+mod sqlite_state;
 mod generic;
 
 pub use generic::*;
 pub use sqlite_block::*;
 pub use sqlite_chain::*;
+pub use sqlite_mempool::*;
+pub use sqlite_state::*;
 
 use crate::{
-    data::{Nonce, Position, Timestamp},
-    Hash,
+    block::Seal,
+    data::{Metadata, Nonce, Position, Target, Timestamp},
+    Hash, PublicKey,
 };
 
 pub struct TempInstance {
+    pub version: u32,
     pub nonce: Nonce,
     pub hash: Hash,
     pub prev_hash: Hash,
     pub merkle_root: Hash,
     pub timestamp: Timestamp,
     pub position: Position,
-}
-
-impl TempInstance {
-    fn new(
-        nonce: Nonce,
-        position: Position,
-        timestamp: Timestamp,
-        hash: Hash,
-        prev_hash: Hash,
-        merkle_root: Hash,
-    ) -> Self {
-        Self {
-            nonce,
-            position,
-            hash,
-            prev_hash,
-            merkle_root,
-            timestamp,
-        }
-    }
+    pub target: Target,
+    pub seal: Option<Seal>,
+    pub metadata: Metadata,
+    pub state_root: Hash,
+    pub receipts_root: Hash,
+    pub base_fee: u128,
+    pub producer: Option<PublicKey>,
 }
 
 pub(crate) struct WrapperMut<T> {
@@ -1,16 +1,17 @@
 use diesel::prelude::*;
 use diesel::sql_types::Text;
-use diesel::sqlite::Sqlite;
 use serde::{Deserialize, Serialize};
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Arc};
 
-use crate::data::{Nonce, Position, Timestamp};
-use crate::error::SerdeError;
+use crate::data::{Metadata, Nonce, Position, Target, Timestamp};
+use crate::error::{DataBaseError, SerdeError};
 use crate::{
     block::ChainedInstance,
+    receipt::Receipt,
     record::{Record, Records},
+    registry::{RecordRegistry, RecordTag, StoredRecord},
 };
-use crate::{Hash, SqliteChainError, TempInstance};
+use crate::{Hash, PublicKey, SqliteChainError, TempInstance};
 
 use super::WrapperMut;
 
@@ -18,6 +19,8 @@ table! {
     records {
         id -> Integer,
         jsonvalues -> Text,
+        type_name -> Nullable<Text>,
+        version -> Nullable<Integer>,
     }
 }
 
@@ -30,11 +33,41 @@ table! {
         nonce -> Text,
         prev_hash -> Text,
         position -> Text,
+        target -> Text,
+        seal -> Text,
+        version -> Text,
+        block_metadata -> Text,
+        state_root -> Text,
+        receipts_root -> Text,
+        base_fee -> Text,
+        producer -> Text,
+    }
+}
+
+table! {
+    receipts {
+        id -> Integer,
+        jsonvalue -> Text,
+    }
+}
+
+table! {
+    finality {
+        id -> Integer,
+        certificate -> Text,
+    }
+}
+
+table! {
+    pruned_records {
+        id -> Integer,
+        count -> BigInt,
     }
 }
 
 pub struct SqliteBlock<X> {
     con: WrapperMut<SqliteConnection>,
+    registry: Arc<RecordRegistry<X>>,
     _data: PhantomData<X>,
 }
 
@@ -67,22 +100,86 @@ impl From<ConnectionError> for SqliteBlockError {
     }
 }
 
+impl<X> SqliteBlock<X> {
+    /// Reads a header column that blocks stored by older versions of this crate may lack.
+    fn column(&self, name: &'static str) -> Option<String> {
+        diesel::sql_query(format!("SELECT {name} AS value FROM metadata LIMIT 1"))
+            .get_result::<ColumnValue>(self.con.get_mut())
+            .ok()
+            .map(|c| c.value)
+    }
+
+    /// Returns the number of records this block had before they were pruned, or `None` if
+    /// its records are still stored.
+    pub fn pruned(&self) -> Option<u64> {
+        // blocks that were never pruned have no `pruned_records` table
+        pruned_records::table
+            .select(pruned_records::count)
+            .first::<i64>(self.con.get_mut())
+            .ok()
+            .map(|count| count as u64)
+    }
+
+    /// Deletes the records of this block, keeping its header.
+    pub(crate) fn prune(&self) -> Result<(), SqliteBlockError> {
+        if self.pruned().is_some() {
+            return Ok(());
+        }
+        let con = self.con.get_mut();
+        let count = records::table
+            .count()
+            .get_result::<i64>(con)
+            .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS pruned_records (
+            id INTEGER PRIMARY KEY,
+            count BIGINT
+        )",
+        )
+        .execute(con)
+        .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        diesel::replace_into(pruned_records::table)
+            .values((pruned_records::id.eq(1), pruned_records::count.eq(count)))
+            .execute(con)
+            .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        diesel::delete(records::table)
+            .execute(con)
+            .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        // give the space back to the file system
+        diesel::sql_query("VACUUM")
+            .execute(con)
+            .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        Ok(())
+    }
+}
+
 impl<X: Record + Serialize> SqliteBlock<X> {
     pub fn new(url: &str) -> Result<Self, SqliteBlockError> {
         let con = SqliteConnection::establish(url)?;
         let val = Self {
             con: WrapperMut::new(con),
+            registry: Arc::default(),
             _data: PhantomData,
         };
         Ok(val)
     }
 
+    /// Reads the records of this block with `registry`, which upgrades records stored
+    /// under older versions of their type.
+    pub fn with_registry(mut self, registry: Arc<RecordRegistry<X>>) -> Self {
+        self.registry = registry;
+        self
+    }
+
     fn create_tables(con: &mut SqliteConnection) -> Result<(), SqliteBlockError> {
         diesel::sql_query(
             "
         CREATE TABLE IF NOT EXISTS records (
             id INTEGER PRIMARY KEY,
-            jsonvalues TEXT
+            jsonvalues TEXT,
+            type_name TEXT,
+            version INTEGER
         )
         ",
         )
@@ -98,7 +195,35 @@ impl<X: Record + Serialize> SqliteBlock<X> {
             merkle_root TEXT,
             nonce TEXT,
             prev_hash TEXT,
-            position TEXT
+            position TEXT,
+            target TEXT,
+            seal TEXT,
+            version TEXT,
+            block_metadata TEXT,
+            state_root TEXT,
+            receipts_root TEXT,
+            base_fee TEXT,
+            producer TEXT
+        )",
+        )
+        .execute(con)
+        .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS receipts (
+            id INTEGER PRIMARY KEY,
+            jsonvalue TEXT
+        )",
+        )
+        .execute(con)
+        .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS finality (
+            id INTEGER PRIMARY KEY,
+            certificate TEXT
         )",
         )
         .execute(con)
@@ -110,7 +235,9 @@ impl<X: Record + Serialize> SqliteBlock<X> {
     pub fn build(
         url: &str,
         records: &[SignedRecord<X>],
+        receipts: &[Receipt],
         cc: &TempInstance,
+        registry: Arc<RecordRegistry<X>>,
     ) -> Result<Self, SqliteBlockError> {
         let TempInstance {
             nonce,
@@ -119,8 +246,16 @@ impl<X: Record + Serialize> SqliteBlock<X> {
             prev_hash,
             merkle_root,
             timestamp,
+            target,
+            seal,
+            version,
+            metadata: block_metadata,
+            state_root,
+            receipts_root,
+            base_fee,
+            producer,
         } = cc;
-        let val = Self::new(url)?;
+        let val = Self::new(url)?.with_registry(registry);
         Self::create_tables(val.con.get_mut())?;
 
         let timestamp = serde_json::to_string(timestamp).unwrap();
@@ -135,6 +270,22 @@ impl<X: Record + Serialize> SqliteBlock<X> {
 
         let position = serde_json::to_string(position).unwrap();
 
+        let target = serde_json::to_string(target).unwrap();
+
+        let seal = serde_json::to_string(seal).unwrap();
+
+        let version = serde_json::to_string(version).unwrap();
+
+        let block_metadata = serde_json::to_string(block_metadata).unwrap();
+
+        let state_root = serde_json::to_string(state_root).unwrap();
+
+        let receipts_root = serde_json::to_string(receipts_root).unwrap();
+
+        let base_fee = serde_json::to_string(base_fee).unwrap();
+
+        let producer = serde_json::to_string(producer).unwrap();
+
         let smt = diesel::insert_into(metadata::table).values((
             metadata::timestamp.eq(timestamp),
             metadata::hash.eq(hash),
@@ -142,11 +293,29 @@ impl<X: Record + Serialize> SqliteBlock<X> {
             metadata::nonce.eq(nonce),
             metadata::prev_hash.eq(prev_hash),
             metadata::position.eq(position),
+            metadata::target.eq(target),
+            metadata::seal.eq(seal),
+            metadata::version.eq(version),
+            metadata::block_metadata.eq(block_metadata),
+            metadata::state_root.eq(state_root),
+            metadata::receipts_root.eq(receipts_root),
+            metadata::base_fee.eq(base_fee),
+            metadata::producer.eq(producer),
         ));
 
+        let tag = val.registry.tag();
         for record in records {
-            let smt = diesel::insert_into(records::table)
-                .values(records::jsonvalues.eq(serde_json::to_string(record).unwrap()));
+            let smt = diesel::insert_into(records::table).values((
+                records::jsonvalues.eq(serde_json::to_string(record).unwrap()),
+                records::type_name.eq(tag.as_ref().map(|tag| &tag.type_name)),
+                records::version.eq(tag.as_ref().map(|tag| tag.version as i32)),
+            ));
+            smt.execute(val.con.get_mut()).unwrap();
+        }
+
+        for receipt in receipts {
+            let smt = diesel::insert_into(receipts::table)
+                .values(receipts::jsonvalue.eq(serde_json::to_string(receipt).unwrap()));
             smt.execute(val.con.get_mut()).unwrap();
         }
 
@@ -154,47 +323,106 @@ impl<X: Record + Serialize> SqliteBlock<X> {
 
         Ok(val)
     }
+
+    /// Stores the certificate that finalized this block, replacing any previous one.
+    pub(crate) fn set_finality(
+        &self,
+        certificate: &FinalityCertificate,
+    ) -> Result<(), SqliteBlockError> {
+        let certificate = serde_json::to_string(certificate).unwrap();
+        diesel::replace_into(finality::table)
+            .values((finality::id.eq(1), finality::certificate.eq(certificate)))
+            .execute(self.con.get_mut())
+            .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        Ok(())
+    }
 }
 
-use crate::block::BlockError;
+use crate::block::{BlockError, BlockHeader, Seal};
+use crate::consensus::finality::FinalityCertificate;
 use crate::record::SignedRecord;
-use records::dsl::records as rq;
 
-#[derive(Deserialize)]
-struct RecordValue<X> {
-    s: SignedRecord<X>,
+#[derive(QueryableByName)]
+struct ColumnValue {
+    #[diesel(sql_type = Text)]
+    value: String,
 }
 
-impl<X> RecordValue<X> {
-    fn new(s: SignedRecord<X>) -> Self {
-        Self { s }
-    }
+#[derive(QueryableByName)]
+struct TaggedRecord {
+    #[diesel(sql_type = Text)]
+    jsonvalues: String,
+    #[diesel(sql_type = diesel::sql_types::Nullable<Text>)]
+    type_name: Option<String>,
+    #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+    version: Option<i32>,
 }
 
-impl<X: for<'a> Deserialize<'a>> Queryable<Text, Sqlite> for RecordValue<X> {
-    type Row = String;
-    fn build(row: Self::Row) -> diesel::deserialize::Result<Self> {
-        let value = serde_json::from_str(&row)?;
-        Ok(RecordValue::new(value))
+impl TaggedRecord {
+    fn tag(&self) -> Option<RecordTag> {
+        match (&self.type_name, self.version) {
+            (Some(type_name), Some(version)) => Some(RecordTag {
+                type_name: type_name.clone(),
+                version: version as u32,
+            }),
+            _ => None,
+        }
     }
 }
 
-impl<X> From<RecordValue<X>> for SignedRecord<X> {
-    fn from(value: RecordValue<X>) -> Self {
-        value.s
+impl<X> SqliteBlock<X> {
+    /// Reads the records of this block as they are stored.
+    fn stored_records(&self) -> Result<Vec<TaggedRecord>, BlockError> {
+        if self.pruned().is_some() {
+            return Err(BlockError::Pruned);
+        }
+        // blocks stored before records were tagged have no tag columns
+        const TAGGED: &str = "SELECT jsonvalues, type_name, version FROM records ORDER BY id";
+        const UNTAGGED: &str =
+            "SELECT jsonvalues, NULL AS type_name, NULL AS version FROM records ORDER BY id";
+        diesel::sql_query(TAGGED)
+            .load::<TaggedRecord>(self.con.get_mut())
+            .or_else(|_| diesel::sql_query(UNTAGGED).load::<TaggedRecord>(self.con.get_mut()))
+            .map_err(|_| BlockError::DataBaseError(DataBaseError::NoSuchTable))
+    }
+
+    /// Replaces the records at the given indexes with the stored forms, such as the
+    /// upgraded records of a snapshot.
+    pub(crate) fn restore_records(
+        &self,
+        stored: &[(u64, StoredRecord)],
+    ) -> Result<(), SqliteBlockError> {
+        let con = self.con.get_mut();
+        let ids = records::table
+            .select(records::id)
+            .order(records::id)
+            .load::<i32>(con)
+            .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        for (index, record) in stored {
+            let id = *ids
+                .get(*index as usize)
+                .ok_or(SqliteBlockError::ConnectionFailed)?;
+            diesel::update(records::table.filter(records::id.eq(id)))
+                .set((
+                    records::jsonvalues.eq(&record.json),
+                    records::type_name.eq(record.tag.as_ref().map(|tag| &tag.type_name)),
+                    records::version.eq(record.tag.as_ref().map(|tag| tag.version as i32)),
+                ))
+                .execute(con)
+                .map_err(|_| SqliteBlockError::ConnectionFailed)?;
+        }
+        Ok(())
     }
 }
 
 impl<X: Record + for<'a> Deserialize<'a> + 'static> ChainedInstance<X> for SqliteBlock<X> {
     fn records(&self) -> Result<Records<X>, BlockError> {
-        let res = rq
-            .select(records::jsonvalues)
-            .load::<RecordValue<X>>(self.con.get_mut())
-            .unwrap();
-        let res = res
-            .into_iter()
-            .map(|record_val| record_val.into())
-            .collect::<Vec<SignedRecord<X>>>();
+        let res = self
+            .stored_records()?
+            .iter()
+            .map(|row| self.registry.decode(row.tag().as_ref(), &row.jsonvalues))
+            .collect::<Result<Vec<SignedRecord<X>>, _>>()
+            .map_err(BlockError::RecordError)?;
         Ok(res.into())
     }
 
@@ -216,6 +444,55 @@ impl<X: Record + for<'a> Deserialize<'a> + 'static> ChainedInstance<X> for Sqlit
         Ok(res)
     }
 
+    fn state_root(&self) -> Result<Hash, BlockError> {
+        // blocks stored before state roots existed commit to no state
+        let res = match self.column("state_root") {
+            Some(res) => serde_json::from_str::<Hash>(&res).unwrap(),
+            None => Hash::default(),
+        };
+        Ok(res)
+    }
+
+    fn receipts(&self) -> Result<Vec<Receipt>, BlockError> {
+        // blocks stored before receipts existed have no `receipts` table
+        let res = receipts::table
+            .select(receipts::jsonvalue)
+            .order(receipts::id)
+            .load::<String>(self.con.get_mut())
+            .unwrap_or_default();
+        let res = res
+            .iter()
+            .map(|receipt| serde_json::from_str::<Receipt>(receipt).unwrap())
+            .collect();
+        Ok(res)
+    }
+
+    fn receipts_root(&self) -> Result<Hash, BlockError> {
+        let res = match self.column("receipts_root") {
+            Some(res) => serde_json::from_str::<Hash>(&res).unwrap(),
+            None => Hash::default(),
+        };
+        Ok(res)
+    }
+
+    fn base_fee(&self) -> Result<u128, BlockError> {
+        // blocks stored before fees existed have a base fee of zero
+        let res = match self.column("base_fee") {
+            Some(res) => serde_json::from_str::<u128>(&res).unwrap(),
+            None => 0,
+        };
+        Ok(res)
+    }
+
+    fn producer(&self) -> Result<Option<PublicKey>, BlockError> {
+        // blocks stored before producers existed name none
+        let res = match self.column("producer") {
+            Some(res) => serde_json::from_str::<Option<PublicKey>>(&res).unwrap(),
+            None => None,
+        };
+        Ok(res)
+    }
+
     fn nonce(&self) -> Result<Nonce, crate::block::BlockError> {
         let res = metadata::table
             .select(metadata::nonce)
@@ -251,4 +528,69 @@ impl<X: Record + for<'a> Deserialize<'a> + 'static> ChainedInstance<X> for Sqlit
         let res = serde_json::from_str::<Timestamp>(&res).unwrap();
         Ok(res)
     }
+
+    fn target(&self) -> Result<Target, BlockError> {
+        // blocks stored before targets existed carry no proof-of-work
+        let res = match self.column("target") {
+            Some(res) => serde_json::from_str::<Target>(&res).unwrap(),
+            None => Target::MAX,
+        };
+        Ok(res)
+    }
+
+    fn seal(&self) -> Result<Option<Seal>, BlockError> {
+        let res = match self.column("seal") {
+            Some(res) => serde_json::from_str::<Option<Seal>>(&res).unwrap(),
+            None => None,
+        };
+        Ok(res)
+    }
+
+    fn metadata(&self) -> Result<Metadata, BlockError> {
+        let res = match self.column("block_metadata") {
+            Some(res) => serde_json::from_str::<Metadata>(&res).unwrap(),
+            None => Metadata::empty(),
+        };
+        Ok(res)
+    }
+
+    fn version(&self) -> Result<u32, BlockError> {
+        // blocks stored before headers were versioned have no version column
+        let res = match self.column("version") {
+            Some(res) => serde_json::from_str::<u32>(&res).unwrap(),
+            None => BlockHeader::LEGACY_VERSION,
+        };
+        Ok(res)
+    }
+
+    fn finality(&self) -> Result<Option<FinalityCertificate>, BlockError> {
+        let res = finality::table
+            .select(finality::certificate)
+            .first::<String>(self.con.get_mut())
+            .optional()
+            .ok()
+            .flatten();
+        let res = res.map(|res| serde_json::from_str::<FinalityCertificate>(&res).unwrap());
+        Ok(res)
+    }
+
+    fn upgraded_records(&self) -> Result<Vec<(u64, StoredRecord)>, BlockError> {
+        let res = self
+            .stored_records()?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, row)| self.registry.is_upgraded(row.tag().as_ref()))
+            .map(|(index, row)| {
+                let tag = row.tag();
+                (
+                    index as u64,
+                    StoredRecord {
+                        tag,
+                        json: row.jsonvalues,
+                    },
+                )
+            })
+            .collect();
+        Ok(res)
+    }
 }
@@ -1,14 +1,28 @@
 use diesel::{insert_into, prelude::*};
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, marker::PhantomData};
+use std::{fmt::Debug, io::Read, marker::PhantomData, sync::Arc};
 
 use crate::{
-    block::{ChainedInstance, LocalInstance, PositionInstance, UnchainedInstance},
+    block::{
+        BlockData, BlockHeader, ChainedInstance, LocalInstance, PositionInstance, Seal,
+        UnchainedInstance,
+    },
     chain::{Chain, ChainError},
+    consensus::{
+        finality::{FinalityCertificate, ValidatorSet},
+        puzzles::{ConsensusPuzzle, WorkPuzzle},
+        retarget::Retarget,
+    },
     data::{Position, ToTimestamp},
     error::{DataBaseError, SerdeError},
-    record::Record,
-    Hash, SqliteBlock, TempInstance,
+    fees::FeeMarket,
+    genesis::{ChainSpec, SpecError},
+    node::MemPoolError,
+    receipt::{self, LogEntry, Receipt},
+    record::{Record, SignedRecord},
+    registry::{RecordRegistry, StoredRecord},
+    snapshot::{ImportMode, SnapshotBlock, SnapshotError, SnapshotReader},
+    Hash, SqliteBlock, SqliteBlockError, SqliteMemPool, TempInstance,
 };
 
 use super::WrapperMut;
@@ -20,9 +34,40 @@ table! {
     }
 }
 
+table! {
+    finalized {
+        id -> Integer,
+        position -> BigInt,
+    }
+}
+
+table! {
+    pruned_blocks {
+        id -> Integer,
+        position -> BigInt,
+    }
+}
+
+table! {
+    logs {
+        id -> Integer,
+        position -> BigInt,
+        record -> Text,
+        topic -> Text,
+        log -> Text,
+    }
+}
+
 pub struct SqliteChain<X> {
     con: WrapperMut<SqliteConnection>,
     url: String,
+    retarget: Option<Retarget>,
+    fee_market: Option<FeeMarket>,
+    mempool: Option<SqliteMemPool<X>>,
+    mempool_error: Option<MemPoolError>,
+    prune_depth: Option<u64>,
+    prune_error: Option<ChainError>,
+    registry: Arc<RecordRegistry<X>>,
     _data: PhantomData<X>,
 }
 
@@ -31,6 +76,10 @@ pub enum SqliteChainError {
     ConnectionError(ConnectionError),
     SerdeError(SerdeError),
     ConnectionFailed,
+    /// The chain spec is not valid.
+    SpecError(SpecError),
+    /// The first block of the chain is not the genesis block of the given spec.
+    GenesisMismatch,
 }
 
 impl From<ConnectionError> for SqliteChainError {
@@ -46,6 +95,9 @@ impl<X> SqliteChain<X> {
 }
 
 impl<X> SqliteChain<X> {
+    /// The number of seconds the timestamp of an appended block may be ahead of the clock.
+    pub const MAX_CLOCK_DRIFT: u64 = 15;
+
     pub fn new(url: &str) -> Result<Self, SqliteChainError> {
         assert!(url.ends_with('/'));
         let basic = format! {"{url}chain.db"};
@@ -57,12 +109,101 @@ impl<X> SqliteChain<X> {
         let value = Self {
             url: url.to_owned(),
             con: WrapperMut::new(con),
+            retarget: None,
+            fee_market: None,
+            mempool: None,
+            mempool_error: None,
+            prune_depth: None,
+            prune_error: None,
+            registry: Arc::default(),
             _data: PhantomData,
         };
 
         Ok(value)
     }
 
+    /// Requires every block appended to this chain to carry the target computed by `retarget`.
+    pub fn with_retarget(mut self, retarget: Retarget) -> Self {
+        self.retarget = Some(retarget);
+        self
+    }
+
+    pub fn retarget(&self) -> Option<&Retarget> {
+        self.retarget.as_ref()
+    }
+
+    /// Requires every block appended to this chain to carry the base fee computed by
+    /// `market` and to be no larger than its maximum size.
+    pub fn with_fee_market(mut self, market: FeeMarket) -> Self {
+        self.fee_market = Some(market);
+        self
+    }
+
+    pub fn fee_market(&self) -> Option<&FeeMarket> {
+        self.fee_market.as_ref()
+    }
+
+    /// Removes the records of every block appended to this chain from `mempool`, through a
+    /// connection to the pool the chain keeps open.
+    ///
+    /// Removal is best effort: the block is appended even if its records cannot be removed,
+    /// and the failure is reported by `last_mempool_error`.
+    pub fn with_mempool(mut self, mempool: &SqliteMemPool<X>) -> Result<Self, SqliteChainError> {
+        let pool =
+            SqliteMemPool::new(mempool.url()).map_err(|_| SqliteChainError::ConnectionFailed)?;
+        self.mempool = Some(pool);
+        Ok(self)
+    }
+
+    /// Keeps the records of only the last `depth` blocks. The records of older blocks are
+    /// deleted as blocks are appended; their headers and Merkle roots are kept.
+    ///
+    /// Everything that reads the records of old blocks then fails with
+    /// `ChainError::Pruned`: `export_snapshot`, `any::records_of`, the `authorities_at` and
+    /// `stakes_at` of PoA and PoS, and a `StateRunner` that has to replay blocks below its
+    /// latest checkpoint. Keep `depth` above the checkpoint interval of such a runner.
+    pub fn with_pruning(mut self, depth: u64) -> Self {
+        self.prune_depth = Some(depth.max(1));
+        self
+    }
+
+    /// Tags the records of this chain with the type name and version of `registry`, and
+    /// upgrades records stored under older versions as they are read.
+    pub fn with_registry(mut self, registry: RecordRegistry<X>) -> Self {
+        self.registry = Arc::new(registry);
+        self
+    }
+
+    pub fn registry(&self) -> &RecordRegistry<X> {
+        &self.registry
+    }
+
+    pub fn prune_depth(&self) -> Option<u64> {
+        self.prune_depth
+    }
+
+    /// Returns the error of the last append after which the chain could not be pruned, if
+    /// no append pruned it since.
+    pub fn last_prune_error(&self) -> Option<ChainError> {
+        self.prune_error
+    }
+
+    /// Returns the error of the last append whose records could not be removed from the
+    /// mempool, if the pool was updated by no append since.
+    pub fn last_mempool_error(&self) -> Option<MemPoolError> {
+        self.mempool_error
+    }
+
+    /// Returns the position of the last block whose records were pruned, or `0` if no
+    /// block was pruned.
+    pub fn pruned_height(&self) -> Result<u64, ChainError> {
+        let height = pruned_blocks::table
+            .select(diesel::dsl::max(pruned_blocks::position))
+            .first::<Option<i64>>(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::NoSuchTable))?;
+        Ok(height.unwrap_or(0) as u64)
+    }
+
     fn create_table(con: &mut SqliteConnection) -> Result<(), SqliteChainError> {
         diesel::sql_query(
             "
@@ -75,6 +216,46 @@ impl<X> SqliteChain<X> {
         .execute(con)
         .map_err(|_| SqliteChainError::ConnectionFailed)?;
 
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS finalized (
+            id INTEGER PRIMARY KEY,
+            position BIGINT
+        )
+        ",
+        )
+        .execute(con)
+        .map_err(|_| SqliteChainError::ConnectionFailed)?;
+
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS pruned_blocks (
+            id INTEGER PRIMARY KEY,
+            position BIGINT
+        )
+        ",
+        )
+        .execute(con)
+        .map_err(|_| SqliteChainError::ConnectionFailed)?;
+
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS logs (
+            id INTEGER PRIMARY KEY,
+            position BIGINT,
+            record TEXT,
+            topic TEXT,
+            log TEXT
+        )
+        ",
+        )
+        .execute(con)
+        .map_err(|_| SqliteChainError::ConnectionFailed)?;
+
+        diesel::sql_query("CREATE INDEX IF NOT EXISTS logs_by_topic ON logs (topic)")
+            .execute(con)
+            .map_err(|_| SqliteChainError::ConnectionFailed)?;
+
         Ok(())
     }
 
@@ -87,6 +268,409 @@ impl<X> SqliteChain<X> {
     }
 }
 
+impl<X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static> SqliteChain<X> {
+    /// Opens the chain at `url` that was started from `spec`.
+    ///
+    /// If the chain is empty, the genesis block of `spec` is written as its first block.
+    /// Otherwise the first block must be that genesis block, built at the header version it
+    /// was stored with, and `SqliteChainError::GenesisMismatch` is returned if it is not.
+    /// The chain uses the retarget of the spec, if any.
+    pub fn open(url: &str, spec: &ChainSpec<X>) -> Result<Self, SqliteChainError> {
+        spec.verify().map_err(SqliteChainError::SpecError)?;
+
+        let mut chain = Self::new(url)?;
+        if let Some(retarget) = spec.consensus.retarget {
+            chain = chain.with_retarget(retarget);
+        }
+
+        match chain.block_at(1.into()) {
+            Ok(block) => {
+                let (hash, header) = block
+                    .hash()
+                    .and_then(|hash| Ok((hash, block.header()?)))
+                    .map_err(|_| SqliteChainError::ConnectionFailed)?;
+                // legacy hashes leave out the metadata, which holds the chain ID
+                if hash != spec.genesis_hash_at(header.version)
+                    || header != spec.genesis_header_at(header.version)
+                {
+                    return Err(SqliteChainError::GenesisMismatch);
+                }
+            }
+            Err(ChainError::AbsentValue) => {
+                chain
+                    .store(&spec.genesis(), &spec.genesis_header())
+                    .map_err(|_| SqliteChainError::ConnectionFailed)?;
+            }
+            Err(_) => return Err(SqliteChainError::ConnectionFailed),
+        }
+
+        Ok(chain)
+    }
+
+    /// Restores the blocks of a snapshot written by `Chain::export_snapshot`, returning the
+    /// number of blocks added.
+    ///
+    /// Blocks of the snapshot that this chain already has must have the same hash. The
+    /// others are checked as `append` checks them, the signatures of their records as
+    /// `mode` requires, and stored with the timestamps they have in the snapshot. If
+    /// anything fails, including the checksum at the end, the blocks added are removed.
+    /// Records upgraded by the registry of the exporting chain must instead upgrade, from
+    /// the form they are stored in, to the record in the snapshot.
+    ///
+    /// Open the chain with its spec first so that the genesis block is not checked for
+    /// proof-of-work.
+    pub fn import_snapshot<Rd: Read>(
+        &mut self,
+        reader: Rd,
+        mode: ImportMode,
+    ) -> Result<u64, SnapshotError> {
+        let start = self.len()?;
+        let result = self.import_blocks(SnapshotReader::new(reader)?, mode);
+        if result.is_err() {
+            let _ = self.rollback(start.into());
+        }
+        result
+    }
+
+    fn import_blocks<Rd: Read>(
+        &mut self,
+        mut snapshot: SnapshotReader<Rd>,
+        mode: ImportMode,
+    ) -> Result<u64, SnapshotError> {
+        let mut trusted = matches!(mode, ImportMode::Checkpoint(_));
+        let mut imported = 0;
+
+        while let Some(block) = snapshot.next_block::<X>()? {
+            let SnapshotBlock {
+                header,
+                seal,
+                records,
+                receipts,
+                upgraded,
+            } = block;
+            // the hash of older blocks cannot be computed from their header
+            if header.version <= BlockHeader::LEGACY_VERSION {
+                return Err(ChainError::NotValid(BlockData::Hash).into());
+            }
+            let hash = header.hash();
+
+            if header.position.pos <= self.len()? {
+                if self.block_at(header.position)?.hash()? != hash {
+                    return Err(ChainError::NotValid(BlockData::Hash).into());
+                }
+            } else {
+                self.import_block(&header, seal, records, receipts, &upgraded, trusted)?;
+                imported += 1;
+            }
+
+            if mode == ImportMode::Checkpoint(hash) {
+                trusted = false;
+            }
+        }
+
+        if trusted {
+            return Err(SnapshotError::CheckpointNotFound);
+        }
+        Ok(imported)
+    }
+
+    fn import_block(
+        &mut self,
+        header: &BlockHeader,
+        seal: Option<Seal>,
+        records: Vec<SignedRecord<X>>,
+        receipts: Vec<Receipt>,
+        upgraded: &[(u64, StoredRecord)],
+        trusted: bool,
+    ) -> Result<(), ChainError> {
+        let (prev_hash, position) = crate::consensus::next_in(self)?;
+        if header.position != position {
+            return Err(ChainError::NotValid(BlockData::Position));
+        }
+        if header.prev_hash != prev_hash {
+            return Err(ChainError::NotValid(BlockData::PrevHash));
+        }
+
+        let mut block = LocalInstance::new(header.metadata.clone(), header.nonce.nonce);
+        block.target = header.target;
+        block.seal = seal;
+        block.state_root = header.state_root.clone();
+        block.base_fee = header.base_fee;
+        block.timestamp = header.timestamp;
+        block.producer = header.producer.clone();
+        if upgraded
+            .iter()
+            .any(|(index, _)| *index >= records.len() as u64)
+        {
+            return Err(ChainError::NotValid(BlockData::Records));
+        }
+        for (index, record) in records.into_iter().enumerate() {
+            if !trusted {
+                // an upgraded record cannot be verified, but it must be what its stored form
+                // upgrades to
+                let valid = match upgraded.iter().find(|(i, _)| *i == index as u64) {
+                    Some((_, stored)) => self
+                        .registry
+                        .decode(stored.tag.as_ref(), &stored.json)
+                        .is_ok_and(|decoded| {
+                            serde_json::to_value(decoded).ok() == serde_json::to_value(&record).ok()
+                        }),
+                    None => record.verify().is_ok() && &record.record().hash() == record.hash(),
+                };
+                if !valid {
+                    return Err(ChainError::NotValid(BlockData::Records));
+                }
+            }
+            block.push(record);
+        }
+        if block.get_merkle_root() != &header.merkle_root {
+            return Err(ChainError::NotValid(BlockData::MerkleRoot));
+        }
+        block.receipts = receipts;
+
+        if let Some(retarget) = self.retarget {
+            if header.target != retarget.next_target(self)? {
+                return Err(ChainError::NotValid(BlockData::Target));
+            }
+        }
+        self.check_fees(&block)?;
+        self.check_timestamp(&block)?;
+        if !WorkPuzzle::new(header.target, header.seal_hash()).verify(header.nonce) {
+            return Err(ChainError::NotValid(BlockData::Nonce));
+        }
+
+        self.store(&block, header)?;
+        self.block_at(position)?
+            .restore_records(upgraded)
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))
+    }
+
+    /// Checks the base fee and the size of `block` against the fee market of the chain.
+    fn check_fees(&self, block: &LocalInstance<X>) -> Result<(), ChainError> {
+        if let Some(market) = self.fee_market {
+            if block.base_fee != market.next_base_fee(self)? {
+                return Err(ChainError::NotValid(BlockData::BaseFee));
+            }
+            if FeeMarket::size(&block.records)? > market.max_size {
+                return Err(ChainError::NotValid(BlockData::Records));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the timestamp of `block` is no earlier than that of the last block and
+    /// no more than `MAX_CLOCK_DRIFT` seconds ahead of the clock of this node.
+    fn check_timestamp(&self, block: &LocalInstance<X>) -> Result<(), ChainError> {
+        let secs = block.timestamp.secs();
+        let now = chrono::Utc::now().to_timestamp().secs();
+        if secs > now.saturating_add(Self::MAX_CLOCK_DRIFT) {
+            return Err(ChainError::NotValid(BlockData::Timestamp));
+        }
+        match self.len()? {
+            0 => Ok(()),
+            len if secs < self.block_at(len.into())?.timestamp()?.secs() => {
+                Err(ChainError::NotValid(BlockData::Timestamp))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `block` has no receipts, or one receipt for each of its records, in their
+    /// order, and that `header` commits to them.
+    fn check_receipts(block: &LocalInstance<X>, header: &BlockHeader) -> Result<(), ChainError> {
+        let matching = block.receipts.is_empty()
+            || (block.receipts.len() == block.records.len()
+                && block
+                    .receipts
+                    .iter()
+                    .zip(&block.records)
+                    .all(|(receipt, record)| &receipt.record == record.hash()));
+        if !matching || receipt::receipts_root(&block.receipts) != header.receipts_root {
+            return Err(ChainError::NotValid(BlockData::ReceiptsRoot));
+        }
+        Ok(())
+    }
+
+    /// Deletes the records of every block but the last `depth` ones, returning the number
+    /// of blocks pruned.
+    ///
+    /// Pruned blocks keep their header, and `records()` fails with `BlockError::Pruned`
+    /// for them.
+    pub fn prune(&mut self, depth: u64) -> Result<u64, ChainError> {
+        let from = self.pruned_height()?;
+        let to = self.len()?.saturating_sub(depth);
+        if to <= from {
+            return Ok(0);
+        }
+        for pos in from + 1..=to {
+            self.block_at(pos.into())?
+                .prune()
+                .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        }
+        insert_into(pruned_blocks::table)
+            .values(pruned_blocks::position.eq(to as i64))
+            .execute(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        Ok(to - from)
+    }
+
+    /// Writes `block` with the given header as the next block of the chain.
+    fn store(&mut self, block: &LocalInstance<X>, header: &BlockHeader) -> Result<(), ChainError> {
+        // the logs of the receipts are indexed, so they must be those of the records
+        Self::check_receipts(block, header)?;
+
+        let hash = match header.version {
+            BlockHeader::LEGACY_VERSION => crate::hash_block(
+                block,
+                &header.prev_hash,
+                &header.timestamp,
+                &header.position,
+            ),
+            _ => header.hash(),
+        };
+        let chained = TempInstance {
+            nonce: header.nonce,
+            position: header.position,
+            timestamp: header.timestamp,
+            hash,
+            prev_hash: header.prev_hash.clone(),
+            merkle_root: header.merkle_root.clone(),
+            target: header.target,
+            seal: block.seal.clone(),
+            version: header.version,
+            metadata: header.metadata.clone(),
+            state_root: header.state_root.clone(),
+            receipts_root: header.receipts_root.clone(),
+            base_fee: header.base_fee,
+            producer: header.producer.clone(),
+        };
+
+        let gen_url = Self::gen_url(&self.url, header.position.pos as i64 - 1);
+        let rows = Self::log_rows(&block.receipts)?;
+
+        // a file left behind by an append that failed is not part of the chain
+        let _ = std::fs::remove_file(&gen_url);
+        let built = SqliteBlock::build(
+            &gen_url,
+            &block.records()?,
+            &block.receipts,
+            &chained,
+            self.registry.clone(),
+        )
+        .map_err(|e| match e {
+            SqliteBlockError::SerdeError(e) => ChainError::SerdeError(e),
+            _ => ChainError::DataBaseError(DataBaseError::ConnectionFailed),
+        });
+
+        // the block is counted by `len` once its row is written, together with its logs
+        let indexed = built.and_then(|_| {
+            self.con
+                .get_mut()
+                .transaction::<_, diesel::result::Error, _>(|con| {
+                    for (topic, record, log) in &rows {
+                        insert_into(logs::table)
+                            .values((
+                                logs::position.eq(header.position.pos as i64),
+                                logs::record.eq(record),
+                                logs::topic.eq(topic),
+                                logs::log.eq(log),
+                            ))
+                            .execute(con)?;
+                    }
+                    insert_into(blocks::table)
+                        .values(blocks::block.eq(&gen_url))
+                        .execute(con)
+                })
+                .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))
+        });
+        if let Err(e) = indexed {
+            let _ = std::fs::remove_file(&gen_url);
+            return Err(e);
+        }
+
+        // the block is stored, so records left in the pool are only reported
+        if let Some(pool) = &self.mempool {
+            self.mempool_error = pool.remove(block.records.iter().map(|r| r.hash())).err();
+        }
+
+        // the block is stored; a failure is reported by `last_prune_error`, and the blocks
+        // it left unpruned are pruned after the next append
+        if let Some(depth) = self.prune_depth {
+            self.prune_error = self.prune(depth).err();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the topic, record and log under which each log of `receipts` is indexed, once
+    /// for each distinct topic of the log.
+    fn log_rows(receipts: &[Receipt]) -> Result<Vec<(String, String, String)>, ChainError> {
+        let mut rows = vec![];
+        for receipt in receipts {
+            for log in &receipt.logs {
+                let value = serde_json::to_string(log)
+                    .map_err(|_| ChainError::SerdeError(SerdeError::SerializationError))?;
+                let mut topics = log.topics.iter().collect::<Vec<_>>();
+                topics.sort();
+                topics.dedup();
+                for topic in topics {
+                    rows.push((topic.clone(), receipt.record.to_hex(), value.clone()));
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Returns every log of the chain with `topic` among its topics, oldest first.
+    ///
+    /// Logs are indexed as blocks are appended, so blocks stored before receipts existed
+    /// have none.
+    pub fn logs(&self, topic: &str) -> Result<Vec<LogEntry>, ChainError> {
+        let rows = logs::table
+            .select((logs::position, logs::record, logs::log))
+            .filter(logs::topic.eq(topic))
+            .order(logs::id)
+            .load::<(i64, String, String)>(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::NoSuchTable))?;
+        rows.into_iter()
+            .map(|(position, record, log)| {
+                Ok(LogEntry {
+                    position: Position::new(position as u64),
+                    record: hex::decode(record)
+                        .map(Hash::from)
+                        .map_err(|_| ChainError::SerdeError(SerdeError::DeserializationError))?,
+                    log: serde_json::from_str(&log)
+                        .map_err(|_| ChainError::SerdeError(SerdeError::DeserializationError))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Marks the block certified by `certificate` as final.
+    ///
+    /// The certificate must be signed by a quorum of `validators` and name the hash of the
+    /// block at its height. Blocks at or below the highest finalized block can no longer be
+    /// rolled back.
+    pub fn finalize(
+        &mut self,
+        certificate: FinalityCertificate,
+        validators: &ValidatorSet,
+    ) -> Result<(), ChainError> {
+        let block = self.block_at(certificate.height().into())?;
+        if !certificate.verify(validators) || &block.hash()? != certificate.block_hash() {
+            return Err(ChainError::NotValid(BlockData::Finality));
+        }
+        block
+            .set_finality(&certificate)
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        insert_into(finalized::table)
+            .values(finalized::position.eq(certificate.height() as i64))
+            .execute(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        Ok(())
+    }
+}
+
 impl<X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static> Chain<X>
     for SqliteChain<X>
 {
@@ -104,10 +688,6 @@ impl<X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static> Chain<X>
 
         let position = (size + 1).into();
 
-        let timestamp = chrono::Utc::now().to_timestamp();
-
-        let merkle_root = block.merkle_root().unwrap().clone();
-
         let prev_hash = match self.block_at(size.into()) {
             Err(ChainError::AbsentValue) => Hash::default(),
             other => {
@@ -116,16 +696,23 @@ impl<X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static> Chain<X>
             }
         };
 
-        let hash = crate::hash_block(&block, &prev_hash, &timestamp, &position);
-
-        let chained = TempInstance::new(nonce, position, timestamp, hash, prev_hash, merkle_root);
-
-        let gen_url = Self::gen_url(&self.url, size as _);
+        if let Some(retarget) = self.retarget {
+            if block.target != retarget.next_target(self)? {
+                return Err(ChainError::NotValid(BlockData::Target));
+            }
+        }
+        self.check_fees(block)?;
+        self.check_timestamp(block)?;
 
-        let smt = insert_into(blocks::table).values(blocks::block.eq(&gen_url));
-        smt.execute(self.con.get_mut()).unwrap();
+        let puzzle = WorkPuzzle::new(
+            block.target,
+            crate::hash_header(block, &prev_hash, &position),
+        );
+        if !puzzle.verify(nonce) {
+            return Err(ChainError::NotValid(BlockData::Nonce));
+        }
 
-        SqliteBlock::build(&gen_url, &*block.records().unwrap(), &chained).unwrap();
+        self.store(block, &block.header(&prev_hash, position))?;
 
         Ok(PositionInstance::new(position))
     }
@@ -142,7 +729,8 @@ impl<X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static> Chain<X>
             .map_err(|_| ChainError::AbsentValue)?;
 
         let block = SqliteBlock::new(&url)
-            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionCannotEstablish))?;
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionCannotEstablish))?
+            .with_registry(self.registry.clone());
 
         Ok(block)
     }
@@ -150,19 +738,67 @@ impl<X: Clone + Record + Serialize + for<'a> Deserialize<'a> + 'static> Chain<X>
     fn len(&self) -> Result<u64, ChainError> {
         Self::size(self.con.get_mut()).map_err(|e| ChainError::DataBaseError(e))
     }
+
+    fn rollback(&mut self, pos: Position) -> Result<(), ChainError> {
+        if pos.pos < self.finalized_height()? {
+            return Err(ChainError::Finalized);
+        }
+
+        let urls: Vec<String> = blocks::table
+            .select(blocks::block)
+            .filter(blocks::id.gt(pos.pos as i32))
+            .load(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::NoSuchTable))?;
+
+        diesel::delete(blocks::table.filter(blocks::id.gt(pos.pos as i32)))
+            .execute(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+
+        for url in urls {
+            let _ = std::fs::remove_file(url);
+        }
+
+        diesel::delete(logs::table.filter(logs::position.gt(pos.pos as i64)))
+            .execute(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+
+        if self.pruned_height()? > pos.pos {
+            diesel::delete(pruned_blocks::table)
+                .execute(self.con.get_mut())
+                .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+            insert_into(pruned_blocks::table)
+                .values(pruned_blocks::position.eq(pos.pos as i64))
+                .execute(self.con.get_mut())
+                .map_err(|_| ChainError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        }
+
+        Ok(())
+    }
+
+    fn finalized_height(&self) -> Result<u64, ChainError> {
+        let height = finalized::table
+            .select(diesel::dsl::max(finalized::position))
+            .first::<Option<i64>>(self.con.get_mut())
+            .map_err(|_| ChainError::DataBaseError(DataBaseError::NoSuchTable))?;
+        Ok(height.unwrap_or(0) as u64)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{self as blockify, block::LocalInstance};
+    use crate::{self as blockify, block::LocalInstance, Hash};
 
     use blockify::{
-        block::{ChainedInstance, UnchainedInstance},
-        chain::Chain,
-        data::Metadata,
+        block::{BlockData, BlockError, BlockHeader, ChainedInstance, UnchainedInstance},
+        chain::{Chain, ChainError},
+        data::{Detail, Metadata, Position, Timestamp},
+        genesis::ChainSpec,
+        receipt::{self, Log, Receipt},
         record::{Record, SignedRecord},
-        SqliteChain,
+        snapshot::SnapshotError,
+        SqliteBlock, SqliteChain, SqliteChainError,
     };
+    use diesel::{Connection, RunQueryDsl, SqliteConnection};
     use serde::{Deserialize, Serialize};
 
     #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
@@ -231,4 +867,382 @@ mod tests {
             &*records_from_block2
         );
     }
+
+    #[test]
+    fn test_hash_commits_to_header() {
+        let chain_url = "target2/tests/headerhash/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+
+        let keypair = crate::generate_ed25519_keypair();
+        let mut metadata = Metadata::empty();
+        metadata.push(Detail::Integer(42));
+        let mut builder = LocalInstance::new(metadata, 7);
+        builder.push(
+            Vote::new("abcd")
+                .record(keypair, Metadata::empty())
+                .unwrap(),
+        );
+        chain.append(&builder).unwrap();
+
+        let block = chain.block_at(1.into()).unwrap();
+        let header = block.header().unwrap();
+        assert_eq!(header.version, BlockHeader::VERSION);
+        assert_eq!(block.hash().unwrap(), header.hash());
+        // version 2 commits to every field the header has had at that version
+        assert_eq!(header.hash(), crate::hash(&header));
+        assert_eq!(
+            crate::expected_block_hash(&block).unwrap(),
+            block.hash().unwrap()
+        );
+
+        // the nonce and the metadata are part of the hash
+        let mut other = header.clone();
+        other.nonce.nonce += 1;
+        assert_ne!(other.hash(), header.hash());
+
+        let mut con = SqliteConnection::establish(&format!("{chain_url}block1.db")).unwrap();
+        diesel::sql_query("UPDATE metadata SET block_metadata = '{\"details\":[]}'")
+            .execute(&mut con)
+            .unwrap();
+        let block = chain.block_at(1.into()).unwrap();
+        assert!(block.metadata().unwrap().details().is_empty());
+        assert_ne!(
+            crate::expected_block_hash(&block).unwrap(),
+            block.hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let chain_url = "target2/tests/blocktimestamps/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::<Vote>::new(chain_url).expect("sqlite connection cannot be established");
+
+        // the chain keeps the timestamp the producer gives
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.timestamp = Timestamp::from_secs(1_700_000_000);
+        chain.append(&block).unwrap();
+        assert_eq!(
+            chain.block_at(1.into()).unwrap().timestamp().unwrap(),
+            block.timestamp
+        );
+
+        // but not one earlier than the last block or too far ahead of its clock
+        block.timestamp = Timestamp::from_secs(1_699_999_999);
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::NotValid(BlockData::Timestamp))
+        ));
+        let now = chrono::Utc::now().timestamp() as u64;
+        block.timestamp = Timestamp::from_secs(now + 60);
+        assert!(matches!(
+            chain.append(&block),
+            Err(ChainError::NotValid(BlockData::Timestamp))
+        ));
+        block.timestamp = Timestamp::from_secs(1_700_000_000);
+        chain.append(&block).unwrap();
+    }
+
+    #[test]
+    fn test_receipts_and_logs() {
+        let chain_url = "target2/tests/receiptlogs/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain =
+            SqliteChain::new(chain_url).expect("sqlite connection cannot be established");
+
+        let keypair = crate::generate_ed25519_keypair();
+        let vote = |data: &str| {
+            Vote::new(data)
+                .record(keypair.clone(), Metadata::empty())
+                .unwrap()
+        };
+        let receipt = |record: &SignedRecord<Vote>, topics: &[&str]| {
+            let mut receipt = Receipt::success(record.hash());
+            receipt.logs.push(Log {
+                source: Hash::default(),
+                topics: topics.iter().map(|t| t.to_string()).collect(),
+                data: record.record().data.as_bytes().to_vec(),
+            });
+            receipt
+        };
+
+        let (a, b) = (vote("a"), vote("b"));
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(a.clone());
+        block.push(b.clone());
+        block.receipts = vec![
+            receipt(&a, &["transfer", "alice", "alice"]),
+            receipt(&b, &["transfer"]),
+        ];
+        chain.append(&block).unwrap();
+
+        let stored = chain.block_at(1.into()).unwrap();
+        assert_eq!(stored.receipts().unwrap(), block.receipts);
+        assert_eq!(
+            stored.receipts_root().unwrap(),
+            receipt::receipts_root(&block.receipts)
+        );
+
+        let transfers = chain.logs("transfer").unwrap();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].log, block.receipts[0].logs[0]);
+        assert_eq!(&transfers[1].record, b.hash());
+        let alice = chain.logs("alice").unwrap();
+        assert_eq!(alice.len(), 1);
+        assert_eq!(&alice[0].record, a.hash());
+        assert_eq!(alice[0].position, Position::new(1));
+        assert!(chain.logs("bob").unwrap().is_empty());
+
+        // receipts must be those of the records of the block, in their order
+        let c = vote("c");
+        let mut forged = LocalInstance::new(Metadata::empty(), 0);
+        forged.push(c.clone());
+        for receipts in [
+            vec![receipt(&c, &["bob"]), receipt(&a, &["bob"])],
+            vec![receipt(&a, &["bob"])],
+        ] {
+            forged.receipts = receipts;
+            assert!(matches!(
+                chain.append(&forged),
+                Err(ChainError::NotValid(BlockData::ReceiptsRoot))
+            ));
+        }
+
+        // and committed to by the header
+        forged.receipts = vec![receipt(&c, &["bob"])];
+        let mut header = forged.header(&stored.hash().unwrap(), 2.into());
+        header.receipts_root = receipt::receipts_root(&[receipt(&c, &["carol"])]);
+        assert!(matches!(
+            chain.store(&forged, &header),
+            Err(ChainError::NotValid(BlockData::ReceiptsRoot))
+        ));
+        assert_eq!(chain.len().unwrap(), 1);
+        assert!(chain.logs("bob").unwrap().is_empty());
+
+        // a block that cannot be written leaves neither a row nor logs behind
+        let path = format!("{chain_url}block2.db");
+        std::fs::create_dir_all(format!("{path}/taken")).unwrap();
+        assert!(chain.append(&forged).is_err());
+        assert_eq!(chain.len().unwrap(), 1);
+        assert!(chain.logs("bob").unwrap().is_empty());
+        std::fs::remove_dir_all(&path).unwrap();
+
+        // nor does a file left behind by it get in the way
+        std::fs::write(&path, b"partial").unwrap();
+        chain.append(&forged).unwrap();
+        assert_eq!(chain.logs("bob").unwrap().len(), 1);
+        assert_eq!(
+            chain.block_at(2.into()).unwrap().records().unwrap().len(),
+            1
+        );
+        chain.rollback(1.into()).unwrap();
+        assert!(chain.logs("bob").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_genesis() {
+        let chain_url = "target2/tests/genesis/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+
+        let keypair = crate::generate_ed25519_keypair();
+        let record = Vote::new("abcd")
+            .record(keypair, Metadata::empty())
+            .unwrap();
+        let spec =
+            ChainSpec::new("testnet", Timestamp::from_secs(1_700_000_000)).with_record(record);
+
+        {
+            let mut chain = SqliteChain::open(chain_url, &spec).unwrap();
+            let genesis = chain.block_at(1.into()).unwrap();
+            assert_eq!(genesis.hash().unwrap(), spec.genesis_hash());
+            assert_eq!(genesis.prev_hash().unwrap(), Hash::default());
+            assert_eq!(genesis.timestamp().unwrap(), spec.timestamp);
+            assert_eq!(&*genesis.records().unwrap(), &spec.records[..]);
+
+            chain
+                .append(&LocalInstance::new(Metadata::empty(), 0))
+                .unwrap();
+            let next = chain.block_at(2.into()).unwrap();
+            assert_eq!(next.prev_hash().unwrap(), spec.genesis_hash());
+        }
+
+        // reopening with the same spec keeps the chain as it is
+        let chain = SqliteChain::open(chain_url, &spec).unwrap();
+        assert_eq!(chain.len().unwrap(), 2);
+
+        let mut other = spec.clone();
+        other.chain_id = "mainnet".to_owned();
+        assert!(matches!(
+            SqliteChain::open(chain_url, &other),
+            Err(SqliteChainError::GenesisMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_genesis_at_older_version() {
+        let chain_url = "target2/tests/oldgenesis/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+
+        let keypair = crate::generate_ed25519_keypair();
+        let record = Vote::new("abcd")
+            .record(keypair, Metadata::empty())
+            .unwrap();
+        let spec =
+            ChainSpec::new("testnet", Timestamp::from_secs(1_700_000_000)).with_record(record);
+
+        // a chain whose genesis block was written at an older header version
+        let mut older = spec.clone();
+        older.header_version = BlockHeader::LEGACY_VERSION;
+        {
+            let chain = SqliteChain::open(chain_url, &older).unwrap();
+            let genesis = chain.block_at(1.into()).unwrap();
+            assert_eq!(genesis.version().unwrap(), BlockHeader::LEGACY_VERSION);
+            assert_eq!(genesis.hash().unwrap(), older.genesis_hash());
+            assert_eq!(
+                crate::expected_block_hash(&genesis).unwrap(),
+                genesis.hash().unwrap()
+            );
+        }
+
+        // is still opened by the spec, whatever version it builds its genesis block at
+        let mut chain = SqliteChain::open(chain_url, &spec).unwrap();
+        assert_ne!(spec.genesis_hash(), older.genesis_hash());
+        chain
+            .append(&LocalInstance::new(Metadata::empty(), 0))
+            .unwrap();
+        assert_eq!(
+            chain.block_at(2.into()).unwrap().prev_hash().unwrap(),
+            older.genesis_hash()
+        );
+
+        let mut other = spec.clone();
+        other.chain_id = "mainnet".to_owned();
+        assert!(matches!(
+            SqliteChain::open(chain_url, &other),
+            Err(SqliteChainError::GenesisMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_pruning() {
+        let chain_url = "target2/tests/pruning/";
+        let _ = std::fs::remove_dir_all(chain_url);
+        std::fs::create_dir_all(chain_url).expect("could not create chain_url");
+        let mut chain = SqliteChain::new(chain_url)
+            .expect("sqlite connection cannot be established")
+            .with_pruning(2);
+
+        let keypair = crate::generate_ed25519_keypair();
+        let mut roots = vec![];
+        for i in 0..5 {
+            let mut block = LocalInstance::new(Metadata::empty(), 0);
+            block.push(
+                Vote::new(&format!("vote{i}"))
+                    .record(keypair.clone(), Metadata::empty())
+                    .unwrap(),
+            );
+            roots.push(block.get_merkle_root().clone());
+            chain.append(&block).unwrap();
+        }
+        assert_eq!(chain.pruned_height().unwrap(), 3);
+
+        for pos in 1..=5u64 {
+            let block = chain.block_at(pos.into()).unwrap();
+            assert_eq!(block.merkle_root().unwrap(), roots[pos as usize - 1]);
+            assert_eq!(
+                crate::expected_block_hash(&block).unwrap(),
+                block.hash().unwrap()
+            );
+            if pos <= 3 {
+                assert_eq!(block.pruned(), Some(1));
+                assert!(matches!(block.records(), Err(BlockError::Pruned)));
+            } else {
+                assert_eq!(block.records().unwrap().len(), 1);
+            }
+        }
+        assert!(matches!(
+            chain.export_snapshot(vec![]),
+            Err(SnapshotError::ChainError(ChainError::Pruned))
+        ));
+
+        chain.rollback(2.into()).unwrap();
+        assert_eq!(chain.pruned_height().unwrap(), 2);
+        assert!(chain.last_prune_error().is_none());
+
+        // blocks are appended even if the chain cannot be pruned
+        let mut con = SqliteConnection::establish(&format!("{chain_url}chain.db")).unwrap();
+        diesel::sql_query("DROP TABLE pruned_blocks")
+            .execute(&mut con)
+            .unwrap();
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(
+            Vote::new("late")
+                .record(keypair, Metadata::empty())
+                .unwrap(),
+        );
+        chain.append(&block).unwrap();
+        assert_eq!(chain.len().unwrap(), 3);
+        assert!(matches!(
+            chain.last_prune_error(),
+            Some(ChainError::DataBaseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_block() {
+        let dir = "target2/tests/legacyblock/";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).expect("could not create dir");
+        let url = format!("{dir}block1.db");
+
+        // a block stored before headers were versioned
+        let keypair = crate::generate_ed25519_keypair();
+        let record = Vote::new("abcd")
+            .record(keypair, Metadata::empty())
+            .unwrap();
+        let mut local = LocalInstance::new(Metadata::empty(), 3);
+        local.push(record.clone());
+        let prev_hash = crate::random_sha256();
+        let timestamp = Timestamp::from_secs(1_000);
+        let position = Position::new(1);
+        let hash = crate::hash_block(&local, &prev_hash, &timestamp, &position);
+
+        let json = |v: String| v.replace('\'', "''");
+        let mut con = SqliteConnection::establish(&url).unwrap();
+        for query in [
+            "CREATE TABLE records (id INTEGER PRIMARY KEY, jsonvalues TEXT)".to_owned(),
+            "CREATE TABLE metadata (id INTEGER PRIMARY KEY, timestamp TEXT, hash TEXT, merkle_root TEXT, nonce TEXT, prev_hash TEXT, position TEXT)".to_owned(),
+            format!(
+                "INSERT INTO records (jsonvalues) VALUES ('{}')",
+                json(serde_json::to_string(&record).unwrap())
+            ),
+            format!(
+                "INSERT INTO metadata (timestamp, hash, merkle_root, nonce, prev_hash, position) VALUES ('{}', '{}', '{}', '{}', '{}', '{}')",
+                json(serde_json::to_string(&timestamp).unwrap()),
+                json(serde_json::to_string(&hash).unwrap()),
+                json(serde_json::to_string(local.get_merkle_root()).unwrap()),
+                json(serde_json::to_string(&local.nonce).unwrap()),
+                json(serde_json::to_string(&prev_hash).unwrap()),
+                json(serde_json::to_string(&position).unwrap()),
+            ),
+        ] {
+            diesel::sql_query(query).execute(&mut con).unwrap();
+        }
+
+        let block = SqliteBlock::<Vote>::new(&url).unwrap();
+        let header = block.header().unwrap();
+        assert_eq!(header.version, BlockHeader::LEGACY_VERSION);
+        assert!(header.metadata.details().is_empty());
+        assert!(block.seal().unwrap().is_none());
+        assert!(block.finality().unwrap().is_none());
+        assert_eq!(crate::expected_block_hash(&block).unwrap(), hash);
+    }
 }
@@ -0,0 +1,345 @@
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+use crate::{
+    data::{Timestamp, ToTimestamp},
+    error::DataBaseError,
+    node::{MemPool, MemPoolError},
+    record::{Record, SignedRecord},
+    Hash,
+};
+
+use super::WrapperMut;
+
+table! {
+    pending_records {
+        id -> Integer,
+        hash -> Text,
+        record -> Text,
+        received -> BigInt,
+        origin -> Nullable<Text>,
+    }
+}
+
+/// A record waiting in a `SqliteMemPool`, with when and where it was received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRecord<X> {
+    pub record: SignedRecord<X>,
+    pub received: Timestamp,
+    pub origin: Option<String>,
+}
+
+/// A `MemPool` stored in a single SQLite file, so that pending records survive restarts.
+///
+/// Records are handed out in the order they were received. If a time-to-live is set, records
+/// older than it are no longer handed out and are deleted by `purge_expired`.
+pub struct SqliteMemPool<X> {
+    con: WrapperMut<SqliteConnection>,
+    url: String,
+    ttl: Option<u64>,
+    _data: PhantomData<X>,
+}
+
+impl<X> SqliteMemPool<X> {
+    /// Opens the pool stored at `url`, creating it if needed.
+    pub fn new(url: &str) -> Result<Self, MemPoolError> {
+        let mut con = SqliteConnection::establish(url)
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::ConnectionCannotEstablish))?;
+        Self::create_table(&mut con)?;
+        Ok(Self {
+            con: WrapperMut::new(con),
+            url: url.to_owned(),
+            ttl: None,
+            _data: PhantomData,
+        })
+    }
+
+    /// Expires records `secs` seconds after they were received.
+    pub fn with_ttl(mut self, secs: u64) -> Self {
+        self.ttl = Some(secs);
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn ttl(&self) -> Option<u64> {
+        self.ttl
+    }
+
+    fn create_table(con: &mut SqliteConnection) -> Result<(), MemPoolError> {
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS pending_records (
+            id INTEGER PRIMARY KEY,
+            hash TEXT UNIQUE,
+            record TEXT,
+            received BIGINT,
+            origin TEXT
+        )
+        ",
+        )
+        .execute(con)
+        .map_err(|_| MemPoolError::DataBaseError(DataBaseError::ConnectionFailed))?;
+
+        Ok(())
+    }
+
+    /// Returns the number of records in the pool, expired or not.
+    pub fn len(&self) -> Result<u64, MemPoolError> {
+        pending_records::table
+            .count()
+            .get_result::<i64>(self.con.get_mut())
+            .map(|c| c as u64)
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::NoSuchTable))
+    }
+
+    pub fn is_empty(&self) -> Result<bool, MemPoolError> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn contains(&self, hash: &Hash) -> Result<bool, MemPoolError> {
+        let count = pending_records::table
+            .filter(pending_records::hash.eq(hash.to_hex()))
+            .count()
+            .get_result::<i64>(self.con.get_mut())
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::NoSuchTable))?;
+        Ok(count > 0)
+    }
+
+    /// Removes the records with the given hashes and returns how many were in the pool.
+    pub fn remove<'a, I: IntoIterator<Item = &'a Hash>>(
+        &self,
+        hashes: I,
+    ) -> Result<usize, MemPoolError> {
+        let hashes = hashes.into_iter().map(|h| h.to_hex()).collect::<Vec<_>>();
+        diesel::delete(pending_records::table.filter(pending_records::hash.eq_any(hashes)))
+            .execute(self.con.get_mut())
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::ConnectionFailed))
+    }
+
+    /// Deletes the records that are older than the time-to-live at `now`.
+    ///
+    /// Returns the number of records deleted.
+    pub fn expire(&self, now: Timestamp) -> Result<usize, MemPoolError> {
+        let deadline = match self.deadline(now) {
+            Some(deadline) => deadline,
+            None => return Ok(0),
+        };
+        diesel::delete(pending_records::table.filter(pending_records::received.lt(deadline)))
+            .execute(self.con.get_mut())
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::ConnectionFailed))
+    }
+
+    /// Deletes the records that are older than the time-to-live.
+    pub fn purge_expired(&self) -> Result<usize, MemPoolError> {
+        self.expire(chrono::Utc::now().to_timestamp())
+    }
+
+    /// Records received before the returned time are expired at `now`.
+    fn deadline(&self, now: Timestamp) -> Option<i64> {
+        self.ttl.map(|ttl| now.secs().saturating_sub(ttl) as i64)
+    }
+}
+
+impl<X: Record + Serialize + for<'a> Deserialize<'a>> SqliteMemPool<X> {
+    /// Adds `record`, received at `received` from the peer `origin`, to the pool.
+    pub fn receive(
+        &mut self,
+        record: SignedRecord<X>,
+        origin: Option<&str>,
+        received: Timestamp,
+    ) -> Result<(), MemPoolError> {
+        if record.verify().is_err() || &record.record().hash() != record.hash() {
+            return Err(MemPoolError::VerificationFailed);
+        }
+        if self.contains(record.hash())? {
+            return Err(MemPoolError::Duplicate);
+        }
+        let json = serde_json::to_string(&record)
+            .map_err(|_| MemPoolError::SerdeError(crate::error::SerdeError::SerializationError))?;
+        diesel::insert_into(pending_records::table)
+            .values((
+                pending_records::hash.eq(record.hash().to_hex()),
+                pending_records::record.eq(json),
+                pending_records::received.eq(received.secs() as i64),
+                pending_records::origin.eq(origin),
+            ))
+            .execute(self.con.get_mut())
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        Ok(())
+    }
+
+    /// Returns the records that have not expired at `now`, oldest first.
+    pub fn pending(&self, now: Timestamp) -> Result<Vec<PendingRecord<X>>, MemPoolError> {
+        let deadline = self.deadline(now).unwrap_or(i64::MIN);
+        let rows = pending_records::table
+            .select((
+                pending_records::record,
+                pending_records::received,
+                pending_records::origin,
+            ))
+            .filter(pending_records::received.ge(deadline))
+            .order((pending_records::received, pending_records::id))
+            .load::<(String, i64, Option<String>)>(self.con.get_mut())
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::NoSuchTable))?;
+        rows.into_iter()
+            .map(|(record, received, origin)| {
+                let record = serde_json::from_str(&record).map_err(|_| {
+                    MemPoolError::SerdeError(crate::error::SerdeError::DeserializationError)
+                })?;
+                Ok(PendingRecord {
+                    record,
+                    received: Timestamp::from_secs(received as u64),
+                    origin,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the oldest record that has not expired at `now`, if any.
+    fn first_pending(&self, now: Timestamp) -> Result<Option<SignedRecord<X>>, MemPoolError> {
+        let deadline = self.deadline(now).unwrap_or(i64::MIN);
+        let row = pending_records::table
+            .select(pending_records::record)
+            .filter(pending_records::received.ge(deadline))
+            .order((pending_records::received, pending_records::id))
+            .first::<String>(self.con.get_mut())
+            .optional()
+            .map_err(|_| MemPoolError::DataBaseError(DataBaseError::NoSuchTable))?;
+        row.map(|record| {
+            serde_json::from_str(&record).map_err(|_| {
+                MemPoolError::SerdeError(crate::error::SerdeError::DeserializationError)
+            })
+        })
+        .transpose()
+    }
+}
+
+impl<X: Record + Serialize + for<'a> Deserialize<'a>> MemPool<X> for SqliteMemPool<X> {
+    fn records(&self) -> Result<Vec<SignedRecord<X>>, MemPoolError> {
+        let now = chrono::Utc::now().to_timestamp();
+        Ok(self.pending(now)?.into_iter().map(|p| p.record).collect())
+    }
+
+    fn poll(&mut self) -> Result<Option<SignedRecord<X>>, MemPoolError> {
+        let now = chrono::Utc::now().to_timestamp();
+        let first = match self.first_pending(now)? {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        self.remove([first.hash()])?;
+        Ok(Some(first))
+    }
+
+    fn append(&mut self, record: SignedRecord<X>) -> Result<(), MemPoolError> {
+        self.receive(record, None, chrono::Utc::now().to_timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{self as blockify, block::LocalInstance, inner::test_dir};
+
+    use blockify::{
+        chain::Chain,
+        data::{Metadata, Timestamp},
+        node::{MemPool, MemPoolError},
+        record::{Record, SignedRecord},
+        SqliteChain, SqliteMemPool,
+    };
+    use diesel::{Connection, RunQueryDsl, SqliteConnection};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Record, Clone, Serialize, Deserialize, PartialEq)]
+    struct Vote {
+        data: String,
+    }
+
+    fn vote(data: &str) -> SignedRecord<Vote> {
+        let keypair = crate::generate_ed25519_keypair();
+        Vote {
+            data: data.to_owned(),
+        }
+        .record(keypair, Metadata::empty())
+        .unwrap()
+    }
+
+    #[test]
+    fn test_survives_reopen() {
+        let url = test_dir("mempoolreopen");
+        let pool_url = format!("{url}mempool.db");
+        let (a, b) = (vote("a"), vote("b"));
+        {
+            let mut pool = SqliteMemPool::new(&pool_url).unwrap();
+            pool.receive(a.clone(), Some("peer-1"), Timestamp::from_secs(100))
+                .unwrap();
+            pool.receive(b.clone(), None, Timestamp::from_secs(200))
+                .unwrap();
+            assert!(matches!(
+                pool.append(a.clone()),
+                Err(MemPoolError::Duplicate)
+            ));
+        }
+
+        let mut pool = SqliteMemPool::<Vote>::new(&pool_url).unwrap().with_ttl(150);
+        let pending = pool.pending(Timestamp::from_secs(210)).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].record, a);
+        assert_eq!(pending[0].origin.as_deref(), Some("peer-1"));
+        assert_eq!(pending[0].received, Timestamp::from_secs(100));
+
+        // `a` is older than the time-to-live at 300
+        let pending = pool.pending(Timestamp::from_secs(300)).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pool.expire(Timestamp::from_secs(300)).unwrap(), 1);
+        assert_eq!(pool.len().unwrap(), 1);
+
+        // `b` is long expired by now
+        assert!(pool.poll().unwrap().is_none());
+        assert_eq!(pool.purge_expired().unwrap(), 1);
+        assert!(pool.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_removed_on_append() {
+        let url = test_dir("mempoolchain");
+        let pool_url = format!("{url}mempool.db");
+        let mut pool = SqliteMemPool::new(&pool_url).unwrap();
+        for data in ["a", "b", "c"] {
+            pool.append(vote(data)).unwrap();
+        }
+
+        let mut chain = SqliteChain::new(&url)
+            .expect("sqlite connection cannot be established")
+            .with_mempool(&pool)
+            .unwrap();
+
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(pool.poll().unwrap().unwrap());
+        let records = pool.records().unwrap();
+        block.push(records[0].clone());
+        chain.append(&block).unwrap();
+
+        let left = pool.records().unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0], records[1]);
+        assert!(chain.last_mempool_error().is_none());
+
+        // blocks are appended even if the pool cannot be updated
+        let mut con = SqliteConnection::establish(&pool_url).unwrap();
+        diesel::sql_query("DROP TABLE pending_records")
+            .execute(&mut con)
+            .unwrap();
+        let mut block = LocalInstance::new(Metadata::empty(), 0);
+        block.push(left[0].clone());
+        chain.append(&block).unwrap();
+        assert_eq!(chain.len().unwrap(), 2);
+        assert!(matches!(
+            chain.last_mempool_error(),
+            Some(MemPoolError::DataBaseError(_))
+        ));
+    }
+}
@@ -0,0 +1,116 @@
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+use crate::{
+    error::{DataBaseError, SerdeError},
+    state::StateError,
+    Hash,
+};
+
+use super::WrapperMut;
+
+table! {
+    checkpoints {
+        id -> Integer,
+        position -> BigInt,
+        hash -> Text,
+        state -> Text,
+    }
+}
+
+/// Snapshots of a state derived from a chain, stored in a single SQLite file and keyed by
+/// the position of the block they were taken after.
+pub struct SqliteCheckpoints<S> {
+    con: WrapperMut<SqliteConnection>,
+    url: String,
+    _data: PhantomData<S>,
+}
+
+impl<S> SqliteCheckpoints<S> {
+    /// Opens the checkpoints stored at `url`, creating the file if needed.
+    pub fn new(url: &str) -> Result<Self, StateError> {
+        let mut con = SqliteConnection::establish(url)
+            .map_err(|_| StateError::DataBaseError(DataBaseError::ConnectionCannotEstablish))?;
+        diesel::sql_query(
+            "
+        CREATE TABLE IF NOT EXISTS checkpoints (
+            id INTEGER PRIMARY KEY,
+            position BIGINT UNIQUE,
+            hash TEXT,
+            state TEXT
+        )
+        ",
+        )
+        .execute(&mut con)
+        .map_err(|_| StateError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        Ok(Self {
+            con: WrapperMut::new(con),
+            url: url.to_owned(),
+            _data: PhantomData,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the positions and block hashes of the checkpoints at or below `position`,
+    /// latest first.
+    pub fn at_or_below(&self, position: u64) -> Result<Vec<(u64, Hash)>, StateError> {
+        let rows = checkpoints::table
+            .select((checkpoints::position, checkpoints::hash))
+            .filter(checkpoints::position.le(position as i64))
+            .order(checkpoints::position.desc())
+            .load::<(i64, String)>(self.con.get_mut())
+            .map_err(|_| StateError::DataBaseError(DataBaseError::NoSuchTable))?;
+        rows.into_iter()
+            .map(|(position, hash)| {
+                let hash = hex::decode(hash)
+                    .map_err(|_| StateError::SerdeError(SerdeError::DeserializationError))?;
+                Ok((position as u64, hash.into()))
+            })
+            .collect()
+    }
+
+    /// Deletes the checkpoints above `position`, returning how many were deleted.
+    pub fn remove_above(&self, position: u64) -> Result<usize, StateError> {
+        diesel::delete(checkpoints::table.filter(checkpoints::position.gt(position as i64)))
+            .execute(self.con.get_mut())
+            .map_err(|_| StateError::DataBaseError(DataBaseError::ConnectionFailed))
+    }
+}
+
+impl<S: Serialize + for<'a> Deserialize<'a>> SqliteCheckpoints<S> {
+    /// Stores `state` as the state after the block at `position` with the given hash,
+    /// replacing any checkpoint at that position.
+    pub fn save(&self, position: u64, hash: &Hash, state: &S) -> Result<(), StateError> {
+        let state = serde_json::to_string(state)
+            .map_err(|_| StateError::SerdeError(SerdeError::SerializationError))?;
+        diesel::replace_into(checkpoints::table)
+            .values((
+                checkpoints::position.eq(position as i64),
+                checkpoints::hash.eq(hash.to_hex()),
+                checkpoints::state.eq(state),
+            ))
+            .execute(self.con.get_mut())
+            .map_err(|_| StateError::DataBaseError(DataBaseError::ConnectionFailed))?;
+        Ok(())
+    }
+
+    /// Returns the state stored at `position`, if any.
+    pub fn load(&self, position: u64) -> Result<Option<S>, StateError> {
+        let state = checkpoints::table
+            .select(checkpoints::state)
+            .filter(checkpoints::position.eq(position as i64))
+            .first::<String>(self.con.get_mut())
+            .optional()
+            .map_err(|_| StateError::DataBaseError(DataBaseError::NoSuchTable))?;
+        state
+            .map(|state| {
+                serde_json::from_str(&state)
+                    .map_err(|_| StateError::SerdeError(SerdeError::DeserializationError))
+            })
+            .transpose()
+    }
+}